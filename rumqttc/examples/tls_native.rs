@@ -33,6 +33,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 println!("Incoming = {i:?}");
             }
             Ok(Event::Outgoing(o)) => println!("Outgoing = {o:?}"),
+            Ok(event) => println!("Event = {event:?}"),
             Err(e) => {
                 println!("Error = {e:?}");
                 return Ok(());
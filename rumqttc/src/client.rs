@@ -2,8 +2,12 @@
 //! async eventloop.
 use std::time::Duration;
 
+use crate::dispatch::{Router, SharedRouter, DEFAULT_STREAM_CAPACITY};
 use crate::mqttbytes::{v4::*, QoS};
-use crate::{valid_filter, valid_topic, ConnectionError, Event, EventLoop, MqttOptions, Request};
+use crate::{
+    valid_filter, valid_topic, ConnectionError, Event, EventLoop, MqttOptions, NoticeFuture,
+    NoticeTx, PublishStream, Request,
+};
 
 use bytes::Bytes;
 use flume::{SendError, Sender, TrySendError};
@@ -42,6 +46,7 @@ fn from(e: TrySendError<Request>) -> Self {
 #[derive(Clone, Debug)]
 pub struct AsyncClient {
     request_tx: Sender<Request>,
+    router: SharedRouter<Publish>,
 }
 
 impl AsyncClient {
@@ -51,8 +56,9 @@ impl AsyncClient {
     pub fn new(options: MqttOptions, cap: usize) -> (AsyncClient, EventLoop) {
         let eventloop = EventLoop::new(options, cap);
         let request_tx = eventloop.requests_tx.clone();
+        let router = eventloop.router.clone();
 
-        let client = AsyncClient { request_tx };
+        let client = AsyncClient { request_tx, router };
 
         (client, eventloop)
     }
@@ -62,7 +68,10 @@ pub fn new(options: MqttOptions, cap: usize) -> (AsyncClient, EventLoop) {
     /// This is mostly useful for creating a test instance where you can
     /// listen on the corresponding receiver.
     pub fn from_senders(request_tx: Sender<Request>) -> AsyncClient {
-        AsyncClient { request_tx }
+        AsyncClient {
+            request_tx,
+            router: Router::shared(DEFAULT_STREAM_CAPACITY),
+        }
     }
 
     /// Sends a MQTT Publish to the `EventLoop`.
@@ -88,6 +97,31 @@ pub async fn publish<S, V>(
         Ok(())
     }
 
+    /// Sends a MQTT Publish to the `EventLoop` and returns a [`NoticeFuture`] which resolves once
+    /// the broker acknowledges it.
+    pub async fn publish_with_ack<S, V>(
+        &self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: V,
+    ) -> Result<NoticeFuture, ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let topic = topic.into();
+        let mut publish = Publish::new(&topic, qos, payload);
+        publish.retain = retain;
+        let (notice_tx, notice) = NoticeTx::new();
+        let publish = Request::PublishWithNotice(publish, notice_tx);
+        if !valid_topic(&topic) {
+            return Err(ClientError::Request(publish));
+        }
+        self.request_tx.send_async(publish).await?;
+        Ok(notice)
+    }
+
     /// Attempts to send a MQTT Publish to the `EventLoop`.
     pub fn try_publish<S, V>(
         &self,
@@ -159,6 +193,42 @@ pub async fn subscribe<S: Into<String>>(&self, topic: S, qos: QoS) -> Result<(),
         Ok(())
     }
 
+    /// Sends a MQTT Subscribe to the `EventLoop` and returns a [`NoticeFuture`] which resolves
+    /// once the broker acknowledges it.
+    pub async fn subscribe_with_ack<S: Into<String>>(
+        &self,
+        topic: S,
+        qos: QoS,
+    ) -> Result<NoticeFuture, ClientError> {
+        let subscribe = Subscribe::new(topic, qos);
+        self.send_subscribe_with_ack(subscribe).await
+    }
+
+    /// Sends a MQTT Subscribe to the `EventLoop` and returns a [`PublishStream`] of incoming
+    /// publishes matching the filter. Shared subscriptions are matched without their
+    /// `$share/{group}/` prefix.
+    pub async fn subscribe_stream<S: Into<String>>(
+        &self,
+        topic: S,
+        qos: QoS,
+    ) -> Result<PublishStream<Publish>, ClientError> {
+        let subscribe = Subscribe::new(topic, qos);
+        if !subscribe_has_valid_filters(&subscribe) {
+            return Err(ClientError::Request(subscribe.into()));
+        }
+
+        // Route before subscribing, so that retained publishes aren't missed
+        let stream = self.stream(&subscribe.filters[0].path);
+        self.request_tx.send_async(subscribe.into()).await?;
+        Ok(stream)
+    }
+
+    /// Returns a [`PublishStream`] of incoming publishes matching `filter` without subscribing,
+    /// e.g. for subscriptions restored by a resumed session
+    pub fn stream<S: AsRef<str>>(&self, filter: S) -> PublishStream<Publish> {
+        self.router.lock().unwrap().route(filter.as_ref())
+    }
+
     /// Attempts to send a MQTT Subscribe to the `EventLoop`
     pub fn try_subscribe<S: Into<String>>(&self, topic: S, qos: QoS) -> Result<(), ClientError> {
         let subscribe = Subscribe::new(topic, qos);
@@ -184,6 +254,31 @@ pub async fn subscribe_many<T>(&self, topics: T) -> Result<(), ClientError>
         Ok(())
     }
 
+    /// Sends a MQTT Subscribe for multiple topics to the `EventLoop` and returns a
+    /// [`NoticeFuture`] which resolves once the broker acknowledges it.
+    pub async fn subscribe_many_with_ack<T>(&self, topics: T) -> Result<NoticeFuture, ClientError>
+    where
+        T: IntoIterator<Item = SubscribeFilter>,
+    {
+        let subscribe = Subscribe::new_many(topics);
+        self.send_subscribe_with_ack(subscribe).await
+    }
+
+    async fn send_subscribe_with_ack(
+        &self,
+        subscribe: Subscribe,
+    ) -> Result<NoticeFuture, ClientError> {
+        let valid = subscribe_has_valid_filters(&subscribe);
+        let (notice_tx, notice) = NoticeTx::new();
+        let request = Request::SubscribeWithNotice(subscribe, notice_tx);
+        if !valid {
+            return Err(ClientError::Request(request));
+        }
+
+        self.request_tx.send_async(request).await?;
+        Ok(notice)
+    }
+
     /// Attempts to send a MQTT Subscribe for multiple topics to the `EventLoop`
     pub fn try_subscribe_many<T>(&self, topics: T) -> Result<(), ClientError>
     where
@@ -301,6 +396,31 @@ pub fn publish<S, V>(
         Ok(())
     }
 
+    /// Sends a MQTT Publish to the `EventLoop` and returns a [`NoticeFuture`] which can be
+    /// [`wait`](NoticeFuture::wait)ed on till the broker acknowledges it.
+    pub fn publish_with_ack<S, V>(
+        &self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: V,
+    ) -> Result<NoticeFuture, ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let topic = topic.into();
+        let mut publish = Publish::new(&topic, qos, payload);
+        publish.retain = retain;
+        let (notice_tx, notice) = NoticeTx::new();
+        let publish = Request::PublishWithNotice(publish, notice_tx);
+        if !valid_topic(&topic) {
+            return Err(ClientError::Request(publish));
+        }
+        self.client.request_tx.send(publish)?;
+        Ok(notice)
+    }
+
     pub fn try_publish<S, V>(
         &self,
         topic: S,
@@ -343,6 +463,17 @@ pub fn subscribe<S: Into<String>>(&self, topic: S, qos: QoS) -> Result<(), Clien
         Ok(())
     }
 
+    /// Sends a MQTT Subscribe to the `EventLoop` and returns a [`NoticeFuture`] which can be
+    /// [`wait`](NoticeFuture::wait)ed on till the broker acknowledges it.
+    pub fn subscribe_with_ack<S: Into<String>>(
+        &self,
+        topic: S,
+        qos: QoS,
+    ) -> Result<NoticeFuture, ClientError> {
+        let subscribe = Subscribe::new(topic, qos);
+        self.send_subscribe_with_ack(subscribe)
+    }
+
     /// Sends a MQTT Subscribe to the `EventLoop`
     pub fn try_subscribe<S: Into<String>>(&self, topic: S, qos: QoS) -> Result<(), ClientError> {
         self.client.try_subscribe(topic, qos)?;
@@ -363,6 +494,29 @@ pub fn subscribe_many<T>(&self, topics: T) -> Result<(), ClientError>
         Ok(())
     }
 
+    /// Sends a MQTT Subscribe for multiple topics to the `EventLoop` and returns a
+    /// [`NoticeFuture`] which can be [`wait`](NoticeFuture::wait)ed on till the broker
+    /// acknowledges it.
+    pub fn subscribe_many_with_ack<T>(&self, topics: T) -> Result<NoticeFuture, ClientError>
+    where
+        T: IntoIterator<Item = SubscribeFilter>,
+    {
+        let subscribe = Subscribe::new_many(topics);
+        self.send_subscribe_with_ack(subscribe)
+    }
+
+    fn send_subscribe_with_ack(&self, subscribe: Subscribe) -> Result<NoticeFuture, ClientError> {
+        let valid = subscribe_has_valid_filters(&subscribe);
+        let (notice_tx, notice) = NoticeTx::new();
+        let request = Request::SubscribeWithNotice(subscribe, notice_tx);
+        if !valid {
+            return Err(ClientError::Request(request));
+        }
+
+        self.client.request_tx.send(request)?;
+        Ok(notice)
+    }
+
     pub fn try_subscribe_many<T>(&self, topics: T) -> Result<(), ClientError>
     where
         T: IntoIterator<Item = SubscribeFilter>,
@@ -447,7 +601,10 @@ fn new(eventloop: EventLoop, runtime: Runtime) -> Connection {
     // Also we can implement IntoIter for this to make it easy to iterate over it
     #[must_use = "Connection should be iterated over a loop to make progress"]
     pub fn iter(&mut self) -> Iter<'_> {
-        Iter { connection: self }
+        Iter {
+            connection: self,
+            done: false,
+        }
     }
 
     /// Attempt to fetch an incoming [`Event`] on the [`EvenLoop`], returning an error
@@ -505,16 +662,24 @@ fn resolve_event(event: Result<Event, ConnectionError>) -> Option<Result<Event,
     }
 }
 
-/// Iterator which polls the `EventLoop` for connection progress
+/// Iterator which polls the `EventLoop` for connection progress. Ends when all the clients
+/// are dropped or after `ConnectionError::ReconnectAttemptsExhausted`
 pub struct Iter<'a> {
     connection: &'a mut Connection,
+    done: bool,
 }
 
 impl Iterator for Iter<'_> {
     type Item = Result<Event, ConnectionError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.connection.recv().ok()
+        if self.done {
+            return None;
+        }
+
+        let event = self.connection.recv().ok()?;
+        self.done = matches!(event, Err(ConnectionError::ReconnectAttemptsExhausted(_)));
+        Some(event)
     }
 }
 
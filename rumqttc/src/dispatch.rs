@@ -0,0 +1,179 @@
+use flume::r#async::RecvStream;
+use flume::{Sender, TrySendError};
+use futures_util::Stream;
+
+use std::fmt::{self, Debug, Formatter};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use crate::mqttbytes::matches;
+
+/// Default number of publishes held by a stream which isn't polled
+pub(crate) const DEFAULT_STREAM_CAPACITY: usize = 100;
+
+/// Streams of incoming publishes, by the filters they are interested in. Shared by the clients
+/// with their eventloop
+pub(crate) struct Router<P> {
+    routes: Vec<Route<P>>,
+    /// Publishes held by each stream
+    capacity: usize,
+}
+
+struct Route<P> {
+    filter: String,
+    tx: Sender<P>,
+    /// Publishes dropped because the stream was full
+    dropped: Arc<AtomicUsize>,
+}
+
+pub(crate) type SharedRouter<P> = Arc<Mutex<Router<P>>>;
+
+impl<P: Clone + 'static> Router<P> {
+    pub(crate) fn shared(capacity: usize) -> SharedRouter<P> {
+        Arc::new(Mutex::new(Router {
+            routes: Vec::new(),
+            capacity,
+        }))
+    }
+
+    /// Returns a stream of publishes matching `filter`
+    pub(crate) fn route(&mut self, filter: &str) -> PublishStream<P> {
+        let (tx, rx) = flume::bounded(self.capacity);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        self.routes.push(Route {
+            filter: strip_shared(filter).to_owned(),
+            tx,
+            dropped: dropped.clone(),
+        });
+
+        PublishStream {
+            stream: rx.into_stream(),
+            dropped,
+        }
+    }
+
+    /// Sends the publish to the streams of all the matching filters, forgetting the streams
+    /// which are dropped. Streams which are full miss the publish, as the eventloop doesn't
+    /// wait on streams which aren't polled
+    pub(crate) fn dispatch(&mut self, topic: &str, publish: &P) {
+        self.routes.retain(|route| {
+            if !matches(topic, &route.filter) {
+                return !route.tx.is_disconnected();
+            }
+
+            match route.tx.try_send(publish.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    route.dropped.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+impl<P> Debug for Router<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let filters: Vec<&str> = self.routes.iter().map(|r| r.filter.as_str()).collect();
+        f.debug_struct("Router").field("filters", &filters).finish()
+    }
+}
+
+/// Shared subscriptions, `$share/{group}/{filter}`, are delivered with topics matching `filter`
+fn strip_shared(filter: &str) -> &str {
+    match filter.strip_prefix("$share/") {
+        Some(shared) => shared.split_once('/').map_or(shared, |(_, filter)| filter),
+        None => filter,
+    }
+}
+
+/// Incoming publishes matching a filter, returned by `subscribe_stream` and `stream` methods of
+/// the async clients.
+///
+/// Publishes are routed while the eventloop is polled and are yielded as events by the
+/// eventloop as well. Dropping the stream stops the routing, but not the subscription. A stream
+/// holds up to `publish_stream_capacity` publishes of the options and publishes routed to it
+/// beyond that are dropped.
+pub struct PublishStream<P: 'static> {
+    stream: RecvStream<'static, P>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl<P: 'static> PublishStream<P> {
+    /// Number of publishes dropped because the stream was full
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<P: 'static> Stream for PublishStream<P> {
+    type Item = P;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<P>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+impl<P: 'static> Debug for PublishStream<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PublishStream")
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_util::StreamExt;
+
+    #[test]
+    fn publishes_are_routed_to_matching_filters() {
+        let router = Router::shared(DEFAULT_STREAM_CAPACITY);
+        let mut router = router.lock().unwrap();
+        let mut temperature = router.route("sensors/+/temp");
+        let mut shared = router.route("$share/group/sensors/#");
+        let dropped = router.route("sensors/#");
+        drop(dropped);
+
+        router.dispatch("sensors/1/temp", &1);
+        router.dispatch("sensors/1/humidity", &2);
+        assert_eq!(router.routes.len(), 2);
+        drop(router);
+
+        let next = |stream: &mut PublishStream<i32>| {
+            futures_util::FutureExt::now_or_never(stream.next()).flatten()
+        };
+
+        assert_eq!(next(&mut temperature), Some(1));
+        assert_eq!(next(&mut temperature), None);
+        assert_eq!(next(&mut shared), Some(1));
+        assert_eq!(next(&mut shared), Some(2));
+    }
+
+    #[test]
+    fn publishes_to_full_streams_are_dropped() {
+        let router = Router::shared(2);
+        let mut router = router.lock().unwrap();
+        let mut stream = router.route("sensors/#");
+        for i in 1..=5 {
+            router.dispatch("sensors/1/temp", &i);
+        }
+
+        // Full streams are still routed to once they are polled
+        assert_eq!(router.routes.len(), 1);
+        assert_eq!(stream.dropped(), 3);
+        let next = |stream: &mut PublishStream<i32>| {
+            futures_util::FutureExt::now_or_never(stream.next()).flatten()
+        };
+
+        assert_eq!(next(&mut stream), Some(1));
+        assert_eq!(next(&mut stream), Some(2));
+        router.dispatch("sensors/1/temp", &6);
+        assert_eq!(next(&mut stream), Some(6));
+        assert_eq!(next(&mut stream), None);
+    }
+}
@@ -0,0 +1,123 @@
+use std::fmt::{self, Debug, Formatter};
+
+use crate::Transport;
+
+/// Address of a broker along with the transport to connect with. Brokers other than the one
+/// `MqttOptions` is created with are added using `MqttOptions::add_fallback_endpoint`.
+#[derive(Clone)]
+pub struct BrokerEndpoint {
+    /// Host of the broker. Path of the socket file for `Transport::Unix` and the complete url
+    /// for websocket transports
+    pub host: String,
+    /// Port of the broker. Ignored for unix and websocket transports
+    pub port: u16,
+    pub transport: Transport,
+}
+
+impl BrokerEndpoint {
+    pub fn new<S: Into<String>>(host: S, port: u16, transport: Transport) -> BrokerEndpoint {
+        BrokerEndpoint {
+            host: host.into(),
+            port,
+            transport,
+        }
+    }
+
+    /// Creates an endpoint from a url like `mqtt://example.com:1883`. Transport is derived
+    /// from the scheme as in `MqttOptions::parse_url`. Encrypted transports use the system's
+    /// root certificates, set `transport` to use custom certificates
+    #[cfg(feature = "url")]
+    pub fn parse_url<S: Into<String>>(url: S) -> Result<BrokerEndpoint, crate::OptionError> {
+        let url = url::Url::parse(&url.into())?;
+        let host = url.host_str().unwrap_or_default().to_owned();
+
+        let (transport, default_port) = match url.scheme() {
+            #[cfg(feature = "use-rustls")]
+            "mqtts" | "ssl" => (Transport::tls_with_default_config(), 8883),
+            "mqtt" | "tcp" => (Transport::Tcp, 1883),
+            #[cfg(feature = "websocket")]
+            "ws" => (Transport::Ws, 8000),
+            #[cfg(all(feature = "use-rustls", feature = "websocket"))]
+            "wss" => (Transport::wss_with_default_config(), 8000),
+            _ => return Err(crate::OptionError::Scheme),
+        };
+
+        let port = url.port().unwrap_or(default_port);
+        let host = match &transport {
+            // Websocket transports connect to the complete url
+            #[cfg(feature = "websocket")]
+            Transport::Ws => url.to_string(),
+            #[cfg(all(feature = "use-rustls", feature = "websocket"))]
+            Transport::Wss(_) => url.to_string(),
+            _ => host,
+        };
+
+        Ok(BrokerEndpoint::new(host, port, transport))
+    }
+}
+
+impl Debug for BrokerEndpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BrokerEndpoint")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+/// Endpoint the eventloop connects to when there are fallback endpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EndpointSelection {
+    /// Connect to the primary endpoint, moving to the next endpoint in the list after every
+    /// failed attempt. Every reconnection after a dropped connection starts with the primary
+    /// endpoint again
+    #[default]
+    Failover,
+    /// Move to the next endpoint in the list after every failed attempt and after every dropped
+    /// connection
+    RoundRobin,
+}
+
+impl EndpointSelection {
+    /// Index of the endpoint to connect to after the connection to endpoint `current` failed or
+    /// dropped
+    pub(crate) fn next(&self, current: usize, count: usize, connected: bool) -> usize {
+        match self {
+            EndpointSelection::Failover if connected => 0,
+            _ => (current + 1) % count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn endpoints_are_selected_as_per_policy() {
+        let failover = EndpointSelection::Failover;
+        assert_eq!(failover.next(0, 3, false), 1);
+        assert_eq!(failover.next(2, 3, false), 0);
+        assert_eq!(failover.next(1, 3, true), 0);
+
+        let round_robin = EndpointSelection::RoundRobin;
+        assert_eq!(round_robin.next(1, 3, true), 2);
+        assert_eq!(round_robin.next(2, 3, false), 0);
+        assert_eq!(round_robin.next(0, 1, true), 0);
+    }
+
+    #[test]
+    #[cfg(all(feature = "url", feature = "websocket"))]
+    fn endpoints_are_parsed_from_urls() {
+        let endpoint = BrokerEndpoint::parse_url("mqtt://example.com").unwrap();
+        assert_eq!(
+            (endpoint.host.as_str(), endpoint.port),
+            ("example.com", 1883)
+        );
+        assert!(matches!(endpoint.transport, Transport::Tcp));
+
+        let endpoint = BrokerEndpoint::parse_url("ws://example.com:9001/mqtt").unwrap();
+        assert_eq!(endpoint.host, "ws://example.com:9001/mqtt");
+        assert!(matches!(endpoint.transport, Transport::Ws));
+    }
+}
@@ -1,16 +1,21 @@
 use crate::{framed::Network, Transport};
-use crate::{Incoming, MqttState, NetworkOptions, Packet, Request, StateError};
-use crate::{MqttOptions, Outgoing};
+use crate::{Incoming, MqttState, NetworkOptions, NoticeError, Packet, Request, StateError};
+use crate::{MqttOptions, OfflineMetrics, Outgoing};
 
+use crate::dispatch::{Router, SharedRouter};
 use crate::framed::AsyncReadWrite;
 use crate::mqttbytes::v4::*;
+use crate::offline;
+use crate::persistence;
 use flume::{bounded, Receiver, Sender};
 use tokio::net::{lookup_host, TcpSocket, TcpStream};
 use tokio::select;
 use tokio::time::{self, Instant, Sleep};
 
 use std::collections::VecDeque;
+use std::future::Future;
 use std::io;
+use std::mem;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::time::Duration;
@@ -57,6 +62,8 @@ pub enum ConnectionError {
     NotConnAck(Packet),
     #[error("Requests done")]
     RequestsDone,
+    #[error("Failed to reconnect after {0} attempts")]
+    ReconnectAttemptsExhausted(u32),
     #[cfg(feature = "websocket")]
     #[error("Invalid Url: {0}")]
     InvalidUrl(#[from] UrlError),
@@ -78,20 +85,45 @@ pub struct EventLoop {
     requests_rx: Receiver<Request>,
     /// Requests handle to send requests
     pub(crate) requests_tx: Sender<Request>,
+    /// Streams of incoming publishes created by the clients
+    pub(crate) router: SharedRouter<Publish>,
     /// Pending packets from last session
     pub pending: VecDeque<Request>,
     /// Network connection to the broker
     pub network: Option<Network>,
     /// Keep alive time
     keepalive_timeout: Option<Pin<Box<Sleep>>>,
+    /// Next reconnection attempt, 0 while connected
+    reconnect_attempt: u32,
+    /// Delay before next reconnection attempt, which was yielded with `Event::Reconnecting`
+    reconnect_delay: Option<Duration>,
+    /// Publishes dropped from the offline buffer
+    offline_metrics: OfflineMetrics,
+    /// Index of the broker in `MqttOptions::endpoints` to connect to
+    endpoint: usize,
     pub network_options: NetworkOptions,
 }
 
+/// Unacked publishes and pubrels of the last session, which are discarded when the session isn't
+/// resumed
+fn from_last_session(request: &Request) -> bool {
+    match request {
+        Request::Publish(publish) => publish.pkid != 0,
+        Request::PubRel(_) => true,
+        _ => false,
+    }
+}
+
 /// Events which can be yielded by the event loop
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
     Incoming(Incoming),
     Outgoing(Outgoing),
+    /// Next poll waits for `delay` and reconnects, as per `ReconnectStrategy`
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
 }
 
 impl EventLoop {
@@ -101,18 +133,39 @@ impl EventLoop {
     /// access and update `options`, `state` and `requests`.
     pub fn new(mqtt_options: MqttOptions, cap: usize) -> EventLoop {
         let (requests_tx, requests_rx) = bounded(cap);
-        let pending = VecDeque::new();
+        let mut pending = VecDeque::new();
         let max_inflight = mqtt_options.inflight;
         let manual_acks = mqtt_options.manual_acks;
 
+        let mut state = MqttState::new(max_inflight, manual_acks);
+        state.persistence = mqtt_options.persistence.clone();
+
+        // Requests persisted by the last run of the process are republished only if the session
+        // is resumed
+        let restored = match mqtt_options.clean_session {
+            true => state.clear_persistence().map(|_| Vec::new()),
+            false => state.restore(),
+        };
+
+        match restored {
+            Ok(requests) => pending.extend(requests),
+            Err(e) => error!("Failed to restore persisted requests: {e:?}"),
+        }
+
+        let router = Router::shared(mqtt_options.publish_stream_capacity());
         EventLoop {
             mqtt_options,
-            state: MqttState::new(max_inflight, manual_acks),
+            state,
             requests_tx,
             requests_rx,
             pending,
             network: None,
             keepalive_timeout: None,
+            reconnect_attempt: 0,
+            reconnect_delay: None,
+            offline_metrics: OfflineMetrics::default(),
+            router,
+            endpoint: 0,
             network_options: NetworkOptions::new(),
         }
     }
@@ -139,7 +192,51 @@ pub fn clean(&mut self) {
             }
         });
 
+        let persistence = self.state.persistence.as_ref();
+        for request in requests_in_channel.iter() {
+            persistence::enqueue(persistence, request);
+        }
         self.pending.extend(requests_in_channel);
+
+        if let Some(buffer) = self.mqtt_options.offline_buffer {
+            let dropped = offline::enforce(
+                &buffer,
+                &mut self.pending,
+                &mut self.offline_metrics,
+                self.state.persistence.as_ref(),
+            );
+            if let Err(e) = self.state.forget(dropped) {
+                error!("Failed to forget dropped publishes: {e:?}");
+            }
+        }
+    }
+
+    /// Publishes buffered while disconnected and publishes dropped as per the limits set with
+    /// `MqttOptions::set_offline_buffer`
+    pub fn offline_metrics(&self) -> OfflineMetrics {
+        self.offline_metrics.with_buffered(&self.pending)
+    }
+
+    /// Waits for `future`, buffering requests from the channel meanwhile if an offline buffer is
+    /// configured
+    async fn buffer_offline<F: Future>(&mut self, future: F) -> Result<F::Output, StateError> {
+        let buffer = match self.mqtt_options.offline_buffer {
+            Some(buffer) => buffer,
+            None => return Ok(future.await),
+        };
+
+        let (output, dropped) = offline::buffer_while(
+            future,
+            &buffer,
+            &self.requests_rx,
+            &mut self.pending,
+            &mut self.offline_metrics,
+            self.state.persistence.as_ref(),
+        )
+        .await;
+
+        self.state.forget(dropped)?;
+        Ok(output)
     }
 
     /// Yields Next notification or outgoing request and periodically pings
@@ -148,18 +245,56 @@ pub fn clean(&mut self) {
     /// **NOTE** Don't block this while iterating
     pub async fn poll(&mut self) -> Result<Event, ConnectionError> {
         if self.network.is_none() {
-            let (network, connack) = match time::timeout(
-                Duration::from_secs(self.network_options.connection_timeout()),
-                connect(&self.mqtt_options, self.network_options.clone()),
-            )
-            .await
-            {
-                Ok(inner) => inner?,
-                Err(_) => return Err(ConnectionError::NetworkTimeout),
+            if let Some(event) = self.backoff().await? {
+                return Ok(event);
+            }
+
+            let mut options = self.mqtt_options.clone();
+            options.set_endpoint(self.mqtt_options.endpoint(self.endpoint));
+            let network_options = self.network_options.clone();
+            let connect = time::timeout(
+                Duration::from_secs(network_options.connection_timeout()),
+                async move { connect(&options, network_options).await },
+            );
+
+            let connection = match self.buffer_offline(connect).await? {
+                Ok(inner) => inner,
+                Err(_) => Err(ConnectionError::NetworkTimeout),
             };
+
+            let (network, connack) = match connection {
+                Ok(v) => v,
+                Err(e) => {
+                    self.reconnect_attempt += 1;
+                    self.next_endpoint(false);
+                    return Err(e);
+                }
+            };
+
+            self.reconnect_attempt = 0;
             // Last session might contain packets which aren't acked. If it's a new session, clear the pending packets.
             if !connack.session_present {
-                self.pending.clear();
+                let buffered = self.mqtt_options.offline_buffer.is_some();
+                for request in mem::take(&mut self.pending) {
+                    // Requests buffered while offline weren't a part of the last session
+                    if buffered && !from_last_session(&request) {
+                        self.pending.push_back(request);
+                        continue;
+                    }
+
+                    if let Request::PublishWithNotice(_, notice)
+                    | Request::SubscribeWithNotice(_, notice) = request
+                    {
+                        notice.error(NoticeError::SessionReset);
+                    }
+                }
+                self.state.reset_notices();
+                self.state.clear_persistence()?;
+
+                // Buffered requests are still to be sent, in the new session
+                for request in self.pending.iter() {
+                    persistence::enqueue(self.state.persistence.as_ref(), request);
+                }
             }
             self.network = Some(network);
 
@@ -171,16 +306,69 @@ pub async fn poll(&mut self) -> Result<Event, ConnectionError> {
         }
 
         match self.select().await {
-            Ok(v) => Ok(v),
+            Ok(v) => {
+                if let Event::Incoming(Packet::Publish(publish)) = &v {
+                    self.dispatch(publish);
+                }
+
+                Ok(v)
+            }
             Err(e) => {
                 // MQTT requires that packets pending acknowledgement should be republished on session resume.
                 // Move pending messages from state to eventloop.
                 self.clean();
+                self.reconnect_attempt = 1;
+                self.next_endpoint(true);
                 Err(e)
             }
         }
     }
 
+    /// Routes an incoming publish to the streams of matching filters
+    fn dispatch(&self, publish: &Publish) {
+        self.router
+            .lock()
+            .unwrap()
+            .dispatch(&publish.topic, publish);
+    }
+
+    /// Moves to the endpoint to connect to after the connection to the current endpoint failed
+    /// or dropped
+    fn next_endpoint(&mut self, connected: bool) {
+        let count = self.mqtt_options.fallback_endpoints.len() + 1;
+        let selection = self.mqtt_options.endpoint_selection;
+        self.endpoint = selection.next(self.endpoint, count, connected);
+    }
+
+    /// Yields `Event::Reconnecting` before a reconnection attempt which has to wait as per
+    /// `ReconnectStrategy` and waits on the next poll. Nothing to do for first connection
+    async fn backoff(&mut self) -> Result<Option<Event>, ConnectionError> {
+        if self.reconnect_attempt == 0 {
+            return Ok(None);
+        }
+
+        // Delay is cleared only after waiting, so that cancelled polls wait again
+        if let Some(delay) = self.reconnect_delay {
+            self.buffer_offline(time::sleep(delay)).await?;
+            self.reconnect_delay = None;
+            return Ok(None);
+        }
+
+        let attempt = self.reconnect_attempt;
+        match self.mqtt_options.reconnect_strategy.delay(attempt) {
+            Some(delay) if delay.is_zero() => Ok(None),
+            Some(delay) => {
+                self.reconnect_delay = Some(delay);
+                Ok(Some(Event::Reconnecting { attempt, delay }))
+            }
+            None => {
+                // Polling again starts over
+                self.reconnect_attempt = 0;
+                Err(ConnectionError::ReconnectAttemptsExhausted(attempt - 1))
+            }
+        }
+    }
+
     /// Select on network and requests and generate keepalive pings when necessary
     async fn select(&mut self) -> Result<Event, ConnectionError> {
         let network = self.network.as_mut().unwrap();
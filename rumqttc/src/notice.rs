@@ -0,0 +1,97 @@
+use flume::{Receiver, Sender};
+
+use crate::mqttbytes::v4::SubscribeReasonCode as V4SubscribeReasonCode;
+use crate::v5::mqttbytes::v5::{
+    PubAckReason, PubCompReason, PubRecReason, SubscribeReasonCode as V5SubscribeReasonCode,
+};
+
+/// Errors while waiting for the broker to acknowledge a request
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum NoticeError {
+    #[error("Eventloop dropped the request before it was acknowledged")]
+    Recv,
+    #[error("Session was reset before the request was acknowledged")]
+    SessionReset,
+    #[error("Connection was lost before the subscription was acknowledged")]
+    Disconnected,
+    #[error("Dropped from the offline buffer before it could be sent")]
+    Dropped,
+    #[error("Subscription failed with reason code: {0:?}")]
+    V4Subscribe(V4SubscribeReasonCode),
+    #[error("Subscription failed with reason code: {0:?}")]
+    V5Subscribe(V5SubscribeReasonCode),
+    #[error("Publish failed with puback reason: {0:?}")]
+    V5PubAck(PubAckReason),
+    #[error("Publish failed with pubrec reason: {0:?}")]
+    V5PubRec(PubRecReason),
+    #[error("Publish failed with pubcomp reason: {0:?}")]
+    V5PubComp(PubCompReason),
+}
+
+/// Resolves once the broker acknowledges a request. Returned by the `*_with_ack` methods of the
+/// clients.
+///
+/// QoS 0 publishes resolve as soon as the eventloop picks them up, before they are written to
+/// the network, so they can still be lost to a failing connection. QoS 1 publishes resolve on
+/// `PubAck`, QoS 2 publishes on `PubComp` and subscriptions on `SubAck`. Unacknowledged publishes
+/// survive reconnections with persistent sessions and resolve once acknowledged in the new
+/// connection. Subscriptions aren't retransmitted and fail when the connection is lost.
+#[derive(Debug)]
+pub struct NoticeFuture(Receiver<Result<(), NoticeError>>);
+
+impl NoticeFuture {
+    /// Blocks the current thread till the acknowledgement is received
+    pub fn wait(self) -> Result<(), NoticeError> {
+        self.0.recv().unwrap_or(Err(NoticeError::Recv))
+    }
+
+    /// Waits asynchronously till the acknowledgement is received
+    pub async fn wait_async(self) -> Result<(), NoticeError> {
+        self.0.recv_async().await.unwrap_or(Err(NoticeError::Recv))
+    }
+}
+
+/// Sending half of a [`NoticeFuture`], carried along with the request into the state
+#[derive(Debug, Clone)]
+pub struct NoticeTx(Sender<Result<(), NoticeError>>);
+
+impl NoticeTx {
+    pub(crate) fn new() -> (NoticeTx, NoticeFuture) {
+        let (tx, rx) = flume::bounded(1);
+        (NoticeTx(tx), NoticeFuture(rx))
+    }
+
+    pub(crate) fn success(self) {
+        // Receiver might not be interested in the result anymore
+        let _ = self.0.try_send(Ok(()));
+    }
+
+    pub(crate) fn error(self, e: NoticeError) {
+        let _ = self.0.try_send(Err(e));
+    }
+}
+
+impl PartialEq for NoticeTx {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.same_channel(&other.0)
+    }
+}
+
+impl Eq for NoticeTx {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn notice_resolves_once() {
+        let (tx, future) = NoticeTx::new();
+        tx.clone().error(NoticeError::SessionReset);
+        tx.success();
+        assert_eq!(future.wait(), Err(NoticeError::SessionReset));
+
+        let (tx, future) = NoticeTx::new();
+        drop(tx);
+        assert_eq!(future.wait(), Err(NoticeError::Recv));
+    }
+}
@@ -0,0 +1,482 @@
+use bytes::Bytes;
+use flume::Receiver;
+use futures_util::future::{self, Either};
+
+use std::collections::VecDeque;
+use std::future::Future;
+
+use crate::persistence::{self, SharedPersistence};
+use crate::NoticeTx;
+
+/// Publishes to drop when the offline buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Drop the oldest buffered publishes to make room for new ones
+    DropOldest,
+    /// Drop new publishes, retaining the buffered ones
+    DropNewest,
+    /// Drop the oldest QoS 0 publishes and the oldest publishes once there are no more QoS 0
+    /// publishes in the buffer
+    DropQoS0First,
+}
+
+/// Limits on publishes buffered while the eventloop isn't connected to the broker. Set using
+/// `MqttOptions::set_offline_buffer`.
+///
+/// Without the limits, requests wait in the request channel till the eventloop reconnects,
+/// blocking publishers once the channel is full, and unacked publishes of the last connection
+/// are buffered without any limit. With the limits, the eventloop keeps pulling requests from
+/// the channel while reconnecting and drops publishes as per `drop_policy` once the buffer is
+/// full. Dropped publishes fail their notices with `NoticeError::Dropped`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OfflineBuffer {
+    /// Maximum number of buffered publishes
+    pub max_messages: usize,
+    /// Maximum size, in bytes, of buffered publishes
+    pub max_bytes: usize,
+    /// Publishes to drop when the buffer is full
+    pub drop_policy: DropPolicy,
+}
+
+impl OfflineBuffer {
+    pub fn new(max_messages: usize, max_bytes: usize, drop_policy: DropPolicy) -> OfflineBuffer {
+        OfflineBuffer {
+            max_messages,
+            max_bytes,
+            drop_policy,
+        }
+    }
+}
+
+/// Publishes waiting in the offline buffer and publishes dropped from it. Returned by
+/// `EventLoop::offline_metrics`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OfflineMetrics {
+    /// Number of publishes waiting to be sent
+    pub buffered_messages: usize,
+    /// Size of publishes waiting to be sent
+    pub buffered_bytes: usize,
+    /// Number of publishes dropped since the eventloop is created
+    pub dropped_messages: u64,
+    /// Size of publishes dropped since the eventloop is created
+    pub dropped_bytes: u64,
+}
+
+/// Publish in a pending request, as seen by the offline buffer
+#[derive(Clone, Copy)]
+pub(crate) struct BufferedPublish {
+    pub(crate) size: usize,
+    pub(crate) qos0: bool,
+    pub(crate) pkid: u16,
+}
+
+/// Requests of v4 and v5 eventloops which can be buffered
+pub(crate) trait OfflineRequest {
+    /// Publish in the request. `None` for the rest of the requests, which are never dropped
+    fn publish(&self) -> Option<BufferedPublish>;
+
+    /// Notice to fail when the request is dropped
+    fn notice(self) -> Option<NoticeTx>;
+
+    /// Publish in the request encoded for persistence, if it's a QoS 1 or 2 publish which waits
+    /// to be sent without a packet id
+    fn queued_packet(&self) -> Option<Bytes>;
+}
+
+impl OfflineMetrics {
+    /// Metrics of requests in `pending` along with the drop counters
+    pub(crate) fn with_buffered<R: OfflineRequest>(mut self, pending: &VecDeque<R>) -> Self {
+        for publish in pending.iter().filter_map(OfflineRequest::publish) {
+            self.buffered_messages += 1;
+            self.buffered_bytes += publish.size;
+        }
+
+        self
+    }
+}
+
+/// Drops publishes from `pending` till it is within the limits of the buffer. Returns packet ids
+/// of the dropped publishes which were already sent in an earlier connection, so that the state
+/// can forget them. Dropped publishes without packet ids are removed from `persistence`
+pub(crate) fn enforce<R: OfflineRequest>(
+    buffer: &OfflineBuffer,
+    pending: &mut VecDeque<R>,
+    metrics: &mut OfflineMetrics,
+    persistence: Option<&SharedPersistence>,
+) -> Vec<u16> {
+    Buffered::new(pending, persistence).enforce(buffer, metrics)
+}
+
+/// Waits for `future` while buffering requests from the channel into `pending`, so that
+/// publishers aren't blocked while the eventloop is reconnecting. Buffered publishes are queued
+/// in `persistence`. Returns packet ids of dropped publishes along with the output of the future
+pub(crate) async fn buffer_while<R, F>(
+    future: F,
+    buffer: &OfflineBuffer,
+    requests_rx: &Receiver<R>,
+    pending: &mut VecDeque<R>,
+    metrics: &mut OfflineMetrics,
+    persistence: Option<&SharedPersistence>,
+) -> (F::Output, Vec<u16>)
+where
+    R: OfflineRequest,
+    F: Future,
+{
+    let mut buffered = Buffered::new(pending, persistence);
+    let mut dropped = Vec::new();
+    let mut future = Box::pin(future);
+
+    loop {
+        let next_request = requests_rx.recv_async();
+        match future::select(future, next_request).await {
+            Either::Left((output, _)) => return (output, dropped),
+            Either::Right((Ok(request), f)) => {
+                buffered.push(request);
+                dropped.extend(buffered.enforce(buffer, metrics));
+                future = f;
+            }
+            // All the clients are dropped, nothing more to buffer
+            Either::Right((Err(_), f)) => {
+                drop(buffered);
+                return (f.await, dropped);
+            }
+        }
+    }
+}
+
+/// Publish along with its position in the pending requests
+type Positioned = (usize, BufferedPublish);
+
+/// Publishes in `pending` along with their totals, which are kept up to date as requests are
+/// buffered, so that the buffer isn't scanned for every request. Dropped publishes are left in
+/// place and removed from `pending` in batches, once there are as many of them as buffered
+/// publishes and when this is dropped, keeping the cost of buffering a request constant on
+/// average
+struct Buffered<'a, R: OfflineRequest> {
+    pending: &'a mut VecDeque<R>,
+    /// Storage in which buffered publishes are queued
+    persistence: Option<&'a SharedPersistence>,
+    /// Positions of buffered publishes in `pending`, oldest first. Publishes which are dropped
+    /// meanwhile are skipped when they are popped
+    publishes: VecDeque<Positioned>,
+    /// Positions of buffered QoS 0 publishes in `pending`, oldest first
+    qos0: VecDeque<Positioned>,
+    /// Whether the request at a position in `pending` is dropped
+    dropped: Vec<bool>,
+    dropped_count: usize,
+    /// Number of buffered publishes
+    count: usize,
+    /// Size of buffered publishes
+    size: usize,
+}
+
+impl<'a, R: OfflineRequest> Buffered<'a, R> {
+    fn new(
+        pending: &'a mut VecDeque<R>,
+        persistence: Option<&'a SharedPersistence>,
+    ) -> Buffered<'a, R> {
+        let mut buffered = Buffered {
+            pending,
+            persistence,
+            publishes: VecDeque::new(),
+            qos0: VecDeque::new(),
+            dropped: Vec::new(),
+            dropped_count: 0,
+            count: 0,
+            size: 0,
+        };
+
+        buffered.index();
+        buffered
+    }
+
+    fn index(&mut self) {
+        self.publishes.clear();
+        self.qos0.clear();
+        self.dropped = vec![false; self.pending.len()];
+        self.dropped_count = 0;
+        self.count = 0;
+        self.size = 0;
+
+        for position in 0..self.pending.len() {
+            if let Some(publish) = self.pending[position].publish() {
+                self.add(position, publish);
+            }
+        }
+    }
+
+    fn push(&mut self, request: R) {
+        if let Some(publish) = request.publish() {
+            self.add(self.pending.len(), publish);
+        }
+
+        persistence::enqueue(self.persistence, &request);
+        self.pending.push_back(request);
+        self.dropped.push(false);
+    }
+
+    fn add(&mut self, position: usize, publish: BufferedPublish) {
+        self.count += 1;
+        self.size += publish.size;
+        if publish.qos0 {
+            self.qos0.push_back((position, publish));
+        }
+
+        self.publishes.push_back((position, publish));
+    }
+
+    /// Drops publishes till the buffer is within its limits. Returns packet ids of dropped
+    /// publishes which were sent in an earlier connection
+    fn enforce(&mut self, buffer: &OfflineBuffer, metrics: &mut OfflineMetrics) -> Vec<u16> {
+        let mut forgotten = Vec::new();
+        while self.count > buffer.max_messages || self.size > buffer.max_bytes {
+            let Some((position, publish)) = self.victim(buffer.drop_policy) else {
+                break;
+            };
+
+            self.dropped[position] = true;
+            self.dropped_count += 1;
+            if publish.pkid != 0 {
+                forgotten.push(publish.pkid);
+            } else {
+                persistence::dequeue(self.persistence, &self.pending[position]);
+            }
+
+            self.count -= 1;
+            self.size -= publish.size;
+            metrics.dropped_messages += 1;
+            metrics.dropped_bytes += publish.size as u64;
+        }
+
+        if self.dropped_count > 0 && self.dropped_count >= self.count {
+            self.compact();
+        }
+
+        forgotten
+    }
+
+    /// Next publish to drop as per `policy`
+    fn victim(&mut self, policy: DropPolicy) -> Option<Positioned> {
+        let dropped = &self.dropped;
+        let live = |(position, _): &Positioned| !dropped[*position];
+
+        match policy {
+            DropPolicy::DropOldest => pop(&mut self.publishes, live, VecDeque::pop_front),
+            DropPolicy::DropNewest => pop(&mut self.publishes, live, VecDeque::pop_back),
+            DropPolicy::DropQoS0First => pop(&mut self.qos0, live, VecDeque::pop_front)
+                .or_else(|| pop(&mut self.publishes, live, VecDeque::pop_front)),
+        }
+    }
+
+    /// Removes dropped requests from `pending`, failing their notices
+    fn compact(&mut self) {
+        if self.dropped_count == 0 {
+            return;
+        }
+
+        let requests = std::mem::take(self.pending);
+        for (request, dropped) in requests.into_iter().zip(self.dropped.iter()) {
+            if !dropped {
+                self.pending.push_back(request);
+            } else if let Some(notice) = request.notice() {
+                notice.error(crate::NoticeError::Dropped);
+            }
+        }
+
+        self.index();
+    }
+}
+
+impl<R: OfflineRequest> Drop for Buffered<'_, R> {
+    fn drop(&mut self) {
+        self.compact();
+    }
+}
+
+/// Pops publishes from an end of `publishes` till it finds one which isn't dropped yet
+fn pop(
+    publishes: &mut VecDeque<Positioned>,
+    live: impl Fn(&Positioned) -> bool,
+    pop: fn(&mut VecDeque<Positioned>) -> Option<Positioned>,
+) -> Option<Positioned> {
+    while let Some(publish) = pop(publishes) {
+        if live(&publish) {
+            return Some(publish);
+        }
+    }
+
+    None
+}
+
+impl OfflineRequest for crate::Request {
+    fn publish(&self) -> Option<BufferedPublish> {
+        match self {
+            crate::Request::Publish(publish) | crate::Request::PublishWithNotice(publish, _) => {
+                Some(BufferedPublish {
+                    size: publish.size(),
+                    qos0: publish.qos == crate::QoS::AtMostOnce,
+                    pkid: publish.pkid,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn notice(self) -> Option<NoticeTx> {
+        match self {
+            crate::Request::PublishWithNotice(_, notice)
+            | crate::Request::SubscribeWithNotice(_, notice) => Some(notice),
+            _ => None,
+        }
+    }
+
+    fn queued_packet(&self) -> Option<Bytes> {
+        match self {
+            crate::Request::Publish(publish) | crate::Request::PublishWithNotice(publish, _)
+                if publish.pkid == 0 =>
+            {
+                crate::state::queued_packet(publish)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl OfflineRequest for crate::v5::Request {
+    fn publish(&self) -> Option<BufferedPublish> {
+        use crate::v5::mqttbytes::QoS;
+        use crate::v5::Request;
+
+        match self {
+            Request::Publish(publish) | Request::PublishWithNotice(publish, _) => {
+                Some(BufferedPublish {
+                    size: publish.size(),
+                    qos0: publish.qos == QoS::AtMostOnce,
+                    pkid: publish.pkid,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn notice(self) -> Option<NoticeTx> {
+        use crate::v5::Request;
+
+        match self {
+            Request::PublishWithNotice(_, notice) | Request::SubscribeWithNotice(_, notice) => {
+                Some(notice)
+            }
+            _ => None,
+        }
+    }
+
+    fn queued_packet(&self) -> Option<Bytes> {
+        use crate::v5::Request;
+
+        match self {
+            Request::Publish(publish) | Request::PublishWithNotice(publish, _)
+                if publish.pkid == 0 =>
+            {
+                crate::v5::state::queued_packet(publish)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Publish, QoS, Request};
+
+    fn publish(qos: QoS, payload: u8) -> Request {
+        Request::Publish(Publish::new("hello/world", qos, vec![payload]))
+    }
+
+    fn payloads(pending: &VecDeque<Request>) -> Vec<u8> {
+        pending
+            .iter()
+            .map(|request| match request {
+                Request::Publish(publish) => publish.payload[0],
+                request => panic!("Unexpected request = {request:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn publishes_are_dropped_as_per_policy() {
+        let requests = [
+            publish(QoS::AtLeastOnce, 1),
+            publish(QoS::AtMostOnce, 2),
+            publish(QoS::AtLeastOnce, 3),
+            publish(QoS::AtMostOnce, 4),
+        ];
+
+        let policies = [
+            (DropPolicy::DropOldest, vec![3, 4]),
+            (DropPolicy::DropNewest, vec![1, 2]),
+            (DropPolicy::DropQoS0First, vec![1, 3]),
+        ];
+
+        for (policy, retained) in policies {
+            let buffer = OfflineBuffer::new(2, usize::MAX, policy);
+            let mut pending = VecDeque::from(requests.to_vec());
+            let mut metrics = OfflineMetrics::default();
+
+            enforce(&buffer, &mut pending, &mut metrics, None);
+            assert_eq!(payloads(&pending), retained);
+            assert_eq!(metrics.dropped_messages, 2);
+        }
+    }
+
+    #[test]
+    fn publishes_buffered_one_by_one_are_dropped_as_per_policy() {
+        let policies = [
+            (DropPolicy::DropOldest, vec![7, 8]),
+            (DropPolicy::DropNewest, vec![1, 2]),
+            (DropPolicy::DropQoS0First, vec![5, 7]),
+        ];
+
+        for (policy, retained) in policies {
+            let buffer = OfflineBuffer::new(2, usize::MAX, policy);
+            let mut pending = VecDeque::new();
+            let mut metrics = OfflineMetrics::default();
+
+            let mut buffered = Buffered::new(&mut pending, None);
+            for i in 1..=8 {
+                let qos = if i % 2 == 0 {
+                    QoS::AtMostOnce
+                } else {
+                    QoS::AtLeastOnce
+                };
+                buffered.push(publish(qos, i));
+                buffered.enforce(&buffer, &mut metrics);
+            }
+            drop(buffered);
+
+            assert_eq!(payloads(&pending), retained, "{policy:?}");
+            assert_eq!(metrics.dropped_messages, 6);
+        }
+    }
+
+    #[test]
+    fn buffer_is_limited_by_size() {
+        let size = match publish(QoS::AtMostOnce, 1) {
+            Request::Publish(publish) => publish.size(),
+            _ => unreachable!(),
+        };
+
+        let buffer = OfflineBuffer::new(usize::MAX, 2 * size, DropPolicy::DropOldest);
+        let mut pending: VecDeque<_> = (1..=3).map(|i| publish(QoS::AtMostOnce, i)).collect();
+        pending.push_front(Request::PingReq(crate::PingReq));
+        let mut metrics = OfflineMetrics::default();
+
+        enforce(&buffer, &mut pending, &mut metrics, None);
+        assert_eq!(pending.len(), 3);
+        assert_eq!(metrics.dropped_bytes, size as u64);
+
+        let metrics = metrics.with_buffered(&pending);
+        assert_eq!(metrics.buffered_messages, 2);
+        assert_eq!(metrics.buffered_bytes, 2 * size);
+    }
+}
@@ -0,0 +1,458 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::offline::OfflineRequest;
+
+/// Storage for outgoing QoS 1 and 2 packets which aren't acked yet, so that they survive restarts
+/// of the process. Set with `MqttOptions::set_persistence`.
+///
+/// Eventloop queues a publish once it takes it from the request channel while disconnected, as it
+/// doesn't have a packet id yet. It stores the publish with its packet id before writing it to the
+/// network, replaces it with a pubrel once it receives a pubrec and removes it once the publish is
+/// completely acked. Queued publishes are removed once they get a packet id or are dropped from
+/// the offline buffer. Publishes still in the request channel aren't persisted, so set an offline
+/// buffer with `MqttOptions::set_offline_buffer` to persist publishes made during an outage.
+///
+/// Only QoS 1 and 2 publishes are persisted. Packets are encoded as on the wire, which makes the
+/// same storage usable with both v4 and v5 eventloops.
+///
+/// Methods are called synchronously from the eventloop, on the thread polling it, for every
+/// outgoing QoS 1 and 2 publish and its acks. Blocking in them delays all the traffic of the
+/// connection.
+pub trait Persistence: Debug + Send {
+    /// Stores an encoded packet, replacing the packet which is already stored with `pkid`
+    fn store(&mut self, pkid: u16, packet: Bytes) -> io::Result<()>;
+
+    /// Removes the packet stored with `pkid`, if any
+    fn remove(&mut self, pkid: u16) -> io::Result<()>;
+
+    /// Queues an encoded publish which waits to be sent without a packet id
+    fn enqueue(&mut self, packet: Bytes) -> io::Result<()>;
+
+    /// Removes a queued publish which is equal to `packet`, if any. Equal publishes are
+    /// interchangeable, so it doesn't matter which one of them is removed
+    fn dequeue(&mut self, packet: &[u8]) -> io::Result<()>;
+
+    /// Returns all the stored packets, in the order they were first stored
+    fn load(&mut self) -> io::Result<Vec<Bytes>>;
+
+    /// Returns all the queued publishes, oldest first
+    fn load_queued(&mut self) -> io::Result<Vec<Bytes>>;
+
+    /// Removes all the stored packets and queued publishes
+    fn clear(&mut self) -> io::Result<()>;
+}
+
+/// Persistence shared by the options with the state of the eventloop
+pub(crate) type SharedPersistence = Arc<Mutex<dyn Persistence>>;
+
+/// Packet id with which queued publishes are encoded, as the encoding requires one. It's reset
+/// when they are restored
+pub(crate) const QUEUED_PKID: u16 = 1;
+
+/// Queues the publish in `request` if it waits to be sent without a packet id. Failures are only
+/// logged, as the request is buffered in memory regardless
+pub(crate) fn enqueue<R: OfflineRequest>(persistence: Option<&SharedPersistence>, request: &R) {
+    let Some(persistence) = persistence else {
+        return;
+    };
+
+    if let Some(packet) = request.queued_packet() {
+        if let Err(e) = persistence.lock().unwrap().enqueue(packet) {
+            error!("Failed to persist queued publish: {e:?}");
+        }
+    }
+}
+
+/// Removes the publish in `request` from the queue, once it is dropped
+pub(crate) fn dequeue<R: OfflineRequest>(persistence: Option<&SharedPersistence>, request: &R) {
+    let Some(persistence) = persistence else {
+        return;
+    };
+
+    if let Some(packet) = request.queued_packet() {
+        if let Err(e) = persistence.lock().unwrap().dequeue(&packet) {
+            error!("Failed to remove dropped publish from persistence: {e:?}");
+        }
+    }
+}
+
+const STORE: u8 = 1;
+const REMOVE: u8 = 2;
+const ENQUEUE: u8 = 3;
+const DEQUEUE: u8 = 4;
+/// Journal is compacted once it is this many times the size of the live packets
+const COMPACTION_RATIO: u64 = 4;
+/// Journals smaller than this aren't compacted, however small the live packets are
+const MIN_COMPACTION_SIZE: u64 = 64 * 1024;
+
+/// [`Persistence`] backed by a journal file. Every change is appended to the journal and synced
+/// to the disk before the eventloop moves on. Journal is compacted when it is opened and once it
+/// grows to a few times the size of the live packets, and truncated whenever there are no
+/// unacked packets or queued publishes.
+///
+/// Writes and syncs are blocking file system calls made on the thread polling the eventloop,
+/// costing a disk flush for every QoS 1 and 2 publish and each of its acks. Poll the eventloop
+/// on a thread or runtime of its own when other tasks shouldn't wait on the disk.
+/// Queued publishes along with their sequence numbers, which identify them in the journal
+type Queue = VecDeque<(u64, Bytes)>;
+
+#[derive(Debug)]
+pub struct FilePersistence {
+    path: PathBuf,
+    journal: File,
+    packets: Vec<(u16, Bytes)>,
+    queued: Queue,
+    /// Sequence number of the next queued publish
+    next_seq: u64,
+    /// Size of the journal
+    journal_size: u64,
+    /// Size of records of the live packets, which is the size of the journal after compaction
+    live_size: u64,
+}
+
+impl FilePersistence {
+    /// Opens the journal at `path`, creating it if it doesn't exist
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FilePersistence> {
+        let path = path.as_ref().to_path_buf();
+        let (packets, queued) = match File::open(&path) {
+            Ok(mut file) => {
+                let mut journal = Vec::new();
+                file.read_to_end(&mut journal)?;
+                replay(Bytes::from(journal))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (Vec::new(), VecDeque::new()),
+            Err(e) => return Err(e),
+        };
+
+        let journal = compact(&path, &packets, &queued)?;
+        let live_size = packets
+            .iter()
+            .map(|(_, packet)| record_size(packet))
+            .sum::<u64>()
+            + queued
+                .iter()
+                .map(|(_, packet)| queued_size(packet))
+                .sum::<u64>();
+        let next_seq = queued.back().map_or(0, |(seq, _)| seq + 1);
+        Ok(FilePersistence {
+            path,
+            journal,
+            packets,
+            queued,
+            next_seq,
+            journal_size: live_size,
+            live_size,
+        })
+    }
+
+    /// Path of the journal
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        if self.journal_size >= MIN_COMPACTION_SIZE
+            && self.journal_size >= COMPACTION_RATIO * self.live_size
+        {
+            // Record is already applied to the live packets, which are all that is written
+            self.journal = compact(&self.path, &self.packets, &self.queued)?;
+            self.journal_size = self.live_size;
+            return Ok(());
+        }
+
+        self.journal.write_all(record)?;
+        self.journal_size += record.len() as u64;
+        self.journal.sync_data()
+    }
+}
+
+impl Persistence for FilePersistence {
+    fn store(&mut self, pkid: u16, packet: Bytes) -> io::Result<()> {
+        let record = store_record(pkid, &packet);
+        self.live_size += record_size(&packet);
+        match self.packets.iter_mut().find(|(id, _)| *id == pkid) {
+            Some((_, stored)) => {
+                self.live_size -= record_size(stored);
+                *stored = packet;
+            }
+            None => self.packets.push((pkid, packet)),
+        }
+
+        self.append(&record)
+    }
+
+    fn remove(&mut self, pkid: u16) -> io::Result<()> {
+        let index = match self.packets.iter().position(|(id, _)| *id == pkid) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+
+        let (_, packet) = self.packets.remove(index);
+        self.live_size -= record_size(&packet);
+        if self.packets.is_empty() && self.queued.is_empty() {
+            return self.clear();
+        }
+
+        let mut record = BytesMut::with_capacity(3);
+        record.put_u8(REMOVE);
+        record.put_u16(pkid);
+        self.append(&record)
+    }
+
+    fn enqueue(&mut self, packet: Bytes) -> io::Result<()> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let record = enqueue_record(seq, &packet);
+        self.live_size += queued_size(&packet);
+        self.queued.push_back((seq, packet));
+        self.append(&record)
+    }
+
+    fn dequeue(&mut self, packet: &[u8]) -> io::Result<()> {
+        let index = match self.queued.iter().position(|(_, queued)| queued == packet) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+
+        // `unwrap` fine as `index` is from `position`
+        let (seq, packet) = self.queued.remove(index).unwrap();
+        self.live_size -= queued_size(&packet);
+        if self.packets.is_empty() && self.queued.is_empty() {
+            return self.clear();
+        }
+
+        let mut record = BytesMut::with_capacity(9);
+        record.put_u8(DEQUEUE);
+        record.put_u64(seq);
+        self.append(&record)
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Bytes>> {
+        Ok(self
+            .packets
+            .iter()
+            .map(|(_, packet)| packet.clone())
+            .collect())
+    }
+
+    fn load_queued(&mut self) -> io::Result<Vec<Bytes>> {
+        Ok(self
+            .queued
+            .iter()
+            .map(|(_, packet)| packet.clone())
+            .collect())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.packets.clear();
+        self.queued.clear();
+        self.live_size = 0;
+        self.journal_size = 0;
+        self.journal.set_len(0)?;
+        self.journal.sync_data()
+    }
+}
+
+/// Rewrites the journal with just the live packets, atomically replacing the old one. Returns
+/// the new journal, opened for appending
+fn compact(path: &Path, packets: &[(u16, Bytes)], queued: &Queue) -> io::Result<File> {
+    let compacted = path.with_extension("compact");
+    let mut file = File::create(&compacted)?;
+    for (pkid, packet) in packets.iter() {
+        file.write_all(&store_record(*pkid, packet))?;
+    }
+    for (seq, packet) in queued.iter() {
+        file.write_all(&enqueue_record(*seq, packet))?;
+    }
+    file.sync_all()?;
+    fs::rename(&compacted, path)?;
+
+    OpenOptions::new().append(true).open(path)
+}
+
+fn record_size(packet: &Bytes) -> u64 {
+    7 + packet.len() as u64
+}
+
+fn store_record(pkid: u16, packet: &Bytes) -> BytesMut {
+    let mut record = BytesMut::with_capacity(7 + packet.len());
+    record.put_u8(STORE);
+    record.put_u16(pkid);
+    record.put_u32(packet.len() as u32);
+    record.extend_from_slice(packet);
+    record
+}
+
+fn queued_size(packet: &Bytes) -> u64 {
+    13 + packet.len() as u64
+}
+
+fn enqueue_record(seq: u64, packet: &Bytes) -> BytesMut {
+    let mut record = BytesMut::with_capacity(13 + packet.len());
+    record.put_u8(ENQUEUE);
+    record.put_u64(seq);
+    record.put_u32(packet.len() as u32);
+    record.extend_from_slice(packet);
+    record
+}
+
+/// Reads a packet prefixed by its length, if it is written completely
+fn read_packet(journal: &mut Bytes) -> Option<Bytes> {
+    if journal.len() < 4 {
+        return None;
+    }
+
+    let len = journal.get_u32() as usize;
+    if journal.len() < len {
+        return None;
+    }
+
+    Some(journal.split_to(len))
+}
+
+/// Replays the journal into live packets and queued publishes. A partially written record at the
+/// end, due to a crash in the middle of a write, is ignored
+fn replay(mut journal: Bytes) -> (Vec<(u16, Bytes)>, Queue) {
+    let mut packets: Vec<(u16, Bytes)> = Vec::new();
+    let mut queued = Queue::new();
+
+    while let Some(&op) = journal.first() {
+        journal.advance(1);
+        match op {
+            STORE if journal.len() >= 2 => {
+                let pkid = journal.get_u16();
+                let Some(packet) = read_packet(&mut journal) else {
+                    break;
+                };
+
+                match packets.iter_mut().find(|(id, _)| *id == pkid) {
+                    Some((_, stored)) => *stored = packet,
+                    None => packets.push((pkid, packet)),
+                }
+            }
+            REMOVE if journal.len() >= 2 => {
+                let pkid = journal.get_u16();
+                packets.retain(|(id, _)| *id != pkid);
+            }
+            ENQUEUE if journal.len() >= 8 => {
+                let seq = journal.get_u64();
+                let Some(packet) = read_packet(&mut journal) else {
+                    break;
+                };
+
+                queued.push_back((seq, packet));
+            }
+            DEQUEUE if journal.len() >= 8 => {
+                let seq = journal.get_u64();
+                queued.retain(|(queued, _)| *queued != seq);
+            }
+            STORE | REMOVE | ENQUEUE | DEQUEUE => break,
+            _ => {
+                warn!("Unknown record in persistence journal. Ignoring rest of the journal");
+                break;
+            }
+        }
+    }
+
+    (packets, queued)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn journal_is_replayed_after_reopening() {
+        let path = std::env::temp_dir().join(format!("rumqttc-journal-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut persistence = FilePersistence::open(&path).unwrap();
+        persistence.store(1, Bytes::from_static(b"one")).unwrap();
+        persistence.store(2, Bytes::from_static(b"two")).unwrap();
+        persistence.store(3, Bytes::from_static(b"three")).unwrap();
+        persistence.store(2, Bytes::from_static(b"rel")).unwrap();
+        persistence.remove(1).unwrap();
+        drop(persistence);
+
+        // Simulate a crash in the middle of writing a record
+        let mut journal = OpenOptions::new().append(true).open(&path).unwrap();
+        journal.write_all(&[STORE, 0, 4, 0, 0]).unwrap();
+
+        let mut persistence = FilePersistence::open(&path).unwrap();
+        let packets = persistence.load().unwrap();
+        assert_eq!(packets, vec![Bytes::from("rel"), Bytes::from("three")]);
+
+        persistence.remove(2).unwrap();
+        persistence.remove(3).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn queued_publishes_are_replayed_after_reopening() {
+        let path = std::env::temp_dir().join(format!("rumqttc-queue-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut persistence = FilePersistence::open(&path).unwrap();
+        persistence.enqueue(Bytes::from_static(b"one")).unwrap();
+        persistence.enqueue(Bytes::from_static(b"two")).unwrap();
+        persistence.enqueue(Bytes::from_static(b"one")).unwrap();
+        persistence.store(1, Bytes::from_static(b"sent")).unwrap();
+        persistence.dequeue(b"one").unwrap();
+        drop(persistence);
+
+        let mut persistence = FilePersistence::open(&path).unwrap();
+        assert_eq!(persistence.load().unwrap(), vec![Bytes::from("sent")]);
+        let queued = persistence.load_queued().unwrap();
+        assert_eq!(queued, vec![Bytes::from("two"), Bytes::from("one")]);
+
+        // Sequence numbers continue after the replayed ones
+        persistence.enqueue(Bytes::from_static(b"three")).unwrap();
+        persistence.dequeue(b"two").unwrap();
+        drop(persistence);
+
+        let mut persistence = FilePersistence::open(&path).unwrap();
+        let queued = persistence.load_queued().unwrap();
+        assert_eq!(queued, vec![Bytes::from("one"), Bytes::from("three")]);
+
+        persistence.remove(1).unwrap();
+        persistence.dequeue(b"one").unwrap();
+        persistence.dequeue(b"three").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn journal_is_compacted_while_packets_are_live() {
+        let path = std::env::temp_dir().join(format!("rumqttc-compaction-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        // Packet 1 stays unacked while others are stored and removed
+        let mut persistence = FilePersistence::open(&path).unwrap();
+        persistence.store(1, Bytes::from(vec![1; 100])).unwrap();
+        for pkid in 2..1000 {
+            persistence.store(pkid, Bytes::from(vec![2; 100])).unwrap();
+            persistence.remove(pkid).unwrap();
+        }
+
+        assert!(fs::metadata(&path).unwrap().len() <= MIN_COMPACTION_SIZE);
+        persistence.store(2, Bytes::from(vec![2; 100])).unwrap();
+        drop(persistence);
+
+        let mut persistence = FilePersistence::open(&path).unwrap();
+        let packets = persistence.load().unwrap();
+        assert_eq!(
+            packets,
+            vec![Bytes::from(vec![1; 100]), Bytes::from(vec![2; 100])]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
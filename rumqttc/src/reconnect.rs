@@ -0,0 +1,105 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// How the eventloop waits before reconnecting to the broker after a connection error. Polling
+/// the eventloop after an error reconnects, this decides how long that poll waits first.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReconnectStrategy {
+    /// Reconnect on the next poll without any delay
+    #[default]
+    Immediate,
+    /// Wait `initial_delay` before the first attempt and double the delay after every failed
+    /// attempt, upto `max_delay`. Next poll after an error yields `Event::Reconnecting` with
+    /// the delay and the poll after that waits before connecting.
+    ExponentialBackoff {
+        initial_delay: Duration,
+        max_delay: Duration,
+        /// Fraction of the delay, between 0 and 1, which is randomized so that clients which
+        /// lost connection together don't reconnect together
+        jitter: f64,
+        /// Consecutive failed attempts after which polling returns
+        /// `ConnectionError::ReconnectAttemptsExhausted`. Unlimited when `None`
+        max_attempts: Option<u32>,
+    },
+}
+
+impl ReconnectStrategy {
+    /// Exponential backoff with 20% jitter and unlimited attempts
+    pub fn exponential_backoff(initial_delay: Duration, max_delay: Duration) -> ReconnectStrategy {
+        ReconnectStrategy::ExponentialBackoff {
+            initial_delay,
+            max_delay,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+
+    /// Delay before reconnection attempt `attempt`, starting with 1. `None` when there are no
+    /// more attempts
+    pub(crate) fn delay(&self, attempt: u32) -> Option<Duration> {
+        let (initial_delay, max_delay, jitter, max_attempts) = match *self {
+            ReconnectStrategy::Immediate => return Some(Duration::ZERO),
+            ReconnectStrategy::ExponentialBackoff {
+                initial_delay,
+                max_delay,
+                jitter,
+                max_attempts,
+            } => (initial_delay, max_delay, jitter, max_attempts),
+        };
+
+        if matches!(max_attempts, Some(max) if attempt > max) {
+            return None;
+        }
+
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = initial_delay.saturating_mul(1 << exponent).min(max_delay);
+
+        // Randomized part of the delay is taken off, so that `max_delay` is never exceeded
+        let jitter = jitter.clamp(0.0, 1.0) * random();
+        Some(delay.mul_f64(1.0 - jitter))
+    }
+}
+
+/// Random number in `[0, 1)`. Good enough to spread reconnections, without a dependency on a
+/// random number generator
+fn random() -> f64 {
+    let value = RandomState::new().build_hasher().finish();
+    (value >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_upto_max_delay() {
+        let strategy = ReconnectStrategy::ExponentialBackoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            jitter: 0.0,
+            max_attempts: Some(5),
+        };
+
+        let delays: Vec<_> = (1..=6).map(|attempt| strategy.delay(attempt)).collect();
+        let secs = |s| Some(Duration::from_secs(s));
+        assert_eq!(delays, [secs(1), secs(2), secs(4), secs(8), secs(10), None]);
+
+        assert_eq!(
+            ReconnectStrategy::Immediate.delay(100),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn jitter_shortens_delay() {
+        let strategy =
+            ReconnectStrategy::exponential_backoff(Duration::from_secs(4), Duration::from_secs(4));
+
+        for attempt in 1..100 {
+            let delay = strategy.delay(attempt).unwrap();
+            assert!(delay <= Duration::from_secs(4), "{delay:?}");
+            assert!(delay >= Duration::from_millis(3200), "{delay:?}");
+        }
+    }
+}
@@ -1,9 +1,11 @@
-use crate::{Event, Incoming, Outgoing, Request};
+use crate::persistence::{SharedPersistence, QUEUED_PKID};
+use crate::{Event, Incoming, NoticeError, NoticeTx, Outgoing, Request};
 
 use crate::mqttbytes::v4::*;
 use crate::mqttbytes::{self, *};
+use bytes::{Bytes, BytesMut};
 use fixedbitset::FixedBitSet;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::{io, time::Instant};
 
 /// Errors during state handling
@@ -68,10 +70,18 @@ pub struct MqttState {
     pub(crate) incoming_pub: FixedBitSet,
     /// Last collision due to broker not acking in order
     pub collision: Option<Publish>,
+    /// Notices of outgoing QoS 1, 2 publishes which aren't acked yet
+    pub(crate) outgoing_pub_notice: Vec<Option<NoticeTx>>,
+    /// Notices of subscriptions which aren't acked yet
+    pub(crate) outgoing_sub_notice: HashMap<u16, NoticeTx>,
+    /// Notice of the publish in collision
+    pub(crate) collision_notice: Option<NoticeTx>,
     /// Buffered incoming packets
     pub events: VecDeque<Event>,
     /// Indicates if acknowledgements should be send immediately
     pub manual_acks: bool,
+    /// Storage of unacked publishes which survives restarts
+    pub(crate) persistence: Option<SharedPersistence>,
 }
 
 impl MqttState {
@@ -93,9 +103,13 @@ pub fn new(max_inflight: u16, manual_acks: bool) -> Self {
             outgoing_rel: FixedBitSet::with_capacity(max_inflight as usize + 1),
             incoming_pub: FixedBitSet::with_capacity(u16::MAX as usize + 1),
             collision: None,
+            outgoing_pub_notice: vec![None; max_inflight as usize + 1],
+            outgoing_sub_notice: HashMap::new(),
+            collision_notice: None,
             // TODO: Optimize these sizes later
             events: VecDeque::with_capacity(100),
             manual_acks,
+            persistence: None,
         }
     }
 
@@ -123,12 +137,137 @@ pub fn clean(&mut self) -> Vec<Request> {
         // remove packet ids of incoming qos2 publishes
         self.incoming_pub.clear();
 
+        // subscriptions aren't retransmitted, so their acks won't arrive in the next connection
+        for (_, notice) in self.outgoing_sub_notice.drain() {
+            notice.error(NoticeError::Disconnected);
+        }
+
         self.await_pingresp = false;
         self.collision_ping_count = 0;
         self.inflight = 0;
         pending
     }
 
+    /// Fails notices of all the requests which are waiting for acknowledgements. Used when the
+    /// broker doesn't resume the session and unacked requests are dropped
+    pub fn reset_notices(&mut self) {
+        let notices = self
+            .outgoing_pub_notice
+            .iter_mut()
+            .filter_map(Option::take)
+            .chain(self.outgoing_sub_notice.drain().map(|(_, notice)| notice))
+            .chain(self.collision_notice.take());
+
+        for notice in notices {
+            notice.error(NoticeError::SessionReset);
+        }
+    }
+
+    /// Returns requests persisted by an earlier run of the process, to be republished
+    pub(crate) fn restore(&mut self) -> Result<Vec<Request>, StateError> {
+        let persistence = match &self.persistence {
+            Some(persistence) => persistence.clone(),
+            None => return Ok(Vec::new()),
+        };
+
+        let mut persistence = persistence.lock().unwrap();
+        let packets = persistence.load()?;
+        let mut requests = Vec::with_capacity(packets.len());
+        for packet in packets {
+            let mut packet = BytesMut::from(&packet[..]);
+            let request = match Packet::read(&mut packet, usize::MAX)? {
+                // Packet ids beyond inflight limit, probably from a run with larger limit, can't
+                // be tracked. Such publishes are republished with new packet ids
+                Packet::Publish(mut publish) => {
+                    if publish.pkid > self.max_inflight {
+                        persistence.remove(publish.pkid)?;
+                        publish.pkid = 0;
+                        if let Some(packet) = queued_packet(&publish) {
+                            persistence.enqueue(packet)?;
+                        }
+                    }
+                    Request::Publish(publish)
+                }
+                Packet::PubRel(pubrel) if pubrel.pkid <= self.max_inflight => {
+                    Request::PubRel(pubrel)
+                }
+                packet => {
+                    warn!("Ignoring persisted packet = {:?}", packet);
+                    continue;
+                }
+            };
+
+            requests.push(request);
+        }
+
+        // Queued publishes are sent after the ones which already have packet ids
+        for packet in persistence.load_queued()? {
+            let mut packet = BytesMut::from(&packet[..]);
+            match Packet::read(&mut packet, usize::MAX)? {
+                Packet::Publish(mut publish) => {
+                    publish.pkid = 0;
+                    requests.push(Request::Publish(publish));
+                }
+                packet => warn!("Ignoring queued packet = {:?}", packet),
+            }
+        }
+
+        Ok(requests)
+    }
+
+    /// Removes all the persisted requests
+    pub(crate) fn clear_persistence(&mut self) -> Result<(), StateError> {
+        if let Some(persistence) = &self.persistence {
+            persistence.lock().unwrap().clear()?;
+        }
+
+        Ok(())
+    }
+
+    /// Forgets unacked publishes of the last connection which are dropped before they could be
+    /// republished
+    pub(crate) fn forget(&mut self, pkids: Vec<u16>) -> Result<(), StateError> {
+        for pkid in pkids {
+            let notice = self.outgoing_pub_notice.get_mut(pkid as usize);
+            if let Some(notice) = notice.and_then(Option::take) {
+                notice.error(NoticeError::Dropped);
+            }
+
+            self.unpersist(pkid)?;
+        }
+
+        Ok(())
+    }
+
+    fn persist(&mut self, pkid: u16, packet: &Packet) -> Result<(), StateError> {
+        if let Some(persistence) = &self.persistence {
+            let mut buffer = BytesMut::new();
+            packet.write(&mut buffer, usize::MAX)?;
+            persistence.lock().unwrap().store(pkid, buffer.freeze())?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the queued copy of a publish which is persisted with its packet id now
+    fn dequeue(&mut self, publish: &Publish) -> Result<(), StateError> {
+        if let Some(persistence) = &self.persistence {
+            if let Some(packet) = queued_packet(publish) {
+                persistence.lock().unwrap().dequeue(&packet)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn unpersist(&mut self, pkid: u16) -> Result<(), StateError> {
+        if let Some(persistence) = &self.persistence {
+            persistence.lock().unwrap().remove(pkid)?;
+        }
+
+        Ok(())
+    }
+
     pub fn inflight(&self) -> u16 {
         self.inflight
     }
@@ -140,9 +279,15 @@ pub fn handle_outgoing_packet(
         request: Request,
     ) -> Result<Option<Packet>, StateError> {
         let packet = match request {
-            Request::Publish(publish) => self.outgoing_publish(publish)?,
+            Request::Publish(publish) => self.outgoing_publish(publish, None)?,
+            Request::PublishWithNotice(publish, notice) => {
+                self.outgoing_publish(publish, Some(notice))?
+            }
             Request::PubRel(pubrel) => self.outgoing_pubrel(pubrel)?,
-            Request::Subscribe(subscribe) => self.outgoing_subscribe(subscribe)?,
+            Request::Subscribe(subscribe) => self.outgoing_subscribe(subscribe, None)?,
+            Request::SubscribeWithNotice(subscribe, notice) => {
+                self.outgoing_subscribe(subscribe, Some(notice))?
+            }
             Request::Unsubscribe(unsubscribe) => self.outgoing_unsubscribe(unsubscribe)?,
             Request::PingReq(_) => self.outgoing_ping()?,
             Request::Disconnect(_) => self.outgoing_disconnect()?,
@@ -168,7 +313,7 @@ pub fn handle_incoming_packet(
         let outgoing = match &packet {
             Incoming::PingResp => self.handle_incoming_pingresp()?,
             Incoming::Publish(publish) => self.handle_incoming_publish(publish)?,
-            Incoming::SubAck(_suback) => self.handle_incoming_suback()?,
+            Incoming::SubAck(suback) => self.handle_incoming_suback(suback)?,
             Incoming::UnsubAck(_unsuback) => self.handle_incoming_unsuback()?,
             Incoming::PubAck(puback) => self.handle_incoming_puback(puback)?,
             Incoming::PubRec(pubrec) => self.handle_incoming_pubrec(pubrec)?,
@@ -184,7 +329,19 @@ pub fn handle_incoming_packet(
         Ok(outgoing)
     }
 
-    fn handle_incoming_suback(&mut self) -> Result<Option<Packet>, StateError> {
+    fn handle_incoming_suback(&mut self, suback: &SubAck) -> Result<Option<Packet>, StateError> {
+        if let Some(notice) = self.outgoing_sub_notice.remove(&suback.pkid) {
+            let failure = suback
+                .return_codes
+                .iter()
+                .find(|code| matches!(code, SubscribeReasonCode::Failure));
+
+            match failure {
+                Some(code) => notice.error(NoticeError::V4Subscribe(*code)),
+                None => notice.success(),
+            }
+        }
+
         Ok(None)
     }
 
@@ -232,9 +389,15 @@ fn handle_incoming_puback(&mut self, puback: &PubAck) -> Result<Option<Packet>,
             return Err(StateError::Unsolicited(puback.pkid));
         }
 
+        if let Some(notice) = self.outgoing_pub_notice[puback.pkid as usize].take() {
+            notice.success();
+        }
+
+        self.unpersist(puback.pkid)?;
         self.inflight -= 1;
         let packet = self.check_collision(puback.pkid).map(|publish| {
             self.outgoing_pub[publish.pkid as usize] = Some(publish.clone());
+            self.outgoing_pub_notice[publish.pkid as usize] = self.collision_notice.take();
             self.inflight += 1;
 
             let event = Event::Outgoing(Outgoing::Publish(publish.pkid));
@@ -244,6 +407,11 @@ fn handle_incoming_puback(&mut self, puback: &PubAck) -> Result<Option<Packet>,
             Packet::Publish(publish)
         });
 
+        if let Some(Packet::Publish(publish)) = &packet {
+            self.persist(puback.pkid, &Packet::Publish(publish.clone()))?;
+            self.dequeue(publish)?;
+        }
+
         Ok(packet)
     }
 
@@ -260,11 +428,12 @@ fn handle_incoming_pubrec(&mut self, pubrec: &PubRec) -> Result<Option<Packet>,
 
         // NOTE: Inflight - 1 for qos2 in comp
         self.outgoing_rel.insert(pubrec.pkid as usize);
-        let pubrel = PubRel { pkid: pubrec.pkid };
+        let pubrel = Packet::PubRel(PubRel { pkid: pubrec.pkid });
+        self.persist(pubrec.pkid, &pubrel)?;
         let event = Event::Outgoing(Outgoing::PubRel(pubrec.pkid));
         self.events.push_back(event);
 
-        Ok(Some(Packet::PubRel(pubrel)))
+        Ok(Some(pubrel))
     }
 
     fn handle_incoming_pubrel(&mut self, pubrel: &PubRel) -> Result<Option<Packet>, StateError> {
@@ -288,8 +457,14 @@ fn handle_incoming_pubcomp(&mut self, pubcomp: &PubComp) -> Result<Option<Packet
         }
 
         self.outgoing_rel.set(pubcomp.pkid as usize, false);
+        if let Some(notice) = self.outgoing_pub_notice[pubcomp.pkid as usize].take() {
+            notice.success();
+        }
+
+        self.unpersist(pubcomp.pkid)?;
         self.inflight -= 1;
         let packet = self.check_collision(pubcomp.pkid).map(|publish| {
+            self.outgoing_pub_notice[publish.pkid as usize] = self.collision_notice.take();
             let event = Event::Outgoing(Outgoing::Publish(publish.pkid));
             self.events.push_back(event);
             self.collision_ping_count = 0;
@@ -297,6 +472,11 @@ fn handle_incoming_pubcomp(&mut self, pubcomp: &PubComp) -> Result<Option<Packet
             Packet::Publish(publish)
         });
 
+        if let Some(Packet::Publish(publish)) = &packet {
+            self.persist(pubcomp.pkid, &Packet::Publish(publish.clone()))?;
+            self.dequeue(publish)?;
+        }
+
         Ok(packet)
     }
 
@@ -307,10 +487,16 @@ fn handle_incoming_pingresp(&mut self) -> Result<Option<Packet>, StateError> {
     }
 
     /// Adds next packet identifier to QoS 1 and 2 publish packets and returns
-    /// it buy wrapping publish in packet
-    fn outgoing_publish(&mut self, mut publish: Publish) -> Result<Option<Packet>, StateError> {
+    /// it buy wrapping publish in packet. Notice is resolved once the publish is acked
+    fn outgoing_publish(
+        &mut self,
+        mut publish: Publish,
+        notice: Option<NoticeTx>,
+    ) -> Result<Option<Packet>, StateError> {
         if publish.qos != QoS::AtMostOnce {
-            if publish.pkid == 0 {
+            // Publishes without packet ids might be queued in persistence
+            let queued = publish.pkid == 0;
+            if queued {
                 publish.pkid = self.next_pkid();
             }
 
@@ -323,6 +509,7 @@ fn outgoing_publish(&mut self, mut publish: Publish) -> Result<Option<Packet>, S
             {
                 info!("Collision on packet id = {:?}", publish.pkid);
                 self.collision = Some(publish);
+                self.collision_notice = notice;
                 let event = Event::Outgoing(Outgoing::AwaitAck(pkid));
                 self.events.push_back(event);
                 return Ok(None);
@@ -332,7 +519,18 @@ fn outgoing_publish(&mut self, mut publish: Publish) -> Result<Option<Packet>, S
             // packet yet. This error is possible only when broker isn't acking sequentially
             self.outgoing_pub[pkid as usize] = Some(publish.clone());
             self.inflight += 1;
-        };
+            self.persist(pkid, &Packet::Publish(publish.clone()))?;
+            if queued {
+                self.dequeue(&publish)?;
+            }
+
+            // Retransmissions don't carry the notice, which is retained from the first attempt
+            if notice.is_some() {
+                self.outgoing_pub_notice[pkid as usize] = notice;
+            }
+        } else if let Some(notice) = notice {
+            notice.success();
+        }
 
         debug!(
             "Publish. Topic = {}, Pkid = {:?}, Payload Size = {:?}",
@@ -409,6 +607,7 @@ fn outgoing_ping(&mut self) -> Result<Option<Packet>, StateError> {
     fn outgoing_subscribe(
         &mut self,
         mut subscription: Subscribe,
+        notice: Option<NoticeTx>,
     ) -> Result<Option<Packet>, StateError> {
         if subscription.filters.is_empty() {
             return Err(StateError::EmptySubscription);
@@ -416,6 +615,9 @@ fn outgoing_subscribe(
 
         let pkid = self.next_pkid();
         subscription.pkid = pkid;
+        if let Some(notice) = notice {
+            self.outgoing_sub_notice.insert(pkid, notice);
+        }
 
         debug!(
             "Subscribe. Topics = {:?}, Pkid = {:?}",
@@ -500,12 +702,28 @@ fn next_pkid(&mut self) -> u16 {
     }
 }
 
+/// Encodes a QoS 1 or 2 publish which waits to be sent without a packet id, to queue it in
+/// persistence
+pub(crate) fn queued_packet(publish: &Publish) -> Option<Bytes> {
+    if publish.qos == QoS::AtMostOnce {
+        return None;
+    }
+
+    let mut publish = publish.clone();
+    publish.pkid = QUEUED_PKID;
+    let mut buffer = BytesMut::new();
+    Packet::Publish(publish)
+        .write(&mut buffer, usize::MAX)
+        .ok()?;
+    Some(buffer.freeze())
+}
+
 #[cfg(test)]
 mod test {
     use super::{MqttState, StateError};
     use crate::mqttbytes::v4::*;
     use crate::mqttbytes::*;
-    use crate::{Event, Incoming, Outgoing, Request};
+    use crate::{Event, Incoming, NoticeError, NoticeTx, Outgoing, Request};
 
     fn build_outgoing_publish(qos: QoS) -> Publish {
         let topic = "hello/world".to_owned();
@@ -555,7 +773,7 @@ fn outgoing_publish_should_set_pkid_and_add_publish_to_queue() {
         let publish = build_outgoing_publish(QoS::AtMostOnce);
 
         // QoS 0 publish shouldn't be saved in queue
-        mqtt.outgoing_publish(publish).unwrap();
+        mqtt.outgoing_publish(publish, None).unwrap();
         assert_eq!(mqtt.last_pkid, 0);
         assert_eq!(mqtt.inflight, 0);
 
@@ -563,12 +781,12 @@ fn outgoing_publish_should_set_pkid_and_add_publish_to_queue() {
         let publish = build_outgoing_publish(QoS::AtLeastOnce);
 
         // Packet id should be set and publish should be saved in queue
-        mqtt.outgoing_publish(publish.clone()).unwrap();
+        mqtt.outgoing_publish(publish.clone(), None).unwrap();
         assert_eq!(mqtt.last_pkid, 1);
         assert_eq!(mqtt.inflight, 1);
 
         // Packet id should be incremented and publish should be saved in queue
-        mqtt.outgoing_publish(publish).unwrap();
+        mqtt.outgoing_publish(publish, None).unwrap();
         assert_eq!(mqtt.last_pkid, 2);
         assert_eq!(mqtt.inflight, 2);
 
@@ -576,12 +794,12 @@ fn outgoing_publish_should_set_pkid_and_add_publish_to_queue() {
         let publish = build_outgoing_publish(QoS::ExactlyOnce);
 
         // Packet id should be set and publish should be saved in queue
-        mqtt.outgoing_publish(publish.clone()).unwrap();
+        mqtt.outgoing_publish(publish.clone(), None).unwrap();
         assert_eq!(mqtt.last_pkid, 3);
         assert_eq!(mqtt.inflight, 3);
 
         // Packet id should be incremented and publish should be saved in queue
-        mqtt.outgoing_publish(publish).unwrap();
+        mqtt.outgoing_publish(publish, None).unwrap();
         assert_eq!(mqtt.last_pkid, 4);
         assert_eq!(mqtt.inflight, 4);
     }
@@ -667,8 +885,8 @@ fn incoming_puback_should_remove_correct_publish_from_queue() {
         let publish1 = build_outgoing_publish(QoS::AtLeastOnce);
         let publish2 = build_outgoing_publish(QoS::ExactlyOnce);
 
-        mqtt.outgoing_publish(publish1).unwrap();
-        mqtt.outgoing_publish(publish2).unwrap();
+        mqtt.outgoing_publish(publish1, None).unwrap();
+        mqtt.outgoing_publish(publish2, None).unwrap();
         assert_eq!(mqtt.inflight, 2);
 
         mqtt.handle_incoming_puback(&PubAck::new(1)).unwrap();
@@ -700,8 +918,8 @@ fn incoming_pubrec_should_release_publish_from_queue_and_add_relid_to_rel_queue(
         let publish1 = build_outgoing_publish(QoS::AtLeastOnce);
         let publish2 = build_outgoing_publish(QoS::ExactlyOnce);
 
-        let _publish_out = mqtt.outgoing_publish(publish1);
-        let _publish_out = mqtt.outgoing_publish(publish2);
+        let _publish_out = mqtt.outgoing_publish(publish1, None);
+        let _publish_out = mqtt.outgoing_publish(publish2, None);
 
         mqtt.handle_incoming_pubrec(&PubRec::new(2)).unwrap();
         assert_eq!(mqtt.inflight, 2);
@@ -719,7 +937,7 @@ fn incoming_pubrec_should_send_release_to_network_and_nothing_to_user() {
         let mut mqtt = build_mqttstate();
 
         let publish = build_outgoing_publish(QoS::ExactlyOnce);
-        let packet = mqtt.outgoing_publish(publish).unwrap().unwrap();
+        let packet = mqtt.outgoing_publish(publish, None).unwrap().unwrap();
         match packet {
             Packet::Publish(publish) => assert_eq!(publish.pkid, 1),
             packet => panic!("Invalid network request: {:?}", packet),
@@ -761,13 +979,80 @@ fn incoming_pubcomp_should_release_correct_pkid_from_release_queue() {
         let mut mqtt = build_mqttstate();
         let publish = build_outgoing_publish(QoS::ExactlyOnce);
 
-        mqtt.outgoing_publish(publish).unwrap();
+        mqtt.outgoing_publish(publish, None).unwrap();
         mqtt.handle_incoming_pubrec(&PubRec::new(1)).unwrap();
 
         mqtt.handle_incoming_pubcomp(&PubComp::new(1)).unwrap();
         assert_eq!(mqtt.inflight, 0);
     }
 
+    #[test]
+    fn notices_are_resolved_on_acks_and_retained_across_retransmissions() {
+        let mut mqtt = build_mqttstate();
+
+        let (tx1, notice1) = NoticeTx::new();
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        mqtt.handle_outgoing_packet(Request::PublishWithNotice(publish, tx1))
+            .unwrap();
+
+        let (tx2, notice2) = NoticeTx::new();
+        let publish = build_outgoing_publish(QoS::ExactlyOnce);
+        mqtt.handle_outgoing_packet(Request::PublishWithNotice(publish, tx2))
+            .unwrap();
+
+        // Retransmission after reconnection doesn't carry the notice
+        for request in mqtt.clean() {
+            mqtt.handle_outgoing_packet(request).unwrap();
+        }
+
+        mqtt.handle_incoming_puback(&PubAck::new(1)).unwrap();
+        assert_eq!(notice1.wait(), Ok(()));
+
+        mqtt.handle_incoming_pubrec(&PubRec::new(2)).unwrap();
+        mqtt.handle_incoming_pubcomp(&PubComp::new(2)).unwrap();
+        assert_eq!(notice2.wait(), Ok(()));
+
+        let (tx, notice) = NoticeTx::new();
+        let subscribe = Subscribe::new("hello/world", QoS::AtLeastOnce);
+        mqtt.handle_outgoing_packet(Request::SubscribeWithNotice(subscribe, tx))
+            .unwrap();
+        let suback = SubAck::new(3, vec![SubscribeReasonCode::Failure]);
+        mqtt.handle_incoming_packet(Incoming::SubAck(suback))
+            .unwrap();
+        assert_eq!(
+            notice.wait(),
+            Err(NoticeError::V4Subscribe(SubscribeReasonCode::Failure))
+        );
+    }
+
+    #[test]
+    fn notices_fail_when_session_is_reset() {
+        let mut mqtt = build_mqttstate();
+
+        let (tx, notice) = NoticeTx::new();
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        mqtt.handle_outgoing_packet(Request::PublishWithNotice(publish, tx))
+            .unwrap();
+
+        mqtt.clean();
+        mqtt.reset_notices();
+        assert_eq!(notice.wait(), Err(NoticeError::SessionReset));
+    }
+
+    #[test]
+    fn subscribe_notices_fail_when_connection_is_lost() {
+        let mut mqtt = build_mqttstate();
+
+        let (tx, notice) = NoticeTx::new();
+        let subscribe = Subscribe::new("hello/world", QoS::AtLeastOnce);
+        mqtt.handle_outgoing_packet(Request::SubscribeWithNotice(subscribe, tx))
+            .unwrap();
+
+        // Resumed sessions don't reset notices
+        assert!(mqtt.clean().is_empty());
+        assert_eq!(notice.wait(), Err(NoticeError::Disconnected));
+    }
+
     #[test]
     fn outgoing_ping_handle_should_throw_errors_for_no_pingresp() {
         let mut mqtt = build_mqttstate();
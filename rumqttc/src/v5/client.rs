@@ -7,7 +7,9 @@
     Unsubscribe, UnsubscribeProperties,
 };
 use super::mqttbytes::QoS;
-use super::{ConnectionError, Event, EventLoop, MqttOptions, Request};
+use super::{ConnectionError, Event, EventLoop, MqttOptions, NoticeFuture, NoticeTx, Request};
+use crate::dispatch::{Router, SharedRouter, DEFAULT_STREAM_CAPACITY};
+use crate::PublishStream;
 use crate::{valid_filter, valid_topic};
 
 use bytes::Bytes;
@@ -47,6 +49,7 @@ fn from(e: TrySendError<Request>) -> Self {
 #[derive(Clone, Debug)]
 pub struct AsyncClient {
     request_tx: Sender<Request>,
+    router: SharedRouter<Publish>,
 }
 
 impl AsyncClient {
@@ -56,8 +59,9 @@ impl AsyncClient {
     pub fn new(options: MqttOptions, cap: usize) -> (AsyncClient, EventLoop) {
         let eventloop = EventLoop::new(options, cap);
         let request_tx = eventloop.requests_tx.clone();
+        let router = eventloop.router.clone();
 
-        let client = AsyncClient { request_tx };
+        let client = AsyncClient { request_tx, router };
 
         (client, eventloop)
     }
@@ -67,7 +71,10 @@ pub fn new(options: MqttOptions, cap: usize) -> (AsyncClient, EventLoop) {
     /// This is mostly useful for creating a test instance where you can
     /// listen on the corresponding receiver.
     pub fn from_senders(request_tx: Sender<Request>) -> AsyncClient {
-        AsyncClient { request_tx }
+        AsyncClient {
+            request_tx,
+            router: Router::shared(DEFAULT_STREAM_CAPACITY),
+        }
     }
 
     /// Sends a MQTT Publish to the `EventLoop`.
@@ -124,6 +131,31 @@ pub async fn publish<S, P>(
         self.handle_publish(topic, qos, retain, payload, None).await
     }
 
+    /// Sends a MQTT Publish to the `EventLoop` and returns a [`NoticeFuture`] which resolves once
+    /// the broker acknowledges it.
+    pub async fn publish_with_ack<S, P>(
+        &self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: P,
+    ) -> Result<NoticeFuture, ClientError>
+    where
+        S: Into<String>,
+        P: Into<Bytes>,
+    {
+        let topic = topic.into();
+        let mut publish = Publish::new(&topic, qos, payload, None);
+        publish.retain = retain;
+        let (notice_tx, notice) = NoticeTx::new();
+        let publish = Request::PublishWithNotice(publish, notice_tx);
+        if !valid_topic(&topic) {
+            return Err(ClientError::Request(publish));
+        }
+        self.request_tx.send_async(publish).await?;
+        Ok(notice)
+    }
+
     /// Attempts to send a MQTT Publish to the `EventLoop`.
     fn handle_try_publish<S, P>(
         &self,
@@ -278,6 +310,44 @@ pub async fn subscribe<S: Into<String>>(&self, topic: S, qos: QoS) -> Result<(),
         self.handle_subscribe(topic, qos, None).await
     }
 
+    /// Sends a MQTT Subscribe to the `EventLoop` and returns a [`NoticeFuture`] which resolves
+    /// once the broker acknowledges it.
+    pub async fn subscribe_with_ack<S: Into<String>>(
+        &self,
+        topic: S,
+        qos: QoS,
+    ) -> Result<NoticeFuture, ClientError> {
+        let filter = Filter::new(topic, qos);
+        let subscribe = Subscribe::new(filter, None);
+        self.send_subscribe_with_ack(subscribe).await
+    }
+
+    /// Sends a MQTT Subscribe to the `EventLoop` and returns a [`PublishStream`] of incoming
+    /// publishes matching the filter. Shared subscriptions are matched without their
+    /// `$share/{group}/` prefix.
+    pub async fn subscribe_stream<S: Into<String>>(
+        &self,
+        topic: S,
+        qos: QoS,
+    ) -> Result<PublishStream<Publish>, ClientError> {
+        let filter = Filter::new(topic, qos);
+        let subscribe = Subscribe::new(filter, None);
+        if !subscribe_has_valid_filters(&subscribe) {
+            return Err(ClientError::Request(subscribe.into()));
+        }
+
+        // Route before subscribing, so that retained publishes aren't missed
+        let stream = self.stream(&subscribe.filters[0].path);
+        self.request_tx.send_async(subscribe.into()).await?;
+        Ok(stream)
+    }
+
+    /// Returns a [`PublishStream`] of incoming publishes matching `filter` without subscribing,
+    /// e.g. for subscriptions restored by a resumed session
+    pub fn stream<S: AsRef<str>>(&self, filter: S) -> PublishStream<Publish> {
+        self.router.lock().unwrap().route(filter.as_ref())
+    }
+
     /// Attempts to send a MQTT Subscribe to the `EventLoop`
     fn handle_try_subscribe<S: Into<String>>(
         &self,
@@ -345,6 +415,31 @@ pub async fn subscribe_many<T>(&self, topics: T) -> Result<(), ClientError>
         self.handle_subscribe_many(topics, None).await
     }
 
+    /// Sends a MQTT Subscribe for multiple topics to the `EventLoop` and returns a
+    /// [`NoticeFuture`] which resolves once the broker acknowledges it.
+    pub async fn subscribe_many_with_ack<T>(&self, topics: T) -> Result<NoticeFuture, ClientError>
+    where
+        T: IntoIterator<Item = Filter>,
+    {
+        let subscribe = Subscribe::new_many(topics, None);
+        self.send_subscribe_with_ack(subscribe).await
+    }
+
+    async fn send_subscribe_with_ack(
+        &self,
+        subscribe: Subscribe,
+    ) -> Result<NoticeFuture, ClientError> {
+        let valid = subscribe_has_valid_filters(&subscribe);
+        let (notice_tx, notice) = NoticeTx::new();
+        let request = Request::SubscribeWithNotice(subscribe, notice_tx);
+        if !valid {
+            return Err(ClientError::Request(request));
+        }
+
+        self.request_tx.send_async(request).await?;
+        Ok(notice)
+    }
+
     /// Attempts to send a MQTT Subscribe for multiple topics to the `EventLoop`
     fn handle_try_subscribe_many<T>(
         &self,
@@ -548,6 +643,31 @@ pub fn publish<S, P>(
         self.handle_publish(topic, qos, retain, payload, None)
     }
 
+    /// Sends a MQTT Publish to the `EventLoop` and returns a [`NoticeFuture`] which can be
+    /// [`wait`](NoticeFuture::wait)ed on till the broker acknowledges it.
+    pub fn publish_with_ack<S, P>(
+        &self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: P,
+    ) -> Result<NoticeFuture, ClientError>
+    where
+        S: Into<String>,
+        P: Into<Bytes>,
+    {
+        let topic = topic.into();
+        let mut publish = Publish::new(&topic, qos, payload, None);
+        publish.retain = retain;
+        let (notice_tx, notice) = NoticeTx::new();
+        let publish = Request::PublishWithNotice(publish, notice_tx);
+        if !valid_topic(&topic) {
+            return Err(ClientError::Request(publish));
+        }
+        self.client.request_tx.send(publish)?;
+        Ok(notice)
+    }
+
     pub fn try_publish_with_properties<S, P>(
         &self,
         topic: S,
@@ -624,6 +744,18 @@ pub fn subscribe<S: Into<String>>(&self, topic: S, qos: QoS) -> Result<(), Clien
         self.handle_subscribe(topic, qos, None)
     }
 
+    /// Sends a MQTT Subscribe to the `EventLoop` and returns a [`NoticeFuture`] which can be
+    /// [`wait`](NoticeFuture::wait)ed on till the broker acknowledges it.
+    pub fn subscribe_with_ack<S: Into<String>>(
+        &self,
+        topic: S,
+        qos: QoS,
+    ) -> Result<NoticeFuture, ClientError> {
+        let filter = Filter::new(topic, qos);
+        let subscribe = Subscribe::new(filter, None);
+        self.send_subscribe_with_ack(subscribe)
+    }
+
     /// Sends a MQTT Subscribe to the `EventLoop`
     pub fn try_subscribe_with_properties<S: Into<String>>(
         &self,
@@ -675,6 +807,29 @@ pub fn subscribe_many<T>(&self, topics: T) -> Result<(), ClientError>
         self.handle_subscribe_many(topics, None)
     }
 
+    /// Sends a MQTT Subscribe for multiple topics to the `EventLoop` and returns a
+    /// [`NoticeFuture`] which can be [`wait`](NoticeFuture::wait)ed on till the broker
+    /// acknowledges it.
+    pub fn subscribe_many_with_ack<T>(&self, topics: T) -> Result<NoticeFuture, ClientError>
+    where
+        T: IntoIterator<Item = Filter>,
+    {
+        let subscribe = Subscribe::new_many(topics, None);
+        self.send_subscribe_with_ack(subscribe)
+    }
+
+    fn send_subscribe_with_ack(&self, subscribe: Subscribe) -> Result<NoticeFuture, ClientError> {
+        let valid = subscribe_has_valid_filters(&subscribe);
+        let (notice_tx, notice) = NoticeTx::new();
+        let request = Request::SubscribeWithNotice(subscribe, notice_tx);
+        if !valid {
+            return Err(ClientError::Request(request));
+        }
+
+        self.client.request_tx.send(request)?;
+        Ok(notice)
+    }
+
     pub fn try_subscribe_many_with_properties<T>(
         &self,
         topics: T,
@@ -795,7 +950,10 @@ fn new(eventloop: EventLoop, runtime: Runtime) -> Connection {
     // Also we can implement IntoIter for this to make it easy to iterate over it
     #[must_use = "Connection should be iterated over a loop to make progress"]
     pub fn iter(&mut self) -> Iter<'_> {
-        Iter { connection: self }
+        Iter {
+            connection: self,
+            done: false,
+        }
     }
 
     /// Attempt to fetch an incoming [`Event`] on the [`EvenLoop`], returning an error
@@ -853,16 +1011,24 @@ fn resolve_event(event: Result<Event, ConnectionError>) -> Option<Result<Event,
     }
 }
 
-/// Iterator which polls the `EventLoop` for connection progress
+/// Iterator which polls the `EventLoop` for connection progress. Ends when all the clients
+/// are dropped or after `ConnectionError::ReconnectAttemptsExhausted`
 pub struct Iter<'a> {
     connection: &'a mut Connection,
+    done: bool,
 }
 
 impl Iterator for Iter<'_> {
     type Item = Result<Event, ConnectionError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.connection.recv().ok()
+        if self.done {
+            return None;
+        }
+
+        let event = self.connection.recv().ok()?;
+        self.done = matches!(event, Err(ConnectionError::ReconnectAttemptsExhausted(_)));
+        Some(event)
     }
 }
 
@@ -1,20 +1,21 @@
 use bytes::Bytes;
 use std::fmt::{self, Debug, Formatter};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 #[cfg(feature = "websocket")]
 use std::{
     future::{Future, IntoFuture},
     pin::Pin,
-    sync::Arc,
 };
 
 mod client;
 mod eventloop;
 mod framed;
 pub mod mqttbytes;
-mod state;
+pub(crate) mod state;
 
 use crate::Outgoing;
+use crate::persistence::SharedPersistence;
 use crate::{NetworkOptions, Transport};
 
 use mqttbytes::v5::*;
@@ -23,6 +24,11 @@
 pub use eventloop::{ConnectionError, Event, EventLoop};
 pub use state::{MqttState, StateError};
 
+pub use crate::{
+    BrokerEndpoint, DropPolicy, EndpointSelection, FilePersistence, NoticeError, NoticeFuture,
+    NoticeTx, OfflineBuffer, OfflineMetrics, Persistence, PublishStream, ReconnectStrategy,
+};
+
 #[cfg(feature = "use-rustls")]
 pub use crate::tls::Error as TlsError;
 
@@ -36,6 +42,8 @@
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Request {
     Publish(Publish),
+    /// Publish which resolves the notice once acknowledged
+    PublishWithNotice(Publish, NoticeTx),
     PubAck(PubAck),
     PubRec(PubRec),
     PubComp(PubComp),
@@ -43,6 +51,8 @@ pub enum Request {
     PingReq,
     PingResp,
     Subscribe(Subscribe),
+    /// Subscribe which resolves the notice once acknowledged
+    SubscribeWithNotice(Subscribe, NoticeTx),
     SubAck(SubAck),
     Unsubscribe(Unsubscribe),
     UnsubAck(UnsubAck),
@@ -101,6 +111,18 @@ pub struct MqttOptions {
     /// If set to `true` MQTT acknowledgements are not sent automatically.
     /// Every incoming publish packet must be manually acknowledged with `client.ack(...)` method.
     manual_acks: bool,
+    /// Delays between attempts to reconnect after a connection error
+    reconnect_strategy: ReconnectStrategy,
+    /// Storage of unacked publishes which survives restarts
+    persistence: Option<SharedPersistence>,
+    /// Limits on publishes buffered while disconnected
+    offline_buffer: Option<OfflineBuffer>,
+    /// Publishes held by each publish stream which isn't polled
+    publish_stream_capacity: usize,
+    /// Brokers to connect to when the one above isn't reachable
+    fallback_endpoints: Vec<BrokerEndpoint>,
+    /// Endpoint to connect to on reconnections
+    endpoint_selection: EndpointSelection,
     network_options: NetworkOptions,
     #[cfg(feature = "proxy")]
     /// Proxy configuration.
@@ -139,6 +161,12 @@ pub fn new<S: Into<String>, T: Into<String>>(id: S, host: T, port: u16) -> MqttO
             default_max_incoming_size: 10 * 1024,
             connect_properties: None,
             manual_acks: false,
+            reconnect_strategy: ReconnectStrategy::Immediate,
+            persistence: None,
+            offline_buffer: None,
+            publish_stream_capacity: crate::dispatch::DEFAULT_STREAM_CAPACITY,
+            fallback_endpoints: Vec::new(),
+            endpoint_selection: EndpointSelection::Failover,
             network_options: NetworkOptions::new(),
             #[cfg(feature = "proxy")]
             proxy: None,
@@ -521,6 +549,89 @@ pub fn manual_acks(&self) -> bool {
         self.manual_acks
     }
 
+    /// Set how long to wait before reconnecting after a connection error
+    pub fn set_reconnect_strategy(&mut self, strategy: ReconnectStrategy) -> &mut Self {
+        self.reconnect_strategy = strategy;
+        self
+    }
+
+    /// Strategy of waiting before reconnecting
+    pub fn reconnect_strategy(&self) -> ReconnectStrategy {
+        self.reconnect_strategy
+    }
+
+    /// Persist unacked QoS 1 and 2 publishes, so that they are republished after a restart of
+    /// the process. Persisted publishes are republished only when `clean_start` is `false` and
+    /// are discarded otherwise
+    pub fn set_persistence<P: Persistence + 'static>(&mut self, persistence: P) -> &mut Self {
+        self.persistence = Some(Arc::new(Mutex::new(persistence)));
+        self
+    }
+
+    /// Limit publishes buffered while the eventloop is disconnected, dropping publishes as per
+    /// the drop policy of the buffer once it is full
+    pub fn set_offline_buffer(&mut self, buffer: OfflineBuffer) -> &mut Self {
+        self.offline_buffer = Some(buffer);
+        self
+    }
+
+    /// Limits on publishes buffered while disconnected
+    pub fn offline_buffer(&self) -> Option<OfflineBuffer> {
+        self.offline_buffer
+    }
+
+    /// Set number of publishes a [`PublishStream`] holds till it's polled. Publishes routed to
+    /// a full stream are dropped and counted by [`PublishStream::dropped`]
+    pub fn set_publish_stream_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.publish_stream_capacity = capacity;
+        self
+    }
+
+    /// Number of publishes a [`PublishStream`] holds till it's polled
+    pub fn publish_stream_capacity(&self) -> usize {
+        self.publish_stream_capacity
+    }
+
+    /// Add a broker to connect to when the brokers before it aren't reachable. Transports of
+    /// the endpoints can differ from each other
+    pub fn add_fallback_endpoint(&mut self, endpoint: BrokerEndpoint) -> &mut Self {
+        self.fallback_endpoints.push(endpoint);
+        self
+    }
+
+    /// All the brokers, starting with the primary broker of the options
+    pub fn endpoints(&self) -> Vec<BrokerEndpoint> {
+        (0..=self.fallback_endpoints.len())
+            .map(|index| self.endpoint(index))
+            .collect()
+    }
+
+    /// Set how the endpoint to connect to is selected on reconnections
+    pub fn set_endpoint_selection(&mut self, selection: EndpointSelection) -> &mut Self {
+        self.endpoint_selection = selection;
+        self
+    }
+
+    pub fn endpoint_selection(&self) -> EndpointSelection {
+        self.endpoint_selection
+    }
+
+    /// Endpoint at `index` of `endpoints()`, primary broker if there is no such endpoint
+    pub(crate) fn endpoint(&self, index: usize) -> BrokerEndpoint {
+        let fallback = index.checked_sub(1);
+        match fallback.and_then(|i| self.fallback_endpoints.get(i)) {
+            Some(endpoint) => endpoint.clone(),
+            None => BrokerEndpoint::new(&self.broker_addr, self.port, self.transport.clone()),
+        }
+    }
+
+    /// Replace the primary broker with `endpoint`
+    pub(crate) fn set_endpoint(&mut self, endpoint: BrokerEndpoint) {
+        self.broker_addr = endpoint.host;
+        self.port = endpoint.port;
+        self.transport = endpoint.transport;
+    }
+
     pub fn network_options(&self) -> NetworkOptions {
         self.network_options.clone()
     }
@@ -736,6 +847,11 @@ fn fmt(&self, f: &mut Formatter) -> fmt::Result {
             .field("last_will", &self.last_will)
             .field("conn_timeout", &self.conn_timeout)
             .field("manual_acks", &self.manual_acks)
+            .field("reconnect_strategy", &self.reconnect_strategy)
+            .field("persistence", &self.persistence)
+            .field("offline_buffer", &self.offline_buffer)
+            .field("fallback_endpoints", &self.fallback_endpoints)
+            .field("endpoint_selection", &self.endpoint_selection)
             .field("connect properties", &self.connect_properties)
             .finish()
     }
@@ -5,9 +5,10 @@
 };
 use super::mqttbytes::{self, Error as MqttError, QoS};
 
-use super::{Event, Incoming, Outgoing, Request};
+use super::{Event, Incoming, NoticeError, NoticeTx, Outgoing, Request};
+use crate::persistence::{SharedPersistence, QUEUED_PKID};
 
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use fixedbitset::FixedBitSet;
 use std::collections::{HashMap, VecDeque};
 use std::{io, time::Instant};
@@ -98,6 +99,12 @@ pub struct MqttState {
     pub(crate) incoming_pub: FixedBitSet,
     /// Last collision due to broker not acking in order
     pub collision: Option<Publish>,
+    /// Notices of outgoing QoS 1, 2 publishes which aren't acked yet
+    pub(crate) outgoing_pub_notice: Vec<Option<NoticeTx>>,
+    /// Notices of subscriptions which aren't acked yet
+    pub(crate) outgoing_sub_notice: HashMap<u16, NoticeTx>,
+    /// Notice of the publish in collision
+    pub(crate) collision_notice: Option<NoticeTx>,
     /// Buffered incoming packets
     pub events: VecDeque<Event>,
     /// Indicates if acknowledgements should be send immediately
@@ -110,6 +117,8 @@ pub struct MqttState {
     pub(crate) max_outgoing_inflight: u16,
     /// Upper limit on the maximum number of allowed inflight QoS1 & QoS2 requests
     max_outgoing_inflight_upper_limit: u16,
+    /// Storage of unacked publishes which survives restarts
+    pub(crate) persistence: Option<SharedPersistence>,
 }
 
 impl MqttState {
@@ -129,6 +138,9 @@ pub fn new(max_inflight: u16, manual_acks: bool) -> Self {
             outgoing_rel: FixedBitSet::with_capacity(max_inflight as usize + 1),
             incoming_pub: FixedBitSet::with_capacity(u16::MAX as usize + 1),
             collision: None,
+            outgoing_pub_notice: vec![None; max_inflight as usize + 1],
+            outgoing_sub_notice: HashMap::new(),
+            collision_notice: None,
             // TODO: Optimize these sizes later
             events: VecDeque::with_capacity(100),
             manual_acks,
@@ -137,6 +149,7 @@ pub fn new(max_inflight: u16, manual_acks: bool) -> Self {
             broker_topic_alias_max: 0,
             max_outgoing_inflight: max_inflight,
             max_outgoing_inflight_upper_limit: max_inflight,
+            persistence: None,
         }
     }
 
@@ -161,12 +174,136 @@ pub fn clean(&mut self) -> Vec<Request> {
         // remove packed ids of incoming qos2 publishes
         self.incoming_pub.clear();
 
+        // subscriptions aren't retransmitted, so their acks won't arrive in the next connection
+        for (_, notice) in self.outgoing_sub_notice.drain() {
+            notice.error(NoticeError::Disconnected);
+        }
+
         self.await_pingresp = false;
         self.collision_ping_count = 0;
         self.inflight = 0;
         pending
     }
 
+    /// Fails notices of all the requests which are waiting for acknowledgements. Used when the
+    /// broker doesn't resume the session and unacked requests are dropped
+    pub fn reset_notices(&mut self) {
+        let notices = self
+            .outgoing_pub_notice
+            .iter_mut()
+            .filter_map(Option::take)
+            .chain(self.outgoing_sub_notice.drain().map(|(_, notice)| notice))
+            .chain(self.collision_notice.take());
+
+        for notice in notices {
+            notice.error(NoticeError::SessionReset);
+        }
+    }
+
+    /// Returns requests persisted by an earlier run of the process, to be republished
+    pub(crate) fn restore(&mut self) -> Result<Vec<Request>, StateError> {
+        let persistence = match &self.persistence {
+            Some(persistence) => persistence.clone(),
+            None => return Ok(Vec::new()),
+        };
+
+        let mut persistence = persistence.lock().unwrap();
+        let packets = persistence.load()?;
+        let max_pkid = self.max_outgoing_inflight_upper_limit;
+        let mut requests = Vec::with_capacity(packets.len());
+        for packet in packets {
+            let mut packet = BytesMut::from(&packet[..]);
+            let request = match Packet::read(&mut packet, None)? {
+                // Packet ids beyond inflight limit, probably from a run with larger limit, can't
+                // be tracked. Such publishes are republished with new packet ids
+                Packet::Publish(mut publish) => {
+                    if publish.pkid > max_pkid {
+                        persistence.remove(publish.pkid)?;
+                        publish.pkid = 0;
+                        if let Some(packet) = queued_packet(&publish) {
+                            persistence.enqueue(packet)?;
+                        }
+                    }
+                    Request::Publish(publish)
+                }
+                Packet::PubRel(pubrel) if pubrel.pkid <= max_pkid => Request::PubRel(pubrel),
+                packet => {
+                    warn!("Ignoring persisted packet = {:?}", packet);
+                    continue;
+                }
+            };
+
+            requests.push(request);
+        }
+
+        // Queued publishes are sent after the ones which already have packet ids
+        for packet in persistence.load_queued()? {
+            let mut packet = BytesMut::from(&packet[..]);
+            match Packet::read(&mut packet, None)? {
+                Packet::Publish(mut publish) => {
+                    publish.pkid = 0;
+                    requests.push(Request::Publish(publish));
+                }
+                packet => warn!("Ignoring queued packet = {:?}", packet),
+            }
+        }
+
+        Ok(requests)
+    }
+
+    /// Removes all the persisted requests
+    pub(crate) fn clear_persistence(&mut self) -> Result<(), StateError> {
+        if let Some(persistence) = &self.persistence {
+            persistence.lock().unwrap().clear()?;
+        }
+
+        Ok(())
+    }
+
+    /// Forgets unacked publishes of the last connection which are dropped before they could be
+    /// republished
+    pub(crate) fn forget(&mut self, pkids: Vec<u16>) -> Result<(), StateError> {
+        for pkid in pkids {
+            let notice = self.outgoing_pub_notice.get_mut(pkid as usize);
+            if let Some(notice) = notice.and_then(Option::take) {
+                notice.error(NoticeError::Dropped);
+            }
+
+            self.unpersist(pkid)?;
+        }
+
+        Ok(())
+    }
+
+    fn persist(&mut self, pkid: u16, packet: &Packet) -> Result<(), StateError> {
+        if let Some(persistence) = &self.persistence {
+            let mut buffer = BytesMut::new();
+            packet.write(&mut buffer, None)?;
+            persistence.lock().unwrap().store(pkid, buffer.freeze())?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the queued copy of a publish which is persisted with its packet id now
+    fn dequeue(&mut self, publish: &Publish) -> Result<(), StateError> {
+        if let Some(persistence) = &self.persistence {
+            if let Some(packet) = queued_packet(publish) {
+                persistence.lock().unwrap().dequeue(&packet)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn unpersist(&mut self, pkid: u16) -> Result<(), StateError> {
+        if let Some(persistence) = &self.persistence {
+            persistence.lock().unwrap().remove(pkid)?;
+        }
+
+        Ok(())
+    }
+
     pub fn inflight(&self) -> u16 {
         self.inflight
     }
@@ -178,9 +315,15 @@ pub fn handle_outgoing_packet(
         request: Request,
     ) -> Result<Option<Packet>, StateError> {
         let packet = match request {
-            Request::Publish(publish) => self.outgoing_publish(publish)?,
+            Request::Publish(publish) => self.outgoing_publish(publish, None)?,
+            Request::PublishWithNotice(publish, notice) => {
+                self.outgoing_publish(publish, Some(notice))?
+            }
             Request::PubRel(pubrel) => self.outgoing_pubrel(pubrel)?,
-            Request::Subscribe(subscribe) => self.outgoing_subscribe(subscribe)?,
+            Request::Subscribe(subscribe) => self.outgoing_subscribe(subscribe, None)?,
+            Request::SubscribeWithNotice(subscribe, notice) => {
+                self.outgoing_subscribe(subscribe, Some(notice))?
+            }
             Request::Unsubscribe(unsubscribe) => self.outgoing_unsubscribe(unsubscribe)?,
             Request::PingReq => self.outgoing_ping()?,
             Request::Disconnect => {
@@ -242,7 +385,19 @@ fn handle_incoming_suback(
                 }
                 _ => {
                     warn!("SubAck Pkid = {:?}, Reason = {:?}", suback.pkid, reason);
-                },
+                }
+            }
+        }
+
+        if let Some(notice) = self.outgoing_sub_notice.remove(&suback.pkid) {
+            let failure = suback
+                .return_codes
+                .iter()
+                .find(|code| !matches!(code, SubscribeReasonCode::Success(_)));
+
+            match failure {
+                Some(code) => notice.error(NoticeError::V5Subscribe(*code)),
+                None => notice.success(),
             }
         }
         Ok(None)
@@ -360,16 +515,29 @@ fn handle_incoming_puback(&mut self, puback: &PubAck) -> Result<Option<Packet>,
         }
 
         self.inflight -= 1;
+        self.unpersist(puback.pkid)?;
+        let notice = self.outgoing_pub_notice[puback.pkid as usize].take();
 
         if puback.reason != PubAckReason::Success
             && puback.reason != PubAckReason::NoMatchingSubscribers
         {
-            warn!("PubAck Pkid = {:?}, reason: {:?}", puback.pkid, puback.reason);
+            warn!(
+                "PubAck Pkid = {:?}, reason: {:?}",
+                puback.pkid, puback.reason
+            );
+            if let Some(notice) = notice {
+                notice.error(NoticeError::V5PubAck(puback.reason));
+            }
             return Ok(None);
         }
 
+        if let Some(notice) = notice {
+            notice.success();
+        }
+
         if let Some(publish) = self.check_collision(puback.pkid) {
             self.outgoing_pub[publish.pkid as usize] = Some(publish.clone());
+            self.outgoing_pub_notice[publish.pkid as usize] = self.collision_notice.take();
             self.inflight += 1;
 
             let pkid = publish.pkid;
@@ -377,6 +545,8 @@ fn handle_incoming_puback(&mut self, puback: &PubAck) -> Result<Option<Packet>,
             self.events.push_back(event);
             self.collision_ping_count = 0;
 
+            self.persist(pkid, &Packet::Publish(publish.clone()))?;
+            self.dequeue(&publish)?;
             return Ok(Some(Packet::Publish(publish)));
         }
 
@@ -397,7 +567,14 @@ fn handle_incoming_pubrec(&mut self, pubrec: &PubRec) -> Result<Option<Packet>,
         if pubrec.reason != PubRecReason::Success
             && pubrec.reason != PubRecReason::NoMatchingSubscribers
         {
-            warn!("PubRec Pkid = {:?}, reason: {:?}", pubrec.pkid, pubrec.reason);
+            warn!(
+                "PubRec Pkid = {:?}, reason: {:?}",
+                pubrec.pkid, pubrec.reason
+            );
+            self.unpersist(pubrec.pkid)?;
+            if let Some(notice) = self.outgoing_pub_notice[pubrec.pkid as usize].take() {
+                notice.error(NoticeError::V5PubRec(pubrec.reason));
+            }
             return Ok(None);
         }
 
@@ -406,7 +583,9 @@ fn handle_incoming_pubrec(&mut self, pubrec: &PubRec) -> Result<Option<Packet>,
         let event = Event::Outgoing(Outgoing::PubRel(pubrec.pkid));
         self.events.push_back(event);
 
-        Ok(Some(Packet::PubRel(PubRel::new(pubrec.pkid, None))))
+        let pubrel = Packet::PubRel(PubRel::new(pubrec.pkid, None));
+        self.persist(pubrec.pkid, &pubrel)?;
+        Ok(Some(pubrel))
     }
 
     fn handle_incoming_pubrel(&mut self, pubrel: &PubRel) -> Result<Option<Packet>, StateError> {
@@ -417,7 +596,10 @@ fn handle_incoming_pubrel(&mut self, pubrel: &PubRel) -> Result<Option<Packet>,
         self.incoming_pub.set(pubrel.pkid as usize, false);
 
         if pubrel.reason != PubRelReason::Success {
-            warn!("PubRel Pkid = {:?}, reason: {:?}", pubrel.pkid, pubrel.reason);
+            warn!(
+                "PubRel Pkid = {:?}, reason: {:?}",
+                pubrel.pkid, pubrel.reason
+            );
             return Ok(None);
         }
 
@@ -430,6 +612,7 @@ fn handle_incoming_pubrel(&mut self, pubrel: &PubRel) -> Result<Option<Packet>,
     fn handle_incoming_pubcomp(&mut self, pubcomp: &PubComp) -> Result<Option<Packet>, StateError> {
         let outgoing = self.check_collision(pubcomp.pkid).map(|publish| {
             let pkid = publish.pkid;
+            self.outgoing_pub_notice[pkid as usize] = self.collision_notice.take();
             let event = Event::Outgoing(Outgoing::Publish(pkid));
             self.events.push_back(event);
             self.collision_ping_count = 0;
@@ -442,12 +625,29 @@ fn handle_incoming_pubcomp(&mut self, pubcomp: &PubComp) -> Result<Option<Packet
             return Err(StateError::Unsolicited(pubcomp.pkid));
         }
         self.outgoing_rel.set(pubcomp.pkid as usize, false);
+        self.unpersist(pubcomp.pkid)?;
+        if let Some(Packet::Publish(publish)) = &outgoing {
+            self.persist(publish.pkid, &Packet::Publish(publish.clone()))?;
+            self.dequeue(publish)?;
+        }
+
+        let notice = self.outgoing_pub_notice[pubcomp.pkid as usize].take();
 
         if pubcomp.reason != PubCompReason::Success {
-            warn!("PubComp Pkid = {:?}, reason: {:?}", pubcomp.pkid, pubcomp.reason);
+            warn!(
+                "PubComp Pkid = {:?}, reason: {:?}",
+                pubcomp.pkid, pubcomp.reason
+            );
+            if let Some(notice) = notice {
+                notice.error(NoticeError::V5PubComp(pubcomp.reason));
+            }
             return Ok(None);
         }
 
+        if let Some(notice) = notice {
+            notice.success();
+        }
+
         self.inflight -= 1;
         Ok(outgoing)
     }
@@ -458,10 +658,16 @@ fn handle_incoming_pingresp(&mut self) -> Result<Option<Packet>, StateError> {
     }
 
     /// Adds next packet identifier to QoS 1 and 2 publish packets and returns
-    /// it buy wrapping publish in packet
-    fn outgoing_publish(&mut self, mut publish: Publish) -> Result<Option<Packet>, StateError> {
+    /// it buy wrapping publish in packet. Notice is resolved once the publish is acked
+    fn outgoing_publish(
+        &mut self,
+        mut publish: Publish,
+        notice: Option<NoticeTx>,
+    ) -> Result<Option<Packet>, StateError> {
         if publish.qos != QoS::AtMostOnce {
-            if publish.pkid == 0 {
+            // Publishes without packet ids might be queued in persistence
+            let queued = publish.pkid == 0;
+            if queued {
                 publish.pkid = self.next_pkid();
             }
 
@@ -474,6 +680,7 @@ fn outgoing_publish(&mut self, mut publish: Publish) -> Result<Option<Packet>, S
             {
                 info!("Collision on packet id = {:?}", publish.pkid);
                 self.collision = Some(publish);
+                self.collision_notice = notice;
                 let event = Event::Outgoing(Outgoing::AwaitAck(pkid));
                 self.events.push_back(event);
                 return Ok(None);
@@ -483,7 +690,18 @@ fn outgoing_publish(&mut self, mut publish: Publish) -> Result<Option<Packet>, S
             // packet yet. This error is possible only when broker isn't acking sequentially
             self.outgoing_pub[pkid as usize] = Some(publish.clone());
             self.inflight += 1;
-        };
+            self.persist(pkid, &Packet::Publish(publish.clone()))?;
+            if queued {
+                self.dequeue(&publish)?;
+            }
+
+            // Retransmissions don't carry the notice, which is retained from the first attempt
+            if notice.is_some() {
+                self.outgoing_pub_notice[pkid as usize] = notice;
+            }
+        } else if let Some(notice) = notice {
+            notice.success();
+        }
 
         debug!(
             "Publish. Topic = {}, Pkid = {:?}, Payload Size = {:?}",
@@ -575,6 +793,7 @@ fn outgoing_ping(&mut self) -> Result<Option<Packet>, StateError> {
     fn outgoing_subscribe(
         &mut self,
         mut subscription: Subscribe,
+        notice: Option<NoticeTx>,
     ) -> Result<Option<Packet>, StateError> {
         if subscription.filters.is_empty() {
             return Err(StateError::EmptySubscription);
@@ -582,6 +801,9 @@ fn outgoing_subscribe(
 
         let pkid = self.next_pkid();
         subscription.pkid = pkid;
+        if let Some(notice) = notice {
+            self.outgoing_sub_notice.insert(pkid, notice);
+        }
 
         debug!(
             "Subscribe. Topics = {:?}, Pkid = {:?}",
@@ -670,11 +892,25 @@ fn next_pkid(&mut self) -> u16 {
     }
 }
 
+/// Encodes a QoS 1 or 2 publish which waits to be sent without a packet id, to queue it in
+/// persistence
+pub(crate) fn queued_packet(publish: &Publish) -> Option<Bytes> {
+    if publish.qos == QoS::AtMostOnce {
+        return None;
+    }
+
+    let mut publish = publish.clone();
+    publish.pkid = QUEUED_PKID;
+    let mut buffer = BytesMut::new();
+    Packet::Publish(publish).write(&mut buffer, None).ok()?;
+    Some(buffer.freeze())
+}
+
 #[cfg(test)]
 mod test {
     use super::mqttbytes::v5::*;
     use super::mqttbytes::*;
-    use super::{Event, Incoming, Outgoing, Request};
+    use super::{Event, Incoming, NoticeError, NoticeTx, Outgoing, Request};
     use super::{MqttState, StateError};
 
     fn build_outgoing_publish(qos: QoS) -> Publish {
@@ -725,7 +961,7 @@ fn outgoing_publish_should_set_pkid_and_add_publish_to_queue() {
         let publish = build_outgoing_publish(QoS::AtMostOnce);
 
         // QoS 0 publish shouldn't be saved in queue
-        mqtt.outgoing_publish(publish).unwrap();
+        mqtt.outgoing_publish(publish, None).unwrap();
         assert_eq!(mqtt.last_pkid, 0);
         assert_eq!(mqtt.inflight, 0);
 
@@ -733,12 +969,12 @@ fn outgoing_publish_should_set_pkid_and_add_publish_to_queue() {
         let publish = build_outgoing_publish(QoS::AtLeastOnce);
 
         // Packet id should be set and publish should be saved in queue
-        mqtt.outgoing_publish(publish.clone()).unwrap();
+        mqtt.outgoing_publish(publish.clone(), None).unwrap();
         assert_eq!(mqtt.last_pkid, 1);
         assert_eq!(mqtt.inflight, 1);
 
         // Packet id should be incremented and publish should be saved in queue
-        mqtt.outgoing_publish(publish).unwrap();
+        mqtt.outgoing_publish(publish, None).unwrap();
         assert_eq!(mqtt.last_pkid, 2);
         assert_eq!(mqtt.inflight, 2);
 
@@ -746,12 +982,12 @@ fn outgoing_publish_should_set_pkid_and_add_publish_to_queue() {
         let publish = build_outgoing_publish(QoS::ExactlyOnce);
 
         // Packet id should be set and publish should be saved in queue
-        mqtt.outgoing_publish(publish.clone()).unwrap();
+        mqtt.outgoing_publish(publish.clone(), None).unwrap();
         assert_eq!(mqtt.last_pkid, 3);
         assert_eq!(mqtt.inflight, 3);
 
         // Packet id should be incremented and publish should be saved in queue
-        mqtt.outgoing_publish(publish).unwrap();
+        mqtt.outgoing_publish(publish, None).unwrap();
         assert_eq!(mqtt.last_pkid, 4);
         assert_eq!(mqtt.inflight, 4);
     }
@@ -763,17 +999,17 @@ fn outgoing_publish_with_max_inflight_is_ok() {
         // QoS2 publish
         let publish = build_outgoing_publish(QoS::ExactlyOnce);
 
-        mqtt.outgoing_publish(publish.clone()).unwrap();
+        mqtt.outgoing_publish(publish.clone(), None).unwrap();
         assert_eq!(mqtt.last_pkid, 1);
         assert_eq!(mqtt.inflight, 1);
 
         // Packet id should be set back down to 0, since we hit the limit
-        mqtt.outgoing_publish(publish.clone()).unwrap();
+        mqtt.outgoing_publish(publish.clone(), None).unwrap();
         assert_eq!(mqtt.last_pkid, 0);
         assert_eq!(mqtt.inflight, 2);
 
         // This should cause a collition
-        mqtt.outgoing_publish(publish.clone()).unwrap();
+        mqtt.outgoing_publish(publish.clone(), None).unwrap();
         assert_eq!(mqtt.last_pkid, 1);
         assert_eq!(mqtt.inflight, 2);
         assert!(mqtt.collision.is_some());
@@ -783,7 +1019,7 @@ fn outgoing_publish_with_max_inflight_is_ok() {
         assert_eq!(mqtt.inflight, 1);
 
         // Now there should be space in the outgoing queue
-        mqtt.outgoing_publish(publish.clone()).unwrap();
+        mqtt.outgoing_publish(publish.clone(), None).unwrap();
         assert_eq!(mqtt.last_pkid, 0);
         assert_eq!(mqtt.inflight, 2);
     }
@@ -867,8 +1103,8 @@ fn incoming_puback_should_remove_correct_publish_from_queue() {
         let publish1 = build_outgoing_publish(QoS::AtLeastOnce);
         let publish2 = build_outgoing_publish(QoS::ExactlyOnce);
 
-        mqtt.outgoing_publish(publish1).unwrap();
-        mqtt.outgoing_publish(publish2).unwrap();
+        mqtt.outgoing_publish(publish1, None).unwrap();
+        mqtt.outgoing_publish(publish2, None).unwrap();
         assert_eq!(mqtt.inflight, 2);
 
         mqtt.handle_incoming_puback(&PubAck::new(1, None)).unwrap();
@@ -902,8 +1138,8 @@ fn incoming_pubrec_should_release_publish_from_queue_and_add_relid_to_rel_queue(
         let publish1 = build_outgoing_publish(QoS::AtLeastOnce);
         let publish2 = build_outgoing_publish(QoS::ExactlyOnce);
 
-        let _publish_out = mqtt.outgoing_publish(publish1);
-        let _publish_out = mqtt.outgoing_publish(publish2);
+        let _publish_out = mqtt.outgoing_publish(publish1, None);
+        let _publish_out = mqtt.outgoing_publish(publish2, None);
 
         mqtt.handle_incoming_pubrec(&PubRec::new(2, None)).unwrap();
         assert_eq!(mqtt.inflight, 2);
@@ -921,7 +1157,7 @@ fn incoming_pubrec_should_send_release_to_network_and_nothing_to_user() {
         let mut mqtt = build_mqttstate();
 
         let publish = build_outgoing_publish(QoS::ExactlyOnce);
-        match mqtt.outgoing_publish(publish).unwrap().unwrap() {
+        match mqtt.outgoing_publish(publish, None).unwrap().unwrap() {
             Packet::Publish(publish) => assert_eq!(publish.pkid, 1),
             packet => panic!("Invalid network request: {:?}", packet),
         }
@@ -961,7 +1197,7 @@ fn incoming_pubcomp_should_release_correct_pkid_from_release_queue() {
         let mut mqtt = build_mqttstate();
         let publish = build_outgoing_publish(QoS::ExactlyOnce);
 
-        mqtt.outgoing_publish(publish).unwrap();
+        mqtt.outgoing_publish(publish, None).unwrap();
         mqtt.handle_incoming_pubrec(&PubRec::new(1, None)).unwrap();
 
         mqtt.handle_incoming_pubcomp(&PubComp::new(1, None))
@@ -969,6 +1205,40 @@ fn incoming_pubcomp_should_release_correct_pkid_from_release_queue() {
         assert_eq!(mqtt.inflight, 0);
     }
 
+    #[test]
+    fn notices_are_resolved_with_ack_reasons() {
+        let mut mqtt = build_mqttstate();
+
+        let (tx1, notice1) = NoticeTx::new();
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        mqtt.handle_outgoing_packet(Request::PublishWithNotice(publish, tx1))
+            .unwrap();
+
+        let (tx2, notice2) = NoticeTx::new();
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        mqtt.handle_outgoing_packet(Request::PublishWithNotice(publish, tx2))
+            .unwrap();
+
+        mqtt.handle_incoming_puback(&PubAck::new(1, None)).unwrap();
+        assert_eq!(notice1.wait(), Ok(()));
+
+        let mut puback = PubAck::new(2, None);
+        puback.reason = PubAckReason::NotAuthorized;
+        mqtt.handle_incoming_puback(&puback).unwrap();
+        assert_eq!(
+            notice2.wait(),
+            Err(NoticeError::V5PubAck(PubAckReason::NotAuthorized))
+        );
+
+        let (tx, notice) = NoticeTx::new();
+        let publish = build_outgoing_publish(QoS::ExactlyOnce);
+        mqtt.handle_outgoing_packet(Request::PublishWithNotice(publish, tx))
+            .unwrap();
+        mqtt.clean();
+        mqtt.reset_notices();
+        assert_eq!(notice.wait(), Err(NoticeError::SessionReset));
+    }
+
     #[test]
     fn outgoing_ping_handle_should_throw_errors_for_no_pingresp() {
         let mut mqtt = build_mqttstate();
@@ -1,3 +1,4 @@
+use futures_util::StreamExt;
 use matches::assert_matches;
 use std::time::{Duration, Instant};
 use tokio::{task, time};
@@ -471,6 +472,42 @@ async fn next_poll_after_connect_failure_reconnects() {
     }
 }
 
+#[tokio::test]
+async fn reconnections_are_delayed_upto_max_attempts() {
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3005);
+    options.set_reconnect_strategy(ReconnectStrategy::ExponentialBackoff {
+        initial_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(1),
+        jitter: 0.0,
+        max_attempts: Some(2),
+    });
+
+    let mut eventloop = EventLoop::new(options, 5);
+    assert_matches!(eventloop.poll().await, Err(ConnectionError::Io(_)));
+
+    for (attempt, delay) in [(1, 100), (2, 200)] {
+        match eventloop.poll().await {
+            Ok(Event::Reconnecting {
+                attempt: a,
+                delay: d,
+            }) => {
+                assert_eq!(a, attempt);
+                assert_eq!(d, Duration::from_millis(delay));
+            }
+            v => panic!("Expected reconnecting event. Found = {:?}", v),
+        }
+
+        let start = Instant::now();
+        assert_matches!(eventloop.poll().await, Err(ConnectionError::Io(_)));
+        assert!(start.elapsed() >= Duration::from_millis(delay));
+    }
+
+    assert_matches!(
+        eventloop.poll().await,
+        Err(ConnectionError::ReconnectAttemptsExhausted(2))
+    );
+}
+
 #[tokio::test]
 async fn reconnection_resumes_from_the_previous_state() {
     let mut options = MqttOptions::new("dummy", "127.0.0.1", 3001);
@@ -548,6 +585,264 @@ async fn reconnection_resends_unacked_packets_from_the_previous_connection_first
     }
 }
 
+#[tokio::test]
+async fn publish_notice_resolves_on_ack_after_reconnection() {
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3006);
+    options
+        .set_keep_alive(Duration::from_secs(5))
+        .set_clean_session(false);
+
+    let (client, mut eventloop) = AsyncClient::new(options, 5);
+    task::spawn(async move {
+        run(&mut eventloop, true).await.unwrap();
+    });
+
+    let notice = client
+        .publish_with_ack("hello/world", QoS::AtLeastOnce, false, vec![1])
+        .await
+        .unwrap();
+
+    // broker connection 1. receive but don't ack
+    let mut broker = Broker::new(3006, 0, false).await;
+    broker.read_publish().await.unwrap();
+    drop(broker);
+
+    // broker connection 2 acks the retransmission
+    let mut broker = Broker::new(3006, 0, true).await;
+    let publish = broker.read_publish().await.unwrap();
+    assert_eq!(publish.pkid, 1);
+    broker.ack(publish.pkid).await;
+
+    let ack = time::timeout(Duration::from_secs(5), notice.wait_async()).await;
+    assert_eq!(ack.unwrap(), Ok(()));
+}
+
+#[tokio::test]
+async fn persisted_publishes_are_republished_after_restart() {
+    let path = std::env::temp_dir().join(format!("rumqttc-reliability-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3007);
+    options
+        .set_keep_alive(Duration::from_secs(5))
+        .set_clean_session(false)
+        .set_persistence(FilePersistence::open(&path).unwrap());
+
+    // first run of the process. broker receives but doesn't ack
+    let (client, mut eventloop) = AsyncClient::new(options, 5);
+    let first_run = task::spawn(async move {
+        run(&mut eventloop, true).await.unwrap();
+    });
+
+    start_requests(3, QoS::AtLeastOnce, 0, client).await;
+    let mut broker = Broker::new(3007, 0, false).await;
+    for i in 1..=3 {
+        let publish = broker.read_publish().await.unwrap();
+        assert_eq!(i, publish.payload[0]);
+    }
+    broker.ack(1).await;
+    time::sleep(Duration::from_millis(100)).await;
+    first_run.abort();
+    drop(broker);
+
+    // second run republishes unacked publishes from the journal
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3007);
+    options
+        .set_keep_alive(Duration::from_secs(5))
+        .set_clean_session(false)
+        .set_persistence(FilePersistence::open(&path).unwrap());
+
+    let mut eventloop = EventLoop::new(options, 5);
+    task::spawn(async move {
+        run(&mut eventloop, true).await.unwrap();
+    });
+
+    let mut broker = Broker::new(3007, 0, true).await;
+    for (pkid, i) in [(2, 2), (3, 3)] {
+        let publish = broker.read_publish().await.unwrap();
+        assert_eq!(publish.pkid, pkid);
+        assert_eq!(i, publish.payload[0]);
+    }
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn publishes_are_buffered_and_dropped_while_offline() {
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3008);
+    options
+        .set_reconnect_strategy(ReconnectStrategy::ExponentialBackoff {
+            initial_delay: Duration::from_millis(300),
+            max_delay: Duration::from_millis(300),
+            jitter: 0.0,
+            max_attempts: None,
+        })
+        .set_offline_buffer(OfflineBuffer::new(2, usize::MAX, DropPolicy::DropOldest));
+
+    let (client, mut eventloop) = AsyncClient::new(options, 1);
+    assert_matches!(eventloop.poll().await, Err(ConnectionError::Io(_)));
+    assert_matches!(eventloop.poll().await, Ok(Event::Reconnecting { .. }));
+
+    // Publishes don't block on the full request channel while eventloop is waiting to reconnect
+    let publishes = async {
+        let mut notices = Vec::new();
+        for i in 1..=4 {
+            let notice = client
+                .publish_with_ack("hello/world", QoS::AtLeastOnce, false, vec![i])
+                .await
+                .unwrap();
+            notices.push(notice);
+        }
+
+        notices
+    };
+
+    let (notices, o) = tokio::join!(publishes, eventloop.poll());
+    assert_matches!(o, Err(ConnectionError::Io(_)));
+
+    let metrics = eventloop.offline_metrics();
+    assert_eq!(metrics.buffered_messages, 2);
+    assert_eq!(metrics.dropped_messages, 2);
+
+    let mut notices = notices.into_iter();
+    for notice in notices.by_ref().take(2) {
+        assert_eq!(notice.wait_async().await, Err(NoticeError::Dropped));
+    }
+
+    task::spawn(async move {
+        run(&mut eventloop, true).await.unwrap();
+    });
+
+    // Buffered publishes are sent though the session isn't resumed
+    let mut broker = Broker::new(3008, 0, false).await;
+    for i in 3..=4 {
+        let publish = broker.read_publish().await.unwrap();
+        assert_eq!(publish.payload[..], [i]);
+        broker.ack(publish.pkid).await;
+    }
+
+    for notice in notices {
+        let ack = time::timeout(Duration::from_secs(5), notice.wait_async()).await;
+        assert_eq!(ack.unwrap(), Ok(()));
+    }
+}
+
+#[tokio::test]
+async fn publishes_buffered_while_offline_survive_restart() {
+    let path = std::env::temp_dir().join(format!("rumqttc-offline-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let options = || {
+        let mut options = MqttOptions::new("dummy", "127.0.0.1", 3012);
+        options
+            .set_keep_alive(Duration::from_secs(5))
+            .set_clean_session(false)
+            .set_reconnect_strategy(ReconnectStrategy::ExponentialBackoff {
+                initial_delay: Duration::from_millis(300),
+                max_delay: Duration::from_millis(300),
+                jitter: 0.0,
+                max_attempts: None,
+            })
+            .set_offline_buffer(OfflineBuffer::new(5, usize::MAX, DropPolicy::DropOldest))
+            .set_persistence(FilePersistence::open(&path).unwrap());
+        options
+    };
+
+    // first run of the process never reaches the broker
+    let (client, mut eventloop) = AsyncClient::new(options(), 1);
+    assert_matches!(eventloop.poll().await, Err(ConnectionError::Io(_)));
+    assert_matches!(eventloop.poll().await, Ok(Event::Reconnecting { .. }));
+
+    let publishes = async {
+        for i in 1..=2 {
+            client
+                .publish("hello/world", QoS::AtLeastOnce, false, vec![i])
+                .await
+                .unwrap();
+        }
+    };
+
+    let (_, o) = tokio::join!(publishes, eventloop.poll());
+    assert_matches!(o, Err(ConnectionError::Io(_)));
+    assert_eq!(eventloop.offline_metrics().buffered_messages, 2);
+    drop(eventloop);
+
+    // second run sends the publishes buffered by the first one
+    let mut eventloop = EventLoop::new(options(), 5);
+    task::spawn(async move {
+        run(&mut eventloop, true).await.unwrap();
+    });
+
+    let mut broker = Broker::new(3012, 0, false).await;
+    for i in 1..=2 {
+        let publish = broker.read_publish().await.unwrap();
+        assert_eq!(publish.payload[..], [i]);
+        broker.ack(publish.pkid).await;
+    }
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn unreachable_broker_fails_over_to_the_next_endpoint() {
+    // Nothing listens on the primary broker's port
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3009);
+    options
+        .set_keep_alive(Duration::from_secs(5))
+        .add_fallback_endpoint(BrokerEndpoint::new("127.0.0.1", 3010, Transport::tcp()));
+
+    let (client, mut eventloop) = AsyncClient::new(options, 5);
+    assert_matches!(eventloop.poll().await, Err(ConnectionError::Io(_)));
+
+    task::spawn(async move {
+        run(&mut eventloop, true).await.unwrap();
+    });
+
+    let mut broker = Broker::new(3010, 0, false).await;
+    client
+        .publish("hello/world", QoS::AtLeastOnce, false, vec![1])
+        .await
+        .unwrap();
+
+    let publish = broker.read_publish().await.unwrap();
+    assert_eq!(publish.payload[..], [1]);
+}
+
+#[tokio::test]
+async fn incoming_publishes_are_routed_to_matching_streams() {
+    let mut options = MqttOptions::new("dummy", "127.0.0.1", 3011);
+    options.set_keep_alive(Duration::from_secs(5));
+
+    let (client, mut eventloop) = AsyncClient::new(options, 5);
+    task::spawn(async move {
+        run(&mut eventloop, false).await.unwrap();
+    });
+
+    let mut broker = Broker::new(3011, 0, false).await;
+    let mut hello = client
+        .subscribe_stream("hello/+", QoS::AtMostOnce)
+        .await
+        .unwrap();
+    let mut other = client.stream("other/#");
+    assert_matches!(broker.read_packet().await, Some(Packet::Subscribe(_)));
+
+    broker.spawn_publishes(2, QoS::AtMostOnce, 0).await;
+    task::spawn(async move {
+        loop {
+            broker.tick().await;
+        }
+    });
+
+    for i in 1..=2 {
+        let publish = time::timeout(Duration::from_secs(5), hello.next()).await;
+        assert_eq!(publish.unwrap().unwrap().payload[..], [1, 2, 3, i]);
+    }
+
+    assert!(time::timeout(Duration::from_millis(100), other.next())
+        .await
+        .is_err());
+}
+
 #[tokio::test]
 async fn state_is_being_cleaned_properly_and_pending_request_calculated_properly() {
     let mut options = MqttOptions::new("dummy", "127.0.0.1", 3004);
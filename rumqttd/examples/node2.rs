@@ -1,17 +1,51 @@
-// use rumqttd::{Link, Broker, Config, RouterConfig};
-//
-// fn main() {
-//     pretty_env_logger::init();
-//
-//     let router = Router::new(1, config);
-//     let link = router.start_with_cluster("localhost:7071", vec![(0, "localhost:7070")]);
-//     let (_link_tx, mut link_rx) = Link::new("local@1", link).unwrap();
-//
-//     // Receiver
-//     loop {
-//         let notification = link_rx.recv().unwrap();
-//         println!("Received = {:?}", notification);
-//     }
-// }
+use rumqttd::{Broker, ClusterSettings, Config, Notification};
 
-fn main() {}
+use std::thread;
+
+// Run this along with `node1` example, from root of this crate. This node connects to
+// `node1` and serves MQTT 3.1.1 clients on 1884
+fn main() {
+    tracing_subscriber::fmt().init();
+
+    let config = config::Config::builder()
+        .add_source(config::File::with_name("rumqttd.toml"))
+        .build()
+        .unwrap();
+
+    let mut config: Config = config.try_deserialize().unwrap();
+    config.id = 1;
+    config.cluster = Some(ClusterSettings {
+        node_id: 1,
+        listen: "127.0.0.1:7071".to_owned(),
+        seniors: vec![(0, "127.0.0.1:7070".to_owned())],
+        juniors: vec![],
+        secret: "node-secret".to_owned(),
+    });
+
+    // Only one v4 server on a port which doesn't conflict with `node1`
+    let mut v4 = config.v4.take().unwrap();
+    let mut server = v4.remove("1").unwrap();
+    server.listen = "0.0.0.0:1884".parse().unwrap();
+    config.v4 = Some([("1".to_owned(), server)].into());
+    config.v5 = None;
+    config.ws = None;
+    config.console = None;
+    config.prometheus = None;
+
+    let mut broker = Broker::new(config);
+    let (mut link_tx, mut link_rx) = broker.link("node2").unwrap();
+    thread::spawn(move || {
+        broker.start().unwrap();
+    });
+
+    link_tx.subscribe("#").unwrap();
+    loop {
+        if let Some(Notification::Forward(forward)) = link_rx.recv().unwrap() {
+            println!(
+                "Topic = {:?}, Payload = {} bytes",
+                forward.publish.topic,
+                forward.publish.payload.len()
+            );
+        }
+    }
+}
@@ -19,16 +19,26 @@
 };
 
 pub use link::alerts;
+pub use link::auth;
+use link::auth::Authenticator;
 pub use link::local;
 pub use link::meters;
-pub use router::{Alert, Forward, IncomingMeter, Meter, Notification, OutgoingMeter};
-use segments::Storage;
-pub use server::Broker;
+pub use link::password;
+use link::password::PasswordFile;
+pub use router::acl;
+use router::acl::{AclConfig, Action, AuthorizationHandler, ClientInfo};
+pub use router::{
+    Alert, FileRetainedStore, Forward, IncomingMeter, Meter, Notification, OutgoingMeter,
+    RetainedStore,
+};
+use segments::{Persist, Storage};
+pub use server::{Broker, PeerCertificate};
 
 pub use self::router::shared_subs::Strategy;
 
 mod link;
 pub mod protocol;
+mod replicator;
 mod router;
 mod segments;
 mod server;
@@ -61,6 +71,8 @@ pub struct Config {
     pub cluster: Option<ClusterSettings>,
     pub console: Option<ConsoleSettings>,
     pub bridge: Option<BridgeConfig>,
+    /// Bridges to multiple remote brokers
+    pub bridges: Option<HashMap<String, BridgeConfig>>,
     pub prometheus: Option<PrometheusSetting>,
     pub metrics: Option<HashMap<MetricType, MetricSettings>>,
 }
@@ -83,6 +95,9 @@ pub enum TlsConfig {
         capath: Option<String>,
         certpath: String,
         keypath: String,
+        /// Certificate revocation lists to check client certificates against
+        #[serde(default)]
+        crlpath: Option<String>,
     },
     NativeTls {
         pkcs12path: String,
@@ -99,10 +114,13 @@ pub fn validate_paths(&self) -> bool {
                 capath,
                 certpath,
                 keypath,
+                crlpath,
             } => {
-                let ca = capath.is_none() || capath.as_ref().is_some_and(|v| Path::new(v).exists());
+                let optional = [capath, crlpath]
+                    .iter()
+                    .all(|v| v.as_ref().map_or(true, |v| Path::new(v).exists()));
 
-                ca && [certpath, keypath].iter().all(|v| Path::new(v).exists())
+                optional && [certpath, keypath].iter().all(|v| Path::new(v).exists())
             }
             TlsConfig::NativeTls { pkcs12path, .. } => Path::new(pkcs12path).exists(),
         }
@@ -127,19 +145,68 @@ pub fn set_auth_handler<F, O>(&mut self, auth_fn: F)
     {
         self.connections.set_auth_handler(auth_fn)
     }
+
+    pub fn set_authenticator<A: Authenticator + 'static>(&mut self, authenticator: A) {
+        self.connections.set_authenticator(authenticator)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BridgeConfig {
     pub name: String,
     pub addr: String,
+    /// QoS of subscription on `sub_path`
+    #[serde(default)]
     pub qos: u8,
-    pub sub_path: Filter,
+    /// Filter on remote broker whose messages are published locally as they are. Same as an
+    /// `in` rule without prefixes
+    #[serde(default)]
+    pub sub_path: Option<Filter>,
     pub reconnection_delay: u64,
     pub ping_delay: u64,
     pub connections: ConnectionSettings,
     #[serde(default)]
     pub transport: Transport,
+    /// MQTT version used with remote broker, 4 or 5
+    #[serde(default = "default_bridge_version")]
+    pub version: u8,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Topics which are exchanged with remote broker
+    #[serde(default)]
+    pub rules: Vec<BridgeRule>,
+}
+
+fn default_bridge_version() -> u8 {
+    4
+}
+
+/// Messages on `local_prefix` + `topic` locally are exchanged with `remote_prefix` + `topic`
+/// on remote broker, in the given direction
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BridgeRule {
+    /// Topic filter, wildcards allowed
+    pub topic: Filter,
+    pub direction: BridgeDirection,
+    /// QoS of subscriptions and publishes on remote broker
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub local_prefix: String,
+    #[serde(default)]
+    pub remote_prefix: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BridgeDirection {
+    /// From remote broker to local one
+    In,
+    /// From local broker to remote one
+    Out,
+    Both,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -150,8 +217,23 @@ pub struct ConnectionSettings {
     pub auth: Option<HashMap<String, String>>,
     #[serde(skip)]
     pub external_auth: Option<AuthHandler>,
+    /// File with `username:password` entries, checked after `auth`. Reloaded when it changes
+    #[serde(default)]
+    pub password_file: Option<PasswordFile>,
     #[serde(default)]
     pub dynamic_filters: bool,
+    /// Authenticators for MQTT 5 enhanced authentication, keyed by authentication method
+    #[serde(skip)]
+    pub authenticators: HashMap<String, Arc<dyn Authenticator>>,
+    /// Identity of clients from their verified certificates
+    #[serde(default)]
+    pub client_cert: Option<ClientCertSettings>,
+    /// Features available to clients of this listener
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    /// Rate limits and quotas of clients of this listener
+    #[serde(default)]
+    pub rate_limits: Option<RateLimits>,
 }
 
 impl ConnectionSettings {
@@ -166,6 +248,13 @@ pub fn set_auth_handler<F, O>(&mut self, auth_fn: F)
             Box::pin(auth)
         }));
     }
+
+    /// Enables enhanced authentication with `authenticator`, replacing the one previously set
+    /// for the same method
+    pub fn set_authenticator<A: Authenticator + 'static>(&mut self, authenticator: A) {
+        let method = authenticator.method().to_owned();
+        self.authenticators.insert(method, Arc::new(authenticator));
+    }
 }
 
 impl fmt::Debug for ConnectionSettings {
@@ -176,22 +265,177 @@ fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             .field("max_inflight_count", &self.max_inflight_count)
             .field("auth", &self.auth)
             .field("external_auth", &self.external_auth.is_some())
+            .field("password_file", &self.password_file)
             .field("dynamic_filters", &self.dynamic_filters)
+            .field("authenticators", &self.authenticators.keys())
+            .field("client_cert", &self.client_cert)
+            .field("capabilities", &self.capabilities)
+            .field("rate_limits", &self.rate_limits)
             .finish()
     }
 }
 
+/// Mapping of client certificates to the identity of clients. Connections without a
+/// certificate, or whose certificate doesn't have the identity, are rejected
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClientCertSettings {
+    /// Field of the certificate which identifies the client
+    #[serde(default)]
+    pub identity: CertIdentity,
+    /// Client id has to be the identity. Clients with empty client id are assigned the identity
+    #[serde(default)]
+    pub enforce_client_id: bool,
+    /// Identity is used as username for authentication and ACLs, replacing the one in connect
+    #[serde(default)]
+    pub use_as_username: bool,
+    /// Certificate is enough to authenticate the client, username and password aren't checked
+    #[serde(default)]
+    pub skip_password_auth: bool,
+}
+
+/// Capabilities of the broker, advertised to MQTT 5 clients in `ConnAck`. Publishes and
+/// subscriptions using features which aren't available are rejected
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Capabilities {
+    /// Maximum QoS of publishes. Subscriptions with a higher QoS are granted this QoS instead
+    pub max_qos: u8,
+    pub retain_available: bool,
+    pub wildcard_subscription_available: bool,
+    pub subscription_identifiers_available: bool,
+    pub shared_subscription_available: bool,
+    /// Maximum QoS 1 and 2 publishes a client can send before they are acknowledged
+    pub receive_maximum: Option<u16>,
+    /// Maximum size of packets sent by clients, limited by `max_payload_size` as well
+    pub max_packet_size: Option<u32>,
+    /// Keep alive used instead of the one requested by clients
+    pub server_keep_alive: Option<u16>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            max_qos: 2,
+            retain_available: true,
+            wildcard_subscription_available: true,
+            subscription_identifiers_available: true,
+            shared_subscription_available: true,
+            receive_maximum: None,
+            max_packet_size: None,
+            server_keep_alive: None,
+        }
+    }
+}
+
+impl Capabilities {
+    pub fn max_qos(&self) -> protocol::QoS {
+        protocol::qos(self.max_qos.min(2)).unwrap()
+    }
+
+    /// Advertises the capabilities which differ from the defaults of MQTT 5 in `properties`
+    pub(crate) fn advertise(&self, properties: &mut protocol::ConnAckProperties) {
+        let unavailable = |available: bool| (!available).then_some(0);
+
+        properties.max_qos = (self.max_qos < 2).then_some(self.max_qos);
+        properties.retain_available = unavailable(self.retain_available);
+        properties.wildcard_subscription_available =
+            unavailable(self.wildcard_subscription_available);
+        properties.subscription_identifiers_available =
+            unavailable(self.subscription_identifiers_available);
+        properties.shared_subscription_available = unavailable(self.shared_subscription_available);
+        properties.receive_max = self.receive_maximum;
+        properties.max_packet_size = self.max_packet_size;
+        properties.server_keep_alive = self.server_keep_alive;
+    }
+}
+
+/// Limits on publishes and subscriptions of every client. Limits of a user, by username,
+/// replace the default ones. Limits which are 0 or not set don't apply
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub publishes_per_sec: Option<u32>,
+    /// Bytes of topics and payloads of publishes per second
+    pub bytes_per_sec: Option<u32>,
+    pub max_subscriptions: Option<usize>,
+    /// What happens to clients which publish faster than their limits
+    pub on_exceed: ExceedAction,
+    pub users: HashMap<String, Quota>,
+}
+
+/// Limits of a user, overriding the default ones in `RateLimits`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quota {
+    pub publishes_per_sec: Option<u32>,
+    pub bytes_per_sec: Option<u32>,
+    pub max_subscriptions: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExceedAction {
+    /// Stop reading from the client till it's within its limits again
+    #[default]
+    Throttle,
+    /// Disconnect the client, with `QuotaExceeded` for MQTT 5 clients
+    Disconnect,
+}
+
+impl RateLimits {
+    /// Limits of the client with `username`
+    pub fn quota(&self, username: Option<&str>) -> Quota {
+        let user = username
+            .and_then(|username| self.users.get(username))
+            .copied()
+            .unwrap_or_default();
+
+        Quota {
+            publishes_per_sec: user.publishes_per_sec.or(self.publishes_per_sec),
+            bytes_per_sec: user.bytes_per_sec.or(self.bytes_per_sec),
+            max_subscriptions: user.max_subscriptions.or(self.max_subscriptions),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CertIdentity {
+    #[default]
+    CommonName,
+    /// First subject alternative name
+    San,
+    Fingerprint,
+}
+
+impl ClientCertSettings {
+    /// Identity of the client with this certificate
+    pub fn identity(&self, cert: &PeerCertificate) -> Option<String> {
+        match self.identity {
+            CertIdentity::CommonName => cert.common_name.clone(),
+            CertIdentity::San => cert.sans.first().cloned(),
+            CertIdentity::Fingerprint => Some(cert.fingerprint.clone()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClusterSettings {
     /// Id with which this node connects to other nodes of the mesh
     pub node_id: NodeId,
     /// Address on which this broker is listening for mesh connections
     pub listen: String,
-    /// Address of clusters that this node has to initiate connection
-    pub seniors: Vec<(ConnectionId, String)>,
+    /// Id and address of nodes that this node has to initiate connection to. Every other
+    /// node connects to this node
+    pub seniors: Vec<(NodeId, String)>,
+    /// Ids of nodes which connect to this node. Connections from any other node are rejected
+    #[serde(default)]
+    pub juniors: Vec<NodeId>,
+    /// Secret shared by all the nodes, which they authenticate each other with
+    pub secret: String,
 }
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct RouterConfig {
     pub max_connections: usize,
     pub max_outgoing_packet_count: u64,
@@ -202,12 +446,84 @@ pub struct RouterConfig {
     // defaults to Round Robin
     #[serde(default)]
     pub shared_subscriptions_strategy: Strategy,
+    /// Directory in which filled up segments are persisted. Logs are kept in memory only when
+    /// this isn't set
+    #[serde(default)]
+    pub log_dir: Option<PathBuf>,
+    /// Maximum number of segments per filter retained in `log_dir`. Segments aren't persisted
+    /// when this is 0, which is warned about at startup if `log_dir` is set
+    #[serde(default)]
+    pub max_disk_segments: usize,
+    /// Milliseconds for which writes to files in `log_dir` are batched before they are synced to
+    /// disk, which happens off the router thread. Writes of the last interval might be lost when
+    /// the host crashes. Files are synced as soon as possible when this is 0
+    #[serde(default)]
+    pub log_sync_interval: u64,
+    /// Seconds for which sessions which don't specify an expiry interval, e.g. persistent
+    /// sessions of MQTT 3.1.1 clients, are retained after disconnection. Forever if not set
+    #[serde(default)]
+    pub default_session_expiry_interval: Option<u32>,
+    /// Store in which retained messages are persisted. Defaults to a file in `log_dir`
+    #[serde(skip)]
+    pub retained_store: Option<Arc<dyn RetainedStore>>,
+    /// Topic level access control of remote clients. Everything is allowed if not set
+    #[serde(default)]
+    pub acl: Option<AclConfig>,
+    /// Authorization of remote clients in addition to `acl`
+    #[serde(skip)]
+    pub authorization_handler: Option<AuthorizationHandler>,
+}
+
+impl RouterConfig {
+    pub fn set_retained_store<S: RetainedStore + 'static>(&mut self, store: S) {
+        self.retained_store = Some(Arc::new(store));
+    }
+
+    pub fn set_authorization_handler<F>(&mut self, authorization_fn: F)
+    where
+        F: Fn(&ClientInfo, Action, &str) -> bool + Send + Sync + 'static,
+    {
+        self.authorization_handler = Some(Arc::new(authorization_fn));
+    }
+}
+
+impl fmt::Debug for RouterConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RouterConfig")
+            .field("max_connections", &self.max_connections)
+            .field("max_outgoing_packet_count", &self.max_outgoing_packet_count)
+            .field("max_segment_size", &self.max_segment_size)
+            .field("max_segment_count", &self.max_segment_count)
+            .field("custom_segment", &self.custom_segment)
+            .field("initialized_filters", &self.initialized_filters)
+            .field(
+                "shared_subscriptions_strategy",
+                &self.shared_subscriptions_strategy,
+            )
+            .field("log_dir", &self.log_dir)
+            .field("max_disk_segments", &self.max_disk_segments)
+            .field("log_sync_interval", &self.log_sync_interval)
+            .field(
+                "default_session_expiry_interval",
+                &self.default_session_expiry_interval,
+            )
+            .field("retained_store", &self.retained_store.is_some())
+            .field("acl", &self.acl)
+            .field(
+                "authorization_handler",
+                &self.authorization_handler.is_some(),
+            )
+            .finish()
+    }
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct SegmentConfig {
     pub max_segment_size: usize,
     pub max_segment_count: usize,
+    /// Overrides `RouterConfig::max_disk_segments` for the filter
+    #[serde(default)]
+    pub max_disk_segments: Option<usize>,
 }
 
 type ReloadHandle = Handle<EnvFilter, Layered<Layer<Registry, Pretty, Format<Pretty>>, Registry>>;
@@ -215,6 +531,10 @@ pub struct SegmentConfig {
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ConsoleSettings {
     pub listen: String,
+    /// Bearer token required by endpoints which modify broker state. They are disabled when
+    /// it isn't set
+    #[serde(default, skip_serializing)]
+    pub admin_token: Option<String>,
     #[serde(skip)]
     filter_handle: Option<ReloadHandle>,
 }
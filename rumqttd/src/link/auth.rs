@@ -0,0 +1,383 @@
+//! MQTT 5 enhanced authentication, where client and broker exchange authentication data in
+//! `CONNECT`, `AUTH` and `CONNACK` packets until the broker is satisfied. The same exchange
+//! is run again when the client re-authenticates during the connection.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/// Challenge/response authentication for one authentication method
+pub trait Authenticator: Send + Sync {
+    /// Name of the method as sent by clients in `Authentication Method`, e.g. `SCRAM-SHA-256`
+    fn method(&self) -> &str;
+    /// Starts a new exchange with the client. Called on connect and on every re-authentication
+    fn start(&self, client_id: &str) -> Box<dyn AuthSession>;
+}
+
+/// State of a single exchange
+pub trait AuthSession: Send {
+    /// Process `Authentication Data` sent by the client and decide on the next step
+    fn step(&mut self, data: Option<Bytes>) -> AuthStep;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthStep {
+    /// Send the data to the client and wait for its response
+    Continue(Option<Bytes>),
+    /// Client is authenticated
+    Success {
+        /// User the client authenticated as, which replaces the unverified username in
+        /// `CONNECT`. Client has no username when the method doesn't identify users
+        username: Option<String>,
+        /// Sent back to the client along with the success, if any
+        data: Option<Bytes>,
+    },
+    /// Client failed to authenticate
+    Failure,
+}
+
+const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+const SCRAM_ITERATIONS: u32 = 4096;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Keys derived from the password of a user. Password itself is never stored
+#[derive(Clone)]
+struct ScramCredentials {
+    salt: Vec<u8>,
+    iterations: u32,
+    stored_key: [u8; 32],
+    server_key: [u8; 32],
+}
+
+/// `SCRAM-SHA-256` as specified in RFC 5802 and RFC 7677, without channel binding. Usernames
+/// and passwords are used as they are, without SASLprep normalization.
+#[derive(Clone)]
+pub struct ScramSha256 {
+    users: Arc<HashMap<String, ScramCredentials>>,
+    /// Derives salts of unknown users, which are challenged like known ones so that they
+    /// can't be told apart
+    fake_salt_key: [u8; 32],
+}
+
+impl Default for ScramSha256 {
+    fn default() -> ScramSha256 {
+        let mut fake_salt_key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut fake_salt_key);
+        ScramSha256 {
+            users: Arc::default(),
+            fake_salt_key,
+        }
+    }
+}
+
+impl ScramSha256 {
+    pub fn new() -> ScramSha256 {
+        ScramSha256::default()
+    }
+
+    /// Adds a user, replacing previous one with same username
+    pub fn add_user(&mut self, username: &str, password: &str) {
+        let mut salt = vec![0; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        let mut salted_password = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(
+            password.as_bytes(),
+            &salt,
+            SCRAM_ITERATIONS,
+            &mut salted_password,
+        );
+
+        let client_key = hmac(&salted_password, b"Client Key");
+        let credentials = ScramCredentials {
+            salt,
+            iterations: SCRAM_ITERATIONS,
+            stored_key: Sha256::digest(client_key).into(),
+            server_key: hmac(&salted_password, b"Server Key"),
+        };
+
+        Arc::make_mut(&mut self.users).insert(username.to_owned(), credentials);
+    }
+}
+
+impl fmt::Debug for ScramSha256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScramSha256")
+            .field("users", &self.users.keys())
+            .finish()
+    }
+}
+
+impl Authenticator for ScramSha256 {
+    fn method(&self) -> &str {
+        SCRAM_SHA_256
+    }
+
+    fn start(&self, _client_id: &str) -> Box<dyn AuthSession> {
+        Box::new(ScramSession {
+            users: self.users.clone(),
+            fake_salt_key: self.fake_salt_key,
+            state: ScramState::ClientFirst,
+        })
+    }
+}
+
+enum ScramState {
+    ClientFirst,
+    ClientFinal {
+        gs2_header: String,
+        client_first_bare: String,
+        server_first: String,
+        nonce: String,
+        username: String,
+        credentials: Box<ScramCredentials>,
+    },
+    Done,
+}
+
+struct ScramSession {
+    users: Arc<HashMap<String, ScramCredentials>>,
+    fake_salt_key: [u8; 32],
+    state: ScramState,
+}
+
+impl ScramSession {
+    /// Handles `gs2-header client-first-message-bare` and replies with `server-first-message`
+    fn client_first(&mut self, message: &str) -> Option<Bytes> {
+        // Channel binding isn't supported, so only `n` and `y` flags are acceptable
+        let (gs2_header, bare) = match message.as_bytes().first()? {
+            b'n' | b'y' => {
+                let authzid_end = message.get(2..)?.find(',')? + 2;
+                message.split_at(authzid_end + 1)
+            }
+            _ => return None,
+        };
+
+        let mut attributes = bare.split(',');
+        let username = attributes.next()?.strip_prefix("n=")?;
+        let client_nonce = attributes.next()?.strip_prefix("r=")?;
+        let username = username.replace("=2C", ",").replace("=3D", "=");
+        let credentials = match self.users.get(&username) {
+            Some(credentials) => credentials.clone(),
+            // Same salt for every attempt, with keys no proof can match. Exchange fails only
+            // after the client's proof, just like with a wrong password
+            None => ScramCredentials {
+                salt: hmac(&self.fake_salt_key, username.as_bytes())[..16].to_vec(),
+                iterations: SCRAM_ITERATIONS,
+                stored_key: [0; 32],
+                server_key: [0; 32],
+            },
+        };
+        let credentials = Box::new(credentials);
+
+        let mut server_nonce = [0; 18];
+        rand::thread_rng().fill_bytes(&mut server_nonce);
+        let nonce = format!("{client_nonce}{}", STANDARD.encode(server_nonce));
+
+        let server_first = format!(
+            "r={nonce},s={},i={}",
+            STANDARD.encode(&credentials.salt),
+            credentials.iterations
+        );
+
+        self.state = ScramState::ClientFinal {
+            gs2_header: gs2_header.to_owned(),
+            client_first_bare: bare.to_owned(),
+            server_first: server_first.clone(),
+            nonce,
+            username,
+            credentials,
+        };
+
+        Some(Bytes::from(server_first))
+    }
+
+    /// Verifies proof in `client-final-message` and replies with `server-final-message` along
+    /// with the authenticated username
+    fn client_final(&mut self, message: &str) -> Option<(String, Bytes)> {
+        let ScramState::ClientFinal {
+            gs2_header,
+            client_first_bare,
+            server_first,
+            nonce,
+            username,
+            credentials,
+        } = std::mem::replace(&mut self.state, ScramState::Done)
+        else {
+            return None;
+        };
+
+        let (without_proof, proof) = message.rsplit_once(",p=")?;
+        let mut attributes = without_proof.split(',');
+        let channel_binding = attributes.next()?.strip_prefix("c=")?;
+        let client_nonce = attributes.next()?.strip_prefix("r=")?;
+        if channel_binding != STANDARD.encode(gs2_header) || client_nonce != nonce {
+            return None;
+        }
+
+        let proof = STANDARD.decode(proof).ok()?;
+        if proof.len() != 32 {
+            return None;
+        }
+
+        let auth_message = format!("{client_first_bare},{server_first},{without_proof}");
+        let client_signature = hmac(&credentials.stored_key, auth_message.as_bytes());
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(client_signature.iter())
+            .map(|(p, s)| p ^ s)
+            .collect();
+
+        let stored_key = Sha256::digest(client_key);
+        if !bool::from(stored_key.as_slice().ct_eq(&credentials.stored_key)) {
+            return None;
+        }
+
+        let server_signature = hmac(&credentials.server_key, auth_message.as_bytes());
+        let server_final = format!("v={}", STANDARD.encode(server_signature));
+        Some((username, Bytes::from(server_final)))
+    }
+}
+
+impl AuthSession for ScramSession {
+    fn step(&mut self, data: Option<Bytes>) -> AuthStep {
+        let Some(message) = data.as_deref().and_then(|d| std::str::from_utf8(d).ok()) else {
+            return AuthStep::Failure;
+        };
+
+        let reply = match self.state {
+            ScramState::ClientFirst => self
+                .client_first(message)
+                .map(|d| AuthStep::Continue(Some(d))),
+            ScramState::ClientFinal { .. } => {
+                self.client_final(message)
+                    .map(|(username, data)| AuthStep::Success {
+                        username: Some(username),
+                        data: Some(data),
+                    })
+            }
+            ScramState::Done => None,
+        };
+
+        reply.unwrap_or(AuthStep::Failure)
+    }
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// Client side of the exchange, as per RFC 5802
+    pub(crate) fn client_final(
+        client_first_bare: &str,
+        server_first: &str,
+        password: &str,
+    ) -> (String, String) {
+        let mut attributes = server_first.split(',');
+        let nonce = attributes.next().unwrap().strip_prefix("r=").unwrap();
+        let salt = attributes.next().unwrap().strip_prefix("s=").unwrap();
+        let iterations = attributes.next().unwrap().strip_prefix("i=").unwrap();
+
+        let mut salted_password = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(
+            password.as_bytes(),
+            &STANDARD.decode(salt).unwrap(),
+            iterations.parse().unwrap(),
+            &mut salted_password,
+        );
+
+        let client_key = hmac(&salted_password, b"Client Key");
+        let stored_key = Sha256::digest(client_key);
+        let without_proof = format!("c=biws,r={nonce}");
+        let auth_message = format!("{client_first_bare},{server_first},{without_proof}");
+        let client_signature = hmac(&stored_key, auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(client_signature.iter())
+            .map(|(k, s)| k ^ s)
+            .collect();
+
+        let server_key = hmac(&salted_password, b"Server Key");
+        let server_signature = hmac(&server_key, auth_message.as_bytes());
+        (
+            format!("{without_proof},p={}", STANDARD.encode(proof)),
+            format!("v={}", STANDARD.encode(server_signature)),
+        )
+    }
+
+    fn exchange(scram: &ScramSha256, username: &str, password: &str) -> AuthStep {
+        let mut session = scram.start("client");
+        let client_first_bare = format!("n={username},r=rOprNGfwEbeRWgbNEkqO");
+
+        let first = Bytes::from(format!("n,,{client_first_bare}"));
+        let server_first = match session.step(Some(first)) {
+            AuthStep::Continue(Some(data)) => String::from_utf8(data.to_vec()).unwrap(),
+            step => return step,
+        };
+
+        let (client_final, server_final) =
+            client_final(&client_first_bare, &server_first, password);
+        match session.step(Some(Bytes::from(client_final))) {
+            AuthStep::Success {
+                username: Some(authenticated),
+                data: Some(data),
+            } => {
+                assert_eq!(authenticated, username);
+                assert_eq!(data, server_final);
+                AuthStep::Success {
+                    username: Some(authenticated),
+                    data: Some(data),
+                }
+            }
+            step => step,
+        }
+    }
+
+    #[test]
+    fn scram_accepts_right_password_only() {
+        let mut scram = ScramSha256::new();
+        scram.add_user("user", "pencil");
+
+        assert!(matches!(
+            exchange(&scram, "user", "pencil"),
+            AuthStep::Success { .. }
+        ));
+        assert_eq!(exchange(&scram, "user", "pen"), AuthStep::Failure);
+        assert_eq!(exchange(&scram, "other", "pencil"), AuthStep::Failure);
+    }
+
+    #[test]
+    fn unknown_users_are_challenged_like_known_ones() {
+        let mut scram = ScramSha256::new();
+        scram.add_user("user", "pencil");
+
+        let salt = |username: &str| {
+            let mut session = scram.start("client");
+            let first = Bytes::from(format!("n,,n={username},r=rOprNGfwEbeRWgbNEkqO"));
+            let AuthStep::Continue(Some(server_first)) = session.step(Some(first)) else {
+                panic!("{username} isn't challenged");
+            };
+
+            let server_first = String::from_utf8(server_first.to_vec()).unwrap();
+            let salt = server_first.split(',').nth(1).unwrap();
+            STANDARD.decode(salt.strip_prefix("s=").unwrap()).unwrap()
+        };
+
+        assert_eq!(salt("user").len(), salt("other").len());
+        assert_eq!(salt("other"), salt("other"));
+        assert_ne!(salt("other"), salt("another"));
+    }
+}
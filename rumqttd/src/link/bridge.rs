@@ -8,7 +8,9 @@
     sync::Arc,
 };
 
-use std::{io, net::AddrParseError, time::Duration};
+use std::{collections::VecDeque, io, net::AddrParseError, time::Duration};
+
+use bytes::Bytes;
 
 use tokio::{
     net::TcpStream,
@@ -31,10 +33,14 @@
 
 use crate::{
     link::{local::LinkError, network::Network},
-    local::LinkBuilder,
-    protocol::{self, Connect, Packet, PingReq, Protocol, QoS, RetainForwardRule, Subscribe},
-    router::Event,
-    BridgeConfig, ConnectionId, Notification, Transport,
+    local::{LinkBuilder, LinkRx, LinkTx},
+    protocol::{
+        self, matches, valid_filter, Connect, ConnectReturnCode, Disconnect, DisconnectReasonCode,
+        Login, Packet, PingReq, Protocol, PubAck, PubAckReason, PubComp, PubRec, PubRecReason,
+        PubRel, PubRelReason, Publish, QoS, RetainForwardRule, Subscribe,
+    },
+    router::{Ack, Event},
+    BridgeConfig, BridgeDirection, BridgeRule, ConnectionId, Forward, Notification, Transport,
 };
 
 use super::network;
@@ -52,20 +58,38 @@ pub async fn start<P>(
 where
     P: Protocol + Clone + Send + 'static,
 {
-    let span = tracing::info_span!("bridge_link");
+    let span = tracing::info_span!("bridge_link", client_id = config.name);
     let _guard = span.enter();
 
+    if config.version != 4 && config.version != 5 {
+        return Err(BridgeError::InvalidVersion(config.version));
+    }
+
+    let rules = rules(&config);
+    for rule in rules.iter() {
+        if protocol::qos(rule.qos).is_none() {
+            return Err(BridgeError::InvalidQos);
+        }
+
+        if !valid_filter(&rule.local_filter()) || !valid_filter(&rule.remote_filter()) {
+            return Err(BridgeError::InvalidRule(rule.topic.clone()));
+        }
+    }
+
     info!(
-        client_id = config.name,
         remote_addr = &config.addr,
-        "Starting bridge with subscription on filter \"{}\"",
-        &config.sub_path,
+        "Starting bridge with {} rules",
+        rules.len()
     );
-    let (mut tx, mut rx, _ack) = LinkBuilder::new(&config.name, router_tx)
-        .dynamic_filters(true)
-        .build()?;
 
-    'outer: loop {
+    // Link is created before connecting to remote broker, so that local publishes are
+    // forwarded once connected
+    let mut link = Some(local_link(&config, &rules, router_tx.clone()).await?);
+
+    // Publishes of the bridge which come back through its own subscriptions
+    let mut local_echoes = Echoes::default();
+
+    loop {
         let mut network = match network_connect(&config, &config.addr, protocol.clone()).await {
             Ok(v) => v,
             Err(e) => {
@@ -75,7 +99,9 @@ pub async fn start<P>(
             }
         };
         info!(remote_addr = &config.addr, "Connected to remote");
-        if let Err(e) = network_init(&config, &mut network).await {
+        // Reads wait for a ping response before timing out
+        network.set_keepalive(config.ping_delay.try_into().unwrap_or(u16::MAX));
+        if let Err(e) = network_init(&config, &rules, &mut network).await {
             warn!(
                 "Unable to connect and subscribe to remote broker, reconnecting - {}",
                 e
@@ -84,81 +110,414 @@ pub async fn start<P>(
             continue;
         }
 
-        let ping_req = Packet::PingReq(PingReq);
         debug!("Received suback from {}", &config.addr);
+        let (mut tx, mut rx) = match link.take() {
+            Some(link) => link,
+            None => local_link(&config, &rules, router_tx.clone()).await?,
+        };
+
+        let result = run(
+            &config,
+            &rules,
+            &mut network,
+            &mut tx,
+            &mut rx,
+            &mut local_echoes,
+        )
+        .await;
+
+        match result {
+            Ok(()) => warn!("Disconnected by remote broker, reconnecting"),
+            Err(ref e) => warn!("Bridge link error, reconnecting - {}", e),
+        }
+
+        // Local link is a persistent session. Router saves its cursors and reads publishes which
+        // weren't acked by remote broker again for next link. Router drops the link itself on
+        // local link errors
+        if !matches!(result, Err(BridgeError::Link(_))) {
+            let disconnect = Disconnect {
+                reason_code: DisconnectReasonCode::NormalDisconnection,
+            };
+            tx.send(Packet::Disconnect(disconnect, None)).await?;
+        }
+
+        sleep(Duration::from_secs(config.reconnection_delay)).await;
+    }
+}
+
+/// Local link of the bridge, subscribed to filters of outward rules with QoS 1 so that
+/// publishes are acked only after remote broker acks them
+async fn local_link(
+    config: &BridgeConfig,
+    rules: &[BridgeRule],
+    router_tx: Sender<(ConnectionId, Event)>,
+) -> Result<(LinkTx, LinkRx), BridgeError> {
+    let (mut tx, rx, _ack) = LinkBuilder::new(&config.name, router_tx)
+        .dynamic_filters(true)
+        .clean_session(false)
+        .session_expiry_interval(Some(u32::MAX))
+        .build()?;
 
-        let mut ping_time = Instant::now();
-        let mut timeout = sleep_until(ping_time + Duration::from_secs(config.ping_delay));
-        let mut ping_unacked = false;
-
-        loop {
-            tokio::select! {
-                packet_res = network.read() => {
-                    // resetting timeout because tokio::select! consumes the old timeout future
-                    timeout = sleep_until(ping_time + Duration::from_secs(config.ping_delay));
-                    let packet = match packet_res {
-                        Ok(v) => v,
-                        Err(e) => {
-                            warn!("Unable to read from network stream, reconnecting - {}", e);
-                            sleep(Duration::from_secs(config.reconnection_delay)).await;
-                            continue 'outer;
+    let filters: Vec<protocol::Filter> = rules
+        .iter()
+        .filter(|rule| rule.outward())
+        .map(|rule| protocol::Filter {
+            path: rule.local_filter(),
+            qos: QoS::AtLeastOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        })
+        .collect();
+
+    if !filters.is_empty() {
+        let subscribe = Subscribe { pkid: 0, filters };
+        tx.send(Packet::Subscribe(subscribe, None)).await?;
+    }
+
+    Ok((tx, rx))
+}
+
+/// Exchanges messages between local link and remote broker until either of them fails. Returns
+/// `Ok` when remote broker disconnects the bridge
+async fn run<P: Protocol>(
+    config: &BridgeConfig,
+    rules: &[BridgeRule],
+    network: &mut Network<P>,
+    tx: &mut LinkTx,
+    rx: &mut LinkRx,
+    local_echoes: &mut Echoes,
+) -> Result<(), BridgeError> {
+    let ping_req = Packet::PingReq(PingReq);
+    let mut ping_time = Instant::now();
+    let mut timeout = sleep_until(ping_time + Duration::from_secs(config.ping_delay));
+    let mut ping_unacked = false;
+
+    let mut remote_echoes = Echoes::default();
+    let mut inflight = Inflight::default();
+
+    loop {
+        tokio::select! {
+            packet = network.read() => {
+                // resetting timeout because tokio::select! consumes the old timeout future
+                timeout = sleep_until(ping_time + Duration::from_secs(config.ping_delay));
+                match packet? {
+                    Packet::Publish(mut publish, mut publish_prop) => {
+                        let local_topic = std::str::from_utf8(&publish.topic)
+                            .ok()
+                            .filter(|topic| !remote_echoes.take(topic, &publish.payload))
+                            .and_then(|topic| rules.iter().find_map(|rule| rule.to_local(topic)));
+
+                        // Acknowledge publishes which aren't forwarded, as router won't
+                        let Some(local_topic) = local_topic else {
+                            if let Some(ack) = ack(&publish) {
+                                network.write(ack).await?;
+                            }
+                            continue;
+                        };
+
+                        if rules.iter().any(|rule| rule.outward() && matches(&local_topic, &rule.local_filter())) {
+                            local_echoes.push(local_topic.clone(), publish.payload.clone());
                         }
-                    };
 
-                    match packet {
-                        Packet::Publish(publish, publish_prop) => {
-                            tx.send(Packet::Publish(publish, publish_prop)).await?;
+                        // Aliases are specific to the connection with remote broker
+                        if let Some(properties) = publish_prop.as_mut() {
+                            properties.topic_alias = None;
                         }
-                        Packet::PingResp(_) => ping_unacked = false,
-                        // TODO: Handle incoming pubrel incase of QoS subscribe
-                        packet => warn!("Expected publish, got {:?}", packet),
+
+                        publish.topic = local_topic.into();
+                        tx.send(Packet::Publish(publish, publish_prop)).await?;
+                    }
+                    Packet::PubRel(pubrel, pubrel_prop) => {
+                        tx.send(Packet::PubRel(pubrel, pubrel_prop)).await?;
                     }
+                    Packet::PubRec(pubrec, _) => {
+                        let pubrel = PubRel {
+                            pkid: pubrec.pkid,
+                            reason: PubRelReason::Success,
+                        };
+                        network.write(Packet::PubRel(pubrel, None)).await?;
+                    }
+                    Packet::PubAck(PubAck { pkid, .. }, _) | Packet::PubComp(PubComp { pkid, .. }, _) => {
+                        inflight.ack(pkid);
+                        ack_local(&mut inflight, tx).await?;
+                    }
+                    Packet::PingResp(_) => ping_unacked = false,
+                    Packet::Disconnect(disconnect, _) => {
+                        info!("Disconnected by remote with {:?}", disconnect.reason_code);
+                        return Ok(());
+                    }
+                    packet => warn!("Expected publish, got {:?}", packet),
                 }
-                o = rx.next() => {
-                    let notif = match o {
-                        Ok(notif) => notif,
-                        Err(e) => {
-                            warn!("Local link error, reconnecting - {}", e);
-                            sleep(Duration::from_secs(config.reconnection_delay)).await;
-                            continue 'outer;
-                        }
-                    };
-                    if let Some(notif) = notif {
-                        match notif {
-                            Notification::DeviceAck(ack) => {
-                                network.write(ack.into()).await?;
-                            },
-                            _ => unreachable!("We should only get device acks"),
+            }
+            notif = rx.next() => {
+                if let Some(notif) = notif? {
+                    match notif {
+                        // Acks of publishes from remote broker
+                        Notification::DeviceAck(
+                            ack @ (Ack::PubAck(_)
+                            | Ack::PubAckWithProperties(..)
+                            | Ack::PubRec(_)
+                            | Ack::PubRecWithProperties(..)
+                            | Ack::PubComp(_)
+                            | Ack::PubCompWithProperties(..)),
+                        ) => {
+                            network.write(ack.into()).await?;
+                        },
+                        Notification::DeviceAck(_) => {}
+                        Notification::Forward(forward) => {
+                            // Forwards are QoS 1 as per subscription of local link
+                            let pkid = forward.publish.pkid;
+                            let tracked = forward.publish.qos != QoS::AtMostOnce;
+
+                            match outgoing(config.version, rules, forward, local_echoes, &mut remote_echoes) {
+                                Some(packet) => {
+                                    let acked = matches!(&packet, Packet::Publish(p, _) if p.qos == QoS::AtMostOnce);
+                                    network.write(packet).await?;
+                                    if tracked {
+                                        inflight.push(pkid, acked);
+                                    }
+                                }
+                                None if tracked => inflight.push(pkid, true),
+                                None => {}
+                            }
+
+                            ack_local(&mut inflight, tx).await?;
                         }
-
+                        Notification::Unschedule => rx.wake().await?,
+                        notif => warn!("Unexpected notification {:?}", notif),
                     }
-                    timeout = sleep_until(ping_time + Duration::from_secs(config.ping_delay));
-
                 }
-                _ = timeout => {
-                    // retry connection if ping not acked till next timeout
-                    if ping_unacked {
-                        warn!("No response to previous ping, reconnecting");
-                        sleep(Duration::from_secs(config.reconnection_delay)).await;
-                        continue 'outer;
-                    }
+                timeout = sleep_until(ping_time + Duration::from_secs(config.ping_delay));
+            }
+            _ = timeout => {
+                // reconnect if ping not acked till next timeout
+                if ping_unacked {
+                    return Err(BridgeError::PingTimeout);
+                }
 
-                    if let Err(e) = network.write(ping_req.clone()).await {
-                        warn!("Unable to write PINGREQ to network stream, reconnecting - {}", e);
-                        sleep(Duration::from_secs(config.reconnection_delay)).await;
-                        continue 'outer;
-                    };
-                    ping_unacked = true;
+                network.write(ping_req.clone()).await?;
+                ping_unacked = true;
 
-                    ping_time = Instant::now();
-                    // resetting timeout because tokio::select! consumes the old timeout future
-                    timeout = sleep_until(ping_time + Duration::from_secs(config.ping_delay));
-                }
+                ping_time = Instant::now();
+                // resetting timeout because tokio::select! consumes the old timeout future
+                timeout = sleep_until(ping_time + Duration::from_secs(config.ping_delay));
             }
         }
     }
 }
 
+/// Acks publishes of local link to router, in the order router sent them
+async fn ack_local(inflight: &mut Inflight, tx: &mut LinkTx) -> Result<(), LinkError> {
+    while let Some(pkid) = inflight.pop_acked() {
+        let puback = PubAck {
+            pkid,
+            reason: PubAckReason::Success,
+        };
+        tx.send(Packet::PubAck(puback, None)).await?;
+    }
+
+    Ok(())
+}
+
+/// Rules of the bridge, including the one from `sub_path`
+fn rules(config: &BridgeConfig) -> Vec<BridgeRule> {
+    let mut rules = config.rules.clone();
+    if let Some(sub_path) = &config.sub_path {
+        rules.push(BridgeRule {
+            topic: sub_path.clone(),
+            direction: BridgeDirection::In,
+            qos: config.qos,
+            local_prefix: String::new(),
+            remote_prefix: String::new(),
+        });
+    }
+
+    rules
+}
+
+impl BridgeRule {
+    fn inward(&self) -> bool {
+        matches!(self.direction, BridgeDirection::In | BridgeDirection::Both)
+    }
+
+    fn outward(&self) -> bool {
+        matches!(self.direction, BridgeDirection::Out | BridgeDirection::Both)
+    }
+
+    fn local_filter(&self) -> String {
+        format!("{}{}", self.local_prefix, self.topic)
+    }
+
+    fn remote_filter(&self) -> String {
+        format!("{}{}", self.remote_prefix, self.topic)
+    }
+
+    /// Local topic of a message from remote broker, if the rule forwards it
+    fn to_local(&self, topic: &str) -> Option<String> {
+        if !self.inward() || !matches(topic, &self.remote_filter()) {
+            return None;
+        }
+
+        let topic = topic.strip_prefix(&self.remote_prefix)?;
+        Some(format!("{}{topic}", self.local_prefix))
+    }
+
+    /// Remote topic of a local message, if the rule forwards it
+    fn to_remote(&self, topic: &str) -> Option<String> {
+        if !self.outward() || !matches(topic, &self.local_filter()) {
+            return None;
+        }
+
+        let topic = topic.strip_prefix(&self.local_prefix)?;
+        Some(format!("{}{topic}", self.remote_prefix))
+    }
+}
+
+/// Publish to remote broker for a local message, if any rule forwards it
+fn outgoing(
+    version: u8,
+    rules: &[BridgeRule],
+    forward: Forward,
+    local_echoes: &mut Echoes,
+    remote_echoes: &mut Echoes,
+) -> Option<Packet> {
+    let Forward {
+        mut publish,
+        mut properties,
+        ..
+    } = forward;
+
+    let topic = std::str::from_utf8(&publish.topic).ok()?;
+    if local_echoes.take(topic, &publish.payload) {
+        return None;
+    }
+
+    let (remote_topic, qos) = rules
+        .iter()
+        .find_map(|rule| Some((rule.to_remote(topic)?, rule.qos)))?;
+
+    // With MQTT 5, subscriptions of `both` rules are no local. Otherwise remote broker sends
+    // the message back if it matches any of them
+    if version == 4
+        && rules
+            .iter()
+            .any(|rule| rule.inward() && matches(&remote_topic, &rule.remote_filter()))
+    {
+        remote_echoes.push(remote_topic.clone(), publish.payload.clone());
+    }
+
+    publish.topic = remote_topic.into();
+    publish.qos = protocol::qos(qos)?;
+    publish.dup = false;
+    // Publishes to remote broker use the packet ids router assigned to them, which are unique
+    // among the ones that aren't acked yet
+    if publish.qos == QoS::AtMostOnce {
+        publish.pkid = 0;
+    }
+
+    match properties.as_mut() {
+        Some(_) if version == 4 => properties = None,
+        Some(properties) => properties.topic_alias = None,
+        None => {}
+    }
+
+    Some(Packet::Publish(publish, properties))
+}
+
+/// Ack of a publish from remote broker
+fn ack(publish: &Publish) -> Option<Packet> {
+    match publish.qos {
+        QoS::AtMostOnce => None,
+        QoS::AtLeastOnce => {
+            let puback = PubAck {
+                pkid: publish.pkid,
+                reason: PubAckReason::Success,
+            };
+            Some(Packet::PubAck(puback, None))
+        }
+        QoS::ExactlyOnce => {
+            let pubrec = PubRec {
+                pkid: publish.pkid,
+                reason: PubRecReason::Success,
+            };
+            Some(Packet::PubRec(pubrec, None))
+        }
+    }
+}
+
+/// Publishes of local link which are forwarded to remote broker. Router requires acks in
+/// the order it sent the publishes, while remote broker may ack them in any order
+#[derive(Debug, Default)]
+struct Inflight {
+    /// Packet id and whether it's acked
+    publishes: VecDeque<(u16, bool)>,
+}
+
+impl Inflight {
+    fn push(&mut self, pkid: u16, acked: bool) {
+        self.publishes.push_back((pkid, acked));
+    }
+
+    fn ack(&mut self, pkid: u16) {
+        match self
+            .publishes
+            .iter_mut()
+            .find(|(id, acked)| *id == pkid && !acked)
+        {
+            Some((_, acked)) => *acked = true,
+            None => warn!(pkid, "Unsolicited ack from remote broker"),
+        }
+    }
+
+    /// Oldest publish if it's acked, along with all the ones before it
+    fn pop_acked(&mut self) -> Option<u16> {
+        match self.publishes.front() {
+            Some(&(pkid, true)) => {
+                self.publishes.pop_front();
+                Some(pkid)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Messages which the bridge forwarded and expects to receive back through its own
+/// subscriptions. They are dropped when they come back to avoid forwarding them in a loop
+#[derive(Debug, Default)]
+struct Echoes {
+    expected: VecDeque<(String, Bytes)>,
+}
+
+impl Echoes {
+    /// Limit on expected echoes, in case some of them never come back
+    const MAX_EXPECTED: usize = 1000;
+
+    fn push(&mut self, topic: String, payload: Bytes) {
+        if self.expected.len() == Self::MAX_EXPECTED {
+            self.expected.pop_front();
+        }
+
+        self.expected.push_back((topic, payload));
+    }
+
+    /// Returns true if message was expected to come back, forgetting it
+    fn take(&mut self, topic: &str, payload: &Bytes) -> bool {
+        let position = self
+            .expected
+            .iter()
+            .position(|(t, p)| t == topic && p == payload);
+
+        match position {
+            Some(position) => {
+                self.expected.remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
 async fn network_connect<P: Protocol>(
     config: &BridgeConfig,
     addr: &str,
@@ -243,6 +602,7 @@ pub async fn tls_connect<P: AsRef<Path>>(
 
 async fn network_init<P: Protocol>(
     config: &BridgeConfig,
+    rules: &[BridgeRule],
     network: &mut Network<P>,
 ) -> Result<(), BridgeError> {
     let connect = Connect {
@@ -250,30 +610,36 @@ async fn network_init<P: Protocol>(
         client_id: config.name.clone(),
         clean_session: true,
     };
-    let packet = Packet::Connect(connect, None, None, None, None);
 
+    let login = config.username.as_ref().map(|username| Login {
+        username: username.clone(),
+        password: config.password.clone().unwrap_or_default(),
+    });
+
+    let packet = Packet::Connect(connect, None, None, None, login);
     send_and_recv(network, packet, |packet| {
-        matches!(packet, Packet::ConnAck(..))
+        matches!(packet, Packet::ConnAck(ack, _) if ack.code == ConnectReturnCode::Success)
     })
     .await?;
 
     // connecting to other router
-    let qos = match config.qos {
-        0 => QoS::AtMostOnce,
-        1 => QoS::AtLeastOnce,
-        2 => QoS::ExactlyOnce,
-        _ => return Err(BridgeError::InvalidQos),
-    };
-
-    let filters = vec![protocol::Filter {
-        path: config.sub_path.clone(),
-        qos,
-        nolocal: false,
-        preserve_retain: false,
-        retain_forward_rule: RetainForwardRule::Never,
-    }];
+    let filters: Vec<protocol::Filter> = rules
+        .iter()
+        .filter(|rule| rule.inward())
+        .map(|rule| protocol::Filter {
+            path: rule.remote_filter(),
+            qos: protocol::qos(rule.qos).unwrap_or(QoS::AtMostOnce),
+            nolocal: config.version == 5 && rule.outward(),
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        })
+        .collect();
+
+    if filters.is_empty() {
+        return Ok(());
+    }
 
-    let subscribe = Subscribe { pkid: 0, filters };
+    let subscribe = Subscribe { pkid: 1, filters };
     let packet = Packet::Subscribe(subscribe, None);
     send_and_recv(network, packet, |packet| {
         matches!(packet, Packet::SubAck(..))
@@ -314,9 +680,138 @@ pub enum BridgeError {
     Link(#[from] LinkError),
     #[error("Invalid qos")]
     InvalidQos,
+    #[error("Invalid MQTT version {0}")]
+    InvalidVersion(u8),
+    #[error("Invalid topic in rule {0}")]
+    InvalidRule(String),
     #[error("Invalid packet")]
     InvalidPacket,
+    #[error("No response to ping from remote broker")]
+    PingTimeout,
     #[cfg(feature = "use-rustls")]
     #[error("Invalid trust_anchor")]
     NoValidCertInChain,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(topic: &str, direction: BridgeDirection, local: &str, remote: &str) -> BridgeRule {
+        BridgeRule {
+            topic: topic.to_owned(),
+            direction,
+            qos: 1,
+            local_prefix: local.to_owned(),
+            remote_prefix: remote.to_owned(),
+        }
+    }
+
+    #[test]
+    fn topics_are_remapped_by_direction() {
+        let rule = rule("sensors/+/temp", BridgeDirection::Both, "", "site1/");
+        assert_eq!(
+            rule.to_local("site1/sensors/a/temp").as_deref(),
+            Some("sensors/a/temp")
+        );
+        assert_eq!(
+            rule.to_remote("sensors/a/temp").as_deref(),
+            Some("site1/sensors/a/temp")
+        );
+        assert_eq!(rule.to_local("sensors/a/temp"), None);
+        assert_eq!(rule.to_remote("sensors/a/humidity"), None);
+
+        let inward = BridgeRule {
+            direction: BridgeDirection::In,
+            ..rule.clone()
+        };
+        assert_eq!(inward.to_remote("sensors/a/temp"), None);
+
+        let outward = BridgeRule {
+            direction: BridgeDirection::Out,
+            ..rule
+        };
+        assert_eq!(outward.to_local("site1/sensors/a/temp"), None);
+    }
+
+    #[test]
+    fn echoes_are_taken_once() {
+        let mut echoes = Echoes::default();
+        let payload = Bytes::from_static(b"hello");
+        echoes.push("a/b".to_owned(), payload.clone());
+
+        assert!(!echoes.take("a/c", &payload));
+        assert!(echoes.take("a/b", &payload));
+        assert!(!echoes.take("a/b", &payload));
+
+        for i in 0..=Echoes::MAX_EXPECTED {
+            echoes.push(i.to_string(), payload.clone());
+        }
+        assert!(!echoes.take("0", &payload));
+        assert!(echoes.take("1", &payload));
+    }
+
+    #[test]
+    fn local_messages_from_remote_are_not_sent_back() {
+        let rules = vec![rule("cmd/#", BridgeDirection::Both, "", "site1/")];
+        let forward = |topic: &'static str| {
+            let mut publish = Publish::new(topic, "on", false);
+            publish.qos = QoS::AtLeastOnce;
+            publish.pkid = 7;
+            Forward {
+                cursor: None,
+                size: 0,
+                publish,
+                properties: None,
+            }
+        };
+
+        let mut local_echoes = Echoes::default();
+        let mut remote_echoes = Echoes::default();
+
+        local_echoes.push("cmd/light".to_owned(), Bytes::from_static(b"on"));
+        let packet = outgoing(
+            4,
+            &rules,
+            forward("cmd/light"),
+            &mut local_echoes,
+            &mut remote_echoes,
+        );
+        assert!(packet.is_none());
+
+        let packet = outgoing(
+            4,
+            &rules,
+            forward("cmd/light"),
+            &mut local_echoes,
+            &mut remote_echoes,
+        );
+        let Some(Packet::Publish(publish, None)) = packet else {
+            panic!("Expected publish, got {packet:?}");
+        };
+        assert_eq!(publish.topic, "site1/cmd/light");
+        assert_eq!(publish.qos, QoS::AtLeastOnce);
+        assert_eq!(publish.pkid, 7);
+
+        // Remote broker sends it back as bridge is subscribed to it with MQTT 3.1.1
+        assert!(remote_echoes.take("site1/cmd/light", &publish.payload));
+    }
+
+    #[test]
+    fn local_publishes_are_acked_in_order() {
+        let mut inflight = Inflight::default();
+        inflight.push(1, false);
+        inflight.push(2, true);
+        inflight.push(3, false);
+        assert_eq!(inflight.pop_acked(), None);
+
+        inflight.ack(3);
+        assert_eq!(inflight.pop_acked(), None);
+
+        inflight.ack(1);
+        assert_eq!(inflight.pop_acked(), Some(1));
+        assert_eq!(inflight.pop_acked(), Some(2));
+        assert_eq!(inflight.pop_acked(), Some(3));
+        assert_eq!(inflight.pop_acked(), None);
+    }
+}
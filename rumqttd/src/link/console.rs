@@ -1,14 +1,20 @@
-use crate::link::local::LinkRx;
+use crate::link::local::{LinkRx, LinkTx};
 use crate::local::LinkBuilder;
+use crate::protocol::{Packet, Publish, QoS};
 use crate::router::{Event, Print};
 use crate::{ConnectionId, ConsoleSettings};
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
-use axum::routing::post;
+use axum::routing::{delete, post};
 use axum::Json;
 use axum::{routing::get, Router};
 use flume::Sender;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::Value;
 use std::sync::Arc;
+use subtle::ConstantTimeEq;
 use tokio::net::TcpListener;
 use tracing::info;
 
@@ -17,6 +23,7 @@ pub struct ConsoleLink {
     config: ConsoleSettings,
     connection_id: ConnectionId,
     router_tx: Sender<(ConnectionId, Event)>,
+    link_tx: Mutex<LinkTx>,
     _link_rx: LinkRx,
 }
 
@@ -32,6 +39,7 @@ pub fn new(config: ConsoleSettings, router_tx: Sender<(ConnectionId, Event)>) ->
         ConsoleLink {
             config,
             router_tx,
+            link_tx: Mutex::new(link_tx),
             _link_rx: link_rx,
             connection_id,
         }
@@ -48,11 +56,18 @@ pub async fn start(console: Arc<ConsoleLink>) {
         .route("/", get(root))
         .route("/config", get(config))
         .route("/router", get(router))
-        .route("/device/:device_id", get(device_with_id))
+        .route(
+            "/device/:device_id",
+            get(device_with_id).delete(disconnect_device),
+        )
+        .route("/device/:device_id/session", delete(delete_session))
         .route("/subscriptions", get(subscriptions))
         .route("/subscriptions/:filter", get(subscriptions_with_filter))
         .route("/waiters/:filter", get(waiters_with_filter))
         .route("/readyqueue", get(readyqueue))
+        .route("/retained", get(retained))
+        .route("/retained/:topic", delete(delete_retained))
+        .route("/publish/:topic", post(publish))
         .route("/logs", post(logs))
         .with_state(console);
 
@@ -64,46 +79,22 @@ async fn root(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
 }
 
 async fn config(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
-    let event = Event::PrintStatus(Print::Config);
-    let message = (console.connection_id, event);
-    if console.router_tx.send(message).is_err() {
-        return Response::builder().status(404).body("".to_owned()).unwrap();
-    }
-
-    Response::new("OK".to_owned())
+    status(&console, Print::Config).await
 }
 
 async fn router(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
-    let event = Event::PrintStatus(Print::Router);
-    let message = (console.connection_id, event);
-    if console.router_tx.send(message).is_err() {
-        return Response::builder().status(404).body("".to_owned()).unwrap();
-    }
-
-    Response::new("OK".to_owned())
+    status(&console, Print::Router).await
 }
 
 async fn device_with_id(
     Path(device_id): Path<String>,
     State(console): State<Arc<ConsoleLink>>,
 ) -> impl IntoResponse {
-    let event = Event::PrintStatus(Print::Connection(device_id));
-    let message = (console.connection_id, event);
-    if console.router_tx.send(message).is_err() {
-        return Response::builder().status(404).body("".to_owned()).unwrap();
-    }
-
-    Response::new("OK".to_owned())
+    status(&console, Print::Connection(device_id)).await
 }
 
 async fn subscriptions(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
-    let event = Event::PrintStatus(Print::Subscriptions);
-    let message = (console.connection_id, event);
-    if console.router_tx.send(message).is_err() {
-        return Response::builder().status(404).body("".to_owned()).unwrap();
-    }
-
-    Response::new("OK".to_owned())
+    status(&console, Print::Subscriptions).await
 }
 
 async fn subscriptions_with_filter(
@@ -111,13 +102,7 @@ async fn subscriptions_with_filter(
     State(console): State<Arc<ConsoleLink>>,
 ) -> impl IntoResponse {
     let filter = filter.replace('.', "/");
-    let event = Event::PrintStatus(Print::Subscription(filter));
-    let message = (console.connection_id, event);
-    if console.router_tx.send(message).is_err() {
-        return Response::builder().status(404).body("".to_owned()).unwrap();
-    }
-
-    Response::new("OK".to_owned())
+    status(&console, Print::Subscription(filter)).await
 }
 
 async fn waiters_with_filter(
@@ -125,23 +110,141 @@ async fn waiters_with_filter(
     State(console): State<Arc<ConsoleLink>>,
 ) -> impl IntoResponse {
     let filter = filter.replace('.', "/");
-    let event = Event::PrintStatus(Print::Waiters(filter));
-    let message = (console.connection_id, event);
-    if console.router_tx.send(message).is_err() {
-        return Response::builder().status(404).body("".to_owned()).unwrap();
+    status(&console, Print::Waiters(filter)).await
+}
+
+async fn readyqueue(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
+    status(&console, Print::ReadyQueue).await
+}
+
+async fn retained(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
+    status(&console, Print::Retained).await
+}
+
+async fn disconnect_device(
+    Path(device_id): Path<String>,
+    State(console): State<Arc<ConsoleLink>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(code) = authorize(&console, &headers) {
+        return code.into_response();
     }
 
-    Response::new("OK".to_owned())
+    admin(&console, |tx| Event::DisconnectClient(device_id, tx)).await
 }
 
-async fn readyqueue(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
-    let event = Event::PrintStatus(Print::ReadyQueue);
-    let message = (console.connection_id, event);
-    if console.router_tx.send(message).is_err() {
-        return Response::builder().status(404).body("".to_owned()).unwrap();
+async fn delete_session(
+    Path(device_id): Path<String>,
+    State(console): State<Arc<ConsoleLink>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(code) = authorize(&console, &headers) {
+        return code.into_response();
     }
 
-    Response::new("OK".to_owned())
+    admin(&console, |tx| Event::DeleteSession(device_id, tx)).await
+}
+
+async fn delete_retained(
+    Path(topic): Path<String>,
+    State(console): State<Arc<ConsoleLink>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(code) = authorize(&console, &headers) {
+        return code.into_response();
+    }
+
+    let topic = topic.replace('.', "/");
+    admin(&console, |tx| Event::DeleteRetained(topic, tx)).await
+}
+
+#[derive(Debug, Deserialize)]
+struct PublishOptions {
+    #[serde(default)]
+    retain: bool,
+}
+
+/// Publishes the body on the topic with QoS 0, as if it was sent by a client
+async fn publish(
+    Path(topic): Path<String>,
+    Query(options): Query<PublishOptions>,
+    State(console): State<Arc<ConsoleLink>>,
+    headers: HeaderMap,
+    payload: String,
+) -> impl IntoResponse {
+    if let Err(code) = authorize(&console, &headers) {
+        return code.into_response();
+    }
+
+    let topic = topic.replace('.', "/");
+    if topic.is_empty() || topic.contains(['+', '#']) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let publish = Publish {
+        dup: false,
+        qos: QoS::AtMostOnce,
+        retain: options.retain,
+        topic: topic.into(),
+        pkid: 0,
+        payload: payload.into(),
+    };
+
+    match console
+        .link_tx
+        .lock()
+        .try_send(Packet::Publish(publish, None))
+    {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
+
+/// Checks bearer token of requests to admin endpoints
+fn authorize(console: &ConsoleLink, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let Some(token) = &console.config.admin_token else {
+        return Err(StatusCode::FORBIDDEN);
+    };
+
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    match bearer {
+        Some(bearer) if bearer.as_bytes().ct_eq(token.as_bytes()).into() => Ok(()),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// Sends admin request to the router and responds with whether it found the item to act on
+async fn admin(console: &ConsoleLink, event: impl FnOnce(Sender<bool>) -> Event) -> Response {
+    let (tx, rx) = flume::bounded(1);
+    let message = (console.connection_id, event(tx));
+    if console.router_tx.send_async(message).await.is_err() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+
+    match rx.recv_async().await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
+
+/// Requests status from the router and responds with it as JSON
+async fn status(console: &ConsoleLink, print: Print) -> Response {
+    let (tx, rx) = flume::bounded(1);
+    let message = (console.connection_id, Event::PrintStatus(print, tx));
+    if console.router_tx.send_async(message).await.is_err() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+
+    match rx.recv_async().await {
+        Ok(Value::Null) => StatusCode::NOT_FOUND.into_response(),
+        Ok(status) => Json(status).into_response(),
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
 }
 
 async fn logs(State(console): State<Arc<ConsoleLink>>, data: String) -> impl IntoResponse {
@@ -5,9 +5,9 @@
 use crate::router::Ack;
 use crate::router::{
     iobufs::{Incoming, Outgoing},
-    Connection, Event, Notification, ShadowRequest,
+    ClusterSession, Connection, Event, Notification, ShadowRequest,
 };
-use crate::ConnectionId;
+use crate::{Capabilities, ConnectionId};
 use bytes::Bytes;
 use flume::{Receiver, RecvError, RecvTimeoutError, SendError, Sender, TrySendError};
 use parking_lot::lock_api::MutexGuard;
@@ -43,12 +43,27 @@ pub struct LinkBuilder<'a> {
     router_tx: Sender<(ConnectionId, Event)>,
     // true by default
     clean_session: bool,
+    // None by default, session expiry is then decided by the router
+    session_expiry_interval: Option<u32>,
     last_will: Option<LastWill>,
     last_will_properties: Option<LastWillProperties>,
     // false by default
     dynamic_filters: bool,
     // default to 0, indicating to not use topic alias
     topic_alias_max: u16,
+    // None by default, router's inflight limit is used then
+    receive_maximum: Option<u16>,
+    // None by default, indicating no limit
+    max_packet_size: Option<u32>,
+    username: Option<String>,
+    // false by default, local links are trusted
+    restricted: bool,
+    // false by default
+    replica: bool,
+    // everything is available by default
+    capabilities: Capabilities,
+    // None by default, indicating no limit
+    max_subscriptions: Option<usize>,
 }
 
 impl<'a> LinkBuilder<'a> {
@@ -58,10 +73,18 @@ pub fn new(client_id: &'a str, router_tx: Sender<(ConnectionId, Event)>) -> Self
             router_tx,
             tenant_id: None,
             clean_session: true,
+            session_expiry_interval: None,
             last_will: None,
             last_will_properties: None,
             dynamic_filters: false,
             topic_alias_max: 0,
+            receive_maximum: None,
+            max_packet_size: None,
+            username: None,
+            restricted: false,
+            replica: false,
+            capabilities: Capabilities::default(),
+            max_subscriptions: None,
         }
     }
 
@@ -88,17 +111,83 @@ pub fn topic_alias_max(mut self, max: u16) -> Self {
         self
     }
 
+    /// Maximum QoS 1 and 2 publishes the client is willing to process concurrently
+    pub fn receive_maximum(mut self, max: Option<u16>) -> Self {
+        self.receive_maximum = max;
+        self
+    }
+
+    /// Maximum size of a packet the client is willing to accept. Larger publishes are dropped
+    pub fn max_packet_size(mut self, max: Option<u32>) -> Self {
+        self.max_packet_size = max;
+        self
+    }
+
     pub fn clean_session(mut self, clean: bool) -> Self {
         self.clean_session = clean;
         self
     }
 
+    pub fn session_expiry_interval(mut self, interval: Option<u32>) -> Self {
+        self.session_expiry_interval = interval;
+        self
+    }
+
+    pub fn username(mut self, username: Option<String>) -> Self {
+        self.username = username;
+        self
+    }
+
+    /// Subject publishes and subscriptions of this link to the router's ACL and authorization
+    /// handler
+    pub fn restricted(mut self, restricted: bool) -> Self {
+        self.restricted = restricted;
+        self
+    }
+
+    /// Receive publishes of other connections as `Notification::ReplicaData` to replicate them
+    /// to another node of the cluster
+    pub(crate) fn replica(mut self, replica: bool) -> Self {
+        self.replica = replica;
+        self
+    }
+
+    /// Restrict publishes and subscriptions of this link to `capabilities`, which are advertised
+    /// in `ConnAck` as well
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Subscriptions beyond `max` are rejected with `QuotaExceeded`
+    pub fn max_subscriptions(mut self, max: Option<usize>) -> Self {
+        self.max_subscriptions = max;
+        self
+    }
+
     pub fn dynamic_filters(mut self, dynamic_filters: bool) -> Self {
         self.dynamic_filters = dynamic_filters;
         self
     }
 
     pub fn build(self) -> Result<(LinkTx, LinkRx, Notification), LinkError> {
+        let link = self.register()?;
+        link.link_rx.recv()?;
+        link.acked()
+    }
+
+    /// Same as `build`, without blocking the thread while the router acks the connection.
+    /// Clients resuming their session in a cluster are acked only after other nodes hand over
+    /// the session
+    #[allow(clippy::result_large_err)]
+    pub async fn build_async(self) -> Result<(LinkTx, LinkRx, Notification), LinkError> {
+        let link = self.register()?;
+        link.link_rx.recv_async().await?;
+        link.acked()
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn register(self) -> Result<Registered, LinkError> {
         // Connect to router
         // Local connections to the router shall have access to all subscriptions
         let mut connection = Connection::new(
@@ -109,10 +198,19 @@ pub fn build(self) -> Result<(LinkTx, LinkRx, Notification), LinkError> {
         );
 
         connection
+            .session_expiry_interval(self.session_expiry_interval)
+            .username(self.username)
+            .restricted(self.restricted)
+            .replica(self.replica)
+            .capabilities(self.capabilities)
+            .max_subscriptions(self.max_subscriptions)
             .last_will(self.last_will, self.last_will_properties)
             .topic_alias_max(self.topic_alias_max);
         let incoming = Incoming::new(connection.client_id.to_owned());
-        let (outgoing, link_rx) = Outgoing::new(connection.client_id.to_owned());
+        let (mut outgoing, link_rx) = Outgoing::new(connection.client_id.to_owned());
+        outgoing
+            .receive_maximum(self.receive_maximum)
+            .max_packet_size(self.max_packet_size);
         let outgoing_data_buffer = outgoing.buffer();
         let incoming_data_buffer = incoming.buffer();
 
@@ -123,9 +221,27 @@ pub fn build(self) -> Result<(LinkTx, LinkRx, Notification), LinkError> {
         };
 
         self.router_tx.send((0, event))?;
+        Ok(Registered {
+            router_tx: self.router_tx,
+            link_rx,
+            incoming_data_buffer,
+            outgoing_data_buffer,
+        })
+    }
+}
 
-        link_rx.recv()?;
-        let notification = outgoing_data_buffer.lock().pop_front().unwrap();
+/// Link which is sent to the router and waits for its connack
+struct Registered {
+    router_tx: Sender<(ConnectionId, Event)>,
+    link_rx: Receiver<()>,
+    incoming_data_buffer: Arc<Mutex<VecDeque<Packet>>>,
+    outgoing_data_buffer: Arc<Mutex<VecDeque<Notification>>>,
+}
+
+impl Registered {
+    #[allow(clippy::result_large_err)]
+    fn acked(self) -> Result<(LinkTx, LinkRx, Notification), LinkError> {
+        let notification = self.outgoing_data_buffer.lock().pop_front().unwrap();
 
         // Right now link identifies failure with dropped rx in router,
         // which is probably ok. We need this here to get id assigned by router
@@ -134,12 +250,13 @@ pub fn build(self) -> Result<(LinkTx, LinkRx, Notification), LinkError> {
             _message => return Err(LinkError::NotConnectionAck),
         };
 
-        let tx = LinkTx::new(id, self.router_tx.clone(), incoming_data_buffer);
-        let rx = LinkRx::new(id, self.router_tx, link_rx, outgoing_data_buffer);
+        let tx = LinkTx::new(id, self.router_tx.clone(), self.incoming_data_buffer);
+        let rx = LinkRx::new(id, self.router_tx, self.link_rx, self.outgoing_data_buffer);
         Ok((tx, rx, notification))
     }
 }
 
+#[derive(Debug)]
 pub struct LinkTx {
     pub(crate) connection_id: ConnectionId,
     router_tx: Sender<(ConnectionId, Event)>,
@@ -191,6 +308,11 @@ pub async fn send(&mut self, data: Packet) -> Result<usize, LinkError> {
         Ok(len)
     }
 
+    /// Send raw device data without blocking
+    pub fn try_send(&mut self, data: Packet) -> Result<usize, LinkError> {
+        self.try_push(data)
+    }
+
     fn try_push(&mut self, data: Packet) -> Result<usize, LinkError> {
         let len = {
             let mut buffer = self.recv_buffer.lock();
@@ -257,7 +379,7 @@ pub fn subscribe<S: Into<String>>(&mut self, filter: S) -> Result<usize, LinkErr
             qos: QoS::AtMostOnce,
             nolocal: false,
             preserve_retain: false,
-            retain_forward_rule: RetainForwardRule::Never,
+            retain_forward_rule: RetainForwardRule::OnNewSubscribe,
         }];
 
         let subscribe = Subscribe { pkid: 0, filters };
@@ -273,7 +395,7 @@ pub fn try_subscribe<S: Into<String>>(&mut self, filter: S) -> Result<usize, Lin
             qos: QoS::AtMostOnce,
             nolocal: false,
             preserve_retain: false,
-            retain_forward_rule: RetainForwardRule::Never,
+            retain_forward_rule: RetainForwardRule::OnNewSubscribe,
         }];
 
         let subscribe = Subscribe { pkid: 0, filters };
@@ -313,6 +435,27 @@ pub fn shadow<S: Into<String>>(&mut self, filter: S) -> Result<(), LinkError> {
         self.router_tx.try_send((self.connection_id, message))?;
         Ok(())
     }
+
+    /// Notifies router that a client connected to the node of this replica
+    pub(crate) async fn client_connected(&mut self, client_id: String) -> Result<(), LinkError> {
+        let message = Event::ClientConnected(client_id);
+        self.router_tx
+            .send_async((self.connection_id, message))
+            .await?;
+        Ok(())
+    }
+
+    /// Hands over session of a client which connected to the node of this replica
+    pub(crate) async fn session_takeover(
+        &mut self,
+        session: ClusterSession,
+    ) -> Result<(), LinkError> {
+        let message = Event::SessionTakeover(session);
+        self.router_tx
+            .send_async((self.connection_id, message))
+            .await?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
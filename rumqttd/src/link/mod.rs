@@ -1,8 +1,11 @@
 pub mod alerts;
+pub mod auth;
 pub mod bridge;
 pub mod console;
 pub mod local;
 pub mod meters;
 pub mod network;
+pub mod password;
+pub mod ratelimit;
 pub mod remote;
 pub mod timer;
@@ -0,0 +1,183 @@
+//! Verification of passwords in static authentication config. Stored passwords are either
+//! plaintext or hashes, which are recognized by their prefix:
+//!
+//! - `$argon2id$`, `$argon2i$`, `$argon2d$`: Argon2 in PHC string format
+//! - `$pbkdf2-sha256$`, `$pbkdf2-sha512$`: PBKDF2 in PHC string format
+//! - `$2a$`, `$2b$`, `$2y$`: bcrypt
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::{fmt, fs, io};
+
+use argon2::Argon2;
+use parking_lot::Mutex;
+use password_hash::PasswordHash;
+use pbkdf2::Pbkdf2;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tracing::{error, info};
+
+/// Checks `password` against `stored`, which is a hash or plaintext password
+pub fn verify(stored: &str, password: &str) -> bool {
+    if is_hash(stored) {
+        return verify_hash(stored, password);
+    }
+
+    stored.as_bytes().ct_eq(password.as_bytes()).into()
+}
+
+/// Whether `stored` is a hash, which is expensive to verify
+pub fn is_hash(stored: &str) -> bool {
+    ["$argon2", "$pbkdf2", "$2a$", "$2b$", "$2y$"]
+        .iter()
+        .any(|prefix| stored.starts_with(prefix))
+}
+
+fn verify_hash(stored: &str, password: &str) -> bool {
+    if stored.starts_with("$2") {
+        return bcrypt::verify(password, stored).unwrap_or(false);
+    }
+
+    let Ok(hash) = PasswordHash::new(stored) else {
+        return false;
+    };
+
+    hash.verify_password(&[&Argon2::default(), &Pbkdf2], password)
+        .is_ok()
+}
+
+/// File with a `username:password` entry per line, where password is usually a hash. Empty
+/// lines and lines starting with `#` are ignored. The file is read again when it changes, so
+/// credentials can be updated without restarting the broker.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "PathBuf", into = "PathBuf")]
+pub struct PasswordFile {
+    path: PathBuf,
+    cache: Arc<Mutex<Cache>>,
+}
+
+#[derive(Default)]
+struct Cache {
+    /// Modification time and length of the file when it was last read
+    version: Option<(SystemTime, u64)>,
+    passwords: HashMap<String, String>,
+}
+
+impl PasswordFile {
+    pub fn new(path: impl Into<PathBuf>) -> PasswordFile {
+        PasswordFile {
+            path: path.into(),
+            cache: Arc::new(Mutex::new(Cache::default())),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stored password of the user. File is read again if it changed since it was last read.
+    /// When it can't be read, previously read passwords are used. This blocks on file system
+    /// calls, so it's called off the runtime threads
+    pub fn password(&self, username: &str) -> Option<String> {
+        let mut cache = self.cache.lock();
+        if let Err(e) = self.reload(&mut cache) {
+            error!(path = ?self.path, "Failed to read password file: {e}");
+        }
+
+        cache.passwords.get(username).cloned()
+    }
+
+    fn reload(&self, cache: &mut Cache) -> io::Result<()> {
+        let metadata = fs::metadata(&self.path)?;
+        let version = (metadata.modified()?, metadata.len());
+        if cache.version == Some(version) {
+            return Ok(());
+        }
+
+        let passwords = parse(&fs::read_to_string(&self.path)?);
+        info!(path = ?self.path, users = passwords.len(), "Loaded password file");
+        cache.passwords = passwords;
+        cache.version = Some(version);
+        Ok(())
+    }
+}
+
+fn parse(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .map(|(username, password)| (username.to_owned(), password.to_owned()))
+        .collect()
+}
+
+impl From<PathBuf> for PasswordFile {
+    fn from(path: PathBuf) -> Self {
+        PasswordFile::new(path)
+    }
+}
+
+impl From<PasswordFile> for PathBuf {
+    fn from(file: PasswordFile) -> Self {
+        file.path
+    }
+}
+
+impl fmt::Debug for PasswordFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PasswordFile").field(&self.path).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use password_hash::{PasswordHasher, SaltString};
+
+    #[test]
+    fn hashes_are_verified() {
+        let salt = SaltString::from_b64("c2FsdHNhbHRzYWx0").unwrap();
+        // Cheap parameters, verification reads them from the hash
+        let params = argon2::Params::new(64, 1, 1, None).unwrap();
+        let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+            .hash_password(b"pencil", &salt)
+            .unwrap()
+            .to_string();
+        let params = pbkdf2::Params {
+            rounds: 1000,
+            output_length: 32,
+        };
+        let pbkdf2 = Pbkdf2
+            .hash_password_customized(b"pencil", None, None, params, &salt)
+            .unwrap()
+            .to_string();
+        let bcrypt = bcrypt::hash("pencil", 4).unwrap();
+
+        for stored in [argon2, pbkdf2, bcrypt, "pencil".to_owned()] {
+            assert!(verify(&stored, "pencil"), "{stored}");
+            assert!(!verify(&stored, "pen"), "{stored}");
+        }
+
+        assert!(!verify("$argon2id$garbage", "$argon2id$garbage"));
+    }
+
+    #[test]
+    fn password_file_is_reloaded_on_change() {
+        let path = std::env::temp_dir().join(format!("rumqttd-passwd-{}", std::process::id()));
+        fs::write(&path, "# users\nalice:one\n\nbob:two\n").unwrap();
+
+        let file = PasswordFile::new(&path);
+        assert_eq!(file.password("alice").as_deref(), Some("one"));
+        assert_eq!(file.password("bob").as_deref(), Some("two"));
+
+        fs::write(&path, "alice:three\n").unwrap();
+        assert_eq!(file.password("alice").as_deref(), Some("three"));
+        assert_eq!(file.password("bob"), None);
+
+        // Last read passwords are used when the file goes missing
+        fs::remove_file(&path).unwrap();
+        assert_eq!(file.password("alice").as_deref(), Some("three"));
+    }
+}
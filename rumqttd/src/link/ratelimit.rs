@@ -0,0 +1,140 @@
+use std::time::{Duration, Instant};
+
+use crate::protocol::Packet;
+use crate::Quota;
+
+/// Bucket which fills with `rate` tokens every second, holding at most a second worth of
+/// them. Taking more tokens than available puts the bucket in debt, which is paid by waiting
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: now,
+        }
+    }
+
+    /// Takes `count` tokens and returns how long it takes for the bucket to be out of debt
+    fn take(&mut self, count: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.tokens -= count as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Limits publishes of a client as per its `Quota`
+pub struct RateLimiter {
+    publishes: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Returns `None` when the quota doesn't limit publishes
+    pub fn new(quota: &Quota) -> Option<RateLimiter> {
+        let now = Instant::now();
+        let bucket = |rate: Option<u32>| {
+            rate.filter(|&rate| rate > 0)
+                .map(|rate| TokenBucket::new(rate, now))
+        };
+
+        let publishes = bucket(quota.publishes_per_sec);
+        let bytes = bucket(quota.bytes_per_sec);
+        if publishes.is_none() && bytes.is_none() {
+            return None;
+        }
+
+        Some(RateLimiter { publishes, bytes })
+    }
+
+    /// Accounts publishes in `packets` read from the client. Returns how long the client has
+    /// to wait to be within its limits, which is zero when it already is
+    pub fn check<'a>(&mut self, packets: impl Iterator<Item = &'a Packet>) -> Duration {
+        self.check_at(packets, Instant::now())
+    }
+
+    fn check_at<'a>(
+        &mut self,
+        packets: impl Iterator<Item = &'a Packet>,
+        now: Instant,
+    ) -> Duration {
+        let (count, size) = packets.fold((0, 0), |(count, size), packet| match packet {
+            Packet::Publish(publish, _) => (count + 1, size + publish.len()),
+            _ => (count, size),
+        });
+
+        if count == 0 {
+            return Duration::ZERO;
+        }
+
+        let publishes = self
+            .publishes
+            .as_mut()
+            .map(|bucket| bucket.take(count, now));
+        let bytes = self.bytes.as_mut().map(|bucket| bucket.take(size, now));
+        publishes.max(bytes).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::Publish;
+
+    #[test]
+    fn publishes_over_the_limit_have_to_wait() {
+        let quota = Quota {
+            publishes_per_sec: Some(10),
+            bytes_per_sec: Some(1000),
+            max_subscriptions: None,
+        };
+
+        let mut limiter = RateLimiter::new(&quota).unwrap();
+        let now = Instant::now();
+        let publishes: Vec<Packet> = (0..15)
+            .map(|_| Packet::Publish(Publish::new("a", "b", false), None))
+            .collect();
+
+        // Bucket holds a second worth of publishes
+        assert_eq!(
+            limiter.check_at(publishes[..10].iter(), now),
+            Duration::ZERO
+        );
+        let wait = limiter.check_at(publishes[10..].iter(), now);
+        assert_eq!(wait, Duration::from_millis(500));
+
+        // Debt is paid after waiting
+        let now = now + wait;
+        assert_eq!(limiter.check_at(publishes[..0].iter(), now), Duration::ZERO);
+        let wait = limiter.check_at(publishes[..1].iter(), now);
+        assert_eq!(wait, Duration::from_millis(100));
+
+        // Bytes are limited as well
+        let large = Packet::Publish(Publish::new(vec![b'a'], vec![0; 2000], false), None);
+        let now = now + Duration::from_secs(10);
+        assert!(limiter.check_at([large].iter(), now) > Duration::from_secs(1));
+    }
+
+    #[test]
+    fn quota_without_rates_doesnt_limit() {
+        let quota = Quota {
+            publishes_per_sec: Some(0),
+            bytes_per_sec: None,
+            max_subscriptions: Some(10),
+        };
+
+        assert!(RateLimiter::new(&quota).is_none());
+    }
+}
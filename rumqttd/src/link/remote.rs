@@ -1,19 +1,26 @@
+use crate::link::auth::{AuthSession, AuthStep, Authenticator};
 use crate::link::local::{LinkError, LinkRx, LinkTx};
 use crate::link::network;
 use crate::link::network::Network;
+use crate::link::password;
+use crate::link::ratelimit::RateLimiter;
 use crate::local::LinkBuilder;
-use crate::protocol::{ConnAck, Connect, ConnectReturnCode, Login, Packet, Protocol};
+use crate::protocol::{
+    Auth, AuthProperties, AuthReasonCode, ConnAck, Connect, ConnectReturnCode, Disconnect,
+    DisconnectReasonCode, Login, Packet, Protocol, PubRecReason, QoS,
+};
 use crate::router::{Event, Notification};
-use crate::{ConnectionId, ConnectionSettings};
+use crate::{ConnectionId, ConnectionSettings, ExceedAction, PeerCertificate};
 
+use bytes::Bytes;
 use flume::{RecvError, SendError, Sender, TrySendError};
 use std::cmp::min;
 use std::collections::VecDeque;
 use std::io;
 use std::sync::Arc;
 use std::time::Duration;
-use subtle::ConstantTimeEq;
 use tokio::time::error::Elapsed;
+use tokio::time::Instant;
 use tokio::{select, time};
 use tracing::{trace, Span};
 
@@ -37,12 +44,26 @@ pub enum Error {
     SessionEnd,
     #[error("Persistent session requires valid client id")]
     InvalidClientId,
+    #[error("Client certificate doesn't have the configured identity")]
+    MissingCertIdentity,
+    #[error("Client id doesn't match certificate identity")]
+    ClientIdMismatch,
     #[error("Unexpected router message")]
     NotConnectionAck,
     #[error("ConnAck error {0}")]
     ConnectionAck(String),
     #[error("Authentication error")]
     InvalidAuth,
+    #[error("Unsupported authentication method {0}")]
+    BadAuthenticationMethod(String),
+    #[error("Unexpected authentication exchange")]
+    UnexpectedAuth,
+    #[error("Receive maximum exceeded")]
+    ReceiveMaximumExceeded,
+    #[error("Rate limit exceeded")]
+    QuotaExceeded,
+    #[error("Last will uses unavailable capability")]
+    UnsupportedWill,
     #[error("Channel try send error")]
     TrySend(#[from] TrySendError<(ConnectionId, Event)>),
     #[error("Link error = {0}")]
@@ -58,6 +79,31 @@ pub struct RemoteLink<P> {
     link_rx: LinkRx,
     notifications: VecDeque<Notification>,
     pub(crate) will_delay_interval: u32,
+    client_id: String,
+    /// Username of the client, which re-authentication can't change
+    username: Option<String>,
+    /// Authenticator used during connect, which is used again on re-authentication
+    authenticator: Option<Arc<dyn Authenticator>>,
+    /// Re-authentication which is waiting for the next step from the client
+    reauth: Option<Box<dyn AuthSession>>,
+    /// Receive maximum of the broker, limiting unacknowledged QoS 1 and 2 publishes of client
+    max_incoming_inflight: Option<usize>,
+    /// QoS 1 and 2 publishes of client which aren't acknowledged yet
+    incoming_inflight: usize,
+    /// Limits publishes of client as per its quota
+    rate_limiter: Option<RateLimiter>,
+    on_exceed: ExceedAction,
+    /// Client isn't read from till then, when it's throttled for exceeding its quota
+    throttled_until: Option<Instant>,
+}
+
+/// Result of enhanced authentication during connect
+pub struct EnhancedAuth {
+    authenticator: Arc<dyn Authenticator>,
+    /// User the client authenticated as
+    username: Option<String>,
+    /// Final authentication data for the client, sent in `ConnAck`
+    data: Option<Bytes>,
 }
 
 impl<P: Protocol> RemoteLink<P> {
@@ -66,23 +112,42 @@ pub async fn new(
         tenant_id: Option<String>,
         mut network: Network<P>,
         connect_packet: Packet,
-        dynamic_filters: bool,
+        config: &ConnectionSettings,
         assigned_client_id: Option<String>,
+        auth: Option<EnhancedAuth>,
     ) -> Result<RemoteLink<P>, Error> {
-        let Packet::Connect(connect, props, lastwill, lastwill_props, _) = connect_packet else {
+        let Packet::Connect(mut connect, props, lastwill, lastwill_props, login) = connect_packet
+        else {
             return Err(Error::NotConnectPacket(connect_packet));
         };
 
         // Register this connection with the router. Router replys with ack which if ok will
         // start the link. Router can sometimes reject the connection (ex max connection limit)
-        let client_id = assigned_client_id.as_ref().unwrap_or(&connect.client_id);
+        let client_id = assigned_client_id
+            .clone()
+            .unwrap_or_else(|| connect.client_id.clone());
         let clean_session = connect.clean_session;
 
+        let capabilities = config.capabilities.clone();
+        if let Some(keep_alive) = capabilities.server_keep_alive {
+            connect.keep_alive = keep_alive;
+        }
+
+        let max_incoming_inflight = capabilities.receive_maximum.map(usize::from);
+        let username = login.map(|login| login.username);
+        let rate_limits = config.rate_limits.clone().unwrap_or_default();
+        let quota = rate_limits.quota(username.as_deref());
+        let rate_limiter = RateLimiter::new(&quota);
+
         let topic_alias_max = props.as_ref().and_then(|p| p.topic_alias_max);
-        let session_expiry = props
+        let receive_maximum = props.as_ref().and_then(|p| p.receive_maximum);
+        let max_packet_size = props.as_ref().and_then(|p| p.max_packet_size);
+        // Absence of session expiry interval in MQTT 5 properties means 0. Without properties,
+        // which is always the case for MQTT 3.1.1, router uses its default
+        let session_expiry_interval = props
             .as_ref()
-            .and_then(|p| p.session_expiry_interval)
-            .unwrap_or(0);
+            .map(|p| p.session_expiry_interval.unwrap_or(0));
+        let session_expiry = session_expiry_interval.unwrap_or(0);
 
         let delay_interval = lastwill_props
             .as_ref()
@@ -93,14 +158,22 @@ pub async fn new(
         // the Will Delay Interval has passed or the Session ends, whichever happens first
         let will_delay_interval = min(session_expiry, delay_interval);
 
-        let (link_tx, link_rx, notification) = LinkBuilder::new(client_id, router_tx)
+        let (link_tx, link_rx, notification) = LinkBuilder::new(&client_id, router_tx)
             .tenant_id(tenant_id)
             .clean_session(clean_session)
+            .session_expiry_interval(session_expiry_interval)
             .last_will(lastwill)
             .last_will_properties(lastwill_props)
-            .dynamic_filters(dynamic_filters)
+            .dynamic_filters(config.dynamic_filters)
+            .username(username.clone())
+            .restricted(true)
             .topic_alias_max(topic_alias_max.unwrap_or(0))
-            .build()?;
+            .receive_maximum(receive_maximum)
+            .max_packet_size(max_packet_size)
+            .capabilities(capabilities)
+            .max_subscriptions(quota.max_subscriptions)
+            .build_async()
+            .await?;
 
         let id = link_rx.id();
         Span::current().record("connection_id", id);
@@ -109,6 +182,10 @@ pub async fn new(
             if let Packet::ConnAck(_ack, props) = &mut packet {
                 let mut new_props = props.clone().unwrap_or_default();
                 new_props.assigned_client_identifier = assigned_client_id;
+                if let Some(auth) = &auth {
+                    new_props.authentication_method = Some(auth.authenticator.method().to_owned());
+                    new_props.authentication_data = auth.data.clone();
+                }
                 *props = Some(new_props);
                 network.write(packet).await?;
             }
@@ -122,6 +199,15 @@ pub async fn new(
             link_rx,
             notifications: VecDeque::with_capacity(100),
             will_delay_interval,
+            client_id,
+            username,
+            authenticator: auth.map(|auth| auth.authenticator),
+            reauth: None,
+            max_incoming_inflight,
+            incoming_inflight: 0,
+            rate_limiter,
+            on_exceed: rate_limits.on_exceed,
+            throttled_until: None,
         })
     }
 
@@ -131,18 +217,78 @@ pub async fn start(&mut self) -> Result<(), Error> {
         // Note:
         // Shouldn't result in bounded queue deadlocks because of blocking n/w send
         loop {
+            let throttled = self
+                .throttled_until
+                .is_some_and(|until| until > Instant::now());
+
             select! {
-                o = self.network.read() => {
+                o = self.network.read(), if !throttled => {
                     let packet = o?;
-                    let len = {
+                    let (len, auths, exceeded, wait) = {
                         let mut buffer = self.link_tx.buffer();
+                        let mut exceeded = false;
+                        let start = buffer.len();
                         buffer.push_back(packet);
                         self.network.readv(&mut buffer)?;
-                        buffer.len()
+
+                        let wait = match &mut self.rate_limiter {
+                            Some(limiter) => limiter.check(buffer.range(start..)),
+                            None => Duration::ZERO,
+                        };
+
+                        if !wait.is_zero() && self.on_exceed == ExceedAction::Disconnect {
+                            buffer.truncate(start);
+                        }
+
+                        if let Some(max) = self.max_incoming_inflight {
+                            self.incoming_inflight += inflight_publishes(buffer.range(start..));
+                            if self.incoming_inflight > max {
+                                buffer.truncate(start);
+                                exceeded = true;
+                            }
+                        }
+
+                        // Re-authentication is handled by the link, router never sees it
+                        let mut auths = VecDeque::new();
+                        if buffer.iter().any(|packet| matches!(packet, Packet::Auth(..))) {
+                            let packets: VecDeque<Packet>;
+                            (auths, packets) = buffer
+                                .drain(..)
+                                .partition(|packet| matches!(packet, Packet::Auth(..)));
+                            *buffer = packets;
+                        }
+
+                        (buffer.len(), auths, exceeded, wait)
                     };
 
+                    if exceeded {
+                        self.disconnect(DisconnectReasonCode::ReceiveMaximumExceeded).await?;
+                        return Err(Error::ReceiveMaximumExceeded);
+                    }
+
+                    if !wait.is_zero() && self.on_exceed == ExceedAction::Disconnect {
+                        self.disconnect(DisconnectReasonCode::QuotaExceeded).await?;
+                        return Err(Error::QuotaExceeded);
+                    }
+
+                    for packet in auths {
+                        if let Packet::Auth(auth, properties) = packet {
+                            self.reauthenticate(auth, properties).await?;
+                        }
+                    }
+
                     trace!("Packets read from network, count = {}", len);
                     self.link_tx.notify().await?;
+
+                    // Client isn't read from till it's within its limits again. Packets to the
+                    // client, acks and forwards, keep flowing meanwhile
+                    if !wait.is_zero() {
+                        trace!("Throttling client for {:?}", wait);
+                        self.throttled_until = Some(Instant::now() + wait);
+                    }
+                }
+                _ = time::sleep_until(self.throttled_until.unwrap_or_else(Instant::now)), if throttled => {
+                    self.throttled_until = None;
                 }
                 // Receive from router when previous when state isn't in collision
                 // due to previously received data request
@@ -159,6 +305,12 @@ pub async fn start(&mut self) -> Result<(), Error> {
                         }
 
                     }
+
+                    if self.max_incoming_inflight.is_some() {
+                        let completed = completed_publishes(packets.iter());
+                        self.incoming_inflight = self.incoming_inflight.saturating_sub(completed);
+                    }
+
                     self.network.writev(packets).await?;
                     if unscheduled {
                         self.link_rx.wake().await?;
@@ -167,6 +319,93 @@ pub async fn start(&mut self) -> Result<(), Error> {
             }
         }
     }
+
+    /// Runs a step of re-authentication initiated by the client. Failure ends the connection
+    async fn reauthenticate(
+        &mut self,
+        auth: Auth,
+        properties: Option<AuthProperties>,
+    ) -> Result<(), Error> {
+        let (method, data) = properties.map_or((None, None), |p| (p.method, p.data));
+        let session = match (&self.authenticator, auth.code, self.reauth.take()) {
+            // Method can't change from the one used during connect
+            (Some(authenticator), _, _) if method.as_deref() != Some(authenticator.method()) => {
+                None
+            }
+            (Some(authenticator), AuthReasonCode::ReAuthenticate, _) => {
+                Some(authenticator.start(&self.client_id))
+            }
+            (Some(_), AuthReasonCode::Continue, session) => session,
+            _ => None,
+        };
+
+        let Some(mut session) = session else {
+            self.disconnect(DisconnectReasonCode::ProtocolError).await?;
+            return Err(Error::UnexpectedAuth);
+        };
+
+        let method = method.unwrap_or_default();
+        match session.step(data) {
+            AuthStep::Continue(data) => {
+                self.reauth = Some(session);
+                let auth = Auth {
+                    code: AuthReasonCode::Continue,
+                };
+                self.network
+                    .write(Packet::Auth(auth, auth_properties(&method, data)))
+                    .await?;
+            }
+            AuthStep::Success { username, data } => {
+                if username.is_some() && username != self.username {
+                    self.disconnect(DisconnectReasonCode::NotAuthorized).await?;
+                    return Err(Error::InvalidAuth);
+                }
+
+                let auth = Auth {
+                    code: AuthReasonCode::Success,
+                };
+                self.network
+                    .write(Packet::Auth(auth, auth_properties(&method, data)))
+                    .await?;
+            }
+            AuthStep::Failure => {
+                self.disconnect(DisconnectReasonCode::NotAuthorized).await?;
+                return Err(Error::InvalidAuth);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn disconnect(&mut self, reason_code: DisconnectReasonCode) -> Result<(), Error> {
+        let disconnect = Disconnect { reason_code };
+        self.network
+            .write(Packet::Disconnect(disconnect, None))
+            .await?;
+        Ok(())
+    }
+}
+
+/// Count of QoS 1 and 2 publishes, which are inflight till they are acknowledged
+fn inflight_publishes<'a>(packets: impl Iterator<Item = &'a Packet>) -> usize {
+    packets
+        .filter(|packet| matches!(packet, Packet::Publish(publish, _) if publish.qos != QoS::AtMostOnce))
+        .count()
+}
+
+/// Count of acknowledgements which complete the flow of QoS 1 and 2 publishes
+fn completed_publishes<'a>(packets: impl Iterator<Item = &'a Packet>) -> usize {
+    packets
+        .filter(|packet| match packet {
+            Packet::PubAck(..) | Packet::PubComp(..) => true,
+            // PubRec with a failure ends the flow, there is no PubRel for it
+            Packet::PubRec(pubrec, _) => !matches!(
+                pubrec.reason,
+                PubRecReason::Success | PubRecReason::NoMatchingSubscribers
+            ),
+            _ => false,
+        })
+        .count()
 }
 
 /// Read MQTT connect packet from network and verify it.
@@ -174,7 +413,8 @@ pub async fn start(&mut self) -> Result<(), Error> {
 pub async fn mqtt_connect<P>(
     config: Arc<ConnectionSettings>,
     network: &mut Network<P>,
-) -> Result<Packet, Error>
+    peer_certificate: Option<&PeerCertificate>,
+) -> Result<(Packet, Option<EnhancedAuth>), Error>
 where
     P: Protocol,
 {
@@ -182,20 +422,81 @@ pub async fn mqtt_connect<P>(
     // DOS attacks by filling total connections that the server can handle with idle open
     // connections which results in server rejecting new connections
     let connection_timeout_ms = config.connection_timeout_ms.into();
-    let packet = time::timeout(Duration::from_millis(connection_timeout_ms), async {
+    let mut packet = time::timeout(Duration::from_millis(connection_timeout_ms), async {
         let packet = network.read().await?;
         Ok::<_, network::Error>(packet)
     })
     .await??;
 
-    let (connect, _props, login) = match packet {
-        Packet::Connect(ref connect, ref props, _, _, ref login) => (connect, props, login),
+    let (connect, props, will, login) = match packet {
+        Packet::Connect(ref mut connect, ref props, ref will, _, ref mut login) => {
+            (connect, props, will, login)
+        }
         packet => return Err(Error::NotConnectPacket(packet)),
     };
 
+    let mut trusted = false;
+    let mut cert_username = false;
+    if let Some(settings) = &config.client_cert {
+        let Some(identity) = peer_certificate.and_then(|cert| settings.identity(cert)) else {
+            connack_failure(network, ConnectReturnCode::NotAuthorized).await?;
+            return Err(Error::MissingCertIdentity);
+        };
+
+        if settings.enforce_client_id {
+            if connect.client_id.is_empty() {
+                connect.client_id = identity.clone();
+            } else if connect.client_id != identity {
+                connack_failure(network, ConnectReturnCode::ClientIdentifierNotValid).await?;
+                return Err(Error::ClientIdMismatch);
+            }
+        }
+
+        if settings.use_as_username {
+            let password = login.take().map(|l| l.password).unwrap_or_default();
+            *login = Some(Login {
+                username: identity,
+                password,
+            });
+        }
+
+        trusted = settings.skip_password_auth;
+        cert_username = settings.use_as_username;
+    }
+
     Span::current().record("client_id", &connect.client_id);
 
-    handle_auth(config.clone(), login.as_ref(), &connect.client_id).await?;
+    let method = props.as_ref().and_then(|p| p.authentication_method.clone());
+    let auth = match method {
+        Some(method) => {
+            let data = props.as_ref().and_then(|p| p.authentication_data.clone());
+            let auth = enhanced_auth(&config, network, &connect.client_id, method, data).await?;
+
+            // Username in connect isn't verified by enhanced authentication, so only the
+            // authenticated one, or the one from the certificate, is used
+            match &auth.username {
+                Some(username) => {
+                    *login = Some(Login {
+                        username: username.clone(),
+                        password: String::new(),
+                    })
+                }
+                None if cert_username => {}
+                None => *login = None,
+            }
+
+            Some(auth)
+        }
+        None if trusted => None,
+        None => {
+            if let Err(e) = handle_auth(config.clone(), login.as_ref(), &connect.client_id).await {
+                connack_failure(network, ConnectReturnCode::BadUserNamePassword).await?;
+                return Err(e);
+            }
+
+            None
+        }
+    };
 
     // When keep_alive feature is disabled client can live forever, which is not good in
     // distributed broker context so currenlty we don't allow it.
@@ -203,23 +504,109 @@ pub async fn mqtt_connect<P>(
         return Err(Error::ZeroKeepAlive);
     }
 
+    if let Some(will) = will {
+        let capabilities = &config.capabilities;
+        if will.qos > capabilities.max_qos() {
+            connack_failure(network, ConnectReturnCode::QoSNotSupported).await?;
+            return Err(Error::UnsupportedWill);
+        }
+
+        if will.retain && !capabilities.retain_available {
+            connack_failure(network, ConnectReturnCode::RetainNotSupported).await?;
+            return Err(Error::UnsupportedWill);
+        }
+    }
+
     let empty_client_id = connect.client_id.is_empty();
     let clean_session = connect.clean_session;
 
     if empty_client_id && !clean_session {
-        let ack = ConnAck {
-            session_present: false,
-            code: ConnectReturnCode::ClientIdentifierNotValid,
+        connack_failure(network, ConnectReturnCode::ClientIdentifierNotValid).await?;
+        return Err(Error::InvalidClientId);
+    }
+
+    // Ok((connect, props, lastwill, lastwill_props))
+    Ok((packet, auth))
+}
+
+async fn connack_failure<P: Protocol>(
+    network: &mut Network<P>,
+    code: ConnectReturnCode,
+) -> Result<(), Error> {
+    let ack = ConnAck {
+        session_present: false,
+        code,
+    };
+
+    network.write(Packet::ConnAck(ack, None)).await?;
+    Ok(())
+}
+
+/// Runs MQTT 5 enhanced authentication with the authenticator of `method` until the client
+/// is either authenticated or rejected
+async fn enhanced_auth<P: Protocol>(
+    config: &ConnectionSettings,
+    network: &mut Network<P>,
+    client_id: &str,
+    method: String,
+    mut data: Option<Bytes>,
+) -> Result<EnhancedAuth, Error> {
+    let Some(authenticator) = config.authenticators.get(&method).cloned() else {
+        connack_failure(network, ConnectReturnCode::BadAuthenticationMethod).await?;
+        return Err(Error::BadAuthenticationMethod(method));
+    };
+
+    let connection_timeout_ms = config.connection_timeout_ms.into();
+    let mut session = authenticator.start(client_id);
+    loop {
+        let challenge = match session.step(data.take()) {
+            AuthStep::Continue(challenge) => challenge,
+            AuthStep::Success { username, data } => {
+                return Ok(EnhancedAuth {
+                    authenticator,
+                    username,
+                    data,
+                })
+            }
+            AuthStep::Failure => {
+                connack_failure(network, ConnectReturnCode::NotAuthorized).await?;
+                return Err(Error::InvalidAuth);
+            }
         };
 
-        let packet = Packet::ConnAck(ack, None);
-        network.write(packet).await?;
+        let auth = Auth {
+            code: AuthReasonCode::Continue,
+        };
+        network
+            .write(Packet::Auth(auth, auth_properties(&method, challenge)))
+            .await?;
 
-        return Err(Error::InvalidClientId);
+        let packet =
+            time::timeout(Duration::from_millis(connection_timeout_ms), network.read()).await??;
+
+        // Client can only continue the exchange with the same method
+        data = match packet {
+            Packet::Auth(auth, Some(properties))
+                if auth.code == AuthReasonCode::Continue
+                    && properties.method.as_ref() == Some(&method) =>
+            {
+                properties.data
+            }
+            _ => {
+                connack_failure(network, ConnectReturnCode::ProtocolError).await?;
+                return Err(Error::UnexpectedAuth);
+            }
+        };
     }
+}
 
-    // Ok((connect, props, lastwill, lastwill_props))
-    Ok(packet)
+fn auth_properties(method: &str, data: Option<Bytes>) -> Option<AuthProperties> {
+    Some(AuthProperties {
+        method: Some(method.to_owned()),
+        data,
+        reason: None,
+        user_properties: Vec::new(),
+    })
 }
 
 async fn handle_auth(
@@ -227,7 +614,7 @@ async fn handle_auth(
     login: Option<&Login>,
     client_id: &str,
 ) -> Result<(), Error> {
-    if config.auth.is_none() && config.external_auth.is_none() {
+    if config.auth.is_none() && config.external_auth.is_none() && config.password_file.is_none() {
         return Ok(());
     }
 
@@ -254,14 +641,27 @@ async fn handle_auth(
         return Ok(());
     }
 
-    if let Some(pairs) = &config.auth {
-        if let Some(stored_password) = pairs.get(username) {
-            if stored_password.as_bytes().ct_eq(password.as_bytes()).into() {
-                return Ok(());
-            }
+    let password = password.to_owned();
+    let verified = match config.auth.as_ref().and_then(|pairs| pairs.get(username)) {
+        Some(stored) if !password::is_hash(stored) => password::verify(stored, &password),
+        None if config.password_file.is_none() => false,
+        stored => {
+            // Hashes are slow to verify by design and the password file is read from disk, so
+            // keep both off the runtime threads
+            let stored = stored.cloned();
+            let password_file = config.password_file.clone();
+            let username = username.to_owned();
+            tokio::task::spawn_blocking(move || {
+                let stored = stored.or_else(|| password_file?.password(&username));
+                stored.is_some_and(|stored| password::verify(&stored, &password))
+            })
+            .await
+            .unwrap_or(false)
         }
+    };
 
-        return Err(Error::InvalidAuth);
+    if verified {
+        return Ok(());
     }
 
     Err(Error::InvalidAuth)
@@ -271,9 +671,20 @@ async fn handle_auth(
 mod tests {
     use std::{collections::HashMap, sync::Arc};
 
+    use bytes::Bytes;
+
+    use crate::link::auth::test::client_final;
+    use crate::link::auth::{AuthSession, AuthStep, Authenticator, ScramSha256};
+    use crate::link::network::Network;
+    use crate::link::password::PasswordFile;
+    use crate::protocol::v5::V5;
+    use crate::protocol::{
+        Auth, AuthProperties, AuthReasonCode, Connect, ConnectProperties, ConnectReturnCode, Packet,
+    };
     use crate::{protocol::Login, ConnectionSettings};
+    use crate::{CertIdentity, ClientCertSettings, PeerCertificate};
 
-    use super::handle_auth;
+    use super::{handle_auth, mqtt_connect, Error};
 
     fn config() -> ConnectionSettings {
         ConnectionSettings {
@@ -282,7 +693,12 @@ fn config() -> ConnectionSettings {
             max_inflight_count: 0,
             auth: None,
             external_auth: None,
+            password_file: None,
             dynamic_filters: false,
+            authenticators: HashMap::new(),
+            client_cert: None,
+            capabilities: Default::default(),
+            rate_limits: None,
         }
     }
 
@@ -368,6 +784,33 @@ async fn login_fails_static_fails_external() {
         assert!(r.is_err());
     }
 
+    #[tokio::test]
+    async fn login_falls_back_to_password_file() {
+        let path =
+            std::env::temp_dir().join(format!("rumqttd-remote-passwd-{}", std::process::id()));
+        std::fs::write(&path, "u:p\n").unwrap();
+
+        let mut map = HashMap::<String, String>::new();
+        map.insert("other".to_owned(), "other".to_owned());
+
+        let mut cfg = config();
+        cfg.auth = Some(map);
+        cfg.password_file = Some(PasswordFile::new(&path));
+        let cfg = Arc::new(cfg);
+
+        let r = handle_auth(cfg.clone(), Some(&login()), "").await;
+        assert!(r.is_ok());
+
+        let wrong = Login {
+            username: "u".to_owned(),
+            password: "wrong".to_owned(),
+        };
+        let r = handle_auth(cfg, Some(&wrong), "").await;
+        assert!(r.is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
     #[tokio::test]
     async fn external_auth_clousre_or_fnptr_type_check_or_fail_compile() {
         let closure = |_: String, _: String, _: String| async { false };
@@ -379,4 +822,293 @@ async fn fnptr(_: String, _: String, _: String) -> bool {
         cfg.set_auth_handler(closure);
         cfg.set_auth_handler(fnptr);
     }
+
+    /// Challenges the client once and expects a fixed response
+    struct Challenge;
+
+    struct ChallengeSession {
+        challenged: bool,
+    }
+
+    impl Authenticator for Challenge {
+        fn method(&self) -> &str {
+            "challenge"
+        }
+
+        fn start(&self, _client_id: &str) -> Box<dyn AuthSession> {
+            Box::new(ChallengeSession { challenged: false })
+        }
+    }
+
+    impl AuthSession for ChallengeSession {
+        fn step(&mut self, data: Option<Bytes>) -> AuthStep {
+            if !self.challenged {
+                self.challenged = true;
+                return AuthStep::Continue(Some(Bytes::from_static(b"question")));
+            }
+
+            match data.as_deref() {
+                Some(b"answer") => AuthStep::Success {
+                    username: None,
+                    data: Some(Bytes::from_static(b"welcome")),
+                },
+                _ => AuthStep::Failure,
+            }
+        }
+    }
+
+    fn connect(method: &str) -> Packet {
+        let connect = Connect {
+            keep_alive: 10,
+            client_id: "client".to_owned(),
+            clean_session: true,
+        };
+
+        let properties = ConnectProperties {
+            session_expiry_interval: None,
+            receive_maximum: None,
+            max_packet_size: None,
+            topic_alias_max: None,
+            request_response_info: None,
+            request_problem_info: None,
+            user_properties: Vec::new(),
+            authentication_method: Some(method.to_owned()),
+            authentication_data: None,
+        };
+
+        Packet::Connect(connect, Some(properties), None, None, None)
+    }
+
+    fn auth_continue(data: &'static [u8]) -> Packet {
+        let properties = AuthProperties {
+            method: Some("challenge".to_owned()),
+            data: Some(Bytes::from_static(data)),
+            reason: None,
+            user_properties: Vec::new(),
+        };
+
+        Packet::Auth(
+            Auth {
+                code: AuthReasonCode::Continue,
+            },
+            Some(properties),
+        )
+    }
+
+    /// Runs `mqtt_connect` against a client which sends `packets` and returns what the client
+    /// received after each of them
+    async fn enhanced_connect(packets: Vec<Packet>) -> (Result<Option<Bytes>, Error>, Vec<Packet>) {
+        let mut cfg = config();
+        cfg.connection_timeout_ms = 1000;
+        cfg.set_authenticator(Challenge);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Network::new(Box::new(client), 1024, 10, V5);
+        let mut server = Network::new(Box::new(server), 1024, 10, V5);
+
+        let server = tokio::spawn(async move {
+            mqtt_connect(Arc::new(cfg), &mut server, None)
+                .await
+                .map(|(_, auth)| auth.unwrap().data)
+        });
+
+        let mut received = Vec::new();
+        for packet in packets {
+            client.write(packet).await.unwrap();
+            if let Ok(packet) = client.read().await {
+                received.push(packet);
+            }
+        }
+
+        (server.await.unwrap(), received)
+    }
+
+    #[tokio::test]
+    async fn enhanced_auth_exchange_succeeds() {
+        let packets = vec![connect("challenge"), auth_continue(b"answer")];
+        let (auth, received) = enhanced_connect(packets).await;
+
+        assert_eq!(auth.unwrap(), Some(Bytes::from_static(b"welcome")));
+        assert!(matches!(
+            &received[0],
+            Packet::Auth(auth, Some(properties))
+                if auth.code == AuthReasonCode::Continue
+                    && properties.data.as_deref() == Some(b"question".as_slice())
+        ));
+    }
+
+    #[tokio::test]
+    async fn enhanced_auth_rejects_wrong_response() {
+        let packets = vec![connect("challenge"), auth_continue(b"guess")];
+        let (auth, received) = enhanced_connect(packets).await;
+
+        assert!(matches!(auth, Err(Error::InvalidAuth)));
+        assert!(matches!(
+            &received[1],
+            Packet::ConnAck(ack, _) if ack.code == ConnectReturnCode::NotAuthorized
+        ));
+    }
+
+    #[tokio::test]
+    async fn enhanced_auth_rejects_unknown_method() {
+        let (auth, received) = enhanced_connect(vec![connect("unknown")]).await;
+
+        assert!(matches!(auth, Err(Error::BadAuthenticationMethod(_))));
+        assert!(matches!(
+            &received[0],
+            Packet::ConnAck(ack, _) if ack.code == ConnectReturnCode::BadAuthenticationMethod
+        ));
+    }
+
+    #[tokio::test]
+    async fn enhanced_auth_replaces_username_in_connect() {
+        let mut scram = ScramSha256::new();
+        scram.add_user("user", "pencil");
+
+        let mut cfg = config();
+        cfg.connection_timeout_ms = 1000;
+        cfg.set_authenticator(scram);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Network::new(Box::new(client), 1024, 10, V5);
+        let mut server = Network::new(Box::new(server), 1024, 10, V5);
+        let server = tokio::spawn(async move {
+            mqtt_connect(Arc::new(cfg), &mut server, None)
+                .await
+                .map(|(packet, _)| packet)
+        });
+
+        // Client authenticates as "user" but claims to be "admin" in connect
+        let client_first_bare = "n=user,r=rOprNGfwEbeRWgbNEkqO";
+        let Packet::Connect(connect, Some(mut properties), ..) = connect("SCRAM-SHA-256") else {
+            unreachable!()
+        };
+        properties.authentication_data = Some(Bytes::from(format!("n,,{client_first_bare}")));
+        let login = Login {
+            username: "admin".to_owned(),
+            password: String::new(),
+        };
+        client
+            .write(Packet::Connect(
+                connect,
+                Some(properties),
+                None,
+                None,
+                Some(login),
+            ))
+            .await
+            .unwrap();
+
+        let Ok(Packet::Auth(_, Some(properties))) = client.read().await else {
+            panic!("client isn't challenged");
+        };
+        let server_first = String::from_utf8(properties.data.unwrap().to_vec()).unwrap();
+        let (client_final, _) = client_final(client_first_bare, &server_first, "pencil");
+        let properties = AuthProperties {
+            method: Some("SCRAM-SHA-256".to_owned()),
+            data: Some(Bytes::from(client_final)),
+            reason: None,
+            user_properties: Vec::new(),
+        };
+        let auth = Auth {
+            code: AuthReasonCode::Continue,
+        };
+        client
+            .write(Packet::Auth(auth, Some(properties)))
+            .await
+            .unwrap();
+
+        let Ok(Packet::Connect(_, _, _, _, Some(login))) = server.await.unwrap() else {
+            panic!("connection rejected");
+        };
+        assert_eq!(login.username, "user");
+    }
+
+    /// Runs `mqtt_connect` for a client with `client_id` and returns the accepted connect
+    /// packet or the connack which rejected it
+    async fn cert_connect(
+        settings: ClientCertSettings,
+        cert: Option<PeerCertificate>,
+        client_id: &str,
+    ) -> Result<Packet, (Error, Packet)> {
+        let mut cfg = config();
+        cfg.connection_timeout_ms = 1000;
+        cfg.auth = Some(HashMap::from([("u".to_owned(), "p".to_owned())]));
+        cfg.client_cert = Some(settings);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Network::new(Box::new(client), 1024, 10, V5);
+        let mut server = Network::new(Box::new(server), 1024, 10, V5);
+
+        let connect = Connect {
+            keep_alive: 10,
+            client_id: client_id.to_owned(),
+            clean_session: true,
+        };
+        client
+            .write(Packet::Connect(connect, None, None, None, None))
+            .await
+            .unwrap();
+
+        match mqtt_connect(Arc::new(cfg), &mut server, cert.as_ref()).await {
+            Ok((packet, _)) => Ok(packet),
+            Err(e) => Err((e, client.read().await.unwrap())),
+        }
+    }
+
+    #[tokio::test]
+    async fn cert_identity_maps_to_client_id_and_username() {
+        let settings = ClientCertSettings {
+            identity: CertIdentity::CommonName,
+            enforce_client_id: true,
+            use_as_username: true,
+            skip_password_auth: true,
+        };
+        let cert = PeerCertificate {
+            common_name: Some("device-1".to_owned()),
+            ..Default::default()
+        };
+
+        let packet = cert_connect(settings.clone(), Some(cert.clone()), "").await;
+        let Ok(Packet::Connect(connect, _, _, _, Some(login))) = packet else {
+            panic!("connection rejected");
+        };
+        assert_eq!(connect.client_id, "device-1");
+        assert_eq!(login.username, "device-1");
+
+        let packet = cert_connect(settings.clone(), Some(cert), "device-2").await;
+        assert!(matches!(
+            packet,
+            Err((Error::ClientIdMismatch, Packet::ConnAck(ack, _)))
+                if ack.code == ConnectReturnCode::ClientIdentifierNotValid
+        ));
+
+        let packet = cert_connect(settings, None, "device-1").await;
+        assert!(matches!(
+            packet,
+            Err((Error::MissingCertIdentity, Packet::ConnAck(ack, _)))
+                if ack.code == ConnectReturnCode::NotAuthorized
+        ));
+    }
+
+    #[tokio::test]
+    async fn cert_identity_still_requires_password_unless_trusted() {
+        let settings = ClientCertSettings {
+            identity: CertIdentity::Fingerprint,
+            ..Default::default()
+        };
+        let cert = PeerCertificate {
+            fingerprint: "ab12".to_owned(),
+            ..Default::default()
+        };
+
+        let packet = cert_connect(settings.clone(), Some(cert.clone()), "device-1").await;
+        assert!(matches!(packet, Err((Error::InvalidAuth, _))));
+
+        let settings = ClientCertSettings {
+            skip_password_auth: true,
+            ..settings
+        };
+        assert!(cert_connect(settings, Some(cert), "device-1").await.is_ok());
+    }
 }
@@ -7,6 +7,9 @@
 use tokio::select;
 use tracing::error;
 
+/// Interval at which router is asked to remove expired sessions
+const SESSIONS_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Channel send error")]
@@ -30,6 +33,8 @@ pub async fn start(
         .get(&MetricType::Meters)
         .map(|interval| tokio::time::interval(Duration::from_secs(interval.push_interval)));
 
+    let mut sessions_purge_interval = tokio::time::interval(SESSIONS_PURGE_INTERVAL);
+
     loop {
         select! {
             _ = alerts_push_interval.as_mut().unwrap().tick(), if alerts_push_interval.is_some() => {
@@ -42,6 +47,11 @@ pub async fn start(
                     error!("Failed to push alerts: {e}");
                 }
             }
+            _ = sessions_purge_interval.tick() => {
+                if let Err(e) = router_tx.send_async((0, Event::PurgeSessions)).await {
+                    error!("Failed to purge sessions: {e}");
+                }
+            }
         }
     }
 }
@@ -38,6 +38,7 @@ pub enum Packet {
     Unsubscribe(Unsubscribe, Option<UnsubscribeProperties>),
     UnsubAck(UnsubAck, Option<UnsubAckProperties>),
     Disconnect(Disconnect, Option<DisconnectProperties>),
+    Auth(Auth, Option<AuthProperties>),
 }
 
 //--------------------------- Connect packet -------------------------------
@@ -574,6 +575,38 @@ pub struct DisconnectProperties {
 }
 //------------------------------------------------------------------------
 
+//--------------------------- Auth packet -------------------------------
+/// Authentication exchange of MQTT 5 enhanced authentication
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Auth {
+    /// Auth Reason Code
+    pub code: AuthReasonCode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AuthReasonCode {
+    /// Authentication is successful
+    Success,
+    /// Continue the authentication with another step
+    Continue,
+    /// Initiate a re-authentication
+    ReAuthenticate,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthProperties {
+    /// Method of authentication
+    pub method: Option<String>,
+    /// Authentication data
+    pub data: Option<Bytes>,
+    /// Human readable reason
+    pub reason: Option<String>,
+    /// List of user properties
+    pub user_properties: Vec<(String, String)>,
+}
+//------------------------------------------------------------------------
+
 /// Quality of service
 #[repr(u8)]
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd)]
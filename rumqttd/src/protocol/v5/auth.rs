@@ -0,0 +1,273 @@
+use super::*;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+fn len(auth: &Auth, properties: &Option<AuthProperties>) -> usize {
+    // Reason code and properties can be omitted together if the reason is success
+    if auth.code == AuthReasonCode::Success && properties.is_none() {
+        return 0;
+    }
+
+    let mut len = 1; // reason code
+    if let Some(p) = properties {
+        let properties_len = properties::len(p);
+        let properties_len_len = len_len(properties_len);
+        len += properties_len_len + properties_len;
+    } else {
+        // just 1 byte representing 0 len properties
+        len += 1;
+    }
+
+    len
+}
+
+pub fn read(
+    fixed_header: FixedHeader,
+    mut bytes: Bytes,
+) -> Result<(Auth, Option<AuthProperties>), Error> {
+    let flags = fixed_header.byte1 & 0b0000_1111;
+    bytes.advance(fixed_header.fixed_header_len);
+
+    if flags != 0x00 {
+        return Err(Error::MalformedPacket);
+    }
+
+    if fixed_header.remaining_len == 0 {
+        return Ok((
+            Auth {
+                code: AuthReasonCode::Success,
+            },
+            None,
+        ));
+    }
+
+    let auth = Auth {
+        code: reason(read_u8(&mut bytes)?)?,
+    };
+
+    // No properties len or properties if remaining len is 1
+    if fixed_header.remaining_len < 2 {
+        return Ok((auth, None));
+    }
+
+    let properties = properties::read(&mut bytes)?;
+    Ok((auth, properties))
+}
+
+pub fn write(
+    auth: &Auth,
+    properties: &Option<AuthProperties>,
+    buffer: &mut BytesMut,
+) -> Result<usize, Error> {
+    let len = len(auth, properties);
+    buffer.put_u8(0xF0);
+
+    let count = write_remaining_length(buffer, len)?;
+    if len == 0 {
+        return Ok(1 + count);
+    }
+
+    buffer.put_u8(code(auth.code));
+    if let Some(p) = properties {
+        properties::write(p, buffer)?;
+    } else {
+        write_remaining_length(buffer, 0)?;
+    }
+
+    Ok(1 + count + len)
+}
+
+mod properties {
+    use super::*;
+
+    pub fn len(properties: &AuthProperties) -> usize {
+        let mut len = 0;
+
+        if let Some(method) = &properties.method {
+            len += 1 + 2 + method.len();
+        }
+
+        if let Some(data) = &properties.data {
+            len += 1 + 2 + data.len();
+        }
+
+        if let Some(reason) = &properties.reason {
+            len += 1 + 2 + reason.len();
+        }
+
+        for (key, value) in properties.user_properties.iter() {
+            len += 1 + 2 + key.len() + 2 + value.len();
+        }
+
+        len
+    }
+
+    pub fn read(bytes: &mut Bytes) -> Result<Option<AuthProperties>, Error> {
+        let mut method = None;
+        let mut data = None;
+        let mut reason = None;
+        let mut user_properties = Vec::new();
+
+        let (properties_len_len, properties_len) = length(bytes.iter())?;
+        bytes.advance(properties_len_len);
+        if properties_len == 0 {
+            return Ok(None);
+        }
+
+        let mut cursor = 0;
+        // read until cursor reaches property length. properties_len = 0 will skip this loop
+        while cursor < properties_len {
+            let prop = read_u8(bytes)?;
+            cursor += 1;
+
+            match property(prop)? {
+                PropertyType::AuthenticationMethod => {
+                    let value = read_mqtt_string(bytes)?;
+                    cursor += 2 + value.len();
+                    method = Some(value);
+                }
+                PropertyType::AuthenticationData => {
+                    let value = read_mqtt_bytes(bytes)?;
+                    cursor += 2 + value.len();
+                    data = Some(value);
+                }
+                PropertyType::ReasonString => {
+                    let value = read_mqtt_string(bytes)?;
+                    cursor += 2 + value.len();
+                    reason = Some(value);
+                }
+                PropertyType::UserProperty => {
+                    let key = read_mqtt_string(bytes)?;
+                    let value = read_mqtt_string(bytes)?;
+                    cursor += 2 + key.len() + 2 + value.len();
+                    user_properties.push((key, value));
+                }
+                _ => return Err(Error::InvalidPropertyType(prop)),
+            }
+        }
+
+        Ok(Some(AuthProperties {
+            method,
+            data,
+            reason,
+            user_properties,
+        }))
+    }
+
+    pub fn write(properties: &AuthProperties, buffer: &mut BytesMut) -> Result<(), Error> {
+        let len = len(properties);
+        write_remaining_length(buffer, len)?;
+
+        if let Some(method) = &properties.method {
+            buffer.put_u8(PropertyType::AuthenticationMethod as u8);
+            write_mqtt_string(buffer, method);
+        }
+
+        if let Some(data) = &properties.data {
+            buffer.put_u8(PropertyType::AuthenticationData as u8);
+            write_mqtt_bytes(buffer, data);
+        }
+
+        if let Some(reason) = &properties.reason {
+            buffer.put_u8(PropertyType::ReasonString as u8);
+            write_mqtt_string(buffer, reason);
+        }
+
+        for (key, value) in properties.user_properties.iter() {
+            buffer.put_u8(PropertyType::UserProperty as u8);
+            write_mqtt_string(buffer, key);
+            write_mqtt_string(buffer, value);
+        }
+
+        Ok(())
+    }
+}
+
+fn reason(num: u8) -> Result<AuthReasonCode, Error> {
+    let code = match num {
+        0x00 => AuthReasonCode::Success,
+        0x18 => AuthReasonCode::Continue,
+        0x19 => AuthReasonCode::ReAuthenticate,
+        num => return Err(Error::InvalidReason(num)),
+    };
+
+    Ok(code)
+}
+
+fn code(reason: AuthReasonCode) -> u8 {
+    match reason {
+        AuthReasonCode::Success => 0x00,
+        AuthReasonCode::Continue => 0x18,
+        AuthReasonCode::ReAuthenticate => 0x19,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::BytesMut;
+
+    fn sample() -> (Auth, Option<AuthProperties>) {
+        let properties = AuthProperties {
+            method: Some("SCRAM".to_owned()),
+            data: Some(Bytes::from_static(b"abc")),
+            reason: None,
+            user_properties: vec![],
+        };
+
+        (
+            Auth {
+                code: AuthReasonCode::Continue,
+            },
+            Some(properties),
+        )
+    }
+
+    fn sample_bytes() -> Vec<u8> {
+        vec![
+            0xF0, // Packet type
+            0x10, // Remaining length
+            0x18, // Auth reason code
+            0x0E, // Properties length
+            0x15, 0x00, 0x05, b'S', b'C', b'R', b'A', b'M', // Authentication method
+            0x16, 0x00, 0x03, b'a', b'b', b'c', // Authentication data
+        ]
+    }
+
+    #[test]
+    fn auth_parsing_works() {
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(&sample_bytes());
+
+        let fixed_header = parse_fixed_header(buffer.iter()).unwrap();
+        let auth_bytes = buffer.split_to(fixed_header.frame_length()).freeze();
+        let auth = read(fixed_header, auth_bytes).unwrap();
+
+        assert_eq!(auth, sample());
+    }
+
+    #[test]
+    fn auth_encoding_works() {
+        let mut buffer = BytesMut::new();
+        let (auth, properties) = sample();
+
+        let size = write(&auth, &properties, &mut buffer).unwrap();
+
+        assert_eq!(&buffer[..], &sample_bytes());
+        assert_eq!(size, sample_bytes().len());
+    }
+
+    #[test]
+    fn empty_auth_is_success() {
+        let mut buffer = BytesMut::new();
+        let auth = Auth {
+            code: AuthReasonCode::Success,
+        };
+
+        write(&auth, &None, &mut buffer).unwrap();
+        assert_eq!(&buffer[..], &[0xF0, 0x00]);
+
+        let fixed_header = parse_fixed_header(buffer.iter()).unwrap();
+        let auth_bytes = buffer.split_to(fixed_header.frame_length()).freeze();
+        assert_eq!(read(fixed_header, auth_bytes).unwrap(), (auth, None));
+    }
+}
@@ -5,13 +5,14 @@
 use super::*;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
+mod auth;
 mod connack;
 mod connect;
 mod disconnect;
 mod ping;
 mod puback;
 mod pubcomp;
-mod publish;
+pub(crate) mod publish;
 mod pubrec;
 mod pubrel;
 mod suback;
@@ -37,6 +38,7 @@ pub enum PacketType {
     PingReq,
     PingResp,
     Disconnect,
+    Auth,
 }
 
 #[repr(u8)]
@@ -123,6 +125,7 @@ pub fn packet_type(&self) -> Result<PacketType, Error> {
             12 => Ok(PacketType::PingReq),
             13 => Ok(PacketType::PingResp),
             14 => Ok(PacketType::Disconnect),
+            15 => Ok(PacketType::Auth),
             _ => Err(Error::InvalidPacketType(num)),
         }
     }
@@ -377,6 +380,12 @@ fn read_mut(&mut self, stream: &mut BytesMut, max_size: usize) -> Result<Packet,
                     },
                     None,
                 )),
+                PacketType::Auth => Ok(Packet::Auth(
+                    Auth {
+                        code: AuthReasonCode::Success,
+                    },
+                    None,
+                )),
                 _ => Err(Error::PayloadRequired),
             };
         }
@@ -388,6 +397,10 @@ fn read_mut(&mut self, stream: &mut BytesMut, max_size: usize) -> Result<Packet,
                     connect::read(fixed_header, packet)?;
                 Packet::Connect(connect, properties, will, willproperties, login)
             }
+            PacketType::ConnAck => {
+                let (connack, properties) = connack::read(fixed_header, packet)?;
+                Packet::ConnAck(connack, properties)
+            }
             PacketType::Publish => {
                 let (publish, properties) = publish::read(fixed_header, packet)?;
                 Packet::Publish(publish, properties)
@@ -426,6 +439,10 @@ fn read_mut(&mut self, stream: &mut BytesMut, max_size: usize) -> Result<Packet,
                 let (pubcomp, properties) = pubcomp::read(fixed_header, packet)?;
                 Packet::PubComp(pubcomp, properties)
             }
+            PacketType::Auth => {
+                let (auth, properties) = auth::read(fixed_header, packet)?;
+                Packet::Auth(auth, properties)
+            }
             _ => unreachable!(),
         };
 
@@ -467,6 +484,7 @@ fn write(&self, packet: Packet, buffer: &mut BytesMut) -> Result<usize, Error> {
             Packet::Disconnect(disconnect, properties) => {
                 disconnect::write(&disconnect, &properties, buffer)?
             }
+            Packet::Auth(auth, properties) => auth::write(&auth, &properties, buffer)?,
             Packet::PingReq(pingreq) => ping::pingreq::write(buffer)?,
             Packet::PingResp(pingresp) => ping::pingresp::write(buffer)?,
             _ => unreachable!(),
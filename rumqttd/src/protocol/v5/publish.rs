@@ -21,6 +21,17 @@ pub fn len(publish: &Publish, properties: &Option<PublishProperties>) -> usize {
     len
 }
 
+/// Size of the complete packet. Accounts for the packet identifier of QoS 1 and 2 publishes
+/// even when it isn't assigned yet
+pub fn packet_len(publish: &Publish, properties: &Option<PublishProperties>) -> usize {
+    let mut len = len(publish, properties);
+    if publish.qos != QoS::AtMostOnce && publish.pkid == 0 {
+        len += 2;
+    }
+
+    1 + len_len(len) + len
+}
+
 pub fn read(
     fixed_header: FixedHeader,
     mut bytes: Bytes,
@@ -92,7 +103,7 @@ pub fn write(
     Ok(1 + count + len)
 }
 
-mod properties {
+pub(crate) mod properties {
     use super::*;
 
     pub fn len(properties: &PublishProperties) -> usize {
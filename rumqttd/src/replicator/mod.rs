@@ -1,65 +1,756 @@
-pub mod local;
-pub mod remote;
+//! Replication of publishes between the nodes of a cluster. Every node connects to its seniors
+//! and accepts connections from the rest, forming a full mesh. Publishes of clients connected
+//! to a node are sent to every other node, which appends them to its commitlogs, so that
+//! subscribers on any node receive them.
+//!
+//! Client ids are unique across the cluster. When a remote client connects to a node, other
+//! nodes disconnect it with `SessionTakenOver` and hand its session over. A client resuming a
+//! session which its node doesn't have is acked once every connected node handed over its
+//! session, or told that it has none. Subscriptions are restored along with messages that
+//! weren't delivered yet, as far as commitlogs of the new node have them. Nodes can order
+//! replicated messages differently, so messages published around the handover might be
+//! delivered twice or missed.
+//!
+//! Nodes talk MQTT 5 with each other. The connecting node sends its id as client id and the
+//! secret shared by the cluster as password. Only nodes listed as `juniors` with the right
+//! secret are accepted. Publishes are exchanged with QoS 1 in both directions.
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::time::Duration;
 
-#[derive(Debug)]
+use bytes::Bytes;
+use flume::{Receiver, Sender};
+use subtle::ConstantTimeEq;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, error::Elapsed};
+use tracing::{error, info, warn};
+
+use crate::link::local::{LinkBuilder, LinkError, LinkRx, LinkTx};
+use crate::link::network::{self, Network};
+use crate::protocol::v5::V5;
+use crate::protocol::{
+    ConnAck, Connect, ConnectReturnCode, Login, Packet, PingReq, PingResp, PubAck, PubAckReason,
+    Publish, PublishProperties, QoS,
+};
+use crate::router::{Event, Notification};
+use crate::{ClusterSettings, ConnectionId, NodeId};
+
+/// Interval of pings on connections between nodes. Connections without any packet from the
+/// other node in 1.5 times of it are considered dead
+const PING_INTERVAL: u16 = 5;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Publishes sent to a node which aren't acked yet
+const MAX_INFLIGHT: usize = 100;
+const MAX_PACKET_SIZE: usize = 10 * 1024 * 1024;
+/// Topics of publishes between nodes, which take over sessions of clients connecting to
+/// another node. Publishes of clients on these topics aren't replicated
+const CLUSTER_PREFIX: &str = "$cluster/";
+const CONNECTED_TOPIC: &str = "$cluster/connected";
+const SESSION_TOPIC: &str = "$cluster/session";
+
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
-    // Mqtt(String),
+    #[error("I/O = {0}")]
+    Io(#[from] io::Error),
+    #[error("Network = {0}")]
+    Network(#[from] network::Error),
+    #[error("Timeout")]
+    Timeout(#[from] Elapsed),
+    #[error("Link error = {0}")]
+    Link(Box<LinkError>),
+    #[error("Expected connect from node")]
+    NotConnect,
+    #[error("Invalid node id {0}")]
+    InvalidNodeId(String),
+    #[error("Invalid secret from node {0}")]
+    InvalidSecret(NodeId),
+    #[error("Node {0} isn't a junior of this node")]
+    UnknownNode(NodeId),
+    #[error("Cluster secret isn't set")]
+    MissingSecret,
+    #[error("Connection refused by node")]
+    ConnectionRefused,
 }
 
-// // Deserializes payload in publish packet into offset + a list of publishes
-// pub fn replica_read(_stream: &mut Bytes) -> Result<ReplicaData, Error> {
-//     let packet = read(stream, 1024 * 1024 * 1024).map_err(|v| Error::Mqtt(v.to_string()))?;
-//     match packet {
-//         Packet::Publish(publish) => {
-//             let mut payload = publish.payload;
-//             let topic = publish.topic;
-//             match topic.as_ref() {
-//                 "data" => {
-//                     let cursor = (payload.get_u64(), payload.get_u64());
-//                     Ok(ReplicaData::Data {
-//                         cursor,
-//                         publishes: payload,
-//                     })
-//                 }
-//                 "acks" => {
-//                     let offset = (payload.get_u64(), payload.get_u64());
-//                     Ok(ReplicaData::Ack(offset))
-//                 }
-//                 v => unreachable!("Replica data type = {}", v),
-//             }
-//         }
-//         Packet::Subscribe(_) => Ok(ReplicaData::Subscribe),
-//         incoming => unreachable!("Replica only supports publish packet. Received = {:?}", incoming),
-//     }
-// }
-
-// #[derive(Debug)]
-// pub enum ReplicaData {
-//     Data {
-//         cursor: (u64, u64),
-//         publishes: Bytes,
-//     },
-//     Ack((u64, u64)),
-//     Subscribe,
-// }
-
-// Reads a stream of bytes and extracts next MQTT packet out of it
-// pub fn read_publish(stream: &mut Bytes) -> Result<Publish, v4::Error> {
-//     let fixed_header = check(stream.iter(), 1024 * 1024 * 1024)?;
-
-//     // Test with a stream with exactly the size to check border panics
-//     let packet = stream.split_to(fixed_header.frame_length());
-//     let packet_type = fixed_header.packet_type()?;
-
-//     let publish = match packet_type {
-//         PacketType::Publish => Publish::read(fixed_header, packet)?,
-//         v => {
-//             unreachable!(
-//                 "Replica deserialization is only for a list of publishes. Received = {:?}",
-//                 v
-//             )
-//         }
-//     };
-
-//     Ok(publish)
-// }
+impl From<LinkError> for Error {
+    fn from(e: LinkError) -> Error {
+        Error::Link(Box::new(e))
+    }
+}
+
+pub struct Cluster {
+    node_id: NodeId,
+    /// Address on which this node is listening for other nodes
+    listen: String,
+    /// Nodes which this node connects to
+    seniors: Vec<(NodeId, String)>,
+    /// Nodes which connect to this node
+    juniors: Vec<NodeId>,
+    secret: String,
+    router_tx: Sender<(ConnectionId, Event)>,
+}
+
+impl Cluster {
+    pub fn new(config: ClusterSettings, router_tx: Sender<(ConnectionId, Event)>) -> Cluster {
+        Cluster {
+            node_id: config.node_id,
+            listen: config.listen,
+            seniors: config.seniors,
+            juniors: config.juniors,
+            secret: config.secret,
+            router_tx,
+        }
+    }
+
+    /// Connects to seniors and accepts connections from other nodes
+    pub async fn start(self) -> Result<(), Error> {
+        let listener = TcpListener::bind(&self.listen).await?;
+        self.run(listener).await
+    }
+
+    async fn run(self, listener: TcpListener) -> Result<(), Error> {
+        if self.secret.is_empty() {
+            return Err(Error::MissingSecret);
+        }
+
+        for (peer_id, addr) in self.seniors.iter() {
+            let replica = Replica::new(self.node_id, *peer_id, self.router_tx.clone())?;
+            let (addr, secret) = (addr.clone(), self.secret.clone());
+            tokio::spawn(async move { replica.connect(addr, secret).await });
+        }
+
+        self.accept(listener).await
+    }
+
+    async fn accept(&self, listener: TcpListener) -> Result<(), Error> {
+        info!(
+            node_id = self.node_id,
+            "Waiting for nodes on {}", self.listen
+        );
+
+        // Connections from nodes are handed to their replica, which survives reconnections
+        let mut replicas: HashMap<NodeId, Sender<Network<V5>>> = HashMap::new();
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let (peer_id, network) = match self.handshake(stream).await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!(?addr, "Rejected connection from node - {e}");
+                    continue;
+                }
+            };
+
+            if peer_id == self.node_id || self.seniors.iter().any(|(id, _)| *id == peer_id) {
+                error!(
+                    peer_id,
+                    "Rejected connection from node which this node connects to"
+                );
+                continue;
+            }
+
+            info!(peer_id, ?addr, "Accepted connection from node");
+            let network = match replicas.get(&peer_id) {
+                Some(tx) => match tx.send(network) {
+                    Ok(()) => continue,
+                    Err(flume::SendError(network)) => network,
+                },
+                None => network,
+            };
+
+            let replica = Replica::new(self.node_id, peer_id, self.router_tx.clone())?;
+            let (tx, rx) = flume::unbounded();
+            tx.send(network).ok();
+            replicas.insert(peer_id, tx);
+            tokio::spawn(async move { replica.serve(rx).await });
+        }
+    }
+
+    /// Reads connect of a node and accepts it if it's a junior of this node with the secret
+    /// of the cluster
+    async fn handshake(&self, stream: TcpStream) -> Result<(NodeId, Network<V5>), Error> {
+        let mut network = Network::new(Box::new(stream), MAX_PACKET_SIZE, MAX_INFLIGHT, V5);
+        network.set_keepalive(PING_INTERVAL);
+
+        let (connect, login) = match time::timeout(RECONNECT_DELAY * 5, network.read()).await?? {
+            Packet::Connect(connect, _, _, _, login) => (connect, login),
+            _ => return Err(Error::NotConnect),
+        };
+
+        let peer_id = connect
+            .client_id
+            .parse()
+            .map_err(|_| Error::InvalidNodeId(connect.client_id))?;
+
+        let authenticated = login.is_some_and(|login| {
+            login
+                .password
+                .as_bytes()
+                .ct_eq(self.secret.as_bytes())
+                .into()
+        });
+
+        let code = if !authenticated {
+            ConnectReturnCode::BadUserNamePassword
+        } else if !self.juniors.contains(&peer_id) {
+            ConnectReturnCode::NotAuthorized
+        } else {
+            ConnectReturnCode::Success
+        };
+
+        let ack = ConnAck {
+            session_present: false,
+            code,
+        };
+        network.write(Packet::ConnAck(ack, None)).await?;
+
+        match code {
+            ConnectReturnCode::Success => Ok((peer_id, network)),
+            ConnectReturnCode::BadUserNamePassword => Err(Error::InvalidSecret(peer_id)),
+            _ => Err(Error::UnknownNode(peer_id)),
+        }
+    }
+}
+
+/// Replication with one other node. Its local link receives publishes of clients on this node
+/// and appends publishes from the other node, without replicating them again
+pub struct Replica {
+    node_id: NodeId,
+    /// Node which this replica represents
+    peer_id: NodeId,
+    link_tx: LinkTx,
+    link_rx: LinkRx,
+    /// Publishes sent to the other node which aren't acked yet. They are sent again after
+    /// reconnection
+    unacked: VecDeque<(u16, Publish, Option<PublishProperties>)>,
+    last_pkid: u16,
+}
+
+impl Replica {
+    fn new(
+        node_id: NodeId,
+        peer_id: NodeId,
+        router_tx: Sender<(ConnectionId, Event)>,
+    ) -> Result<Replica, Error> {
+        let client_id = format!("cluster-node-{peer_id}");
+        let (link_tx, link_rx, _ack) = LinkBuilder::new(&client_id, router_tx)
+            .dynamic_filters(true)
+            .replica(true)
+            .build()?;
+
+        Ok(Replica {
+            node_id,
+            peer_id,
+            link_tx,
+            link_rx,
+            unacked: VecDeque::new(),
+            last_pkid: 0,
+        })
+    }
+
+    /// Connects to the other node and reconnects whenever the connection fails
+    async fn connect(mut self, addr: String, secret: String) {
+        loop {
+            let network = match self.network_connect(&addr, &secret).await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!(peer_id = self.peer_id, "Failed to connect to node - {e}");
+                    time::sleep(RECONNECT_DELAY).await;
+                    continue;
+                }
+            };
+
+            info!(peer_id = self.peer_id, addr, "Connected to node");
+            if let Err(e) = self.run(network).await {
+                warn!(peer_id = self.peer_id, "Connection to node failed - {e}");
+            }
+
+            time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    /// Runs connections the other node makes to this node
+    async fn serve(mut self, networks: Receiver<Network<V5>>) {
+        while let Ok(network) = networks.recv_async().await {
+            if let Err(e) = self.run(network).await {
+                warn!(peer_id = self.peer_id, "Connection from node failed - {e}");
+            }
+        }
+    }
+
+    async fn network_connect(&self, addr: &str, secret: &str) -> Result<Network<V5>, Error> {
+        let socket = TcpStream::connect(addr).await?;
+        let mut network = Network::new(Box::new(socket), MAX_PACKET_SIZE, MAX_INFLIGHT, V5);
+        network.set_keepalive(PING_INTERVAL);
+
+        let connect = Connect {
+            keep_alive: PING_INTERVAL,
+            client_id: self.node_id.to_string(),
+            clean_session: true,
+        };
+        let login = Login {
+            username: String::new(),
+            password: secret.to_owned(),
+        };
+        network
+            .write(Packet::Connect(connect, None, None, None, Some(login)))
+            .await?;
+
+        match time::timeout(RECONNECT_DELAY * 5, network.read()).await?? {
+            Packet::ConnAck(ack, _) if ack.code == ConnectReturnCode::Success => Ok(network),
+            _ => Err(Error::ConnectionRefused),
+        }
+    }
+
+    /// Exchanges publishes with the other node until the connection fails
+    async fn run(&mut self, mut network: Network<V5>) -> Result<(), Error> {
+        // Publishes which might not have reached the other node with previous connection
+        for (pkid, publish, properties) in self.unacked.iter() {
+            let mut publish = publish.clone();
+            publish.pkid = *pkid;
+            publish.dup = true;
+            network
+                .write(Packet::Publish(publish, properties.clone()))
+                .await?;
+        }
+
+        let mut ping = time::interval(Duration::from_secs(PING_INTERVAL as u64));
+        loop {
+            tokio::select! {
+                packet = network.read() => match packet? {
+                    Packet::Publish(publish, properties) => {
+                        let pkid = publish.pkid;
+                        let qos = publish.qos;
+                        self.incoming(publish, properties).await?;
+
+                        if qos != QoS::AtMostOnce {
+                            let puback = PubAck {
+                                pkid,
+                                reason: PubAckReason::Success,
+                            };
+                            network.write(Packet::PubAck(puback, None)).await?;
+                        }
+                    }
+                    Packet::PubAck(puback, _) => {
+                        self.unacked.retain(|(pkid, ..)| *pkid != puback.pkid);
+                    }
+                    Packet::PingReq(_) => network.write(Packet::PingResp(PingResp)).await?,
+                    Packet::PingResp(_) => {}
+                    Packet::Disconnect(..) => return Ok(()),
+                    packet => warn!(peer_id = self.peer_id, "Unexpected packet from node {:?}", packet),
+                },
+                notification = self.link_rx.next(), if self.unacked.len() < MAX_INFLIGHT => {
+                    match notification? {
+                        // Publishes of clients on cluster topics would be taken as sessions
+                        Some(Notification::ReplicaData(forward)) if forward.publish.topic.starts_with(CLUSTER_PREFIX.as_bytes()) => {}
+                        Some(Notification::ReplicaData(forward)) => {
+                            self.outgoing(&mut network, forward.publish, forward.properties).await?;
+                        }
+                        Some(Notification::ClientConnected(client_id)) => {
+                            let publish = Publish::new(Bytes::from(CONNECTED_TOPIC), Bytes::from(client_id), false);
+                            self.outgoing(&mut network, publish, None).await?;
+                        }
+                        Some(Notification::SessionTakeover(session)) => {
+                            let payload = serde_json::to_vec(&session).unwrap_or_default();
+                            let publish = Publish::new(Bytes::from(SESSION_TOPIC), Bytes::from(payload), false);
+                            self.outgoing(&mut network, publish, None).await?;
+                        }
+                        Some(Notification::Unschedule) => self.link_rx.wake().await?,
+                        // Acks of publishes from the other node
+                        Some(Notification::DeviceAck(_)) | None => {}
+                        Some(notification) => warn!(peer_id = self.peer_id, "Unexpected notification {:?}", notification),
+                    }
+                }
+                _ = ping.tick() => network.write(Packet::PingReq(PingReq)).await?,
+            }
+        }
+    }
+
+    /// Handles publish from the other node. Publishes on cluster topics carry sessions of clients
+    /// and the rest are appended to commitlogs
+    async fn incoming(
+        &mut self,
+        mut publish: Publish,
+        properties: Option<PublishProperties>,
+    ) -> Result<(), Error> {
+        match &publish.topic[..] {
+            topic if topic == CONNECTED_TOPIC.as_bytes() => {
+                match String::from_utf8(publish.payload.to_vec()) {
+                    Ok(client_id) => self.link_tx.client_connected(client_id).await?,
+                    Err(e) => warn!(peer_id = self.peer_id, "Invalid client id from node - {e}"),
+                }
+            }
+            topic if topic == SESSION_TOPIC.as_bytes() => {
+                match serde_json::from_slice(&publish.payload) {
+                    Ok(session) => self.link_tx.session_takeover(session).await?,
+                    Err(e) => warn!(peer_id = self.peer_id, "Invalid session from node - {e}"),
+                }
+            }
+            _ => {
+                publish.pkid = 0;
+                publish.dup = false;
+                publish.qos = QoS::AtMostOnce;
+                self.link_tx
+                    .send(Packet::Publish(publish, properties))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends a publish to the other node, which is kept until the other node acks it
+    async fn outgoing(
+        &mut self,
+        network: &mut Network<V5>,
+        mut publish: Publish,
+        properties: Option<PublishProperties>,
+    ) -> Result<(), Error> {
+        publish.qos = QoS::AtLeastOnce;
+        publish.pkid = self.next_pkid();
+
+        self.unacked
+            .push_back((publish.pkid, publish.clone(), properties.clone()));
+        network.write(Packet::Publish(publish, properties)).await?;
+        Ok(())
+    }
+
+    fn next_pkid(&mut self) -> u16 {
+        self.last_pkid = self.last_pkid.checked_add(1).unwrap_or(1);
+        self.last_pkid
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::DisconnectReasonCode;
+    use crate::router::{Ack, Router};
+    use crate::RouterConfig;
+    use std::collections::HashSet;
+    use std::thread;
+    use std::time::Instant;
+
+    /// Node of a cluster with its own router. Connections of the node are closed when it's
+    /// dropped
+    struct Node {
+        router_tx: Sender<(ConnectionId, Event)>,
+        addr: String,
+        shutdown: Option<Sender<()>>,
+        thread: Option<thread::JoinHandle<()>>,
+    }
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            self.shutdown.take();
+            if let Some(thread) = self.thread.take() {
+                thread.join().unwrap();
+            }
+        }
+    }
+
+    /// Starts a node listening on a free port, which connects to the nodes before it. Nodes
+    /// after it connect to it
+    fn node(node_id: NodeId, seniors: &[Node]) -> Node {
+        let config = RouterConfig {
+            max_connections: 10,
+            max_outgoing_packet_count: 200,
+            max_segment_size: 1024 * 1024,
+            max_segment_count: 10,
+            ..Default::default()
+        };
+
+        let router_tx = Router::new(node_id, config).spawn();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let cluster = Cluster::new(
+            ClusterSettings {
+                node_id,
+                listen: addr.clone(),
+                seniors: seniors
+                    .iter()
+                    .enumerate()
+                    .map(|(id, node)| (id, node.addr.clone()))
+                    .collect(),
+                juniors: (node_id + 1..10).collect(),
+                secret: "secret".to_owned(),
+            },
+            router_tx.clone(),
+        );
+
+        // Replicas spawned by the cluster are dropped along with the runtime
+        let (shutdown, shutdown_rx) = flume::bounded::<()>(1);
+        let thread = thread::spawn(move || {
+            runtime.block_on(async {
+                tokio::select! {
+                    _ = cluster.run(listener) => {},
+                    _ = shutdown_rx.recv_async() => {},
+                }
+            });
+        });
+
+        Node {
+            router_tx,
+            addr,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        }
+    }
+
+    /// Starts nodes of a cluster and waits till every node receives publishes of every other
+    /// node through the mesh
+    fn cluster(count: usize) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        for node_id in 0..count {
+            let node = node(node_id, &nodes);
+            nodes.push(node);
+        }
+
+        let mut probes: Vec<(LinkTx, LinkRx)> = nodes
+            .iter()
+            .enumerate()
+            .map(|(node_id, node)| {
+                let client_id = format!("probe-{node_id}");
+                let (mut tx, rx, _) = LinkBuilder::new(&client_id, node.router_tx.clone())
+                    .dynamic_filters(true)
+                    .build()
+                    .unwrap();
+                tx.subscribe("probe/+").unwrap();
+                (tx, rx)
+            })
+            .collect();
+
+        let mut pending: HashSet<(usize, usize)> = (0..count)
+            .flat_map(|from| (0..count).map(move |to| (from, to)))
+            .filter(|(from, to)| from != to)
+            .collect();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !pending.is_empty() {
+            assert!(Instant::now() < deadline, "Mesh not connected {pending:?}");
+            for (node_id, (tx, _)) in probes.iter_mut().enumerate() {
+                tx.publish(format!("probe/{node_id}"), "").unwrap();
+            }
+
+            for (to, (_, rx)) in probes.iter_mut().enumerate() {
+                let timeout = Instant::now() + Duration::from_millis(100);
+                while let Ok(notification) = rx.recv_deadline(timeout) {
+                    let Some(Notification::Forward(forward)) = notification else {
+                        continue;
+                    };
+
+                    let from = std::str::from_utf8(&forward.publish.topic[6..]).unwrap();
+                    pending.remove(&(from.parse().unwrap(), to));
+                }
+            }
+        }
+
+        nodes
+    }
+
+    /// Builds link of a remote client with persistent session
+    fn client(client_id: &str, router_tx: &Sender<(ConnectionId, Event)>) -> (LinkTx, LinkRx) {
+        let (tx, rx, ack) = session(client_id, router_tx);
+        assert!(!ack.session_present, "{client_id} resumed a session");
+        (tx, rx)
+    }
+
+    /// Builds link of a remote client with persistent session and returns its connack
+    fn session(
+        client_id: &str,
+        router_tx: &Sender<(ConnectionId, Event)>,
+    ) -> (LinkTx, LinkRx, ConnAck) {
+        let (tx, rx, notification) = LinkBuilder::new(client_id, router_tx.clone())
+            .clean_session(false)
+            .restricted(true)
+            .dynamic_filters(true)
+            .build()
+            .unwrap();
+
+        match notification {
+            Notification::DeviceAck(Ack::ConnAck(_, ack, _)) => (tx, rx, ack),
+            notification => panic!("Expected connack, got {notification:?}"),
+        }
+    }
+
+    fn next(rx: &mut LinkRx) -> Notification {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match rx.recv_deadline(deadline).unwrap() {
+                Some(Notification::DeviceAck(_)) | None => continue,
+                Some(notification) => return notification,
+            }
+        }
+    }
+
+    fn suback(rx: &mut LinkRx) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(Notification::DeviceAck(Ack::SubAck(_))) =
+                rx.recv_deadline(deadline).unwrap()
+            {
+                return;
+            }
+        }
+    }
+
+    fn payload(rx: &mut LinkRx) -> Bytes {
+        match next(rx) {
+            Notification::Forward(forward) => forward.publish.payload,
+            notification => panic!("Expected forward, got {notification:?}"),
+        }
+    }
+
+    #[test]
+    fn nodes_without_secret_or_membership_are_rejected() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let (router_tx, _router_rx) = flume::bounded(1);
+            let settings = ClusterSettings {
+                node_id: 0,
+                listen: String::new(),
+                seniors: vec![],
+                juniors: vec![1],
+                secret: "secret".to_owned(),
+            };
+            let cluster = Cluster::new(settings, router_tx);
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let nodes = [
+                (1, "secret", ConnectReturnCode::Success),
+                (1, "wrong", ConnectReturnCode::BadUserNamePassword),
+                (2, "secret", ConnectReturnCode::NotAuthorized),
+            ];
+
+            for (node_id, secret, expected) in nodes {
+                let node = async {
+                    let stream = TcpStream::connect(addr).await.unwrap();
+                    let mut network =
+                        Network::new(Box::new(stream), MAX_PACKET_SIZE, MAX_INFLIGHT, V5);
+                    let connect = Connect {
+                        keep_alive: PING_INTERVAL,
+                        client_id: node_id.to_string(),
+                        clean_session: true,
+                    };
+                    let login = Login {
+                        username: String::new(),
+                        password: secret.to_owned(),
+                    };
+                    let packet = Packet::Connect(connect, None, None, None, Some(login));
+                    network.write(packet).await.unwrap();
+
+                    match network.read().await.unwrap() {
+                        Packet::ConnAck(ack, _) => ack.code,
+                        packet => panic!("Expected connack, got {packet:?}"),
+                    }
+                };
+
+                let accept = async {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    cluster.handshake(stream).await.map(|(peer_id, _)| peer_id)
+                };
+
+                let (code, accepted) = tokio::join!(node, accept);
+                assert_eq!(code, expected);
+                match expected {
+                    ConnectReturnCode::Success => assert_eq!(accepted.unwrap(), node_id),
+                    _ => assert!(accepted.is_err()),
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn publishes_are_replicated_to_all_nodes() {
+        let nodes = cluster(3);
+        let mut links = Vec::new();
+        for node in nodes.iter() {
+            let (mut tx, mut rx, _) = LinkBuilder::new("client", node.router_tx.clone())
+                .dynamic_filters(true)
+                .build()
+                .unwrap();
+            tx.subscribe("hello/+").unwrap();
+            suback(&mut rx);
+            links.push((tx, rx));
+        }
+
+        links[1].0.publish("hello/world", "from 1").unwrap();
+        for (node_id, (_, rx)) in links.iter_mut().enumerate() {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let forward = loop {
+                match rx.recv_deadline(deadline).unwrap() {
+                    Some(Notification::Forward(forward)) => break forward,
+                    _ => continue,
+                }
+            };
+
+            assert_eq!(forward.publish.payload, "from 1", "node {node_id}");
+            assert_eq!(forward.publish.topic, "hello/world");
+        }
+
+        // Publishes aren't replicated back, so each node receives it only once
+        for (_, rx) in links.iter_mut() {
+            let deadline = Instant::now() + Duration::from_millis(500);
+            while let Ok(notification) = rx.recv_deadline(deadline) {
+                assert!(!matches!(notification, Some(Notification::Forward(_))));
+            }
+        }
+    }
+
+    #[test]
+    fn sessions_are_taken_over_by_other_nodes() {
+        let nodes = cluster(2);
+        let (node0, node1) = (&nodes[0].router_tx, &nodes[1].router_tx);
+
+        let (mut publisher, _publisher_rx) = client("publisher", node0);
+        let (mut watcher, mut watcher_rx) = client("watcher", node1);
+        watcher.subscribe("devices/+").unwrap();
+        suback(&mut watcher_rx);
+
+        // Client which moves from node 0 to node 1 while it's disconnected
+        let (mut device, mut device_rx) = client("device", node0);
+        device.subscribe("devices/+").unwrap();
+        suback(&mut device_rx);
+        node0.send((device_rx.id(), Event::Disconnect)).unwrap();
+
+        // Node 1 knows that the device connected to node 0 once it receives publishes which
+        // were made after that, as both are sent in order
+        publisher.publish("devices/1", "m1").unwrap();
+        assert_eq!(payload(&mut watcher_rx), "m1");
+
+        // Message which wasn't delivered on node 0 is delivered by node 1
+        let (_device, mut device_rx, ack) = session("device", node1);
+        assert!(ack.session_present);
+        assert_eq!(payload(&mut device_rx), "m1");
+
+        publisher.publish("devices/2", "m2").unwrap();
+        assert_eq!(payload(&mut device_rx), "m2");
+        assert_eq!(payload(&mut watcher_rx), "m2");
+
+        // Client which is still connected to node 0 is disconnected
+        let (_sensor, mut sensor_rx) = client("sensor", node0);
+        publisher.publish("devices/3", "m3").unwrap();
+        assert_eq!(payload(&mut watcher_rx), "m3");
+
+        let (_sensor_1, _sensor_1_rx, ack) = session("sensor", node1);
+        assert!(ack.session_present);
+        match next(&mut sensor_rx) {
+            Notification::Disconnect(disconnect, _) => {
+                assert_eq!(
+                    disconnect.reason_code,
+                    DisconnectReasonCode::SessionTakenOver
+                )
+            }
+            notification => panic!("Expected disconnect, got {notification:?}"),
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use std::fmt;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+/// Identity of the client whose action is being authorized
+#[derive(Debug, Clone, Copy)]
+pub struct ClientInfo<'a> {
+    /// Client id, without the tenant prefix
+    pub client_id: &'a str,
+    /// Username in the connect packet, if any
+    pub username: Option<&'a str>,
+    pub tenant_id: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Publish,
+    Subscribe,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Allow,
+    #[default]
+    Deny,
+}
+
+/// Called with the topic of every publish and the filter of every subscription of remote
+/// clients. Runs on the router thread, so it has to return quickly
+pub type AuthorizationHandler = Arc<dyn Fn(&ClientInfo, Action, &str) -> bool + Send + Sync>;
+
+/// Rules which are checked in order, the first one which matches decides the permission
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AclConfig {
+    /// Permission when no rule matches
+    #[serde(default)]
+    pub default: Permission,
+    #[serde(default)]
+    pub rules: Vec<AclRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AclRule {
+    /// Rule only applies to client with this username
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Rule only applies to client with this client id
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// Rule only applies to clients of this tenant
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Topic filter, wildcards allowed. `%u` and `%c` are replaced with username and client id.
+    /// With `validate-tenant-prefix`, topics of tenants start with their `/tenants/<id>/` prefix
+    pub topic: String,
+    /// Actions the rule applies to, all of them when empty
+    #[serde(default)]
+    pub actions: Vec<Action>,
+    #[serde(default = "allow")]
+    pub permission: Permission,
+}
+
+fn allow() -> Permission {
+    Permission::Allow
+}
+
+impl AclConfig {
+    pub fn permission(&self, client: &ClientInfo, action: Action, topic: &str) -> Permission {
+        self.rules
+            .iter()
+            .find_map(|rule| rule.permission(client, action, topic))
+            .unwrap_or(self.default)
+    }
+}
+
+impl AclRule {
+    /// Permission of this rule, if it applies to the action
+    fn permission(&self, client: &ClientInfo, action: Action, topic: &str) -> Option<Permission> {
+        let applies = self.username.as_deref().map_or(true, |u| client.username == Some(u))
+            && self.client_id.as_deref().map_or(true, |c| client.client_id == c)
+            && self.tenant.as_deref().map_or(true, |t| client.tenant_id == Some(t))
+            && (self.actions.is_empty() || self.actions.contains(&action));
+
+        if !applies {
+            return None;
+        }
+
+        let filter = self.filter(client)?;
+        let matched = match (action, self.permission) {
+            // Subscription is denied if it can receive any of the denied topics
+            (Action::Subscribe, Permission::Deny) => overlaps(&filter, topic),
+            _ => covers(&filter, topic),
+        };
+
+        matched.then_some(self.permission)
+    }
+
+    /// Topic filter with substitutions. Rule doesn't apply when a substitution is missing or
+    /// would inject wildcards or levels into the filter
+    fn filter(&self, client: &ClientInfo) -> Option<String> {
+        let mut filter = self.topic.clone();
+        for (pattern, value) in [("%u", client.username), ("%c", Some(client.client_id))] {
+            if !filter.contains(pattern) {
+                continue;
+            }
+
+            let value = value.filter(|v| !v.is_empty() && !v.contains(['+', '#', '/']))?;
+            filter = filter.replace(pattern, value);
+        }
+
+        Some(filter)
+    }
+}
+
+/// Router side of authorization, which combines ACL and authorization handler. Both of them
+/// have to allow an action when configured
+#[derive(Clone, Default)]
+pub(crate) struct Authorizer {
+    acl: Option<AclConfig>,
+    handler: Option<AuthorizationHandler>,
+}
+
+impl Authorizer {
+    pub fn new(acl: Option<AclConfig>, handler: Option<AuthorizationHandler>) -> Authorizer {
+        Authorizer { acl, handler }
+    }
+
+    pub fn authorize(&self, client: &ClientInfo, action: Action, topic: &str) -> bool {
+        let allowed = self
+            .acl
+            .as_ref()
+            .map_or(true, |acl| acl.permission(client, action, topic) == Permission::Allow);
+
+        allowed && self.handler.as_ref().map_or(true, |f| f(client, action, topic))
+    }
+}
+
+impl fmt::Debug for Authorizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Authorizer")
+            .field("acl", &self.acl)
+            .field("handler", &self.handler.is_some())
+            .finish()
+    }
+}
+
+/// Checks if everything `filter` matches is also matched by `rule`. Works for topics too, as
+/// they are filters without wildcards
+fn covers(rule: &str, filter: &str) -> bool {
+    // Wildcards in first level don't match topics starting with `$`
+    if filter.starts_with('$') && rule.starts_with(['+', '#']) {
+        return false;
+    }
+
+    let mut rule = rule.split('/');
+    let mut filter = filter.split('/');
+    loop {
+        match (rule.next(), filter.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(f)) if f != "#" => continue,
+            (Some(r), Some(f)) if r == f => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Checks if there is any topic which is matched by both the filters
+fn overlaps(a: &str, b: &str) -> bool {
+    let mut a = a.split('/');
+    let mut b = b.split('/');
+    loop {
+        match (a.next(), b.next()) {
+            (Some("#"), _) | (_, Some("#")) => return true,
+            (Some(x), Some(y)) if x == "+" || y == "+" || x == y => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn client<'a>(client_id: &'a str, username: Option<&'a str>) -> ClientInfo<'a> {
+        ClientInfo {
+            client_id,
+            username,
+            tenant_id: None,
+        }
+    }
+
+    fn rule(topic: &str, actions: Vec<Action>, permission: Permission) -> AclRule {
+        AclRule {
+            username: None,
+            client_id: None,
+            tenant: None,
+            topic: topic.to_owned(),
+            actions,
+            permission,
+        }
+    }
+
+    #[test]
+    fn filter_coverage() {
+        assert!(covers("a/#", "a"));
+        assert!(covers("a/#", "a/+/c"));
+        assert!(covers("a/+", "a/+"));
+        assert!(!covers("a/+", "a/#"));
+        assert!(!covers("a/b", "a/+"));
+        assert!(!covers("#", "$SYS/a"));
+
+        assert!(overlaps("a/b/#", "a/+/c"));
+        assert!(overlaps("#", "x"));
+        assert!(!overlaps("a/b", "a/c"));
+        assert!(!overlaps("a/+", "a/b/c"));
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let acl = AclConfig {
+            default: Permission::Deny,
+            rules: vec![
+                rule("devices/secret/#", vec![], Permission::Deny),
+                rule("devices/%c/#", vec![], Permission::Allow),
+                rule("users/%u/inbox", vec![Action::Subscribe], Permission::Allow),
+                rule("devices/#", vec![Action::Subscribe], Permission::Allow),
+            ],
+        };
+
+        let c = client("d1", Some("alice"));
+        let check = |action, topic| acl.permission(&c, action, topic);
+
+        assert_eq!(check(Action::Publish, "devices/d1/temp"), Permission::Allow);
+        assert_eq!(check(Action::Publish, "devices/d2/temp"), Permission::Deny);
+        assert_eq!(check(Action::Subscribe, "devices/d2/temp"), Permission::Allow);
+        assert_eq!(check(Action::Subscribe, "devices/+/temp"), Permission::Deny);
+        assert_eq!(check(Action::Subscribe, "devices/#"), Permission::Deny);
+        assert_eq!(check(Action::Subscribe, "users/alice/inbox"), Permission::Allow);
+        assert_eq!(check(Action::Publish, "users/alice/inbox"), Permission::Deny);
+
+        // Substitutions which would widen the filter don't apply
+        let c = client("+", None);
+        assert_eq!(
+            acl.permission(&c, Action::Publish, "devices/d1/temp"),
+            Permission::Deny
+        );
+    }
+
+    #[test]
+    fn handler_and_acl_must_both_allow() {
+        let acl = AclConfig {
+            default: Permission::Allow,
+            rules: vec![],
+        };
+        let handler: AuthorizationHandler = Arc::new(|_, action, _| action == Action::Subscribe);
+        let authorizer = Authorizer::new(Some(acl), Some(handler));
+
+        let c = client("d1", None);
+        assert!(authorizer.authorize(&c, Action::Subscribe, "a"));
+        assert!(!authorizer.authorize(&c, Action::Publish, "a"));
+        assert!(Authorizer::default().authorize(&c, Action::Publish, "a"));
+    }
+}
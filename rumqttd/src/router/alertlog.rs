@@ -8,6 +8,7 @@ pub mod alert {
     pub enum AlertKind {
         CursorJump { filter: String, lost: usize },
         BadPublish { topic: String },
+        OversizedPublish { filter: String, size: usize },
     }
 
     impl AlertKind {
@@ -15,6 +16,7 @@ pub fn name(&self) -> String {
             match self {
                 Self::CursorJump { .. } => "cursor_jump".to_owned(),
                 Self::BadPublish { .. } => "bad_publish".to_owned(),
+                Self::OversizedPublish { .. } => "oversized_publish".to_owned(),
             }
         }
 
@@ -22,6 +24,9 @@ pub fn description(&self) -> String {
             match self {
                 Self::CursorJump { filter, lost, .. } => format!("Filter: {filter}, Lost: {lost}"),
                 Self::BadPublish { topic, .. } => format!("Topic: {topic}"),
+                Self::OversizedPublish { filter, size } => {
+                    format!("Filter: {filter}, Size: {size}")
+                }
             }
         }
     }
@@ -51,6 +56,23 @@ pub fn cursorjump(client_id: &str, filter: &str, lost: usize) -> Alert {
         }
     }
 
+    pub fn oversizedpublish(client_id: &str, filter: &str, size: usize) -> Alert {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+
+        Alert {
+            timestamp,
+            sequence: 0,
+            client_id: client_id.to_owned(),
+            kind: AlertKind::OversizedPublish {
+                filter: filter.to_owned(),
+                size,
+            },
+        }
+    }
+
     pub fn _badpublish(client_id: &str, topic: &str) -> Alert {
         let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
@@ -1,10 +1,11 @@
 use slab::Slab;
 
 use crate::protocol::LastWillProperties;
-use crate::Filter;
 use crate::{protocol::LastWill, Topic};
+use crate::{Capabilities, Filter};
 use std::collections::{HashMap, HashSet};
 
+use super::acl::ClientInfo;
 use super::ConnectionEvents;
 
 /// Used to register a new connection with the router
@@ -15,10 +16,26 @@ pub struct Connection {
     pub client_id: String,
     /// Id of client's organisation/tenant and the prefix associated with tenant's MQTT topic
     pub tenant_prefix: Option<String>,
+    pub(crate) tenant_id: Option<String>,
+    /// Username the client connected with
+    pub(crate) username: Option<String>,
+    /// Publishes and subscriptions are checked against the authorizer of the router. Local
+    /// links are trusted and aren't restricted
+    pub(crate) restricted: bool,
+    /// Link of the cluster to another node. Publishes of clients are replicated to it, while
+    /// its own publishes aren't replicated again
+    pub(crate) replica: bool,
+    /// Features of the broker available to this connection
+    pub(crate) capabilities: Capabilities,
+    /// Maximum number of subscriptions of this connection
+    pub(crate) max_subscriptions: Option<usize>,
     /// Dynamically create subscription filters incase they didn't exist during a publish
     pub dynamic_filters: bool,
     /// Clean session
     pub clean: bool,
+    /// Seconds for which session is retained after disconnection. `None` when client didn't
+    /// specify one, e.g. MQTT 3.1.1 clients
+    pub(crate) session_expiry_interval: Option<u32>,
     /// Subscriptions
     pub subscriptions: HashSet<Filter>,
     /// Last will of this connection
@@ -45,10 +62,10 @@ pub fn new(
     ) -> Connection {
         // Change client id to -> tenant_id.client_id and derive topic path prefix
         // to validate topics
-        let (client_id, tenant_prefix) = match tenant_id {
+        let (client_id, tenant_prefix) = match &tenant_id {
             Some(tenant_id) => {
-                let tenant_prefix = Some("/tenants/".to_owned() + &tenant_id + "/");
-                let client_id = tenant_id + "." + &client_id;
+                let tenant_prefix = Some("/tenants/".to_owned() + tenant_id + "/");
+                let client_id = tenant_id.to_owned() + "." + &client_id;
                 (client_id, tenant_prefix)
             }
             None => (client_id, None),
@@ -57,8 +74,15 @@ pub fn new(
         Connection {
             client_id,
             tenant_prefix,
+            tenant_id,
+            username: None,
+            restricted: false,
+            replica: false,
+            capabilities: Capabilities::default(),
+            max_subscriptions: None,
             dynamic_filters,
             clean,
+            session_expiry_interval: None,
             subscriptions: HashSet::default(),
             last_will: None,
             last_will_properties: None,
@@ -77,6 +101,50 @@ pub fn topic_alias_max(&mut self, max: u16) -> &mut Connection {
         self
     }
 
+    pub fn session_expiry_interval(&mut self, interval: Option<u32>) -> &mut Connection {
+        self.session_expiry_interval = interval;
+        self
+    }
+
+    pub fn username(&mut self, username: Option<String>) -> &mut Connection {
+        self.username = username;
+        self
+    }
+
+    pub fn restricted(&mut self, restricted: bool) -> &mut Connection {
+        self.restricted = restricted;
+        self
+    }
+
+    pub(crate) fn replica(&mut self, replica: bool) -> &mut Connection {
+        self.replica = replica;
+        self
+    }
+
+    pub fn capabilities(&mut self, capabilities: Capabilities) -> &mut Connection {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn max_subscriptions(&mut self, max: Option<usize>) -> &mut Connection {
+        self.max_subscriptions = max;
+        self
+    }
+
+    /// Identity used to authorize actions of this connection
+    pub(crate) fn client_info(&self) -> ClientInfo<'_> {
+        let client_id = match &self.tenant_id {
+            Some(tenant_id) => &self.client_id[tenant_id.len() + 1..],
+            None => &self.client_id,
+        };
+
+        ClientInfo {
+            client_id,
+            username: self.username.as_deref(),
+            tenant_id: self.tenant_id.as_deref(),
+        }
+    }
+
     pub fn last_will(
         &mut self,
         will: Option<LastWill>,
@@ -1,34 +1,191 @@
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::segments::Syncer;
+use crate::Cursor;
 
 use super::{
+    kvlog::KvLog,
     scheduler::{PauseReason, Tracker},
     ConnectionEvents,
 };
 
-pub struct Graveyard {
+/// Storage for the state of disconnected connections
+pub trait SessionStore: Send {
+    /// Save the state of connection with client id `id`, replacing previously saved one
+    fn save(&mut self, id: String, state: SavedState);
+    /// Saved state of connection with client id `id`, which is left in the store
+    fn get(&self, id: &str) -> Option<&SavedState>;
+    /// Remove and return saved state of connection with client id `id`
+    fn retrieve(&mut self, id: &str) -> Option<SavedState>;
+    /// Keep only the saved states for which `f` returns true
+    fn retain(&mut self, f: &mut dyn FnMut(&str, &SavedState) -> bool);
+}
+
+/// Keeps saved states in memory, they are lost when the broker stops
+#[derive(Default)]
+pub struct MemorySessionStore {
     connections: HashMap<String, SavedState>,
 }
 
-impl Graveyard {
-    pub fn new() -> Graveyard {
-        Graveyard {
-            connections: HashMap::new(),
+impl SessionStore for MemorySessionStore {
+    fn save(&mut self, id: String, state: SavedState) {
+        self.connections.insert(id, state);
+    }
+
+    fn get(&self, id: &str) -> Option<&SavedState> {
+        self.connections.get(id)
+    }
+
+    fn retrieve(&mut self, id: &str) -> Option<SavedState> {
+        self.connections.remove(id)
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(&str, &SavedState) -> bool) {
+        self.connections.retain(|id, state| f(id, state));
+    }
+}
+
+/// Keeps saved states in memory and persists the ones with a session to a file, so that
+/// persistent sessions survive broker restarts
+pub struct FileSessionStore {
+    connections: HashMap<String, SavedState>,
+    log: KvLog,
+}
+
+impl FileSessionStore {
+    pub fn new<P: AsRef<Path>>(path: P, syncer: Syncer) -> io::Result<FileSessionStore> {
+        let mut log = KvLog::open(path, syncer)?;
+
+        let mut connections = HashMap::new();
+        for (id, state) in log.load()? {
+            match serde_json::from_slice(&state) {
+                Ok(state) => {
+                    connections.insert(id, state);
+                }
+                Err(e) => warn!(error = ?e, "Ignoring unreadable session of {id}"),
+            }
+        }
+
+        info!(
+            "Loaded {} sessions from {:?}",
+            connections.len(),
+            log.path()
+        );
+        Ok(FileSessionStore { connections, log })
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn save(&mut self, id: String, state: SavedState) {
+        let persisted = match &state.session_state {
+            Some(_) => serde_json::to_vec(&state)
+                .map_err(io::Error::from)
+                .and_then(|state| self.log.insert(&id, &state)),
+            // Only metrics remain, which aren't worth persisting. Drop previous session, if any
+            None => match self.connections.get(&id) {
+                Some(saved) if saved.session_state.is_some() => self.log.remove(&id),
+                _ => Ok(()),
+            },
+        };
+
+        if let Err(e) = persisted {
+            error!(error = ?e, "Failed to persist session of {id}");
         }
+
+        self.connections.insert(id, state);
+    }
+
+    fn get(&self, id: &str) -> Option<&SavedState> {
+        self.connections.get(id)
+    }
+
+    fn retrieve(&mut self, id: &str) -> Option<SavedState> {
+        let state = self.connections.remove(id)?;
+        if state.session_state.is_some() {
+            if let Err(e) = self.log.remove(id) {
+                error!(error = ?e, "Failed to remove persisted session of {id}");
+            }
+        }
+
+        Some(state)
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(&str, &SavedState) -> bool) {
+        let log = &mut self.log;
+        self.connections.retain(|id, state| {
+            if f(id, state) {
+                return true;
+            }
+
+            if state.session_state.is_some() {
+                if let Err(e) = log.remove(id) {
+                    error!(error = ?e, "Failed to remove persisted session of {id}");
+                }
+            }
+
+            false
+        });
+    }
+}
+
+pub struct Graveyard {
+    store: Box<dyn SessionStore>,
+}
+
+impl Graveyard {
+    pub fn new(store: Box<dyn SessionStore>) -> Graveyard {
+        Graveyard { store }
     }
 
     /// Add a new connection.
     /// Return tracker of previous connection if connection id already exists
     pub fn retrieve(&mut self, id: &str) -> Option<SavedState> {
-        self.connections.remove(id)
+        self.store
+            .retrieve(id)
+            .filter(|state| !state.is_expired(SystemTime::now()))
     }
 
-    /// Save connection tracker
+    /// Saved state of a connection, without removing it
+    pub fn peek(&self, id: &str) -> Option<&SavedState> {
+        self.store
+            .get(id)
+            .filter(|state| !state.is_expired(SystemTime::now()))
+    }
+
+    /// Remove saved state of a connection. Returns false if there wasn't one
+    pub fn remove(&mut self, id: &str) -> bool {
+        self.store.retrieve(id).is_some()
+    }
+
+    /// Remove all the saved states which have expired
+    pub fn purge_expired(&mut self) -> usize {
+        let now = SystemTime::now();
+        let mut purged = 0;
+        self.store.retain(&mut |_, state| {
+            let expired = state.is_expired(now);
+            purged += expired as usize;
+            !expired
+        });
+
+        purged
+    }
+
+    /// Save connection tracker. State is kept for `expiry_interval` seconds, or forever if it
+    /// is `None`
     pub fn save_state(
         &mut self,
         mut tracker: Tracker,
         subscriptions: HashSet<String>,
         metrics: ConnectionEvents,
         unacked_pubrels: VecDeque<u16>,
+        inflight: Vec<InflightPublish>,
+        expiry_interval: Option<u32>,
     ) {
         tracker.pause(PauseReason::Busy);
         let id = tracker.id.clone();
@@ -37,39 +194,167 @@ pub fn save_state(
             tracker,
             subscriptions,
             unacked_pubrels,
+            inflight,
         };
 
-        self.connections.insert(
+        self.store.save(
             id,
             SavedState {
                 session_state: Some(session_state),
                 metrics,
+                expires_at: expires_at(expiry_interval),
             },
         );
     }
 
     /// Save only metrics for connection
-    pub fn save_metrics(&mut self, id: String, metrics: ConnectionEvents) {
-        self.connections.insert(
+    pub fn save_metrics(
+        &mut self,
+        id: String,
+        metrics: ConnectionEvents,
+        expiry_interval: Option<u32>,
+    ) {
+        self.store.save(
             id,
             SavedState {
                 session_state: None,
                 metrics,
+                expires_at: expires_at(expiry_interval),
             },
         );
     }
 }
 
-#[derive(Debug)]
+/// `u32::MAX` is treated as no expiry as per MQTT 5
+fn expires_at(expiry_interval: Option<u32>) -> Option<SystemTime> {
+    match expiry_interval {
+        Some(interval) if interval != u32::MAX => {
+            SystemTime::now().checked_add(Duration::from_secs(interval.into()))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SavedState {
     pub session_state: Option<SessionState>,
     pub metrics: ConnectionEvents,
+    /// Wall clock time after which state is discarded, as it has to hold across restarts
+    #[serde(default)]
+    pub expires_at: Option<SystemTime>,
+}
+
+impl SavedState {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SessionState {
     pub tracker: Tracker,
     pub subscriptions: HashSet<String>,
     // used for pubrel in qos2
     pub unacked_pubrels: VecDeque<u16>,
+    /// Publishes which weren't acknowledged, in the order they were sent
+    #[serde(default)]
+    pub inflight: Vec<InflightPublish>,
+}
+
+/// QoS 1 or 2 publish which is resent with its packet id when the session is resumed. Publish
+/// itself is read from the commitlog again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InflightPublish {
+    pub pkid: u16,
+    /// Filter of the subscription the publish was sent for
+    pub filter: String,
+    pub cursor: Cursor,
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashSet, VecDeque};
+    use std::time::Duration;
+
+    use super::{FileSessionStore, Graveyard, InflightPublish, MemorySessionStore};
+    use crate::router::scheduler::Tracker;
+    use crate::router::ConnectionEvents;
+    use crate::segments::Syncer;
+
+    #[test]
+    fn file_store_restores_sessions_only() {
+        let path = std::env::temp_dir().join("rumqttd-file-session-store");
+        let _ = std::fs::remove_file(&path);
+
+        let store = FileSessionStore::new(&path, Syncer::spawn(Duration::ZERO)).unwrap();
+        let mut graveyard = Graveyard::new(Box::new(store));
+        let subscriptions = HashSet::from(["a/+".to_owned()]);
+        let inflight = InflightPublish {
+            pkid: 3,
+            filter: "a/+".to_owned(),
+            cursor: (0, 7),
+        };
+        graveyard.save_state(
+            Tracker::new("persistent".to_owned()),
+            subscriptions.clone(),
+            ConnectionEvents::default(),
+            VecDeque::from([1, 2]),
+            vec![inflight.clone()],
+            None,
+        );
+        graveyard.save_state(
+            Tracker::new("reconnected".to_owned()),
+            HashSet::new(),
+            ConnectionEvents::default(),
+            VecDeque::new(),
+            Vec::new(),
+            None,
+        );
+        graveyard.save_metrics("clean".to_owned(), ConnectionEvents::default(), None);
+        assert!(graveyard.retrieve("reconnected").is_some());
+        drop(graveyard);
+
+        let store = FileSessionStore::new(&path, Syncer::spawn(Duration::ZERO)).unwrap();
+        let mut graveyard = Graveyard::new(Box::new(store));
+        assert!(graveyard.retrieve("clean").is_none());
+        assert!(graveyard.retrieve("reconnected").is_none());
+
+        let session = graveyard
+            .retrieve("persistent")
+            .unwrap()
+            .session_state
+            .unwrap();
+        assert_eq!(session.tracker.id, "persistent");
+        assert_eq!(session.subscriptions, subscriptions);
+        assert_eq!(session.unacked_pubrels, VecDeque::from([1, 2]));
+        assert_eq!(session.inflight, [inflight]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn expired_sessions_are_purged() {
+        let mut graveyard = Graveyard::new(Box::<MemorySessionStore>::default());
+        for (id, expiry) in [("expired", Some(0)), ("live", Some(100)), ("forever", None)] {
+            graveyard.save_state(
+                Tracker::new(id.to_owned()),
+                HashSet::new(),
+                ConnectionEvents::default(),
+                VecDeque::new(),
+                Vec::new(),
+                expiry,
+            );
+        }
+        graveyard.save_metrics(
+            "never".to_owned(),
+            ConnectionEvents::default(),
+            Some(u32::MAX),
+        );
+
+        assert_eq!(graveyard.purge_expired(), 1);
+        assert!(graveyard.retrieve("expired").is_none());
+        assert!(graveyard.retrieve("live").is_some());
+        assert!(graveyard.retrieve("forever").is_some());
+        assert!(graveyard.retrieve("never").is_some());
+    }
 }
@@ -1,7 +1,4 @@
-use std::{
-    collections::{HashMap, VecDeque},
-    sync::Arc,
-};
+use std::{collections::VecDeque, sync::Arc};
 
 use flume::{Receiver, Sender};
 use parking_lot::Mutex;
@@ -62,8 +59,14 @@ pub struct Outgoing {
     inflight_buffer: VecDeque<(u16, FilterIdx, Option<Cursor>)>,
     /// PubRels waiting for PubComp
     pub(crate) unacked_pubrels: VecDeque<u16>,
+    /// Inflight publishes of the resumed session which are sent before new publishes
+    retransmissions: VecDeque<Forward>,
     /// Last packet id
     last_pkid: u16,
+    /// Maximum QoS 1 and 2 publishes which can be inflight, as per client's receive maximum
+    max_inflight: usize,
+    /// Maximum size of a packet the client accepts
+    max_packet_size: Option<usize>,
     /// Metrics of outgoing messages of this connection
     pub(crate) meter: OutgoingMeter,
 }
@@ -85,8 +88,11 @@ pub(crate) fn new(client_id: String) -> (Self, Receiver<()>) {
             data_buffer: Arc::new(Mutex::new(data_buffer)),
             inflight_buffer,
             unacked_pubrels,
+            retransmissions: VecDeque::new(),
             handle,
             last_pkid: 0,
+            max_inflight: MAX_INFLIGHT,
+            max_packet_size: None,
             meter: Default::default(),
         };
 
@@ -98,8 +104,27 @@ pub(crate) fn buffer(&self) -> Arc<Mutex<VecDeque<Notification>>> {
         self.data_buffer.clone()
     }
 
+    /// Limits inflight publishes to the receive maximum of the client. Receive maximum is
+    /// capped by the broker's own limit and 0, which isn't allowed, is ignored
+    pub(crate) fn receive_maximum(&mut self, receive_maximum: Option<u16>) -> &mut Self {
+        self.max_inflight = match receive_maximum {
+            Some(max) if max > 0 => MAX_INFLIGHT.min(max as usize),
+            _ => MAX_INFLIGHT,
+        };
+        self
+    }
+
+    pub(crate) fn max_packet_size(&mut self, max_packet_size: Option<u32>) -> &mut Self {
+        self.max_packet_size = max_packet_size.map(|size| size as usize);
+        self
+    }
+
+    pub(crate) fn packet_size_limit(&self) -> Option<usize> {
+        self.max_packet_size
+    }
+
     pub fn free_slots(&self) -> usize {
-        MAX_INFLIGHT - self.inflight_buffer.len()
+        self.max_inflight.saturating_sub(self.inflight_buffer.len())
     }
 
     pub fn push_notification(&mut self, notification: Notification) -> usize {
@@ -152,10 +177,10 @@ pub fn push_forwards(
         let buffer_count = buffer.len();
         let inflight_count = self.inflight_buffer.len();
 
-        if inflight_count > MAX_INFLIGHT {
+        if inflight_count > self.max_inflight {
             warn!(
                 "More inflight publishes than max allowed, inflight count = {}, max allowed = {}",
-                inflight_count, MAX_INFLIGHT
+                inflight_count, self.max_inflight
             );
         }
 
@@ -201,51 +226,102 @@ pub fn register_pubcomp(&mut self, pkid: u16) -> Option<()> {
         Some(())
     }
 
-    // Here we are assuming that the first unique filter_idx we find while iterating will have the
-    // least corresponding cursor because of the way we insert into the inflight_buffer
-    pub fn retransmission_map(&self) -> HashMap<FilterIdx, Cursor> {
-        let mut o = HashMap::new();
-        for (_, filter_idx, cursor) in self.inflight_buffer.iter() {
-            // if cursor in None, it means it was a retained publish
-            if !o.contains_key(filter_idx) && cursor.is_some() {
-                o.insert(*filter_idx, cursor.unwrap());
-            }
+    /// Inflight publishes as packet id, filter and cursor, in the order they were sent.
+    /// Retained publishes don't have a cursor and aren't included
+    pub(crate) fn inflight(&self) -> impl Iterator<Item = (u16, FilterIdx, Cursor)> + '_ {
+        self.inflight_buffer
+            .iter()
+            .filter_map(|&(pkid, filter_idx, cursor)| Some((pkid, filter_idx, cursor?)))
+    }
+
+    /// Continues packet ids of a resumed session. `inflight` publishes, which carry their
+    /// original packet ids, are sent again before new publishes
+    pub(crate) fn resume(
+        &mut self,
+        unacked_pubrels: VecDeque<u16>,
+        inflight: Vec<(FilterIdx, Forward)>,
+    ) {
+        // Acks are in order, so the last inflight publish is the latest one
+        self.last_pkid = unacked_pubrels.back().copied().unwrap_or(0);
+        self.unacked_pubrels = unacked_pubrels;
+
+        for (filter_idx, forward) in inflight {
+            let pkid = forward.publish.pkid;
+            self.last_pkid = pkid;
+            self.inflight_buffer
+                .push_back((pkid, filter_idx, forward.cursor));
+            self.retransmissions.push_back(forward);
+        }
+
+        if self.last_pkid == MAX_PKID {
+            self.last_pkid = 0;
+        }
+    }
+
+    /// Sends publishes of the resumed session again
+    pub(crate) fn retransmit(&mut self) {
+        if self.retransmissions.is_empty() {
+            return;
+        }
+
+        let mut buffer = self.data_buffer.lock();
+        for forward in self.retransmissions.drain(..) {
+            self.meter.publish_count += 1;
+            buffer.push_back(Notification::Forward(forward));
         }
 
-        o
+        self.handle.try_send(()).ok();
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::protocol::{Publish, QoS};
+
+    #[test]
+    fn resumed_sessions_continue_packet_ids() {
+        let (mut outgoing, _) = Outgoing::new("resume-test".to_string());
+        let forward = |pkid, cursor| {
+            let mut publish = Publish::new("a", "b", false);
+            publish.qos = QoS::AtLeastOnce;
+            publish.pkid = pkid;
+            publish.dup = true;
+            Forward {
+                cursor: Some(cursor),
+                size: 0,
+                publish,
+                properties: None,
+            }
+        };
+
+        let inflight = vec![(0, forward(99, (0, 1))), (1, forward(100, (0, 0)))];
+        outgoing.resume(VecDeque::from([98]), inflight);
+        outgoing.retransmit();
+
+        let inflight: Vec<_> = outgoing.inflight().collect();
+        assert_eq!(inflight, [(99, 0, (0, 1)), (100, 1, (0, 0))]);
+        assert_eq!(outgoing.buffer().lock().len(), 2);
+
+        // Packet ids wrap around after the last one
+        let publishes = [forward(0, (0, 2)), forward(0, (0, 3))];
+        outgoing.push_forwards(publishes.into_iter(), 1, 0);
+        let pkids: Vec<u16> = outgoing.inflight().map(|(pkid, ..)| pkid).collect();
+        assert_eq!(pkids, [99, 100, 1, 2]);
+    }
 
     #[test]
-    fn retransmission_map_is_calculated_accurately() {
-        let (mut outgoing, _) = Outgoing::new("retransmission-test".to_string());
-        let mut result = HashMap::new();
-
-        result.insert(0, (0, 8));
-        result.insert(1, (0, 1));
-        result.insert(2, (1, 1));
-        result.insert(3, (1, 0));
-
-        let buf = vec![
-            (1, 0, Some((0, 8))),
-            (1, 0, Some((0, 10))),
-            (1, 1, Some((0, 1))),
-            (3, 1, Some((0, 4))),
-            (2, 2, Some((1, 1))),
-            (1, 2, Some((2, 6))),
-            (1, 2, Some((2, 1))),
-            (1, 3, Some((1, 0))),
-            (1, 3, Some((1, 1))),
-            (1, 3, Some((1, 3))),
-            (1, 3, Some((1, 3))),
-        ];
-
-        outgoing.inflight_buffer.extend(buf);
-        assert_eq!(outgoing.retransmission_map(), result);
+    fn free_slots_are_limited_by_receive_maximum() {
+        let (mut outgoing, _) = Outgoing::new("receive-maximum-test".to_string());
+        outgoing.receive_maximum(Some(10));
+        outgoing.inflight_buffer.push_back((1, 0, None));
+        assert_eq!(outgoing.free_slots(), 9);
+
+        outgoing.receive_maximum(Some(u16::MAX));
+        assert_eq!(outgoing.free_slots(), MAX_INFLIGHT - 1);
+
+        outgoing.receive_maximum(Some(0));
+        assert_eq!(outgoing.free_slots(), MAX_INFLIGHT - 1);
     }
 
     // use super::{Outgoing, MAX_INFLIGHT};
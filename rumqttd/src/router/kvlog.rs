@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tracing::warn;
+
+use crate::segments::Syncer;
+
+const INSERT: u8 = 1;
+const REMOVE: u8 = 0;
+
+/// Key value pairs persisted as an append only log of inserts and removals in a single file.
+/// The log is compacted every time it is loaded. Appends are synced to disk by `syncer`.
+pub(crate) struct KvLog {
+    path: PathBuf,
+    file: Arc<File>,
+    syncer: Syncer,
+}
+
+impl KvLog {
+    pub fn open<P: AsRef<Path>>(path: P, syncer: Syncer) -> io::Result<KvLog> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(KvLog {
+            path,
+            file: Arc::new(file),
+            syncer,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replays the log and returns all the live pairs
+    pub fn load(&mut self) -> io::Result<HashMap<String, Bytes>> {
+        let mut log = Bytes::from(fs::read(&self.path)?);
+
+        let mut pairs = HashMap::new();
+        while log.has_remaining() {
+            let Some((op, key, value)) = read_record(&mut log) else {
+                warn!("Ignoring truncated record at the end of {:?}", self.path);
+                break;
+            };
+
+            match op {
+                INSERT => pairs.insert(key, value),
+                _ => pairs.remove(&key),
+            };
+        }
+
+        // Compact the log to hold only the live pairs
+        let tmp = self.path.with_extension("compact");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let mut record = BytesMut::new();
+        for (key, value) in pairs.iter() {
+            record.clear();
+            write_record(&mut record, INSERT, key, Some(value));
+            writer.write_all(&record)?;
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.file = Arc::new(OpenOptions::new().append(true).open(&self.path)?);
+
+        Ok(pairs)
+    }
+
+    pub fn insert(&mut self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.append(INSERT, key, Some(value))
+    }
+
+    pub fn remove(&mut self, key: &str) -> io::Result<()> {
+        self.append(REMOVE, key, None)
+    }
+
+    fn append(&mut self, op: u8, key: &str, value: Option<&[u8]>) -> io::Result<()> {
+        let len = value.map_or(0, |v| 4 + v.len());
+        let mut record = BytesMut::with_capacity(1 + 2 + key.len() + len);
+        write_record(&mut record, op, key, value);
+
+        self.file.as_ref().write_all(&record)?;
+        self.syncer.sync(self.file.clone());
+        Ok(())
+    }
+}
+
+/// Record is [op][key length][key] followed by [value length][value] for inserts
+fn write_record(buffer: &mut BytesMut, op: u8, key: &str, value: Option<&[u8]>) {
+    buffer.put_u8(op);
+    buffer.put_u16(key.len() as u16);
+    buffer.extend_from_slice(key.as_bytes());
+    if let Some(value) = value {
+        buffer.put_u32(value.len() as u32);
+        buffer.extend_from_slice(value);
+    }
+}
+
+fn read_record(log: &mut Bytes) -> Option<(u8, String, Bytes)> {
+    if log.remaining() < 3 {
+        return None;
+    }
+
+    let op = log.get_u8();
+    let key_len = log.get_u16() as usize;
+    if log.remaining() < key_len {
+        return None;
+    }
+    let key = String::from_utf8(log.split_to(key_len).to_vec()).ok()?;
+
+    if op != INSERT {
+        return Some((REMOVE, key, Bytes::new()));
+    }
+
+    if log.remaining() < 4 {
+        return None;
+    }
+    let len = log.get_u32() as usize;
+    if log.remaining() < len {
+        return None;
+    }
+
+    Some((op, key, log.split_to(len)))
+}
@@ -1,19 +1,24 @@
 use super::Ack;
 use slab::Slab;
-use tracing::{info, trace};
+use tracing::{error, info, trace, warn};
 
+use crate::protocol::v5::publish::properties;
 use crate::protocol::{
-    matches, ConnAck, ConnAckProperties, PingResp, PubAck, PubComp, PubRec, PubRel, Publish,
-    PublishProperties, SubAck, UnsubAck,
+    matches, ConnAck, ConnAckProperties, PingResp, PubAck, PubComp, PubCompReason, PubRec, PubRel,
+    Publish, PublishProperties, SubAck, UnsubAck,
+};
+use crate::router::{
+    DataRequest, FileRetainedStore, FilterIdx, RetainedStore, SubscriptionMeter, Waiters,
 };
-use crate::router::{DataRequest, FilterIdx, SubscriptionMeter, Waiters};
 use crate::{ConnectionId, Filter, Offset, RouterConfig, Topic};
 
-use crate::segments::{CommitLog, Position};
-use crate::Storage;
+use crate::segments::{CommitLog, Position, Syncer};
+use crate::{Persist, Storage};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::collections::{HashMap, VecDeque};
 use std::io;
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 type PubWithProp = (Publish, Option<PublishProperties>);
 
@@ -22,6 +27,8 @@ pub struct PublishData {
     pub publish: Publish,
     pub properties: Option<PublishProperties>,
     pub timestamp: Instant,
+    /// Client id of the publisher, to skip forwarding to subscriptions with No Local
+    pub publisher: Option<String>,
 }
 
 impl From<PubWithProp> for PublishData {
@@ -30,6 +37,7 @@ fn from((publish, properties): PubWithProp) -> Self {
             publish,
             properties,
             timestamp: Instant::now(),
+            publisher: None,
         }
     }
 }
@@ -43,11 +51,91 @@ fn size(&self) -> usize {
     }
 }
 
+/// Persisted as arrival time in unix milliseconds and client id of the publisher prefixed by its
+/// length, followed by properties in their MQTT 5 encoding and the publish itself
+impl Persist for PublishData {
+    fn serialize(&self, buffer: &mut BytesMut) {
+        // `Instant` is meaningless across restarts, so wall clock time is persisted instead
+        let arrival = SystemTime::now()
+            .checked_sub(self.timestamp.elapsed())
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_millis() as u64);
+        buffer.put_u64(arrival);
+
+        // Client ids can't be empty, so empty one stands for a publish without publisher
+        let publisher = self.publisher.as_deref().unwrap_or_default();
+        buffer.put_u16(publisher.len() as u16);
+        buffer.extend_from_slice(publisher.as_bytes());
+
+        match &self.properties {
+            Some(props) if properties::write(props, buffer).is_ok() => {}
+            _ => buffer.put_u8(0),
+        }
+
+        buffer.extend_from_slice(&self.publish.serialize());
+    }
+
+    fn deserialize(mut buffer: Bytes) -> io::Result<Self> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        if buffer.len() < 10 {
+            return Err(invalid("publish data too short".to_owned()));
+        }
+
+        let arrival = UNIX_EPOCH + Duration::from_millis(buffer.get_u64());
+        let publisher_len = buffer.get_u16() as usize;
+        if buffer.len() < publisher_len {
+            return Err(invalid("publish data too short".to_owned()));
+        }
+        let publisher = match buffer.split_to(publisher_len) {
+            publisher if publisher.is_empty() => None,
+            publisher => {
+                Some(String::from_utf8(publisher.to_vec()).map_err(|e| invalid(e.to_string()))?)
+            }
+        };
+        let mut properties = properties::read(&mut buffer).map_err(|e| invalid(e.to_string()))?;
+
+        // header, pkid and topic length
+        if buffer.len() < 5
+            || buffer.len() < 5 + u16::from_be_bytes([buffer[3], buffer[4]]) as usize
+        {
+            return Err(invalid("publish data too short".to_owned()));
+        }
+        let publish = Publish::deserialize(buffer);
+
+        let age = SystemTime::now()
+            .duration_since(arrival)
+            .unwrap_or_default();
+        let timestamp = match Instant::now().checked_sub(age) {
+            Some(timestamp) => timestamp,
+            None => {
+                // Monotonic clock started after the publish arrived (e.g. reboot), account for
+                // the age in expiry interval itself
+                if let Some(expiry) = properties
+                    .as_mut()
+                    .and_then(|p| p.message_expiry_interval.as_mut())
+                {
+                    *expiry = expiry.saturating_sub(age.as_secs() as u32);
+                }
+                Instant::now()
+            }
+        };
+
+        Ok(PublishData {
+            publish,
+            properties,
+            timestamp,
+            publisher,
+        })
+    }
+}
+
 /// Stores 'device' data and 'actions' data in native commitlog
 /// organized by subscription filter. Device data is replicated
 /// while actions data is not
 pub struct DataLog {
     pub config: RouterConfig,
+    /// Syncs segments and retained messages written to `log_dir`
+    syncer: Syncer,
     /// Native commitlog data organized by subscription. Contains
     /// device data and actions data logs.
     ///
@@ -59,20 +147,46 @@ pub struct DataLog {
     /// Map of subscription filter name to filter index
     filter_indexes: HashMap<Filter, FilterIdx>,
     retained_publishes: HashMap<Topic, PublishData>,
+    /// Persistent copy of `retained_publishes`, if any
+    retained_store: Option<Arc<dyn RetainedStore>>,
     /// List of filters associated with a topic
     publish_filters: HashMap<Topic, Vec<FilterIdx>>,
 }
 
 impl DataLog {
-    pub fn new(config: RouterConfig) -> io::Result<DataLog> {
+    pub fn new(config: RouterConfig, syncer: Syncer) -> io::Result<DataLog> {
         let mut native = Slab::new();
         let mut filter_indexes = HashMap::new();
-        let retained_publishes = HashMap::new();
+        let mut retained_publishes = HashMap::new();
         let publish_filters = HashMap::new();
 
+        let retained_store: Option<Arc<dyn RetainedStore>> =
+            match (config.retained_store.clone(), &config.log_dir) {
+                (Some(store), _) => Some(store),
+                (None, Some(dir)) => {
+                    let path = dir.join("retained.log");
+                    Some(Arc::new(FileRetainedStore::with_syncer(
+                        path,
+                        syncer.clone(),
+                    )?))
+                }
+                (None, None) => None,
+            };
+
+        if let Some(store) = &retained_store {
+            for (topic, message) in store.load()? {
+                match PublishData::deserialize(message) {
+                    Ok(data) => {
+                        retained_publishes.insert(topic, data);
+                    }
+                    Err(e) => warn!(error = ?e, "Ignoring unreadable retained message on {topic}"),
+                }
+            }
+        }
+
         if let Some(warmup_filters) = config.initialized_filters.clone() {
             for filter in warmup_filters {
-                let data = Data::new(&filter, &config);
+                let data = Data::new(&filter, &config, &syncer);
 
                 // Add commitlog to datalog and add datalog index to filter to
                 // datalog index map
@@ -83,10 +197,12 @@ pub fn new(config: RouterConfig) -> io::Result<DataLog> {
 
         Ok(DataLog {
             config,
+            syncer,
             native,
             publish_filters,
             filter_indexes,
             retained_publishes,
+            retained_store,
         })
     }
 
@@ -151,7 +267,7 @@ pub fn next_native_offset(&mut self, filter: &str) -> (FilterIdx, Offset) {
         let (filter_idx, data) = match filter_indexes.get(filter) {
             Some(idx) => (*idx, self.native.get(*idx).unwrap()),
             None => {
-                let data = Data::new(filter, &self.config);
+                let data = Data::new(filter, &self.config, &self.syncer);
 
                 // Add commitlog to datalog and add datalog index to filter to
                 // datalog index map
@@ -172,11 +288,27 @@ pub fn next_native_offset(&mut self, filter: &str) -> (FilterIdx, Offset) {
         (filter_idx, data.log.next_offset())
     }
 
+    /// Number of messages in commitlog of the filter after `cursor`
+    pub fn count_after(&self, filter_idx: FilterIdx, cursor: Offset) -> u64 {
+        self.native.get(filter_idx).map_or(0, |data| {
+            let (_, next) = data.log.next_offset();
+            next.saturating_sub(cursor.1)
+        })
+    }
+
+    /// Offset from which the last `count` messages in commitlog of the filter are read
+    pub fn offset_from_end(&self, filter_idx: FilterIdx, count: u64) -> Offset {
+        // unwrap fine as `filter_idx` is from `next_native_offset`
+        let data = self.native.get(filter_idx).unwrap();
+        data.log.offset_from_end(count)
+    }
+
     pub fn native_readv(
         &self,
         filter_idx: FilterIdx,
         offset: Offset,
         len: u64,
+        nolocal: Option<&str>,
     ) -> io::Result<(Position, Vec<(PubWithProp, Offset)>)> {
         // unwrap to get index of `self.native` is fine here, because when a new subscribe packet
         // arrives in `Router::handle_device_payload`, it first calls the function
@@ -192,6 +324,11 @@ pub fn native_readv(
 
         let now = Instant::now();
         o.retain_mut(|(pubdata, _)| {
+            // Skip publishes of the subscriber itself
+            if nolocal.is_some() && pubdata.publisher.as_deref() == nolocal {
+                return false;
+            }
+
             // Keep data if no properties exists, which implies no message expiry!
             let Some(properties) = pubdata.properties.as_mut() else {
                 return true;
@@ -262,11 +399,51 @@ pub fn insert_to_retained_publishes(
         topic: Topic,
     ) {
         let pub_with_props = (publish, publish_properties);
-        self.retained_publishes.insert(topic, pub_with_props.into());
+        let data: PublishData = pub_with_props.into();
+        if let Some(store) = &self.retained_store {
+            let mut message = BytesMut::new();
+            data.serialize(&mut message);
+            if let Err(e) = store.insert(&topic, message.freeze()) {
+                error!(error = ?e, "Failed to persist retained message on {topic}");
+            }
+        }
+
+        self.retained_publishes.insert(topic, data);
+    }
+
+    /// Removes retained message on the topic. Returns false if there wasn't one
+    pub fn remove_from_retained_publishes(&mut self, topic: Topic) -> bool {
+        if self.retained_publishes.remove(&topic).is_none() {
+            return false;
+        }
+
+        if let Some(store) = &self.retained_store {
+            if let Err(e) = store.remove(&topic) {
+                error!(error = ?e, "Failed to remove persisted retained message on {topic}");
+            }
+        }
+
+        true
     }
 
-    pub fn remove_from_retained_publishes(&mut self, topic: Topic) {
-        self.retained_publishes.remove(&topic);
+    /// Retained messages which haven't expired. Unlike `read_retained_messages`, this doesn't
+    /// update their expiry intervals, which is meant for delivering them to subscribers
+    pub fn retained_messages(&self) -> impl Iterator<Item = &Publish> {
+        let now = Instant::now();
+        self.retained_publishes
+            .values()
+            .filter(move |pubdata| {
+                let expiry_interval = pubdata
+                    .properties
+                    .as_ref()
+                    .and_then(|properties| properties.message_expiry_interval);
+
+                match expiry_interval {
+                    Some(interval) => ((now - pubdata.timestamp).as_secs() as u32) < interval,
+                    None => true,
+                }
+            })
+            .map(|pubdata| &pubdata.publish)
     }
 
     pub fn read_retained_messages(&mut self, filter: &str) -> Vec<PubWithProp> {
@@ -274,7 +451,8 @@ pub fn read_retained_messages(&mut self, filter: &str) -> Vec<PubWithProp> {
         let now = Instant::now();
 
         // discard expired retained messages
-        self.retained_publishes.retain(|_, pubdata| {
+        let store = &self.retained_store;
+        self.retained_publishes.retain(|topic, pubdata| {
             // Keep data if no properties exists, which implies no message expiry!
             let Some(properties) = pubdata.properties.as_mut() else {
                 return true;
@@ -294,6 +472,8 @@ pub fn read_retained_messages(&mut self, filter: &str) -> Vec<PubWithProp> {
                 // set message_expiry_interval to (original value - time spent waiting in server)
                 // ref: https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901112
                 *message_expiry_interval -= time_spent;
+            } else if let Some(Err(e)) = store.as_ref().map(|store| store.remove(topic)) {
+                error!(error = ?e, "Failed to remove expired retained message on {topic}");
             }
 
             is_valid
@@ -317,11 +497,12 @@ pub struct Data<T> {
 
 impl<T> Data<T>
 where
-    T: Storage + Clone,
+    T: Storage + Persist + Clone,
 {
-    pub fn new(filter: &str, router_config: &RouterConfig) -> Data<T> {
+    pub fn new(filter: &str, router_config: &RouterConfig, syncer: &Syncer) -> Data<T> {
         let mut max_segment_size = router_config.max_segment_size;
         let mut max_mem_segments = router_config.max_segment_count;
+        let mut max_disk_segments = router_config.max_disk_segments;
 
         // Override segment config for selected filter
         if let Some(config) = &router_config.custom_segment {
@@ -330,12 +511,31 @@ pub fn new(filter: &str, router_config: &RouterConfig) -> Data<T> {
                     info!("Overriding segment config for filter: {}", filter);
                     max_segment_size = segment_config.max_segment_size;
                     max_mem_segments = segment_config.max_segment_count;
+                    if let Some(count) = segment_config.max_disk_segments {
+                        max_disk_segments = count;
+                    }
                 }
             }
         }
 
-        // max_segment_size: usize, max_mem_segments: usize
-        let log = CommitLog::new(max_segment_size, max_mem_segments).unwrap();
+        let log = match &router_config.log_dir {
+            Some(dir) if max_disk_segments > 0 => {
+                let dir = dir.join(filter_dir(filter));
+                CommitLog::with_disk(
+                    max_segment_size,
+                    max_mem_segments,
+                    &dir,
+                    max_disk_segments,
+                    syncer.clone(),
+                )
+                .unwrap_or_else(|e| {
+                    error!(error = ?e, "Failed to open {dir:?}, keeping {filter} in memory");
+                    CommitLog::new(max_segment_size, max_mem_segments).unwrap()
+                })
+            }
+            // max_segment_size: usize, max_mem_segments: usize
+            _ => CommitLog::new(max_segment_size, max_mem_segments).unwrap(),
+        };
 
         let waiters = Waiters::with_capacity(10);
         let metrics = SubscriptionMeter::default();
@@ -367,6 +567,20 @@ pub fn append(
     }
 }
 
+/// Name of the directory in which segments of `filter` are persisted. Everything apart from
+/// alphanumerics, `-` and `_` is percent encoded to keep the name a single valid path component
+fn filter_dir(filter: &str) -> String {
+    let mut dir = String::with_capacity(filter.len());
+    for byte in filter.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => dir.push(byte as char),
+            _ => dir.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    dir
+}
+
 /// Acks log for a subscription
 #[derive(Debug)]
 pub struct AckLog {
@@ -406,15 +620,32 @@ pub fn pubrec(&mut self, publish: Publish, props: Option<PublishProperties>, ack
         self.committed.push_back(ack);
     }
 
+    /// Pubrec which rejects the publish, so there is nothing to record
+    pub fn pubrec_failure(&mut self, ack: PubRec) {
+        let ack = Ack::PubRec(ack);
+        self.committed.push_back(ack);
+    }
+
     pub fn pubrel(&mut self, ack: PubRel) {
         let ack = Ack::PubRel(ack);
         self.committed.push_back(ack);
     }
 
-    pub fn pubcomp(&mut self, ack: PubComp) -> Option<(Publish, Option<PublishProperties>)> {
-        let ack = Ack::PubComp(ack);
-        self.committed.push_back(ack);
-        self.recorded.pop_front()
+    /// Completes the qos 2 publish with the same pkid and returns it. Pubcomp says that the
+    /// pkid isn't found if there is no such publish, e.g. it was rejected
+    pub fn pubcomp(&mut self, mut ack: PubComp) -> Option<(Publish, Option<PublishProperties>)> {
+        let recorded = self
+            .recorded
+            .iter()
+            .position(|(publish, _)| publish.pkid == ack.pkid)
+            .and_then(|i| self.recorded.remove(i));
+
+        if recorded.is_none() {
+            ack.reason = PubCompReason::PacketIdentifierNotFound;
+        }
+
+        self.committed.push_back(Ack::PubComp(ack));
+        recorded
     }
 
     pub fn pingresp(&mut self, ack: PingResp) {
@@ -434,9 +665,14 @@ pub fn readv(&mut self) -> &mut VecDeque<Ack> {
 
 #[cfg(test)]
 mod test {
-    use super::DataLog;
+    use super::{DataLog, PublishData};
+    use crate::protocol::{Publish, PublishProperties, QoS};
     use crate::router::shared_subs::Strategy;
-    use crate::RouterConfig;
+    use crate::segments::Syncer;
+    use crate::{Persist, RouterConfig};
+    use bytes::{Bytes, BytesMut};
+    use std::collections::VecDeque;
+    use std::time::Duration;
 
     #[test]
     fn publish_filters_updating_correctly_on_new_topic_subscription() {
@@ -448,8 +684,15 @@ fn publish_filters_updating_correctly_on_new_topic_subscription() {
             custom_segment: None,
             initialized_filters: None,
             shared_subscriptions_strategy: Strategy::RoundRobin,
+            log_dir: None,
+            max_disk_segments: 0,
+            log_sync_interval: 0,
+            default_session_expiry_interval: None,
+            retained_store: None,
+            acl: None,
+            authorization_handler: None,
         };
-        let mut data = DataLog::new(config).unwrap();
+        let mut data = DataLog::new(config, Syncer::spawn(Duration::ZERO)).unwrap();
         data.next_native_offset("topic/a");
         data.matches("topic/a");
 
@@ -468,8 +711,15 @@ fn publish_filters_updating_correctly_on_new_publish() {
             custom_segment: None,
             initialized_filters: None,
             shared_subscriptions_strategy: Strategy::RoundRobin,
+            log_dir: None,
+            max_disk_segments: 0,
+            log_sync_interval: 0,
+            default_session_expiry_interval: None,
+            retained_store: None,
+            acl: None,
+            authorization_handler: None,
         };
-        let mut data = DataLog::new(config).unwrap();
+        let mut data = DataLog::new(config, Syncer::spawn(Duration::ZERO)).unwrap();
         data.next_native_offset("+/+");
 
         data.matches("topic/a");
@@ -477,6 +727,147 @@ fn publish_filters_updating_correctly_on_new_publish() {
         assert_eq!(data.publish_filters.get("topic/a").unwrap().len(), 1);
     }
 
+    #[test]
+    fn retained_publishes_are_restored_from_log_dir() {
+        let dir = std::env::temp_dir().join("rumqttd-retained-publishes-restored");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let config = RouterConfig {
+            max_segment_size: 1024,
+            max_connections: 10,
+            max_segment_count: 10,
+            max_outgoing_packet_count: 1024,
+            custom_segment: None,
+            initialized_filters: None,
+            shared_subscriptions_strategy: Strategy::RoundRobin,
+            log_dir: Some(dir.clone()),
+            max_disk_segments: 0,
+            log_sync_interval: 0,
+            default_session_expiry_interval: None,
+            retained_store: None,
+            acl: None,
+            authorization_handler: None,
+        };
+
+        let properties = PublishProperties {
+            message_expiry_interval: Some(100),
+            ..Default::default()
+        };
+        let mut data = DataLog::new(config.clone(), Syncer::spawn(Duration::ZERO)).unwrap();
+        for topic in ["a/1", "a/2", "b/1"] {
+            let publish = Publish::new(topic, "payload", true);
+            data.insert_to_retained_publishes(publish, Some(properties.clone()), topic.to_owned());
+        }
+        data.remove_from_retained_publishes("a/2".to_owned());
+        drop(data);
+
+        let mut data = DataLog::new(config, Syncer::spawn(Duration::ZERO)).unwrap();
+        let retained = data.read_retained_messages("a/+");
+        assert_eq!(retained.len(), 1);
+        assert_eq!(retained[0].0, Publish::new("a/1", "payload", true));
+        assert_eq!(retained[0].1, Some(properties));
+        assert_eq!(data.read_retained_messages("#").len(), 2);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn listing_retained_publishes_doesnt_shorten_their_expiry() {
+        let config = RouterConfig {
+            max_segment_size: 1024,
+            max_connections: 10,
+            max_segment_count: 10,
+            max_outgoing_packet_count: 1024,
+            ..Default::default()
+        };
+
+        let properties = PublishProperties {
+            message_expiry_interval: Some(100),
+            ..Default::default()
+        };
+        let mut data = DataLog::new(config, Syncer::spawn(Duration::ZERO)).unwrap();
+        let publish = Publish::new("a/1", "payload", true);
+        data.insert_to_retained_publishes(publish, Some(properties), "a/1".to_owned());
+        data.retained_publishes.get_mut("a/1").unwrap().timestamp -= Duration::from_secs(10);
+
+        for _ in 0..3 {
+            assert_eq!(data.retained_messages().count(), 1);
+        }
+
+        let retained = data.read_retained_messages("#");
+        let properties = retained[0].1.as_ref().unwrap();
+        assert_eq!(properties.message_expiry_interval, Some(90));
+    }
+
+    #[test]
+    fn publish_data_persistence_roundtrip() {
+        let mut publish = Publish::new("hello/world", "payload", true);
+        publish.qos = QoS::AtLeastOnce;
+        publish.pkid = 10;
+        let properties = PublishProperties {
+            message_expiry_interval: Some(100),
+            user_properties: vec![("key".to_owned(), "value".to_owned())],
+            ..Default::default()
+        };
+        let mut data = PublishData::from((publish.clone(), Some(properties.clone())));
+        data.publisher = Some("client".to_owned());
+
+        let mut buffer = BytesMut::new();
+        data.serialize(&mut buffer);
+        let restored = PublishData::deserialize(buffer.freeze()).unwrap();
+        assert_eq!(restored.publish, publish);
+        assert_eq!(restored.properties, Some(properties));
+        assert_eq!(restored.publisher.as_deref(), Some("client"));
+
+        let data = PublishData::from((publish, None));
+        let mut buffer = BytesMut::new();
+        data.serialize(&mut buffer);
+        let restored = PublishData::deserialize(buffer.freeze()).unwrap();
+        assert_eq!(restored.properties, None);
+        assert_eq!(restored.publisher, None);
+        assert!(PublishData::deserialize(Bytes::from_static(&[0; 12])).is_err());
+    }
+
+    #[test]
+    fn publishes_read_from_disk_skip_their_publisher() {
+        let dir = std::env::temp_dir().join("rumqttd-publishes-read-from-disk");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let config = RouterConfig {
+            max_segment_size: 1024,
+            max_connections: 10,
+            max_segment_count: 2,
+            max_outgoing_packet_count: 1024,
+            log_dir: Some(dir.clone()),
+            max_disk_segments: 10,
+            ..Default::default()
+        };
+
+        // Enough publishes to push the first segments out of memory
+        let mut data = DataLog::new(config, Syncer::spawn(Duration::ZERO)).unwrap();
+        let (filter_idx, _) = data.next_native_offset("a/b");
+        for i in 0..50 {
+            let publisher = if i % 2 == 0 { "client" } else { "other" };
+            let payload = format!("{publisher}-{i:0>100}");
+            let publish = Publish::new("a/b".to_owned(), payload, false);
+            let mut publish_data = PublishData::from((publish, None));
+            publish_data.publisher = Some(publisher.to_owned());
+
+            let log = data.native.get_mut(filter_idx).unwrap();
+            log.append(publish_data, &mut VecDeque::new());
+        }
+
+        let (_, publishes) = data
+            .native_readv(filter_idx, (0, 0), 10, Some("client"))
+            .unwrap();
+        assert!(!publishes.is_empty());
+        for ((publish, _), _) in publishes {
+            assert!(publish.payload.starts_with(b"other-"));
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
     //     #[test]
     //     fn appends_are_written_to_correct_commitlog() {
     //         pretty_env_logger::init();
@@ -491,7 +882,7 @@ fn publish_filters_updating_correctly_on_new_publish() {
     //             dynamic_log: true,
     //         };
 
-    //         let mut data = DataLog::new(config).unwrap();
+    //         let mut data = DataLog::new(config, Syncer::spawn(Duration::ZERO)).unwrap();
     //         data.next_native_offset("/devices/2321/actions");
     //         for i in 0..2 {
     //             let publish = Publish::new("/devices/2321/events/imu/jsonarray", QoS::AtLeastOnce, vec![1, 2, 3]);
@@ -15,11 +15,14 @@
     ConnectionId, Filter, RouterId, Topic,
 };
 
+pub mod acl;
 mod alertlog;
 mod connection;
 mod graveyard;
 pub mod iobufs;
+mod kvlog;
 mod logs;
+mod retained;
 mod routing;
 mod scheduler;
 pub(crate) mod shared_subs;
@@ -27,6 +30,7 @@
 
 pub use alertlog::Alert;
 pub use connection::Connection;
+pub use retained::{FileRetainedStore, RetainedStore};
 pub use routing::Router;
 pub use waiters::Waiters;
 
@@ -61,10 +65,24 @@ pub enum Event {
     SendAlerts,
     /// Collect and send meters to all meters links
     SendMeters,
-    /// Get metrics of a connection or all connections
-    PrintStatus(Print),
+    /// Remove saved state of sessions which have expired
+    PurgeSessions,
+    /// Get metrics of a connection or all connections. Replied as JSON, which is null when
+    /// the requested item doesn't exist
+    PrintStatus(Print, flume::Sender<serde_json::Value>),
+    /// Disconnect client with given client id. Replies if the client was connected
+    DisconnectClient(String, flume::Sender<bool>),
+    /// Delete saved session of client with given client id. Replies if there was one
+    DeleteSession(String, flume::Sender<bool>),
+    /// Delete retained message on a topic. Replies if there was one
+    DeleteRetained(Topic, flume::Sender<bool>),
     /// Publish Will message
     PublishWill((String, Option<String>)),
+    /// Client connected to the node of this replica. Its connection on this node is dropped and
+    /// its session is handed over
+    ClientConnected(String),
+    /// Session of a client which was handed over by the node of this replica
+    SessionTakeover(ClusterSession),
 }
 
 /// Notification from router to connection
@@ -74,12 +92,13 @@ pub enum Notification {
     Forward(Forward),
     /// Acks reply for connection data
     DeviceAck(Ack),
-    /// Data reply
-    ReplicaData {
-        cursor: (u64, u64),
-        size: usize,
-        payload: Bytes,
-    },
+    /// Publish of a client on this node, to be replicated to another node of the cluster
+    ReplicaData(Forward),
+    /// Client connected to this node, its connection and session on other nodes have to be
+    /// taken over
+    ClientConnected(String),
+    /// Session of a client which connected to the node of this replica
+    SessionTakeover(ClusterSession),
     /// Acks reply for replication data
     ReplicaAcks {
         offset: (u64, u64),
@@ -110,6 +129,19 @@ fn from(notification: Notification) -> Self {
     }
 }
 
+/// Session of a client which moves between nodes of a cluster. Offsets of commitlogs differ
+/// between nodes, so cursors are carried as number of messages that weren't delivered yet.
+/// Nodes append replicated publishes in the order they receive them, which isn't the same on
+/// every node, so delivery resumes from about the same message: messages published around the
+/// handover might be delivered twice or missed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClusterSession {
+    pub client_id: String,
+    /// Filter, qos and number of undelivered messages of every subscription. `None` when the
+    /// node doesn't have a session of the client
+    pub subscriptions: Option<Vec<(Filter, u8, u64)>>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Forward {
     pub cursor: Option<(u64, u64)>,
@@ -196,6 +228,10 @@ pub struct DataRequest {
     max_count: usize,
     pub(crate) forward_retained: bool,
     pub(crate) group: Option<String>,
+    /// Skip publishes of the subscriber itself
+    pub(crate) nolocal: bool,
+    /// Forward publishes with the retain flag they are published with
+    pub(crate) preserve_retain: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -388,6 +424,8 @@ pub fn get_total_size(&self) -> usize {
 pub struct OutgoingMeter {
     pub publish_count: usize,
     pub total_size: usize,
+    /// Publishes dropped for exceeding the maximum packet size of the client
+    pub dropped_count: usize,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -410,4 +448,5 @@ pub enum Print {
     Subscriptions,
     Subscription(Filter),
     Waiters(Filter),
+    Retained,
 }
@@ -0,0 +1,106 @@
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tracing::info;
+
+use super::kvlog::KvLog;
+use crate::segments::Syncer;
+use crate::Topic;
+
+/// Storage for retained messages which outlives the broker. Messages are handed over as opaque
+/// bytes which carry the publish, its properties and arrival time, so that message expiry keeps
+/// counting across restarts.
+pub trait RetainedStore: Send + Sync {
+    /// All the retained messages in the store. Called once when the router starts
+    fn load(&self) -> io::Result<Vec<(Topic, Bytes)>>;
+    /// Insert or replace the retained message on `topic`
+    fn insert(&self, topic: &str, message: Bytes) -> io::Result<()>;
+    /// Remove the retained message on `topic`
+    fn remove(&self, topic: &str) -> io::Result<()>;
+}
+
+/// Stores retained messages as an append only log of inserts and removals in a single file. The
+/// log is compacted every time it is loaded.
+pub struct FileRetainedStore {
+    log: Mutex<KvLog>,
+}
+
+impl FileRetainedStore {
+    /// Opens the store, syncing every write on a thread of its own
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<FileRetainedStore> {
+        Self::with_syncer(path, Syncer::spawn(Duration::ZERO))
+    }
+
+    pub(crate) fn with_syncer<P: AsRef<Path>>(
+        path: P,
+        syncer: Syncer,
+    ) -> io::Result<FileRetainedStore> {
+        let log = KvLog::open(path, syncer)?;
+        Ok(FileRetainedStore {
+            log: Mutex::new(log),
+        })
+    }
+}
+
+impl RetainedStore for FileRetainedStore {
+    fn load(&self) -> io::Result<Vec<(Topic, Bytes)>> {
+        let mut log = self.log.lock();
+        let retained = log.load()?;
+
+        info!(
+            "Loaded {} retained messages from {:?}",
+            retained.len(),
+            log.path()
+        );
+        Ok(retained.into_iter().collect())
+    }
+
+    fn insert(&self, topic: &str, message: Bytes) -> io::Result<()> {
+        self.log.lock().insert(topic, &message)
+    }
+
+    fn remove(&self, topic: &str) -> io::Result<()> {
+        self.log.lock().remove(topic)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileRetainedStore, RetainedStore};
+    use bytes::Bytes;
+
+    #[test]
+    fn file_store_survives_reopen() {
+        let path = std::env::temp_dir().join("rumqttd-file-retained-store");
+        let _ = std::fs::remove_file(&path);
+
+        let store = FileRetainedStore::new(&path).unwrap();
+        assert!(store.load().unwrap().is_empty());
+        store.insert("a/b", Bytes::from_static(b"1")).unwrap();
+        store.insert("a/c", Bytes::from_static(b"2")).unwrap();
+        store.insert("a/b", Bytes::from_static(b"3")).unwrap();
+        store.remove("a/c").unwrap();
+        drop(store);
+
+        let store = FileRetainedStore::new(&path).unwrap();
+        let retained = store.load().unwrap();
+        assert_eq!(retained, vec![("a/b".to_owned(), Bytes::from_static(b"3"))]);
+
+        // Appends continue after compaction
+        store.insert("a/d", Bytes::from_static(b"4")).unwrap();
+        let mut retained = store.load().unwrap();
+        retained.sort();
+        assert_eq!(
+            retained,
+            vec![
+                ("a/b".to_owned(), Bytes::from_static(b"3")),
+                ("a/d".to_owned(), Bytes::from_static(b"4"))
+            ]
+        );
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,27 +1,31 @@
 use crate::protocol::{
     ConnAck, ConnAckProperties, ConnectReturnCode, Disconnect, DisconnectReasonCode, LastWill,
     LastWillProperties, Packet, PingResp, PubAck, PubAckReason, PubComp, PubCompReason, PubRec,
-    PubRecReason, PubRel, PubRelReason, Publish, PublishProperties, QoS, SubAck,
+    PubRecReason, PubRel, PubRelReason, Publish, PublishProperties, QoS, RetainForwardRule, SubAck,
     SubscribeReasonCode, UnsubAck, UnsubAckReason,
 };
+use crate::router::acl::{Action, Authorizer};
 use crate::router::alertlog::alert;
 use crate::router::scheduler::{PauseReason, Tracker};
-use crate::router::{ConnectionEvents, Forward};
-use crate::segments::Position;
+use crate::router::{ClusterSession, ConnectionEvents, Forward};
+use crate::segments::{Position, Syncer};
 use crate::*;
+use bytes::Bytes;
 use flume::{bounded, Receiver, RecvError, Sender, TryRecvError};
 use slab::Slab;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::str::Utf8Error;
 use std::thread;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 use tracing::{debug, error, info, trace, warn};
 
 use super::alertlog::{Alert, AlertLog};
-use super::graveyard::Graveyard;
+use super::graveyard::{
+    FileSessionStore, Graveyard, InflightPublish, MemorySessionStore, SessionStore,
+};
 use super::iobufs::{Incoming, Outgoing};
-use super::logs::{AckLog, DataLog};
+use super::logs::{AckLog, DataLog, PublishData};
 use super::scheduler::{ScheduleReason, Scheduler};
 use super::shared_subs::SharedGroup;
 use super::{
@@ -55,6 +59,9 @@ pub enum RouterError {
 // TODO: set this to some appropriate value
 const TOPIC_ALIAS_MAX: u16 = 4096;
 
+/// Publishes buffered for a link to another node of the cluster, while it isn't reading them
+const MAX_REPLICA_BUFFER: usize = 100_000;
+
 pub struct Router {
     id: RouterId,
     /// Id of this router. Used to index native commitlog to store data from
@@ -102,6 +109,23 @@ pub struct Router {
     shared_subscriptions: HashMap<String, SharedGroup>,
     /// Will messages per client_id
     last_wills: HashMap<String, (LastWill, Option<LastWillProperties>)>,
+    /// Authorization of publishes and subscriptions of restricted connections
+    authorizer: Authorizer,
+    /// Links of the cluster to other nodes, which receive publishes of clients on this node
+    replicas: Vec<ConnectionId>,
+    /// Connections resuming a session which this node doesn't have, waiting on other nodes of
+    /// the cluster to hand it over
+    handovers: HashMap<ConnectionId, Handover>,
+}
+
+/// Connack of a client which is held back till every other node of the cluster hands over the
+/// session of the client, if it has one
+struct Handover {
+    properties: ConnAckProperties,
+    /// Links of nodes which are yet to hand over the session
+    replicas: Vec<ConnectionId>,
+    /// Subscriptions of sessions which were handed over
+    sessions: Vec<Vec<(Filter, u8, u64)>>,
 }
 
 impl Router {
@@ -120,11 +144,36 @@ pub fn new(router_id: RouterId, config: RouterConfig) -> Router {
             ..RouterMeter::default()
         };
 
+        // Only retained messages and sessions are persisted when no filter retains segments
+        let disk_segments = config.max_disk_segments > 0
+            || config
+                .custom_segment
+                .iter()
+                .flat_map(|segments| segments.values())
+                .any(|segment| segment.max_disk_segments.unwrap_or(0) > 0);
+        if let (Some(dir), false) = (&config.log_dir, disk_segments) {
+            warn!("max_disk_segments is 0, segments won't be persisted in {dir:?}");
+        }
+
+        // Writes to log_dir are synced to disk off the router thread
+        let syncer = Syncer::spawn(Duration::from_millis(config.log_sync_interval));
+        let session_store: Box<dyn SessionStore> = match &config.log_dir {
+            Some(dir) => match FileSessionStore::new(dir.join("sessions.log"), syncer.clone()) {
+                Ok(store) => Box::new(store),
+                Err(e) => {
+                    error!(error = ?e, "Failed to open session store, keeping sessions in memory");
+                    Box::<MemorySessionStore>::default()
+                }
+            },
+            None => Box::<MemorySessionStore>::default(),
+        };
+
+        let authorizer = Authorizer::new(config.acl.clone(), config.authorization_handler.clone());
         let max_connections = config.max_connections;
         Router {
             id: router_id,
             config: config.clone(),
-            graveyard: Graveyard::new(),
+            graveyard: Graveyard::new(session_store),
             meters,
             alerts,
             connections,
@@ -132,7 +181,7 @@ pub fn new(router_id: RouterId, config: RouterConfig) -> Router {
             subscription_map: Default::default(),
             ibufs,
             obufs,
-            datalog: DataLog::new(config.clone()).unwrap(),
+            datalog: DataLog::new(config.clone(), syncer).unwrap(),
             alertlog: AlertLog::new(config),
             ackslog,
             scheduler: Scheduler::with_capacity(max_connections),
@@ -143,6 +192,9 @@ pub fn new(router_id: RouterId, config: RouterConfig) -> Router {
             cache: Some(VecDeque::with_capacity(MAX_CHANNEL_CAPACITY)),
             shared_subscriptions: HashMap::new(),
             last_wills: HashMap::new(),
+            authorizer,
+            replicas: Vec::new(),
+            handovers: HashMap::new(),
         }
     }
 
@@ -255,12 +307,45 @@ fn events(&mut self, id: ConnectionId, data: Event) {
             Event::SendMeters => {
                 self.send_meters();
             }
-            Event::PrintStatus(metrics) => print_status(self, metrics),
+            Event::PurgeSessions => {
+                let purged = self.graveyard.purge_expired();
+                if purged > 0 {
+                    info!("Purged {purged} expired sessions");
+                }
+            }
+            Event::PrintStatus(metrics, tx) => {
+                let status = status(self, metrics);
+                tx.try_send(status).ok();
+            }
+            Event::DisconnectClient(client_id, tx) => {
+                let connection_id = self.connection_map.get(&client_id).copied();
+                if let Some(id) = connection_id {
+                    info!(client_id, "Disconnecting client on admin request");
+                    let reason = DisconnectReasonCode::AdministrativeAction;
+                    self.handle_disconnection(id, Some(reason));
+                }
+
+                tx.try_send(connection_id.is_some()).ok();
+            }
+            Event::DeleteSession(client_id, tx) => {
+                let deleted = self.graveyard.remove(&client_id);
+                if deleted {
+                    info!(client_id, "Deleted saved session on admin request");
+                }
+
+                tx.try_send(deleted).ok();
+            }
+            Event::DeleteRetained(topic, tx) => {
+                let deleted = self.datalog.remove_from_retained_publishes(topic);
+                tx.try_send(deleted).ok();
+            }
             Event::PublishWill((client_id, _tenant_id)) => self.handle_last_will(
                 client_id,
                 #[cfg(feature = "validate-tenant-prefix")]
                 _tenant_id,
             ),
+            Event::ClientConnected(client_id) => self.handle_client_connected(id, client_id),
+            Event::SessionTakeover(session) => self.handle_session_takeover(id, session),
         }
     }
 
@@ -306,8 +391,9 @@ fn handle_new_connection(
         let previous_session = saved.as_ref().is_some_and(|s| s.session_state.is_some());
         // for qos2 pending pubrels
         let mut pending_acks = VecDeque::new();
+        let mut inflight = Vec::new();
 
-        let tracker = if !clean_session {
+        let mut tracker = if !clean_session {
             // if there was some saved state, restore the metrics
             // and get the session's state if present
             let saved_state = saved.and_then(|saved| {
@@ -323,7 +409,7 @@ fn handle_new_connection(
                     connection.subscriptions = session_state.subscriptions;
                     // for using in acklog
                     pending_acks.clone_from(&session_state.unacked_pubrels);
-                    outgoing.unacked_pubrels = session_state.unacked_pubrels;
+                    inflight = session_state.inflight;
                     session_state.tracker
                 },
             )
@@ -333,6 +419,29 @@ fn handle_new_connection(
             Tracker::new(client_id.clone())
         };
 
+        // Saved state might outlive the commitlogs it points to when it is restored after a
+        // restart. Point requests to current commitlogs and keep cursors within them
+        for request in tracker.data_requests.iter_mut() {
+            let (filter_idx, next_offset) = self.datalog.next_native_offset(&request.filter);
+            request.filter_idx = filter_idx;
+            if request.cursor > next_offset {
+                request.cursor = next_offset;
+            }
+        }
+
+        let retransmissions = inflight
+            .into_iter()
+            .filter_map(|inflight| {
+                let request = tracker
+                    .data_requests
+                    .iter()
+                    .find(|request| request.filter == inflight.filter)?;
+
+                retransmission(&self.datalog, request, inflight)
+            })
+            .collect();
+        outgoing.resume(pending_acks.clone(), retransmissions);
+
         let ackslog = AckLog::new();
 
         let time = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
@@ -348,13 +457,29 @@ fn handle_new_connection(
         }
 
         if let Some(will) = connection.last_will.take() {
-            self.last_wills.insert(
-                client_id.clone(),
-                (will, connection.last_will_properties.take()),
-            );
+            let topic = std::str::from_utf8(&will.topic).unwrap_or_default();
+            if authorize(&self.authorizer, &connection, Action::Publish, topic) {
+                self.last_wills.insert(
+                    client_id.clone(),
+                    (will, connection.last_will_properties.take()),
+                );
+            } else {
+                warn!("Dropping last will on unauthorized topic {}", topic);
+            }
         }
 
+        let replica = connection.replica;
+        let restricted = connection.restricted;
+        let mut properties = ConnAckProperties {
+            topic_alias_max: Some(TOPIC_ALIAS_MAX),
+            ..Default::default()
+        };
+        connection.capabilities.advertise(&mut properties);
+
         let connection_id = self.connections.insert(connection);
+        if replica {
+            self.replicas.push(connection_id);
+        }
         assert_eq!(self.ibufs.insert(incoming), connection_id);
         assert_eq!(self.obufs.insert(outgoing), connection_id);
 
@@ -370,18 +495,24 @@ fn handle_new_connection(
             .check_tracker_duplicates(connection_id)
             .is_none());
 
-        let ack = ConnAck {
-            session_present: !clean_session && previous_session,
-            code: ConnectReturnCode::Success,
-        };
-
-        let properties = ConnAckProperties {
-            topic_alias_max: Some(TOPIC_ALIAS_MAX),
-            ..Default::default()
-        };
-
         let ackslog = self.ackslog.get_mut(connection_id).unwrap();
-        ackslog.connack(connection_id, ack, Some(properties));
+        // Other nodes of the cluster might have the session which the client is resuming
+        let handover = restricted && !clean_session && !previous_session;
+        if handover && !self.replicas.is_empty() {
+            let handover = Handover {
+                properties,
+                replicas: self.replicas.clone(),
+                sessions: Vec::new(),
+            };
+            self.handovers.insert(connection_id, handover);
+        } else {
+            let ack = ConnAck {
+                session_present: !clean_session && previous_session,
+                code: ConnectReturnCode::Success,
+            };
+
+            ackslog.connack(connection_id, ack, Some(properties));
+        }
 
         pending_acks.into_iter().for_each(|pkid| {
             // NOTE: will it be better if we store the whole PubRel
@@ -393,10 +524,24 @@ fn handle_new_connection(
             ackslog.pubrel(pubrel)
         });
 
+        // Links of other nodes are sent publishes as soon as they are appended, which shouldn't
+        // get ahead of the connack
+        if replica {
+            ack_device_data(ackslog, self.obufs.get_mut(connection_id).unwrap());
+        }
+
         self.scheduler
             .reschedule(connection_id, ScheduleReason::Init);
 
         self.router_meters.total_connections += 1;
+
+        // Sessions of clients are cluster wide, other nodes hand over the session of this client
+        if restricted {
+            for replica in self.replicas.clone() {
+                let notification = Notification::ClientConnected(client_id.clone());
+                self.notify_replica(replica, notification);
+            }
+        }
     }
 
     fn handle_new_meter(&mut self, tx: Sender<Vec<Meter>>) {
@@ -438,6 +583,21 @@ fn handle_disconnection(&mut self, id: ConnectionId, reason: Option<DisconnectRe
                 }
             };
 
+            // Pending acks, like connack of a connection which was just made and taken over by
+            // another node, are sent before disconnect
+            if let Some(ackslog) = self.ackslog.get_mut(id) {
+                if let Some(handover) = self.handovers.remove(&id) {
+                    let ack = ConnAck {
+                        session_present: false,
+                        code: ConnectReturnCode::Success,
+                    };
+
+                    ackslog.connack(id, ack, Some(handover.properties));
+                }
+
+                ack_device_data(ackslog, outgoing);
+            }
+
             let disconnect = Disconnect { reason_code };
 
             let disconnect_notification = Notification::Disconnect(disconnect, None);
@@ -457,6 +617,8 @@ fn handle_disconnection(&mut self, id: ConnectionId, reason: Option<DisconnectRe
         let mut tracker = self.scheduler.remove(id);
         self.connection_map.remove(&client_id);
         self.ackslog.remove(id);
+        self.replicas.retain(|&replica| replica != id);
+        self.handovers.remove(&id);
 
         // Don't remove connection id from readyqueue with index. This will
         // remove wrong connection from readyqueue. Instead just leave disconnected
@@ -464,7 +626,6 @@ fn handle_disconnection(&mut self, id: ConnectionId, reason: Option<DisconnectRe
         // self.readyqueue.remove(id);
 
         let inflight_data_requests = self.datalog.clean(id);
-        let retransmissions = outgoing.retransmission_map();
 
         // Remove connections from all groups and
         // discard empty group ( group with no client )
@@ -494,40 +655,74 @@ fn handle_disconnection(&mut self, id: ConnectionId, reason: Option<DisconnectRe
             connection.events.events.pop_front();
         }
 
+        // Sessions without an expiry interval of their own, which is the case for all MQTT 3.1.1
+        // clients, end with the connection if they are clean and otherwise use router's default
+        let session_expiry_interval = match connection.session_expiry_interval {
+            Some(interval) => Some(interval),
+            None if connection.clean => Some(0),
+            None => self.config.default_session_expiry_interval,
+        };
+
         // Save state for persistent sessions
-        if !connection.clean {
+        if session_expiry_interval != Some(0) {
             // Add inflight data requests back to tracker
             inflight_data_requests
                 .into_iter()
                 .for_each(|r| tracker.register_data_request(r));
 
-            for request in tracker.data_requests.iter_mut() {
-                if let Some(cursor) = retransmissions.get(&request.filter_idx) {
-                    request.cursor = *cursor;
-                    // reset the group cursor
-                    if let Some(group_name) = &request.group {
-                        // TODO: Test this more
-                        self.shared_subscriptions
-                            .get_mut(group_name)
-                            .expect("group must exists")
-                            .cursor = *cursor;
-                    }
-                }
-            }
+            // Publishes which weren't acknowledged are sent again, with the same packet ids,
+            // when the session resumes
+            let inflight = outgoing
+                .inflight()
+                .filter_map(|(pkid, filter_idx, cursor)| {
+                    let request = tracker
+                        .data_requests
+                        .iter()
+                        .find(|request| request.filter_idx == filter_idx)?;
+
+                    Some(InflightPublish {
+                        pkid,
+                        filter: request.filter.clone(),
+                        cursor,
+                    })
+                })
+                .collect();
 
             self.graveyard.save_state(
                 tracker,
                 connection.subscriptions,
                 connection.events,
                 outgoing.unacked_pubrels,
+                inflight,
+                session_expiry_interval,
             );
         } else {
             tracker.pause(PauseReason::Busy);
             let id = tracker.id.clone();
             // Only save metrics in clean session
-            self.graveyard.save_metrics(id, connection.events);
+            self.graveyard.save_metrics(
+                id,
+                connection.events,
+                self.config.default_session_expiry_interval,
+            );
         }
         self.router_meters.total_connections -= 1;
+
+        // Nodes which are gone don't hand over sessions
+        if connection.replica {
+            let handed_over: Vec<ConnectionId> = self
+                .handovers
+                .iter_mut()
+                .filter_map(|(&client, handover)| {
+                    handover.replicas.retain(|&replica| replica != id);
+                    handover.replicas.is_empty().then_some(client)
+                })
+                .collect();
+
+            for client in handed_over {
+                self.complete_handover(client);
+            }
+        }
     }
 
     /// Handles new incoming data on a topic
@@ -564,6 +759,48 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                     let qos = publish.qos;
                     let pkid = publish.pkid;
 
+                    let connection = self.connections.get(id).unwrap();
+                    let capabilities = &connection.capabilities;
+                    let unsupported = if qos > capabilities.max_qos() {
+                        Some(DisconnectReasonCode::QoSNotSupported)
+                    } else if publish.retain && !capabilities.retain_available {
+                        Some(DisconnectReasonCode::RetainNotSupported)
+                    } else {
+                        None
+                    };
+
+                    if let Some(code) = unsupported {
+                        error!(reason = ?code, "Publish uses unavailable capability");
+                        self.router_meters.failed_publishes += 1;
+                        disconnect = true;
+                        disconnect_reason = Some(code);
+                        break;
+                    }
+
+                    let topic = publish_topic(connection, &publish, &properties);
+                    if !topic.map_or(true, |t| {
+                        authorize(&self.authorizer, connection, Action::Publish, t)
+                    }) {
+                        warn!("Publish not authorized");
+                        self.router_meters.failed_publishes += 1;
+
+                        let ackslog = self.ackslog.get_mut(id).unwrap();
+                        match qos {
+                            QoS::AtLeastOnce => ackslog.puback(PubAck {
+                                pkid,
+                                reason: PubAckReason::NotAuthorized,
+                            }),
+                            QoS::ExactlyOnce => ackslog.pubrec_failure(PubRec {
+                                pkid,
+                                reason: PubRecReason::NotAuthorized,
+                            }),
+                            QoS::AtMostOnce => continue,
+                        }
+
+                        force_ack = true;
+                        continue;
+                    }
+
                     // Prepare acks for the above publish
                     // If any of the publish in the batch results in force flush,
                     // set global force flush flag. Force flush is triggered when the
@@ -606,6 +843,7 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                     };
 
                     self.router_meters.total_publishes += 1;
+                    let replica_data = self.replica_data(id, &publish, &properties);
 
                     // Try to append publish to commitlog
                     match append_to_commitlog(
@@ -616,11 +854,15 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                         &mut self.notifications,
                         &mut self.connections,
                     ) {
-                        Ok(_offset) => {
+                        Ok(offset) => {
                             // Even if one of the data in the batch is appended to commitlog,
                             // set new data. This triggers notifications to wake waiters.
                             // Don't overwrite this flag to false if it is already true.
                             new_data = true;
+
+                            if let Some((publish, properties)) = replica_data {
+                                self.replicate(publish, properties, offset);
+                            }
                         }
                         Err(e) => {
                             // Disconnect on bad publishes
@@ -673,7 +915,57 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                             filter = filter_path;
                         };
 
+                        if group.is_some() && f.nolocal {
+                            error!("No Local can't be set on shared subscriptions");
+                            disconnect = true;
+                            disconnect_reason = Some(DisconnectReasonCode::ProtocolError);
+                            break;
+                        }
+
+                        if !authorize(&self.authorizer, connection, Action::Subscribe, &filter) {
+                            warn!("Subscription not authorized");
+                            return_codes.push(SubscribeReasonCode::NotAuthorized);
+                            continue;
+                        }
+
                         let subscription_id = props.as_ref().and_then(|p| p.id);
+                        let capabilities = &connection.capabilities;
+                        let unsupported =
+                            if group.is_some() && !capabilities.shared_subscription_available {
+                                Some(SubscribeReasonCode::SharedSubscriptionsNotSupported)
+                            } else if filter.contains(['+', '#'])
+                                && !capabilities.wildcard_subscription_available
+                            {
+                                Some(SubscribeReasonCode::WildcardSubscriptionsNotSupported)
+                            } else if subscription_id.is_some()
+                                && !capabilities.subscription_identifiers_available
+                            {
+                                Some(SubscribeReasonCode::SubscriptionIdNotSupported)
+                            } else {
+                                None
+                            };
+
+                        if let Some(code) = unsupported {
+                            warn!(reason = ?code, "Subscription uses unavailable capability");
+                            return_codes.push(code);
+                            continue;
+                        }
+
+                        let subscribed = connection.subscriptions.contains(&f.path);
+                        let quota_exceeded = connection
+                            .max_subscriptions
+                            .is_some_and(|max| connection.subscriptions.len() >= max);
+
+                        if quota_exceeded && !subscribed {
+                            warn!("Subscription quota exceeded");
+                            return_codes.push(SubscribeReasonCode::QuotaExceeded);
+                            continue;
+                        }
+
+                        // Subscriptions are granted the maximum QoS which is available
+                        if f.qos > capabilities.max_qos() {
+                            f.qos = capabilities.max_qos();
+                        }
 
                         if subscription_id == Some(0) {
                             error!("Subscription identifier can't be 0");
@@ -812,12 +1104,16 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                     // successfully in graveyard.
                     let (publish, props) = match ackslog.pubcomp(pubcomp) {
                         Some(v) => v,
+                        // Publish was rejected or lost with previous connection. Pubcomp
+                        // says so
                         None => {
-                            disconnect = true;
-                            break;
+                            self.scheduler.reschedule(id, ScheduleReason::IncomingAck);
+                            continue;
                         }
                     };
 
+                    let replica_data = self.replica_data(id, &publish, &props);
+
                     // Try to append publish to commitlog
                     match append_to_commitlog(
                         id,
@@ -827,11 +1123,15 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                         &mut self.notifications,
                         &mut self.connections,
                     ) {
-                        Ok(_offset) => {
+                        Ok(offset) => {
                             // Even if one of the data in the batch is appended to commitlog,
                             // set new data. This triggers notifications to wake waiters.
                             // Don't overwrite this flag to false if it is already true.
                             new_data = true;
+
+                            if let Some((publish, properties)) = replica_data {
+                                self.replicate(publish, properties, offset);
+                            }
                         }
                         Err(e) => {
                             // Disconnect on bad publishes
@@ -866,10 +1166,22 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
 
                     force_ack = true;
                 }
-                Packet::Disconnect(_, _) => {
+                Packet::Disconnect(_, properties) => {
                     let span = tracing::info_span!("disconnect");
                     let _guard = span.enter();
                     disconnect = true;
+
+                    if let Some(interval) = properties.and_then(|p| p.session_expiry_interval) {
+                        let connection = self.connections.get_mut(id).unwrap();
+                        // Session which ends with the connection can't be extended on disconnect
+                        // ref: https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901211
+                        if connection.session_expiry_interval == Some(0) && interval != 0 {
+                            warn!("Ignoring session expiry interval set on disconnect");
+                        } else {
+                            connection.session_expiry_interval = Some(interval);
+                        }
+                    }
+
                     // delete the last will message
                     self.last_wills.remove(&client_id);
                     break;
@@ -957,14 +1269,19 @@ fn prepare_filter(
                 .insert(filter_path.clone(), subscription_id);
         }
 
-        // check is group is None because retained messages aren't sent
-        // for shared subscriptions
-        // TODO: use retain forward rules
-        let forward_retained = group.is_none();
-
         // call to `insert(_)` returns `true` if it didn't contain the filter_path already
         // i.e. its a new subscription
-        if connection.subscriptions.insert(filter_path.clone()) {
+        let new_subscription = connection.subscriptions.insert(filter_path.clone());
+
+        // retained messages aren't sent for shared subscriptions
+        let forward_retained = group.is_none()
+            && match filter.retain_forward_rule {
+                RetainForwardRule::OnEverySubscribe => true,
+                RetainForwardRule::OnNewSubscribe => new_subscription,
+                RetainForwardRule::Never => false,
+            };
+
+        if new_subscription {
             let request = DataRequest {
                 filter: filter_path.clone(),
                 filter_idx,
@@ -972,19 +1289,43 @@ fn prepare_filter(
                 cursor,
                 read_count: 0,
                 max_count: 100,
-                // set true for new subscriptions
                 forward_retained,
                 group,
+                nolocal: filter.nolocal,
+                preserve_retain: filter.preserve_retain,
             };
 
             self.scheduler.track(id, request);
             self.scheduler.reschedule(id, ScheduleReason::NewFilter);
             debug_assert!(self.scheduler.check_tracker_duplicates(id).is_none())
-        }
+        } else {
+            // Re-subscription replaces options of the existing subscription. Its request is
+            // either tracked, parked in waiters or waiting in notifications to be tracked again
+            let request = self
+                .scheduler
+                .take(id, filter_path)
+                .or_else(|| self.datalog.remove_waiters_for_id(id, filter_path))
+                .or_else(|| {
+                    let index = self
+                        .notifications
+                        .iter()
+                        .position(|(i, request)| *i == id && &request.filter == filter_path)?;
+                    self.notifications.remove(index).map(|(_, request)| request)
+                });
 
-        // TODO: figure out how we can update existing DataRequest
-        // helpful in re-subscriptions and forwarding retained messages on
-        // every subscribe
+            match request {
+                Some(mut request) => {
+                    request.qos = filter.qos as u8;
+                    request.nolocal = filter.nolocal;
+                    request.preserve_retain = filter.preserve_retain;
+                    request.forward_retained |= forward_retained;
+
+                    self.scheduler.track(id, request);
+                    self.scheduler.reschedule(id, ScheduleReason::NewFilter);
+                }
+                None => warn!("Request of existing subscription on {filter_path} not found"),
+            }
+        }
 
         let meter = &mut self.ibufs.get_mut(id).unwrap().meter;
         meter.register_subscription(filter_path.clone());
@@ -1016,6 +1357,8 @@ fn consume(&mut self) -> Option<()> {
 
         // We always try to ack when ever a connection is scheduled
         ack_device_data(ackslog, outgoing);
+        // Inflight publishes of a resumed session go before new publishes
+        outgoing.retransmit();
 
         let connection = &mut self.connections[id];
 
@@ -1123,6 +1466,7 @@ pub fn handle_last_will(
             ..Default::default()
         });
 
+        let replica_data = (publish.clone(), properties.clone());
         match append_will_message(
             publish,
             properties,
@@ -1131,7 +1475,10 @@ pub fn handle_last_will(
             #[cfg(feature = "validate-tenant-prefix")]
             tenant_prefix,
         ) {
-            Ok(_offset) => {
+            Ok(offset) => {
+                let (publish, properties) = replica_data;
+                self.replicate(publish, properties, offset);
+
                 // Prepare all the consumers which are waiting for new data
                 while let Some((id, request)) = self.notifications.pop_front() {
                     self.scheduler.track(id, request);
@@ -1149,6 +1496,214 @@ pub fn handle_last_will(
         };
     }
 
+    /// Copy of a publish of a client to replicate to other nodes, with its topic alias resolved.
+    /// Publishes which came from other nodes aren't replicated again
+    fn replica_data(
+        &self,
+        id: ConnectionId,
+        publish: &Publish,
+        properties: &Option<PublishProperties>,
+    ) -> Option<(Publish, Option<PublishProperties>)> {
+        let connection = self.connections.get(id)?;
+        if self.replicas.is_empty() || connection.replica {
+            return None;
+        }
+
+        let topic = publish_topic(connection, publish, properties)?;
+        let mut replica = publish.clone();
+        replica.topic = Bytes::copy_from_slice(topic.as_bytes());
+        replica.pkid = 0;
+        replica.dup = false;
+
+        let mut properties = properties.clone();
+        if let Some(properties) = properties.as_mut() {
+            properties.topic_alias = None;
+        }
+
+        Some((replica, properties))
+    }
+
+    /// Sends a publish to links of other nodes of the cluster
+    fn replicate(
+        &mut self,
+        publish: Publish,
+        properties: Option<PublishProperties>,
+        offset: Offset,
+    ) {
+        for &id in self.replicas.iter() {
+            let Some(outgoing) = self.obufs.get_mut(id) else {
+                continue;
+            };
+
+            let mut buffer = outgoing.data_buffer.lock();
+            if buffer.len() >= MAX_REPLICA_BUFFER {
+                warn!(
+                    client_id = outgoing.client_id,
+                    "Replica buffer full, dropping publish"
+                );
+                continue;
+            }
+
+            buffer.push_back(Notification::ReplicaData(Forward {
+                cursor: Some(offset),
+                size: publish.len(),
+                publish: publish.clone(),
+                properties: properties.clone(),
+            }));
+            drop(buffer);
+
+            outgoing.handle.try_send(()).ok();
+        }
+    }
+
+    /// Sends a notification, which isn't a publish, to the link of another node
+    fn notify_replica(&mut self, id: ConnectionId, notification: Notification) {
+        let Some(outgoing) = self.obufs.get_mut(id) else {
+            return;
+        };
+
+        outgoing.data_buffer.lock().push_back(notification);
+        outgoing.handle.try_send(()).ok();
+    }
+
+    /// Disconnects a client which connected to the node of replica `id` and hands its session
+    /// over to that node. The other node waits on this one to ack its client, so a session is
+    /// handed over even when this node doesn't have one
+    fn handle_client_connected(&mut self, id: ConnectionId, client_id: String) {
+        let span = tracing::info_span!("cluster_takeover", client_id);
+        let _guard = span.enter();
+
+        let connection_id = self.connection_map.get(&client_id).copied();
+        if let Some(connection_id) = connection_id {
+            if self.connections[connection_id].restricted {
+                info!("Client connected to another node, session taken over");
+                let reason = DisconnectReasonCode::SessionTakenOver;
+                self.handle_disconnection(connection_id, Some(reason));
+            } else {
+                warn!("Client on another node has client id of a local link");
+            }
+        }
+
+        let subscriptions = self
+            .graveyard
+            .retrieve(&client_id)
+            .and_then(|saved| saved.session_state)
+            .map(|state| {
+                state
+                    .tracker
+                    .data_requests
+                    .iter()
+                    .map(|request| {
+                        // Publishes which weren't acknowledged are delivered again by the
+                        // other node
+                        let cursor = state
+                            .inflight
+                            .iter()
+                            .filter(|inflight| inflight.filter == request.filter)
+                            .fold(request.cursor, |cursor, inflight| {
+                                cursor.min(inflight.cursor)
+                            });
+                        let pending = self.datalog.count_after(request.filter_idx, cursor);
+                        (request.filter.clone(), request.qos, pending)
+                    })
+                    .collect()
+            });
+
+        let session = ClusterSession {
+            client_id,
+            subscriptions,
+        };
+
+        self.notify_replica(id, Notification::SessionTakeover(session));
+    }
+
+    /// Collects the session of a client handed over by the node of replica `id`. Client is acked
+    /// once every node handed over its session
+    fn handle_session_takeover(&mut self, id: ConnectionId, session: ClusterSession) {
+        let span = tracing::info_span!("cluster_takeover", client_id = session.client_id);
+        let _guard = span.enter();
+
+        let Some(&connection_id) = self.connection_map.get(&session.client_id) else {
+            warn!("Client disconnected before its session was handed over");
+            return;
+        };
+
+        let Some(handover) = self.handovers.get_mut(&connection_id) else {
+            // Clients which are acked already were told that there is no session to resume
+            if session.subscriptions.is_some() {
+                warn!("Dropping session handed over after client was acked");
+            }
+            return;
+        };
+
+        handover.replicas.retain(|&replica| replica != id);
+        handover.sessions.extend(session.subscriptions);
+        if handover.replicas.is_empty() {
+            self.complete_handover(connection_id);
+        }
+    }
+
+    /// Acks a client which waited on other nodes to hand over its session and restores its
+    /// subscriptions, if any node had the session. Undelivered messages are read from the
+    /// commitlogs of this node, which has them replicated
+    fn complete_handover(&mut self, id: ConnectionId) {
+        let Some(handover) = self.handovers.remove(&id) else {
+            return;
+        };
+
+        let session_present = !handover.sessions.is_empty();
+        let ack = ConnAck {
+            session_present,
+            code: ConnectReturnCode::Success,
+        };
+
+        // Connack goes before publishes of restored subscriptions
+        let ackslog = self.ackslog.get_mut(id).unwrap();
+        ackslog.connack(id, ack, Some(handover.properties));
+        ack_device_data(ackslog, self.obufs.get_mut(id).unwrap());
+
+        if session_present {
+            info!("Restoring session handed over by another node");
+        }
+
+        for (path, qos, pending) in handover.sessions.into_iter().flatten() {
+            let Some(qos) = protocol::qos(qos) else {
+                continue;
+            };
+
+            let (group, filter) = match extract_group(&path) {
+                Some((group, filter)) => (Some(group), filter),
+                None => (None, path.clone()),
+            };
+
+            if !authorize(
+                &self.authorizer,
+                &self.connections[id],
+                Action::Subscribe,
+                &filter,
+            ) {
+                warn!("Subscription on {} not authorized", filter);
+                continue;
+            }
+
+            let (idx, mut cursor) = self.datalog.next_native_offset(&filter);
+            // Shared groups keep their own cursor on this node
+            if group.is_none() {
+                cursor = self.datalog.offset_from_end(idx, pending);
+            }
+
+            let filter = protocol::Filter {
+                path,
+                qos,
+                nolocal: false,
+                preserve_retain: false,
+                retain_forward_rule: protocol::RetainForwardRule::Never,
+            };
+
+            self.prepare_filter(id, cursor, idx, &filter, group, None);
+        }
+    }
+
     fn send_meters(&mut self) {
         let mut meters = Vec::with_capacity(10);
         if let Some(router_meter) = self.router_meters.get() {
@@ -1234,8 +1789,8 @@ fn append_to_commitlog(
     }
 
     // after recording retained message, we also send that message to existing subscribers
-    // as normal publish message. Therefore we are setting retain to false
-    publish.retain = false;
+    // as normal publish message. Retain flag is cleared while forwarding, unless the
+    // subscription has Retain As Published
     let pkid = publish.pkid;
 
     let filter_idxs = datalog.matches(topic);
@@ -1253,8 +1808,9 @@ fn append_to_commitlog(
     let mut o = (0, 0);
     for filter_idx in filter_idxs {
         let datalog = datalog.native.get_mut(filter_idx).unwrap();
-        let publish_data = (publish.clone(), properties.clone());
-        let (offset, filter) = datalog.append(publish_data.into(), notifications);
+        let mut publish_data = PublishData::from((publish.clone(), properties.clone()));
+        publish_data.publisher = Some(connection.client_id.clone());
+        let (offset, filter) = datalog.append(publish_data, notifications);
         debug!(
             pkid,
             "Appended to commitlog: {}[{}, {})", filter, offset.0, offset.1,
@@ -1268,7 +1824,7 @@ fn append_to_commitlog(
 }
 
 fn append_will_message(
-    mut publish: Publish,
+    publish: Publish,
     properties: Option<PublishProperties>,
     datalog: &mut DataLog,
     notifications: &mut VecDeque<(ConnectionId, DataRequest)>,
@@ -1305,8 +1861,8 @@ fn append_will_message(
     }
 
     // after recording retained message, we also send that message to existing subscribers
-    // as normal publish message. Therefore we are setting retain to false
-    publish.retain = false;
+    // as normal publish message. Retain flag is cleared while forwarding, unless the
+    // subscription has Retain As Published
     let pkid = publish.pkid;
 
     let filter_idxs = datalog.matches(topic);
@@ -1363,6 +1919,40 @@ fn validate_and_set_topic_alias(
     Ok(())
 }
 
+/// Reads inflight publish of a resumed session from the commitlog again to resend it with its
+/// packet id, unless the commitlog doesn't have it anymore
+fn retransmission(
+    datalog: &DataLog,
+    request: &DataRequest,
+    inflight: InflightPublish,
+) -> Option<(FilterIdx, Forward)> {
+    let (_, publishes) = datalog
+        .native_readv(request.filter_idx, inflight.cursor, 1, None)
+        .ok()?;
+    let ((mut publish, properties), offset) = publishes.into_iter().next()?;
+    if offset != inflight.cursor {
+        warn!(
+            pkid = inflight.pkid,
+            "Inflight publish isn't in commitlog anymore"
+        );
+        return None;
+    }
+
+    publish.qos = protocol::qos(request.qos).unwrap();
+    publish.pkid = inflight.pkid;
+    publish.dup = true;
+    publish.retain &= request.preserve_retain;
+
+    let forward = Forward {
+        cursor: Some(offset),
+        size: 0,
+        publish,
+        properties,
+    };
+
+    Some((request.filter_idx, forward))
+}
+
 /// Sweep ackslog for all the pending acks.
 /// We write everything to outgoing buf with out worrying about buffer size
 /// because acks most certainly won't cause memory bloat
@@ -1460,6 +2050,7 @@ fn forward_device_data(
     }
 
     let mut publishes = Vec::new();
+    let nolocal = request.nolocal.then_some(connection.client_id.as_str());
 
     if request.forward_retained {
         // NOTE: ideally we want to limit the number of read messages
@@ -1476,7 +2067,7 @@ fn forward_device_data(
     }
 
     let (next, publishes_from_datalog) =
-        match datalog.native_readv(request.filter_idx, request.cursor, inflight_slots) {
+        match datalog.native_readv(request.filter_idx, request.cursor, inflight_slots, nolocal) {
             Ok(v) => v,
             Err(e) => {
                 error!(error = ?e, "Failed to read from commitlog {}", e);
@@ -1533,6 +2124,7 @@ fn forward_device_data(
 
     let qos = request.qos;
     let filter_idx = request.filter_idx;
+    let preserve_retain = request.preserve_retain;
     request.read_count += publishes.len();
     request.cursor = next;
     // println!("{:?} {:?} {}", start, next, request.read_count);
@@ -1556,6 +2148,9 @@ fn forward_device_data(
     }
 
     let subscription_id = connection.subscription_ids.get(&request.filter);
+    let publish_count = publishes.len();
+    let packet_size_limit = outgoing.packet_size_limit();
+    let mut oversized = Vec::new();
 
     // Fill and notify device data
     let forwards = publishes
@@ -1563,6 +2158,11 @@ fn forward_device_data(
         .map(|((mut publish, mut properties), offset)| {
             publish.qos = protocol::qos(qos).unwrap();
 
+            // Retained messages sent on subscription always have the retain flag set
+            if offset.is_some() {
+                publish.retain &= preserve_retain;
+            }
+
             // if there is some topic alias to use, set it in publish properties
             if topic_alias.is_some() {
                 let mut props = properties.unwrap_or_default();
@@ -1588,10 +2188,39 @@ fn forward_device_data(
                 publish,
                 properties,
             }
+        })
+        // Publishes larger than the maximum packet size of the client are dropped as if they
+        // were delivered, instead of making the client disconnect
+        .filter(|forward| {
+            let size = protocol::v5::publish::packet_len(&forward.publish, &forward.properties);
+            let fits = packet_size_limit.map_or(true, |max| size <= max);
+            if !fits {
+                oversized.push(size);
+            }
+
+            fits
         });
 
     let (len, inflight) = outgoing.push_forwards(forwards, qos, filter_idx);
 
+    for size in oversized.iter() {
+        warn!(
+            size,
+            "Dropping publish larger than max packet size of client"
+        );
+        let alert = alert::oversizedpublish(&outgoing.client_id, &request.filter, *size);
+        alertlog.log(alert);
+    }
+
+    outgoing.meter.dropped_count += oversized.len();
+
+    // A new alias is established by the first publish carrying it
+    if !topic_alias_already_exists && oversized.len() == publish_count {
+        if let Some(aliases) = connection.broker_topic_aliases.as_mut() {
+            aliases.remove_alias(&request.filter);
+        }
+    }
+
     debug!(
         inflight_count = inflight,
         forward_count = len,
@@ -1643,16 +2272,11 @@ fn retrieve_shadow(datalog: &mut DataLog, outgoing: &mut Outgoing, shadow: Shado
     }
 }
 
-fn print_status(router: &mut Router, metrics: Print) {
+/// Status of the router as requested by console
+fn status(router: &mut Router, metrics: Print) -> serde_json::Value {
     match metrics {
-        Print::Config => {
-            let config = router.config.clone();
-            println!("{config:#?}");
-        }
-        Print::Router => {
-            let metrics = router.router_meters.clone();
-            println!("{metrics:#?}");
-        }
+        Print::Config => json(&router.config),
+        Print::Router => json(&router.router_meters),
         Print::Connection(id) => {
             let metrics = router.connection_map.get(&id).map(|v| {
                 let c = router
@@ -1666,17 +2290,22 @@ fn print_status(router: &mut Router, metrics: Print) {
 
             let metrics = match metrics {
                 Some(v) => Some(v),
-                None => router.graveyard.retrieve(&id).map(|v| {
-                    (
-                        v.metrics,
-                        v.session_state
-                            .map(|s| s.tracker)
-                            .unwrap_or(Tracker::new(id)),
-                    )
+                None => router.graveyard.peek(&id).map(|v| {
+                    let tracker = match &v.session_state {
+                        Some(s) => s.tracker.clone(),
+                        None => Tracker::new(id),
+                    };
+                    (v.metrics.clone(), tracker)
                 }),
             };
 
-            println!("{metrics:#?}");
+            match metrics {
+                Some((events, tracker)) => serde_json::json!({
+                    "events": json(&events),
+                    "tracker": json(&tracker),
+                }),
+                None => serde_json::Value::Null,
+            }
         }
         Print::Subscriptions => {
             let metrics: HashMap<Filter, Vec<String>> = router
@@ -1692,28 +2321,44 @@ fn print_status(router: &mut Router, metrics: Print) {
                 })
                 .collect();
 
-            println!("{metrics:#?}");
-        }
-        Print::Subscription(filter) => {
-            let metrics = router.datalog.meter(&filter);
-            println!("{metrics:#?}");
+            json(&metrics)
         }
+        Print::Subscription(filter) => json(&router.datalog.meter(&filter)),
         Print::Waiters(filter) => {
-            if let Some(waiters) = router.datalog.waiters(&filter) {
-                let v: Vec<(String, DataRequest)> = waiters
+            let waiters = router.datalog.waiters(&filter).map(|waiters| {
+                waiters
                     .waiters()
                     .iter()
                     .map(|(id, request)| (router.obufs[*id].client_id.clone(), request.clone()))
-                    .collect();
+                    .collect::<Vec<(String, DataRequest)>>()
+            });
 
-                println!("{v:#?}");
-            }
+            json(&waiters)
         }
-        Print::ReadyQueue => {
-            let metrics = router.scheduler.readyqueue.clone();
-            println!("{metrics:#?}");
+        Print::ReadyQueue => json(&router.scheduler.readyqueue),
+        Print::Retained => {
+            let retained = router
+                .datalog
+                .retained_messages()
+                .map(|publish| {
+                    serde_json::json!({
+                        "topic": String::from_utf8_lossy(&publish.topic),
+                        "payload": String::from_utf8_lossy(&publish.payload),
+                        "qos": publish.qos as u8,
+                    })
+                })
+                .collect();
+
+            serde_json::Value::Array(retained)
         }
-    };
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> serde_json::Value {
+    serde_json::to_value(value).unwrap_or_else(|e| {
+        error!("Failed to serialize status: {e}");
+        serde_json::Value::Null
+    })
 }
 
 fn validate_subscription(
@@ -1740,6 +2385,35 @@ fn validate_subscription(
     Ok(())
 }
 
+/// Checks if connection is allowed to perform the action on the topic. Connections which aren't
+/// restricted are allowed everything
+fn authorize(
+    authorizer: &Authorizer,
+    connection: &Connection,
+    action: Action,
+    topic: &str,
+) -> bool {
+    !connection.restricted || authorizer.authorize(&connection.client_info(), action, topic)
+}
+
+/// Topic of the publish, resolving topic alias if needed. `None` if topic can't be resolved,
+/// which is handled while appending to commitlog
+fn publish_topic<'a>(
+    connection: &'a Connection,
+    publish: &'a Publish,
+    properties: &Option<PublishProperties>,
+) -> Option<&'a str> {
+    if !publish.topic.is_empty() {
+        return std::str::from_utf8(&publish.topic).ok();
+    }
+
+    let alias = properties.as_ref().and_then(|p| p.topic_alias)?;
+    connection
+        .topic_aliases
+        .get(&alias)
+        .map(|topic| topic.as_str())
+}
+
 fn validate_clientid(client_id: &str) -> Result<(), RouterError> {
     trace!("Validating Client ID = {}", client_id,);
     // Ensure that only client devices of the tenant can
@@ -1756,6 +2430,311 @@ fn extract_group(filter: &str) -> Option<(String, String)> {
             .map(|(group, path)| (group.to_string(), path.to_string()))
     })
 }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::link::local::{LinkBuilder, LinkRx, LinkTx};
+    use crate::protocol::{Filter, Subscribe};
+    use crate::router::Ack;
+    use crate::Capabilities;
+    use std::time::{Duration, Instant};
+
+    fn router() -> Sender<(ConnectionId, Event)> {
+        let config = RouterConfig {
+            max_connections: 10,
+            max_outgoing_packet_count: 200,
+            max_segment_size: 1024 * 1024,
+            max_segment_count: 10,
+            ..Default::default()
+        };
+
+        Router::new(0, config).spawn()
+    }
+
+    fn filter(path: &str, qos: QoS) -> Filter {
+        Filter {
+            path: path.to_owned(),
+            qos,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::OnNewSubscribe,
+        }
+    }
+
+    fn subscribe(tx: &mut LinkTx, filters: Vec<Filter>) {
+        let subscribe = Subscribe { pkid: 1, filters };
+        tx.try_send(Packet::Subscribe(subscribe, None)).unwrap();
+    }
+
+    /// Notifications received till the link is idle
+    fn notifications(rx: &mut LinkRx) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+        let deadline = Instant::now() + Duration::from_millis(500);
+        while let Ok(notification) = rx.recv_deadline(deadline) {
+            notifications.extend(notification);
+        }
+
+        notifications
+    }
+
+    /// Publishes forwarded till the link is idle
+    fn forwards(rx: &mut LinkRx) -> Vec<Publish> {
+        notifications(rx)
+            .into_iter()
+            .filter_map(|notification| match notification {
+                Notification::Forward(forward) => Some(forward.publish),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Topics and retain flags of forwarded publishes
+    fn topics(rx: &mut LinkRx) -> Vec<(String, bool)> {
+        forwards(rx)
+            .into_iter()
+            .map(|publish| {
+                let topic = String::from_utf8(publish.topic.to_vec()).unwrap();
+                (topic, publish.retain)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn subscription_options_are_applied_while_forwarding() {
+        let router_tx = router();
+        let link = |client_id| {
+            LinkBuilder::new(client_id, router_tx.clone())
+                .dynamic_filters(true)
+                .build()
+                .unwrap()
+        };
+
+        let (mut publisher, _publisher_rx, _) = link("publisher");
+        let (mut client, mut client_rx, _) = link("client");
+        let options = |nolocal, retain_forward_rule| {
+            vec![Filter {
+                nolocal,
+                preserve_retain: true,
+                retain_forward_rule,
+                ..filter("a/+", QoS::AtMostOnce)
+            }]
+        };
+
+        let retained = Publish::new("a/1", "retained", true);
+        publisher.try_send(Packet::Publish(retained, None)).unwrap();
+        subscribe(
+            &mut client,
+            options(true, RetainForwardRule::OnEverySubscribe),
+        );
+        assert_eq!(topics(&mut client_rx), [("a/1".to_owned(), true)]);
+
+        // Own publishes aren't forwarded and retain flag is preserved
+        client.publish("a/2", "local").unwrap();
+        let retained = Publish::new("a/3", "retained", true);
+        publisher.try_send(Packet::Publish(retained, None)).unwrap();
+        assert_eq!(topics(&mut client_rx), [("a/3".to_owned(), true)]);
+
+        // Retained messages are forwarded on every subscribe only when asked
+        subscribe(
+            &mut client,
+            options(false, RetainForwardRule::OnNewSubscribe),
+        );
+        assert_eq!(topics(&mut client_rx), []);
+        subscribe(
+            &mut client,
+            options(false, RetainForwardRule::OnEverySubscribe),
+        );
+        let mut retained = topics(&mut client_rx);
+        retained.sort();
+        assert_eq!(
+            retained,
+            [("a/1".to_owned(), true), ("a/3".to_owned(), true)]
+        );
+
+        // No Local is replaced by re-subscription as well
+        client.publish("a/4", "local").unwrap();
+        assert_eq!(topics(&mut client_rx), [("a/4".to_owned(), false)]);
+    }
+
+    #[test]
+    fn receive_maximum_and_max_packet_size_limit_forwards() {
+        let router_tx = router();
+        let (mut publisher, _publisher_rx, _) = LinkBuilder::new("publisher", router_tx.clone())
+            .build()
+            .unwrap();
+        let (mut client, mut client_rx, _) = LinkBuilder::new("client", router_tx)
+            .dynamic_filters(true)
+            .receive_maximum(Some(2))
+            .max_packet_size(Some(64))
+            .build()
+            .unwrap();
+
+        subscribe(&mut client, vec![filter("a/+", QoS::AtLeastOnce)]);
+        notifications(&mut client_rx);
+
+        publisher.publish("a/1", vec![0; 100]).unwrap();
+        for topic in ["a/2", "a/3", "a/4"] {
+            publisher.publish(topic, "small").unwrap();
+        }
+
+        // Oversized publish is dropped and doesn't take an inflight slot
+        let forwarded: Vec<String> = topics(&mut client_rx)
+            .into_iter()
+            .map(|(topic, _)| topic)
+            .collect();
+        assert_eq!(forwarded, ["a/2", "a/3"]);
+    }
+
+    #[test]
+    fn unavailable_capabilities_are_advertised_and_rejected() {
+        let capabilities = Capabilities {
+            max_qos: 1,
+            retain_available: false,
+            wildcard_subscription_available: false,
+            ..Default::default()
+        };
+
+        let (mut tx, mut rx, connack) = LinkBuilder::new("client", router())
+            .dynamic_filters(true)
+            .capabilities(capabilities)
+            .build()
+            .unwrap();
+
+        let Notification::DeviceAck(Ack::ConnAck(_, _, Some(properties))) = connack else {
+            panic!("unexpected connack {connack:?}");
+        };
+        assert_eq!(properties.max_qos, Some(1));
+        assert_eq!(properties.retain_available, Some(0));
+        assert_eq!(properties.wildcard_subscription_available, Some(0));
+        assert_eq!(properties.shared_subscription_available, None);
+
+        let filters = vec![
+            filter("a/+", QoS::AtMostOnce),
+            filter("a/b", QoS::ExactlyOnce),
+        ];
+        subscribe(&mut tx, filters);
+
+        let received = notifications(&mut rx);
+        let [Notification::DeviceAck(Ack::SubAck(suback))] = received.as_slice() else {
+            panic!("unexpected notifications {received:?}");
+        };
+        assert_eq!(
+            suback.return_codes,
+            [
+                SubscribeReasonCode::WildcardSubscriptionsNotSupported,
+                SubscribeReasonCode::QoS1
+            ]
+        );
+
+        let retained = Publish::new("a/b", "retained", true);
+        tx.try_send(Packet::Publish(retained, None)).unwrap();
+
+        assert!(matches!(
+            notifications(&mut rx).as_slice(),
+            [Notification::Disconnect(disconnect, _)]
+                if disconnect.reason_code == DisconnectReasonCode::RetainNotSupported
+        ));
+    }
+
+    #[test]
+    fn inflight_publishes_are_resent_with_same_pkids() {
+        let router_tx = router();
+        let client = || {
+            LinkBuilder::new("client", router_tx.clone())
+                .clean_session(false)
+                .build()
+                .unwrap()
+        };
+        let puback = |tx: &mut LinkTx, pkid| {
+            let puback = PubAck {
+                pkid,
+                reason: PubAckReason::Success,
+            };
+            tx.try_send(Packet::PubAck(puback, None)).unwrap();
+        };
+        // Packet ids and dup flags of forwarded publishes
+        let pkids = |rx: &mut LinkRx| -> Vec<(u16, bool)> {
+            forwards(rx)
+                .into_iter()
+                .map(|publish| (publish.pkid, publish.dup))
+                .collect()
+        };
+
+        let (mut publisher, _publisher_rx, _) = LinkBuilder::new("publisher", router_tx.clone())
+            .build()
+            .unwrap();
+        let (mut tx, mut rx, _) = client();
+        subscribe(&mut tx, vec![filter("a/b", QoS::AtLeastOnce)]);
+        notifications(&mut rx);
+
+        for _ in 0..3 {
+            publisher.publish("a/b", "hello").unwrap();
+        }
+
+        assert_eq!(pkids(&mut rx), [(1, false), (2, false), (3, false)]);
+        puback(&mut tx, 1);
+        notifications(&mut rx);
+
+        router_tx.send((rx.id(), Event::Disconnect)).unwrap();
+        let (_tx, mut rx, _) = client();
+        assert_eq!(pkids(&mut rx), [(2, true), (3, true)]);
+
+        // Packet ids of new publishes continue after the resent ones
+        publisher.publish("a/b", "hello").unwrap();
+        assert_eq!(pkids(&mut rx), [(4, false)]);
+    }
+
+    #[test]
+    fn status_of_offline_client_keeps_its_session() {
+        let mut router = Router::new(0, RouterConfig::default());
+        let subscriptions = HashSet::from(["a/b".to_owned()]);
+        router.graveyard.save_state(
+            Tracker::new("client".to_owned()),
+            subscriptions.clone(),
+            ConnectionEvents::default(),
+            VecDeque::new(),
+            Vec::new(),
+            None,
+        );
+
+        let status = status(&mut router, Print::Connection("client".to_owned()));
+        assert_eq!(status["tracker"]["id"], "client");
+
+        let saved = router.graveyard.retrieve("client").unwrap();
+        assert_eq!(saved.session_state.unwrap().subscriptions, subscriptions);
+    }
+
+    #[test]
+    fn subscriptions_beyond_quota_are_rejected() {
+        let (mut tx, mut rx, _) = LinkBuilder::new("client", router())
+            .max_subscriptions(Some(2))
+            .build()
+            .unwrap();
+
+        let filters = ["a", "b", "a", "c"]
+            .into_iter()
+            .map(|path| filter(path, QoS::AtMostOnce))
+            .collect();
+        subscribe(&mut tx, filters);
+
+        let received = notifications(&mut rx);
+        let [Notification::DeviceAck(Ack::SubAck(suback))] = received.as_slice() else {
+            panic!("unexpected notifications {received:?}");
+        };
+
+        // Subscribing again to an existing filter isn't a new subscription
+        assert_eq!(
+            suback.return_codes,
+            [
+                SubscribeReasonCode::QoS0,
+                SubscribeReasonCode::QoS0,
+                SubscribeReasonCode::QoS0,
+                SubscribeReasonCode::QuotaExceeded
+            ]
+        );
+    }
+}
+
 // #[cfg(test)]
 // #[allow(non_snake_case)]
 // mod test {
@@ -54,6 +54,17 @@ pub fn untrack(&mut self, id: ConnectionId, filter: &Filter) {
         tracker.unregister_data_request(filter.clone());
     }
 
+    /// Removes the request on `filter` from the tracker to update it
+    pub fn take(&mut self, id: ConnectionId, filter: &Filter) -> Option<DataRequest> {
+        let tracker = self.trackers.get_mut(id).unwrap();
+        let index = tracker
+            .data_requests
+            .iter()
+            .position(|request| &request.filter == filter)?;
+
+        tracker.data_requests.remove(index)
+    }
+
     pub fn trackv(&mut self, id: ConnectionId, requests: VecDeque<DataRequest>) {
         let tracker = self.trackers.get_mut(id).unwrap();
         tracker.data_requests.extend(requests);
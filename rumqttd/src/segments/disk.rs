@@ -0,0 +1,309 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tracing::{info, warn};
+
+use super::segment::{Segment, SegmentPosition};
+use super::{Persist, Storage, Syncer};
+use crate::{Cursor, Offset};
+
+/// Index file starts with the absolute offset of the first log in the chunk
+const HEADER_SIZE: usize = 8;
+/// Every log has an entry of (position, length) in the index file
+const ENTRY_SIZE: usize = 16;
+
+/// A segment which has been flushed to disk. Combines the index file, which holds the position
+/// of every log within the segment file, and the segment file, which holds the logs themselves.
+pub(crate) struct Chunk {
+    /// (position, length) of every log in the segment file
+    index: Vec<(u64, u64)>,
+    /// File with serialized logs
+    segment: File,
+    /// The absolute offset of the first log in this chunk
+    pub(crate) absolute_offset: u64,
+    index_path: PathBuf,
+    segment_path: PathBuf,
+}
+
+impl Chunk {
+    /// Writes the given memory segment to disk as chunk with id `id`
+    fn create<T: Storage + Persist + Clone>(
+        dir: &Path,
+        id: u64,
+        segment: &Segment<T>,
+        syncer: &Syncer,
+    ) -> io::Result<Chunk> {
+        let (index_path, segment_path) = paths(dir, id);
+
+        let mut data = BytesMut::with_capacity(segment.size() as usize);
+        let mut index = Vec::with_capacity(segment.data.len());
+        for item in segment.data.iter() {
+            let position = data.len() as u64;
+            item.serialize(&mut data);
+            index.push((position, data.len() as u64 - position));
+        }
+
+        let mut header = BytesMut::with_capacity(HEADER_SIZE + index.len() * ENTRY_SIZE);
+        header.put_u64(segment.absolute_offset);
+        for (position, len) in index.iter() {
+            header.put_u64(*position);
+            header.put_u64(*len);
+        }
+
+        // Files are synced later, off the router thread. An index which made it to disk without
+        // its segment file points beyond the end of it, and the chunk is ignored when it's opened
+        let mut file = File::create(&segment_path)?;
+        file.write_all(&data)?;
+        syncer.sync(Arc::new(file));
+
+        let mut file = File::create(&index_path)?;
+        file.write_all(&header)?;
+        syncer.sync(Arc::new(file));
+
+        Ok(Chunk {
+            index,
+            segment: File::open(&segment_path)?,
+            absolute_offset: segment.absolute_offset,
+            index_path,
+            segment_path,
+        })
+    }
+
+    /// Opens a chunk which was previously flushed to disk
+    fn open(dir: &Path, id: u64) -> io::Result<Chunk> {
+        let (index_path, segment_path) = paths(dir, id);
+
+        let mut header = Bytes::from(fs::read(&index_path)?);
+        if header.len() < HEADER_SIZE || (header.len() - HEADER_SIZE) % ENTRY_SIZE != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupted index file {index_path:?}"),
+            ));
+        }
+
+        let segment = File::open(&segment_path)?;
+        let segment_len = segment.metadata()?.len();
+
+        let absolute_offset = header.get_u64();
+        let mut index = Vec::with_capacity(header.len() / ENTRY_SIZE);
+        while header.has_remaining() {
+            let position = header.get_u64();
+            let len = header.get_u64();
+            if position + len > segment_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("index points beyond the end of {segment_path:?}"),
+                ));
+            }
+
+            index.push((position, len));
+        }
+
+        Ok(Chunk {
+            index,
+            segment,
+            absolute_offset,
+            index_path,
+            segment_path,
+        })
+    }
+
+    /// Number of logs in the chunk
+    #[inline]
+    fn len(&self) -> u64 {
+        self.index.len() as u64
+    }
+
+    #[inline]
+    pub(crate) fn next_offset(&self) -> u64 {
+        self.absolute_offset + self.len()
+    }
+
+    /// Same as [`Segment::readv`], but reads the logs from disk
+    pub(crate) fn readv<T: Persist>(
+        &self,
+        cursor: Cursor,
+        len: u64,
+        out: &mut Vec<(T, Offset)>,
+    ) -> io::Result<SegmentPosition> {
+        // This substraction can never overflow as checking of offset happens at
+        // `CommitLog::readv`.
+        let idx = cursor.1 - self.absolute_offset;
+        if idx >= self.len() {
+            return Ok(SegmentPosition::Done(self.next_offset()));
+        }
+
+        let limit = (idx + len).min(self.len());
+        let (start, _) = self.index[idx as usize];
+        let (last, last_len) = self.index[limit as usize - 1];
+
+        // All the requested logs are contiguous in the segment file, read them in one go
+        let mut buf = vec![0; (last + last_len - start) as usize];
+        let mut file = &self.segment;
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut buf)?;
+        let buf = Bytes::from(buf);
+
+        for (i, (position, len)) in self.index[idx as usize..limit as usize].iter().enumerate() {
+            let position = (position - start) as usize;
+            let item = T::deserialize(buf.slice(position..position + *len as usize))?;
+            out.push((item, (cursor.0, cursor.1 + i as u64)));
+        }
+
+        if limit >= self.len() {
+            Ok(SegmentPosition::Done(self.next_offset()))
+        } else {
+            Ok(SegmentPosition::Next(self.absolute_offset + limit))
+        }
+    }
+
+    fn remove(self) -> io::Result<()> {
+        fs::remove_file(&self.index_path)?;
+        fs::remove_file(&self.segment_path)
+    }
+}
+
+/// Manages the chunks of a commitlog which have been flushed to disk. Chunks are identified by
+/// the index of the segment they were created from and are always contiguous.
+pub(crate) struct DiskHandler {
+    dir: PathBuf,
+    /// Index of the oldest chunk on disk
+    head: u64,
+    chunks: VecDeque<Chunk>,
+    /// Maximum number of chunks to retain on disk
+    max_segments: usize,
+    syncer: Syncer,
+}
+
+impl DiskHandler {
+    /// Opens the directory and loads all the chunks which were previously written to it
+    pub(crate) fn new<P: AsRef<Path>>(
+        dir: P,
+        max_segments: usize,
+        syncer: Syncer,
+    ) -> io::Result<DiskHandler> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("index") {
+                continue;
+            }
+
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+
+        let mut handler = DiskHandler {
+            dir,
+            head: 0,
+            chunks: VecDeque::new(),
+            max_segments,
+            syncer,
+        };
+
+        for id in ids {
+            let chunk = match Chunk::open(&handler.dir, id) {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    warn!(error = ?e, "Ignoring unreadable chunk {id} in {:?}", handler.dir);
+                    continue;
+                }
+            };
+
+            // Only the latest contiguous run of chunks is usable for reads
+            if handler.chunks.is_empty() || handler.head + handler.chunks.len() as u64 != id {
+                if !handler.chunks.is_empty() {
+                    warn!("Chunks before {id} in {:?} aren't contiguous", handler.dir);
+                }
+                handler.chunks.clear();
+                handler.head = id;
+            }
+
+            handler.chunks.push_back(chunk);
+        }
+
+        if !handler.chunks.is_empty() {
+            info!(
+                "Loaded {} chunks from {:?}, starting at {}",
+                handler.chunks.len(),
+                handler.dir,
+                handler.head
+            );
+        }
+
+        handler.apply_retention()?;
+        Ok(handler)
+    }
+
+    /// Index of the oldest chunk on disk
+    #[inline]
+    pub(crate) fn head(&self) -> u64 {
+        self.head
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Index and absolute offset of the segment which follows the last chunk on disk
+    pub(crate) fn next(&self) -> Option<(u64, u64)> {
+        let chunk = self.chunks.back()?;
+        Some((self.head + self.chunks.len() as u64, chunk.next_offset()))
+    }
+
+    pub(crate) fn get(&self, id: u64) -> Option<&Chunk> {
+        self.chunks.get(id.checked_sub(self.head)? as usize)
+    }
+
+    /// Flushes a full memory segment with index `id` to disk
+    pub(crate) fn insert<T: Storage + Persist + Clone>(
+        &mut self,
+        id: u64,
+        segment: &Segment<T>,
+    ) -> io::Result<()> {
+        let chunk = Chunk::create(&self.dir, id, segment, &self.syncer)?;
+
+        if self.chunks.is_empty() || self.head + self.chunks.len() as u64 != id {
+            // Older chunks can't be read contiguously with this one anymore
+            for chunk in self.chunks.drain(..) {
+                chunk.remove()?;
+            }
+            self.head = id;
+        }
+
+        self.chunks.push_back(chunk);
+        self.apply_retention()
+    }
+
+    fn apply_retention(&mut self) -> io::Result<()> {
+        while self.chunks.len() > self.max_segments {
+            if let Some(chunk) = self.chunks.pop_front() {
+                chunk.remove()?;
+            }
+            self.head += 1;
+        }
+
+        Ok(())
+    }
+}
+
+fn paths(dir: &Path, id: u64) -> (PathBuf, PathBuf) {
+    (
+        dir.join(format!("{id:020}.index")),
+        dir.join(format!("{id:020}.segment")),
+    )
+}
@@ -1,11 +1,16 @@
 use crate::Offset;
-use std::{collections::VecDeque, io};
+use bytes::{Bytes, BytesMut};
+use std::{collections::VecDeque, io, path::Path};
 
+mod disk;
 mod segment;
+mod syncer;
 pub mod utils;
 
+use disk::DiskHandler;
 use segment::{Segment, SegmentPosition};
-use tracing::warn;
+pub(crate) use syncer::Syncer;
+use tracing::{error, warn};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Position {
@@ -17,6 +22,12 @@ pub trait Storage {
     fn size(&self) -> usize;
 }
 
+/// Conversion of logs to and from the bytes written in disk segments
+pub trait Persist: Sized {
+    fn serialize(&self, buffer: &mut BytesMut);
+    fn deserialize(buffer: Bytes) -> io::Result<Self>;
+}
+
 /// There are 2 limits which are enforced:
 /// - limit on size of each segment created by this log in bytes
 /// - limit on number of segments in memory
@@ -25,6 +36,11 @@ pub trait Storage {
 /// When the limit on the number of memory segments is reached, we remove the oldest segment from
 /// memory segments.
 ///
+/// If the log is created with [`CommitLog::with_disk`], every segment which fills up is also
+/// flushed to disk, where a separate limit on the number of segments is enforced. Reads of
+/// segments which are no longer in memory are served from disk, and the log resumes from the
+/// segments on disk when it is created again with the same directory.
+///
 /// This shifting of segments happens everytime the limit on the size of a segment exceeds the
 /// limit. Note that the size of a segment might go beyond the limit if the single last log was put
 /// at the offset which is within the limit but the logs size was large enough to be beyond the
@@ -54,11 +70,13 @@ pub struct CommitLog<T> {
     max_mem_segments: usize,
     /// Total size of active segment, used for enforcing the contraints.
     segments: VecDeque<Segment<T>>,
+    /// Segments flushed to disk, if persistence is enabled.
+    disk: Option<DiskHandler>,
 }
 
 impl<T> CommitLog<T>
 where
-    T: Storage + Clone,
+    T: Storage + Persist + Clone,
 {
     /// Create a new `CommitLog` with the given contraints. If `max_mem_segments` is 0, then only
     /// the active segment is maintained.
@@ -80,15 +98,56 @@ pub fn new(max_segment_size: usize, max_mem_segments: usize) -> io::Result<Self>
             max_segment_size,
             max_mem_segments,
             segments,
+            disk: None,
         })
     }
 
+    /// Create a new `CommitLog` which also flushes filled up segments to `dir`, retaining at most
+    /// `max_disk_segments` of them. Segments already present in `dir` are loaded and the log
+    /// continues from where they end. Flushed segments are synced to disk by `syncer`.
+    pub(crate) fn with_disk<P: AsRef<Path>>(
+        max_segment_size: usize,
+        max_mem_segments: usize,
+        dir: P,
+        max_disk_segments: usize,
+        syncer: Syncer,
+    ) -> io::Result<Self> {
+        let mut log = Self::new(max_segment_size, max_mem_segments)?;
+        let disk = DiskHandler::new(dir, max_disk_segments, syncer)?;
+
+        if let Some((tail, absolute_offset)) = disk.next() {
+            log.head = tail;
+            log.tail = tail;
+            log.segments.clear();
+            log.segments
+                .push_back(Segment::with_offset(absolute_offset));
+        }
+
+        log.disk = Some(disk);
+        Ok(log)
+    }
+
     #[inline]
     pub fn next_offset(&self) -> (u64, u64) {
         // `unwrap` fine as we are guaranteed that active segment always exist and is at the end
         (self.tail, self.active_segment().next_offset())
     }
 
+    /// Offset of the entry `count` entries before the end of the log. Offset of the oldest entry
+    /// in memory when there are fewer of them
+    pub fn offset_from_end(&self, count: u64) -> (u64, u64) {
+        let (_, next) = self.next_offset();
+        let target = next.saturating_sub(count);
+        for (idx, segment) in self.segments.iter().enumerate().rev() {
+            if segment.absolute_offset <= target {
+                return (self.head + idx as u64, target);
+            }
+        }
+
+        // `unwrap` fine as there is always at least the active segment
+        (self.head, self.segments.front().unwrap().absolute_offset)
+    }
+
     #[inline]
     pub fn _head_and_tail(&self) -> (u64, u64) {
         (self.head, self.tail)
@@ -148,6 +207,14 @@ fn apply_retention(&mut self) {
             // Read absolute_offset before applying memory retention, in case there is only 1
             // segment allowed.
             let absolute_offset = self.active_segment().next_offset();
+            if let Some(disk) = self.disk.as_mut() {
+                // `unwrap` fine as active segment always exists
+                let segment = self.segments.back().unwrap();
+                if let Err(e) = disk.insert(self.tail, segment) {
+                    error!(error = ?e, "Failed to flush segment {} to disk", self.tail);
+                }
+            }
+
             // If active segment is full and segments are full, apply retention policy.
             if self.memory_segments_count() >= self.max_mem_segments {
                 self.segments.pop_front();
@@ -183,6 +250,55 @@ pub fn readv(
             return Ok(Position::Done { start, end: start });
         }
 
+        if cursor.0 < self.head {
+            if let Some(disk) = self.disk.as_ref().filter(|disk| disk.len() > 0) {
+                if cursor.0 < disk.head() {
+                    // `unwrap` fine as disk isn't empty
+                    let head_absolute_offset = disk.get(disk.head()).unwrap().absolute_offset;
+                    warn!(
+                        "given index {} less than disk head {}, jumping to disk head",
+                        cursor.0, head_absolute_offset
+                    );
+                    cursor = (disk.head(), head_absolute_offset);
+                    start = cursor;
+                }
+
+                while cursor.0 < self.head {
+                    let Some(chunk) = disk.get(cursor.0) else {
+                        break;
+                    };
+
+                    if chunk.absolute_offset > cursor.1 {
+                        warn!(
+                            "offset specified {} if less than actual {}, jumping",
+                            cursor.1, chunk.absolute_offset
+                        );
+                        start.1 = chunk.absolute_offset;
+                        cursor.1 = chunk.absolute_offset;
+                    }
+
+                    match chunk.readv(cursor, len, out)? {
+                        SegmentPosition::Next(offset) => {
+                            return Ok(Position::Next {
+                                start,
+                                end: (cursor.0, offset),
+                            });
+                        }
+                        SegmentPosition::Done(next_offset) => {
+                            if next_offset >= cursor.1 {
+                                len -= next_offset - cursor.1;
+                            }
+                            cursor = (cursor.0 + 1, next_offset);
+                        }
+                    }
+
+                    if len == 0 {
+                        return Ok(Position::Next { start, end: cursor });
+                    }
+                }
+            }
+        }
+
         if cursor.0 < self.head {
             let head_absolute_offset = self.segments.front().unwrap().absolute_offset;
             warn!(
@@ -273,6 +389,7 @@ mod tests {
     use super::{Position::*, *};
     use bytes::Bytes;
     use pretty_assertions::assert_eq;
+    use std::time::Duration;
 
     fn random_payload(id: u8, size: u64) -> Bytes {
         Bytes::from(vec![id; size as usize])
@@ -347,6 +464,29 @@ fn inmemory_appends_and_retention_policy_works() {
         assert_eq!(log.len(), 2);
     }
 
+    #[test]
+    fn offset_from_end_is_within_memory() {
+        let packet_size = 1024;
+        // Segments of 10 entries, 2 of them in memory
+        let mut log: CommitLog<Bytes> = CommitLog::new(10 * 1024, 2).unwrap();
+        for i in 0..25 {
+            log.append(random_payload(i, packet_size));
+        }
+
+        assert_eq!(log.next_offset(), (2, 25));
+        assert_eq!(log.offset_from_end(0), (2, 25));
+        assert_eq!(log.offset_from_end(3), (2, 22));
+        assert_eq!(log.offset_from_end(10), (1, 15));
+        // Older entries aren't in memory anymore
+        assert_eq!(log.offset_from_end(20), (1, 10));
+
+        let mut out = Vec::new();
+        log.readv(log.offset_from_end(3), 10, &mut out).unwrap();
+        out.into_iter()
+            .enumerate()
+            .for_each(|(i, v)| verify(22 + i, packet_size, v));
+    }
+
     #[test]
     fn active_segment_appends_and_reads_works() {
         let max_segment_size = 1024 * 100; // 100K
@@ -675,4 +815,89 @@ fn read_jump_from_deleted_segment_works() {
             }
         );
     }
+
+    #[test]
+    fn read_from_disk_segments_works() {
+        let dir = std::env::temp_dir().join("rumqttd-read-from-disk-segments");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let max_segment_size = 1024 * 10; // 10K
+        let packet_size: u64 = 1024;
+        // 1 as active, 1 as inactive but in mem, 5 on disk
+        let mut log = CommitLog::with_disk(max_segment_size, 2, &dir, 5, Syncer::spawn(Duration::ZERO)).unwrap();
+
+        // Fill 4 segments and start the 5th, pushing first 3 out of memory
+        for i in 0..41 {
+            log.append(random_payload(i, packet_size));
+        }
+        assert_eq!(log.head, 3);
+        assert_eq!(log.tail, 4);
+
+        // Read across disk and memory segments
+        let mut out = Vec::new();
+        let next = log.readv((0, 0), 35, &mut out).unwrap();
+        assert_eq!(
+            next,
+            Next {
+                start: (0, 0),
+                end: (3, 35)
+            }
+        );
+        assert_eq!(out.len(), 35);
+        out.into_iter()
+            .enumerate()
+            .for_each(|(i, v)| verify(i, packet_size, v));
+
+        // Fill 5 more segments so that the oldest are removed from disk as well
+        for i in 0..50 {
+            log.append(random_payload(i, packet_size));
+        }
+
+        let mut out = Vec::new();
+        let next = log.readv((0, 0), 5, &mut out).unwrap();
+        assert_eq!(
+            next,
+            Next {
+                start: (4, 40),
+                end: (4, 45)
+            }
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn log_resumes_from_disk_segments() {
+        let dir = std::env::temp_dir().join("rumqttd-log-resumes-from-disk-segments");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let max_segment_size = 1024 * 10; // 10K
+        let packet_size: u64 = 1024;
+        let mut log = CommitLog::with_disk(max_segment_size, 2, &dir, 10, Syncer::spawn(Duration::ZERO)).unwrap();
+
+        // Fill 3 segments, last one stays active and isn't flushed
+        for i in 0..30 {
+            log.append(random_payload(i, packet_size));
+        }
+        drop(log);
+
+        let log: CommitLog<Bytes> = CommitLog::with_disk(max_segment_size, 2, &dir, 10, Syncer::spawn(Duration::ZERO)).unwrap();
+        assert_eq!(log.next_offset(), (2, 20));
+
+        let mut out = Vec::new();
+        let next = log.readv((0, 0), 30, &mut out).unwrap();
+        assert_eq!(
+            next,
+            Done {
+                start: (0, 0),
+                end: (2, 20)
+            }
+        );
+        assert_eq!(out.len(), 20);
+        out.into_iter()
+            .enumerate()
+            .for_each(|(i, v)| verify(i, packet_size, v));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
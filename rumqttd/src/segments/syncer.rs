@@ -0,0 +1,49 @@
+use std::fs::File;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flume::Sender;
+use tracing::error;
+
+/// Syncs written files to disk on a thread of its own, so that writers, like the router, don't
+/// wait on the disk. Files written within `interval` of each other are synced together, so
+/// writes of the last interval might be lost when the host crashes. The thread stops once every
+/// handle is dropped, after syncing the files which are left.
+#[derive(Debug, Clone)]
+pub(crate) struct Syncer {
+    tx: Sender<Arc<File>>,
+}
+
+impl Syncer {
+    pub(crate) fn spawn(interval: Duration) -> Syncer {
+        let (tx, rx) = flume::unbounded::<Arc<File>>();
+        let syncer = thread::Builder::new().name("syncer".to_owned());
+        syncer
+            .spawn(move || {
+                while let Ok(file) = rx.recv() {
+                    let mut files = vec![file];
+                    let deadline = Instant::now() + interval;
+                    while let Ok(file) = rx.recv_deadline(deadline) {
+                        if !files.iter().any(|f| Arc::ptr_eq(f, &file)) {
+                            files.push(file);
+                        }
+                    }
+
+                    for file in files {
+                        if let Err(e) = file.sync_data() {
+                            error!(error = ?e, "Failed to sync file to disk");
+                        }
+                    }
+                }
+            })
+            .unwrap();
+
+        Syncer { tx }
+    }
+
+    /// Syncs `file` along with other files written in the current interval
+    pub(crate) fn sync(&self, file: Arc<File>) {
+        self.tx.send(file).ok();
+    }
+}
@@ -1,6 +1,7 @@
 use crate::protocol::Publish;
-use crate::Storage;
-use bytes::Bytes;
+use crate::{Persist, Storage};
+use bytes::{Bytes, BytesMut};
+use std::io;
 
 impl Storage for Bytes {
     fn size(&self) -> usize {
@@ -21,3 +22,23 @@ fn size(&self) -> usize {
         self.len()
     }
 }
+
+impl Persist for Bytes {
+    fn serialize(&self, buffer: &mut BytesMut) {
+        buffer.extend_from_slice(self);
+    }
+
+    fn deserialize(buffer: Bytes) -> io::Result<Self> {
+        Ok(buffer)
+    }
+}
+
+impl Persist for Vec<u8> {
+    fn serialize(&self, buffer: &mut BytesMut) {
+        buffer.extend_from_slice(self);
+    }
+
+    fn deserialize(buffer: Bytes) -> io::Result<Self> {
+        Ok(buffer.to_vec())
+    }
+}
@@ -7,9 +7,10 @@
 use crate::protocol::v4::V4;
 use crate::protocol::v5::V5;
 use crate::protocol::{Packet, Protocol};
+use crate::replicator::Cluster;
 #[cfg(any(feature = "use-rustls", feature = "use-native-tls"))]
 use crate::server::tls::{self, TLSAcceptor};
-use crate::{meters, ConnectionSettings, Meter};
+use crate::{meters, ConnectionSettings, Meter, PeerCertificate};
 use flume::{RecvError, SendError, Sender};
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
@@ -75,66 +76,10 @@ pub fn new(config: Config) -> Broker {
         let router_config = config.router.clone();
         let router: Router = Router::new(config.id, router_config);
 
-        // Setup cluster if cluster settings are configured.
-        match config.cluster.clone() {
-            Some(_cluster_config) => {
-                // let node_id = cluster_config.node_id;
-                // let listen = cluster_config.listen;
-                // let seniors = cluster_config.seniors;
-                // let mut cluster = RemoteCluster::new(node_id, &listen, seniors);
-                // Broker::setup_remote_cluster(&mut router, node_id, &mut cluster);
-
-                // Start router first and then cluster in the background
-                let router_tx = router.spawn();
-                // cluster.spawn();
-                Broker { config, router_tx }
-            }
-            None => {
-                let router_tx = router.spawn();
-                Broker { config, router_tx }
-            }
-        }
+        let router_tx = router.spawn();
+        Broker { config, router_tx }
     }
 
-    // pub fn new_local_cluster(
-    //     config: Config,
-    //     node_id: NodeId,
-    //     seniors: Vec<(NodeId, Sender<ReplicationData>)>,
-    // ) -> (Broker, Sender<ReplicationData>) {
-    //     let config = Arc::new(config);
-    //     let router_config = config.router.clone();
-
-    //     let mut router = Router::new(config.id, router_config);
-    //     let (mut cluster, tx) = LocalCluster::new(node_id, seniors);
-    //     Broker::setup_local_cluster(&mut router, node_id, &mut cluster);
-
-    //     // Start router first and then cluster in the background
-    //     let router_tx = router.spawn();
-    //     cluster.spawn();
-
-    //     (Broker { config, router_tx }, tx)
-    // }
-
-    // fn setup_remote_cluster(router: &mut Router, node_id: NodeId, cluster: &mut RemoteCluster) {
-    //     // Retrieve pre-configured list of router <-> replica link from router
-    //     // and add the link to cluster
-    //     let nodes: Vec<NodeId> = (0..MAX_NODES).filter(|v| *v != node_id).collect();
-    //     for node_id in nodes {
-    //         let link = router.get_replica_handle(node_id);
-    //         cluster.add_replica_router_handle(node_id, link);
-    //     }
-    // }
-
-    // fn setup_local_cluster(router: &mut Router, node_id: NodeId, cluster: &mut LocalCluster) {
-    //     // Retrieve pre-configured list of router <-> replica link from router
-    //     // and add the link to cluster
-    //     let nodes: Vec<NodeId> = (0..MAX_NODES).filter(|v| *v != node_id).collect();
-    //     for node_id in nodes {
-    //         let link = router.get_replica_handle(node_id);
-    //         cluster.add_replica_router_handle(node_id, link);
-    //     }
-    // }
-
     // Link to get meters
     pub fn meters(&self) -> Result<meters::MetersLink, meters::LinkError> {
         let link = meters::MetersLink::new(self.router_tx.clone())?;
@@ -172,31 +117,58 @@ pub fn start(&mut self) -> Result<(), Error> {
         // so we collect handles for all of the spawned servers
         let mut server_thread_handles = Vec::new();
 
-        if let Some(metrics_config) = self.config.metrics.clone() {
-            let timer_thread = thread::Builder::new().name("timer".to_owned());
+        // Timer pushes metrics, if configured, and also drives purging of expired sessions
+        let metrics_config = self.config.metrics.clone().unwrap_or_default();
+        let timer_thread = thread::Builder::new().name("timer".to_owned());
+        let router_tx = self.router_tx.clone();
+        timer_thread.spawn(move || {
+            let mut runtime = tokio::runtime::Builder::new_current_thread();
+            let runtime = runtime.enable_all().build().unwrap();
+
+            runtime.block_on(async move {
+                timer::start(metrics_config, router_tx).await;
+            });
+        })?;
+
+        // Spawn bridges in separate threads.
+        let bridges = self
+            .config
+            .bridges
+            .clone()
+            .unwrap_or_default()
+            .into_values();
+        for bridge_config in self.config.bridge.clone().into_iter().chain(bridges) {
+            let bridge_thread = thread::Builder::new().name(bridge_config.name.clone());
             let router_tx = self.router_tx.clone();
-            timer_thread.spawn(move || {
+            bridge_thread.spawn(move || {
                 let mut runtime = tokio::runtime::Builder::new_current_thread();
                 let runtime = runtime.enable_all().build().unwrap();
 
                 runtime.block_on(async move {
-                    timer::start(metrics_config, router_tx).await;
+                    let result = match bridge_config.version {
+                        5 => bridge::start(bridge_config, router_tx, V5).await,
+                        _ => bridge::start(bridge_config, router_tx, V4).await,
+                    };
+
+                    if let Err(e) = result {
+                        error!(error=?e, "Bridge Link error");
+                    };
                 });
             })?;
         }
 
-        // Spawn bridge in a separate thread.
-        if let Some(bridge_config) = self.config.bridge.clone() {
-            let bridge_thread = thread::Builder::new().name(bridge_config.name.clone());
-            let router_tx = self.router_tx.clone();
-            bridge_thread.spawn(move || {
+        // Connections to other nodes of the cluster
+        if let Some(cluster_config) = self.config.cluster.clone() {
+            let cluster_thread = thread::Builder::new().name("cluster".to_owned());
+            let cluster = Cluster::new(cluster_config, self.router_tx.clone());
+            cluster_thread.spawn(move || {
                 let mut runtime = tokio::runtime::Builder::new_current_thread();
                 let runtime = runtime.enable_all().build().unwrap();
 
                 runtime.block_on(async move {
-                    if let Err(e) = bridge::start(bridge_config, router_tx, V4).await {
-                        error!(error=?e, "Bridge Link error");
-                    };
+                    if let Err(e) = cluster.start().await {
+                        error!(error=?e, "Cluster error");
+                    }
                 });
             })?;
         }
@@ -366,12 +338,15 @@ pub fn new(
     }
 
     // Depending on TLS or not create a new Network
-    async fn tls_accept(&self, stream: TcpStream) -> Result<(Box<dyn N>, Option<String>), Error> {
+    async fn tls_accept(
+        &self,
+        stream: TcpStream,
+    ) -> Result<(Box<dyn N>, Option<PeerCertificate>), Error> {
         #[cfg(any(feature = "use-rustls", feature = "use-native-tls"))]
         match &self.config.tls {
             Some(c) => {
-                let (tenant_id, network) = TLSAcceptor::new(c)?.accept(stream).await?;
-                Ok((network, tenant_id))
+                let (peer_certificate, network) = TLSAcceptor::new(c)?.accept(stream).await?;
+                Ok((network, peer_certificate))
             }
             None => Ok((Box::new(stream), None)),
         }
@@ -400,7 +375,7 @@ async fn start(&mut self, link_type: LinkType) -> Result<(), Error> {
                 }
             };
 
-            let (network, tenant_id) = match self.tls_accept(stream).await {
+            let (network, peer_certificate) = match self.tls_accept(stream).await {
                 Ok(o) => o,
                 Err(e) => {
                     error!(error=?e, "Tls accept error");
@@ -408,6 +383,8 @@ async fn start(&mut self, link_type: LinkType) -> Result<(), Error> {
                 }
             };
 
+            let tenant_id = peer_certificate.as_ref().and_then(|c| c.tenant_id.clone());
+
             info!(
                 name=?self.config.name, ?addr, count, tenant=?tenant_id, "accept"
             );
@@ -430,7 +407,7 @@ async fn start(&mut self, link_type: LinkType) -> Result<(), Error> {
                     task::spawn(
                         remote(
                             config,
-                            tenant_id.clone(),
+                            peer_certificate,
                             router_tx,
                             stream,
                             protocol,
@@ -446,7 +423,7 @@ async fn start(&mut self, link_type: LinkType) -> Result<(), Error> {
                 LinkType::Remote => task::spawn(
                     remote(
                         config,
-                        tenant_id.clone(),
+                        peer_certificate,
                         router_tx,
                         network,
                         protocol,
@@ -491,28 +468,33 @@ fn on_request(
 /// sending a mqtt connection packet to make the server reach its concurrent connection limit).
 async fn remote<P: Protocol>(
     config: Arc<ConnectionSettings>,
-    tenant_id: Option<String>,
+    peer_certificate: Option<PeerCertificate>,
     router_tx: Sender<(ConnectionId, Event)>,
     stream: Box<dyn N>,
     protocol: P,
     will_handlers: Arc<Mutex<HashMap<String, Sender<AwaitingWill>>>>,
 ) {
+    let max_packet_size = config.capabilities.max_packet_size;
+    let max_incoming_size = max_packet_size.map_or(config.max_payload_size, |size| {
+        config.max_payload_size.min(size as usize)
+    });
     let mut network = Network::new(
         stream,
-        config.max_payload_size,
+        max_incoming_size,
         config.max_inflight_count,
         protocol,
     );
 
-    let dynamic_filters = config.dynamic_filters;
+    let tenant_id = peer_certificate.as_ref().and_then(|c| c.tenant_id.clone());
 
-    let connect_packet = match mqtt_connect(config, &mut network).await {
-        Ok(p) => p,
-        Err(e) => {
-            error!(error=?e, "Error while handling MQTT connect packet");
-            return;
-        }
-    };
+    let (connect_packet, auth) =
+        match mqtt_connect(config.clone(), &mut network, peer_certificate.as_ref()).await {
+            Ok(p) => p,
+            Err(e) => {
+                error!(error=?e, "Error while handling MQTT connect packet");
+                return;
+            }
+        };
 
     let (mut client_id, clean_session) = match &connect_packet {
         Packet::Connect(ref connect, _, _, _, _) => {
@@ -556,8 +538,9 @@ async fn remote<P: Protocol>(
         tenant_id.clone(),
         network,
         connect_packet,
-        dynamic_filters,
+        &config,
         assigned_client_id,
+        auth,
     )
     .await
     {
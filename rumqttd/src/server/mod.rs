@@ -6,5 +6,17 @@
 
 pub use broker::Broker;
 
+/// Details of a verified client certificate
+#[derive(Debug, Clone, Default)]
+pub struct PeerCertificate {
+    /// Organization of the subject
+    pub tenant_id: Option<String>,
+    pub common_name: Option<String>,
+    /// DNS, email and URI subject alternative names
+    pub sans: Vec<String>,
+    /// Hex encoded SHA-256 of the DER encoded certificate
+    pub fingerprint: String,
+}
+
 // pub trait IO: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
 // impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> IO for T {}
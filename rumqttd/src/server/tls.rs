@@ -7,9 +7,7 @@
     tokio_native_tls::native_tls::Error as NativeTlsError,
 };
 
-use crate::TlsConfig;
-#[cfg(feature = "verify-client-cert")]
-use tokio_rustls::rustls::{server::WebPkiClientVerifier, RootCertStore};
+use crate::{PeerCertificate, TlsConfig};
 #[cfg(feature = "use-rustls")]
 use {
     rustls_pemfile::Item,
@@ -17,6 +15,12 @@
     tokio_rustls::rustls::{pki_types::PrivateKeyDer, Error as RustlsError, ServerConfig},
     tracing::error,
 };
+#[cfg(feature = "verify-client-cert")]
+use {
+    sha2::{Digest, Sha256},
+    tokio_rustls::rustls::{server::WebPkiClientVerifier, RootCertStore},
+    x509_parser::{certificate::X509Certificate, extensions::GeneralName},
+};
 
 use crate::link::network::N;
 
@@ -45,6 +49,10 @@ pub enum Error {
     ServerKeyNotFound(String),
     #[error("CA file {0} no found")]
     CaFileNotFound(String),
+    #[error("CRL file {0} not found")]
+    CrlFileNotFound(String),
+    #[error("Invalid CRL file {0}")]
+    InvalidCrl(String),
     #[cfg(not(feature = "use-native-tls"))]
     NativeTlsNotEnabled,
     #[cfg(not(feature = "use-rustls"))]
@@ -60,10 +68,52 @@ pub enum Error {
 }
 
 #[cfg(feature = "verify-client-cert")]
-/// Extract uid from certificate's subject organization field
-fn extract_tenant_id(der: &[u8]) -> Result<Option<String>, Error> {
+/// Extract identity details of the client from its certificate
+fn peer_certificate(der: &[u8]) -> Result<PeerCertificate, Error> {
     let (_, cert) =
         x509_parser::parse_x509_certificate(der).map_err(|_| Error::CertificateParse)?;
+
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(str::to_owned);
+
+    let san = cert
+        .subject_alternative_name()
+        .map_err(|_| Error::CertificateParse)?;
+    let sans = san
+        .map(|san| {
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(v) | GeneralName::RFC822Name(v) | GeneralName::URI(v) => {
+                        Some(v.to_string())
+                    }
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let fingerprint = Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+
+    Ok(PeerCertificate {
+        tenant_id: extract_tenant_id(&cert)?,
+        common_name,
+        sans,
+        fingerprint,
+    })
+}
+
+#[cfg(feature = "verify-client-cert")]
+/// Extract uid from certificate's subject organization field
+fn extract_tenant_id(cert: &X509Certificate) -> Result<Option<String>, Error> {
     let tenant_id = match cert.subject().iter_organization().next() {
         Some(org) => match org.as_str() {
             Ok(val) => val.to_string(),
@@ -102,7 +152,8 @@ pub fn new(config: &TlsConfig) -> Result<Self, Error> {
                 capath,
                 certpath,
                 keypath,
-            } => Self::rustls(capath, certpath, keypath),
+                crlpath,
+            } => Self::rustls(capath, certpath, keypath, crlpath),
             #[cfg(feature = "use-native-tls")]
             TlsConfig::NativeTls {
                 pkcs12path,
@@ -115,25 +166,28 @@ pub fn new(config: &TlsConfig) -> Result<Self, Error> {
         }
     }
 
-    pub async fn accept(&self, stream: TcpStream) -> Result<(Option<String>, Box<dyn N>), Error> {
+    pub async fn accept(
+        &self,
+        stream: TcpStream,
+    ) -> Result<(Option<PeerCertificate>, Box<dyn N>), Error> {
         match self {
             #[cfg(feature = "use-rustls")]
             TLSAcceptor::Rustls { acceptor } => {
                 let stream = acceptor.accept(stream).await?;
 
                 #[cfg(feature = "verify-client-cert")]
-                let tenant_id = {
+                let peer_certificate = {
                     let (_, session) = stream.get_ref();
                     let peer_certificates = session
                         .peer_certificates()
                         .ok_or(Error::NoPeerCertificate)?;
-                    extract_tenant_id(&peer_certificates[0])?
+                    Some(peer_certificate(&peer_certificates[0])?)
                 };
                 #[cfg(not(feature = "verify-client-cert"))]
-                let tenant_id: Option<String> = None;
+                let peer_certificate: Option<PeerCertificate> = None;
 
                 let network = Box::new(stream);
-                Ok((tenant_id, network))
+                Ok((peer_certificate, network))
             }
             #[cfg(feature = "use-native-tls")]
             TLSAcceptor::NativeTLS { acceptor } => {
@@ -181,6 +235,7 @@ fn rustls(
         ca_path: &Option<String>,
         cert_path: &String,
         key_path: &String,
+        crl_path: &Option<String>,
     ) -> Result<TLSAcceptor, Error> {
         #[cfg(feature = "verify-client-cert")]
         let Some(ca_path) = ca_path
@@ -192,8 +247,8 @@ fn rustls(
         };
 
         #[cfg(not(feature = "verify-client-cert"))]
-        if ca_path.is_some() {
-            tracing::warn!("verify-client-cert feature is disabled, CA cert will be ignored and no client authentication is done.");
+        if ca_path.is_some() || crl_path.is_some() {
+            tracing::warn!("verify-client-cert feature is disabled, CA cert and CRLs will be ignored and no client authentication is done.");
         }
 
         let (certs, key) = {
@@ -227,12 +282,26 @@ fn rustls(
                 .add(ca_cert)
                 .map_err(|_| Error::InvalidCACert(ca_path.to_string()))?;
 
+            // Acceptor is created for every connection, so changes to CRLs apply to new
+            // connections without a restart
+            let crls = match crl_path {
+                Some(crl_path) => {
+                    let crl_file = File::open(crl_path);
+                    let crl_file =
+                        crl_file.map_err(|_| Error::CrlFileNotFound(crl_path.clone()))?;
+                    rustls_pemfile::crls(&mut BufReader::new(crl_file))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|_| Error::InvalidCrl(crl_path.clone()))?
+                }
+                None => Vec::new(),
+            };
+
             // This will only return an error if no trust anchors are provided or invalid CRLs are
-            // provided. We always provide a trust anchor, and don't provide any CRLs, so it is safe
-            // to unwrap.
+            // provided. We always provide a trust anchor
             let verifier = WebPkiClientVerifier::builder(Arc::new(store))
+                .with_crls(crls)
                 .build()
-                .unwrap();
+                .map_err(|_| Error::InvalidCrl(crl_path.clone().unwrap_or_default()))?;
             builder.with_client_cert_verifier(verifier)
         };
 